@@ -0,0 +1,60 @@
+// Compares the two state lock strategies under concurrent requests:
+//
+//     cargo bench --bench lock
+//     cargo bench --bench lock --features sync-lock
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use warp::{test::request, Filter};
+use warp_rate_limit::*;
+
+const TASKS: usize = 64;
+const REQUESTS_PER_TASK: usize = 2_000;
+const CLIENTS: usize = 256;
+
+fn main() {
+    let strategy = if cfg!(feature = "sync-lock") {
+        "std::sync::Mutex (sync-lock)"
+    } else {
+        "tokio::sync::RwLock"
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Warm up, then keep the best of a few runs
+    runtime.block_on(run());
+    let best = (0..5).map(|_| runtime.block_on(run())).min().unwrap();
+
+    let requests = TASKS * REQUESTS_PER_TASK;
+    println!(
+        "{strategy}: {requests} requests from {TASKS} tasks in {best:?} ({:.0} requests/s)",
+        requests as f64 / best.as_secs_f64()
+    );
+}
+
+async fn run() -> Duration {
+    let route =
+        with_rate_limit(RateLimitConfig::max_per_minute(u32::MAX).with_ip_header("X-Client"))
+            .map(|info: RateLimitInfo| info.remaining.to_string());
+
+    let start = Instant::now();
+    let mut set = JoinSet::new();
+    for task in 0..TASKS {
+        let route = route.clone();
+        set.spawn(async move {
+            for i in 0..REQUESTS_PER_TASK {
+                let client = (task * REQUESTS_PER_TASK + i) % CLIENTS;
+                request()
+                    .header(
+                        "X-Client",
+                        format!("10.0.{}.{}", client / 256, client % 256),
+                    )
+                    .reply(&route)
+                    .await;
+            }
+        });
+    }
+    while set.join_next().await.is_some() {}
+    start.elapsed()
+}
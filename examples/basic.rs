@@ -5,12 +5,10 @@ use warp_rate_limit::*;
 #[tokio::main]
 async fn main() {
     // Let's set up a rate limit configuration of 5 requests per 30 seconds:
-    let rate_limit = RateLimitConfig {
-        max_requests: 5,
-        window: std::time::Duration::from_secs(30),
-        retry_after_format: RetryAfterFormat::HttpDate,
-        ..Default::default()
-    };
+    let rate_limit = RateLimitConfig::default()
+        .with_max_requests(5)
+        .with_window(std::time::Duration::from_secs(30))
+        .with_retry_after_format(RetryAfterFormat::HttpDate);
 
     // We'll have a single route, /hello, that will be rate limited:
     let hello = warp::path!("hello")
@@ -42,8 +40,11 @@ async fn handle_request(rate_limit_info: RateLimitInfo) -> Result<impl Reply, Re
                     e
                 );
             }
-            RateLimitError::Other(e) => {
-                eprintln!("Unexpected error setting rate limit headers: {}", e);
+            RateLimitError::HeaderExists(name) => {
+                eprintln!("Rate limit header {} was already set", name);
+            }
+            other => {
+                eprintln!("Unexpected error setting rate limit headers: {}", other);
             }
         }
     }
@@ -88,8 +89,11 @@ async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible
                         e
                     );
                 }
-                RateLimitError::Other(e) => {
-                    eprintln!("Unexpected error setting rate limit headers: {}", e);
+                RateLimitError::HeaderExists(name) => {
+                    eprintln!("Rate limit header {} was already set", name);
+                }
+                other => {
+                    eprintln!("Unexpected error setting rate limit headers: {}", other);
                 }
             }
         }
@@ -20,12 +20,10 @@ struct MyCustomError {
 #[tokio::main]
 async fn main() {
     // Configure rate limiting: 3 requests per 30 seconds
-    let rate_limit = RateLimitConfig {
-        max_requests: 3,
-        window: std::time::Duration::from_secs(30),
-        retry_after_format: RetryAfterFormat::Seconds,
-        ..Default::default()
-    };
+    let rate_limit = RateLimitConfig::default()
+        .with_max_requests(3)
+        .with_window(std::time::Duration::from_secs(30))
+        .with_retry_after_format(RetryAfterFormat::Seconds);
 
     // Create routes
     let api = warp::path!("api" / "data")
@@ -0,0 +1,138 @@
+//! Ready-made HTTP routes to manage a rate limiter, e.g. from an internal dashboard
+//!
+//! # Security
+//!
+//! **These routes don't authenticate anyone.** Whoever can reach them can list the
+//! clients of the limiter, reset them and ban them. Mount them behind your own
+//! authentication filter, or on a listener only reachable by operators:
+//!
+//! ```rust,no_run,ignore
+//! let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100))?;
+//! let admin = warp::header::exact("authorization", "Bearer s3cr3t")
+//!     .and(admin::routes(limiter.clone()));
+//! ```
+//!
+//! | Route | |
+//! |---|---|
+//! | `GET /ratelimit/keys?offset=0&limit=100` | Tracked clients, as [`EntrySnapshot`]s, see [`RateLimiterHandle::snapshot`] |
+//! | `GET /ratelimit/keys/{key}` | [`RateLimitInfo`] of a client, see [`RateLimiterHandle::usage`], 404 when untracked |
+//! | `DELETE /ratelimit/keys/{key}` | Resets a client, see [`RateLimiterHandle::reset_key`], 404 when untracked |
+//! | `POST /ratelimit/keys/{key}/ban` | Bans a client for the `duration` of the JSON body, as seconds or like `"1h30m"`, see [`RateLimiterHandle::block_key`] |
+//! | `GET /ratelimit/stats` | [`Stats`] of the limiter |
+//!
+//! Keys are the ones of the snapshots, usually ip addresses, so redacted keys can't
+//! be managed.
+
+#[cfg(doc)]
+use crate::{EntrySnapshot, RateLimitInfo};
+use crate::{RateLimitCounters, RateLimiterHandle};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+// Clients listed by `GET /ratelimit/keys` when the query doesn't say
+const DEFAULT_PAGE_SIZE: usize = 100;
+// Largest body accepted by `POST /ratelimit/keys/{key}/ban`
+const MAX_BODY_SIZE: u64 = 1024;
+
+/// Counters of a rate limiter, as served by `GET /ratelimit/stats`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Number of clients currently tracked, see [`RateLimiterHandle::key_count`]
+    pub key_count: usize,
+    /// Totals of the requests seen, see [`RateLimiterHandle::counters`]
+    pub counters: RateLimitCounters,
+    /// Counters of each label, see [`RateLimiterHandle::label_counters`]
+    pub labels: HashMap<Arc<str>, RateLimitCounters>,
+}
+
+#[derive(Deserialize)]
+struct Page {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+}
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ban {
+    #[serde(with = "crate::config::duration")]
+    duration: Duration,
+}
+
+/// The admin routes of `limiter`, see the [module](self) documentation, which must
+/// be mounted behind an authentication filter
+///
+/// Requests matching none of the routes are rejected, so that they fall through to
+/// the other routes of the server.
+pub fn routes(
+    limiter: RateLimiterHandle,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let limiter = warp::any().map(move || limiter.clone());
+    let keys = warp::path!("ratelimit" / "keys");
+    let key = warp::path!("ratelimit" / "keys" / String);
+
+    let list = keys
+        .and(warp::get())
+        .and(warp::query::<Page>())
+        .and(limiter.clone())
+        .then(|page: Page, limiter: RateLimiterHandle| async move {
+            let entries = limiter.snapshot(page.offset, page.limit).await;
+            warp::reply::json(&entries).into_response()
+        });
+    let usage = key.and(warp::get()).and(limiter.clone()).then(
+        |key: String, limiter: RateLimiterHandle| async move {
+            match limiter.usage(&key).await {
+                Some(info) => warp::reply::json(&info).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        },
+    );
+    let reset = key.and(warp::delete()).and(limiter.clone()).then(
+        |key: String, limiter: RateLimiterHandle| async move {
+            let status = if limiter.reset_key(&key).await {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::NOT_FOUND
+            };
+            status.into_response()
+        },
+    );
+    let ban = warp::path!("ratelimit" / "keys" / String / "ban")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json::<Ban>())
+        .and(limiter.clone())
+        .then(
+            |key: String, ban: Ban, limiter: RateLimiterHandle| async move {
+                limiter.block_key(&key, ban.duration).await;
+                StatusCode::NO_CONTENT.into_response()
+            },
+        );
+    let stats = warp::path!("ratelimit" / "stats")
+        .and(warp::get())
+        .and(limiter)
+        .then(|limiter: RateLimiterHandle| async move {
+            let stats = Stats {
+                key_count: limiter.key_count(),
+                counters: limiter.counters(),
+                labels: limiter.label_counters(),
+            };
+            warp::reply::json(&stats).into_response()
+        });
+
+    list.or(usage)
+        .unify()
+        .or(reset)
+        .unify()
+        .or(ban)
+        .unify()
+        .or(stats)
+        .unify()
+}
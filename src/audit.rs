@@ -0,0 +1,129 @@
+use crate::Timestamp;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// Destination of the [`AuditEntry`]s of a rate limiter, see
+/// [`RateLimitConfig::audit_sink`](crate::RateLimitConfig::audit_sink)
+///
+/// Implement it to keep a durable record of the actions taken against clients, e.g. by
+/// writing them to a database, or use the provided [`AuditRing`].
+pub trait AuditSink: Send + Sync {
+    /// Records an action taken against a client. Runs on the request's task once the
+    /// limiter state is unlocked, so it must be cheap; a panic in it is caught and
+    /// logged.
+    fn record(&self, entry: AuditEntry);
+
+    /// The `n` most recent entries, newest first, for the sinks keeping them around.
+    /// Returns nothing by default.
+    fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        let _ = n;
+        Vec::new()
+    }
+}
+
+impl<S: AuditSink + ?Sized> AuditSink for Arc<S> {
+    fn record(&self, entry: AuditEntry) {
+        (**self).record(entry)
+    }
+
+    fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        (**self).recent(n)
+    }
+}
+
+/// An action taken against a client, such as denying it access
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct AuditEntry {
+    /// Key of the client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Label of the configuration of the rate limiter
+    pub label: Option<Arc<str>>,
+    /// Why the action was taken
+    pub reason: AuditReason,
+    /// How long the action lasts, `None` when it only applies to this request
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::seconds::option"))]
+    pub duration: Option<Duration>,
+    /// When the action was taken
+    pub at: Timestamp,
+    /// When the action ends, `None` when it only applies to this request
+    pub until: Option<Timestamp>,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(
+        key: Option<String>,
+        label: Option<Arc<str>>,
+        reason: AuditReason,
+        duration: Option<Duration>,
+        at: Timestamp,
+    ) -> Self {
+        Self {
+            key,
+            label,
+            reason,
+            duration,
+            at,
+            until: duration.map(|duration| at + duration),
+        }
+    }
+}
+
+/// Why an [`AuditEntry`] was recorded
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum AuditReason {
+    /// The client is located in a denied country, see
+    /// [`RateLimitConfig::country_rules`](crate::RateLimitConfig::country_rules)
+    #[cfg(feature = "geoip")]
+    CountryDenied {
+        /// ISO code of the country of the client
+        country: String,
+    },
+    /// The client was banned by [`RateLimiterHandle::block_key`](crate::RateLimiterHandle::block_key)
+    Blocked,
+}
+
+/// An [`AuditSink`] keeping the latest entries in memory, dropping the oldest ones
+/// once full
+///
+/// Read them back with [`RateLimiterHandle::recent_audit`](crate::RateLimiterHandle::recent_audit).
+#[derive(Debug)]
+pub struct AuditRing {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditRing {
+    /// Creates a ring keeping the latest `capacity` entries
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+}
+
+impl AuditSink for AuditRing {
+    fn record(&self, entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        // Entries are pushed and popped as a whole, a panic can't leave a broken one
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    fn recent(&self, n: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.iter().rev().take(n).cloned().collect()
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    rate_limit_filter, ConfigError, RateLimitConfig, RateLimitInfo, RateLimitRecover, RateLimiter,
+};
+use std::future::ready;
+use warp::{reply::Response, Filter, Rejection};
+
+/// Creates a rate limiting filter answering rate limited requests itself, so that no
+/// recover handler is needed
+///
+/// Admitted requests extract `Ok(info)`, while rate limited ones extract the
+/// `429 Too Many Requests` response of [`crate::handle_rate_limit_rejection`], with
+/// its headers, as `Err(response)`. No `RateLimitRejection` ever leaves the filter.
+///
+/// ```rust,no_run,ignore
+/// let route = warp::path!("hello")
+///     .and(with_rate_limit_auto(RateLimitConfig::default()))
+///     .map(|admission: Result<RateLimitInfo, Response>| match admission {
+///         Ok(info) => with_rate_limit_headers("Hello world", &info).into_response(),
+///         Err(limited) => limited,
+///     });
+/// ```
+///
+/// # Panics
+///
+/// If the configuration is invalid, see [`try_with_rate_limit_auto`] for a fallible version.
+pub fn with_rate_limit_auto(
+    config: RateLimitConfig,
+) -> impl Filter<Extract = (Result<RateLimitInfo, Response>,), Error = Rejection> + Clone {
+    try_with_rate_limit_auto(config)
+        .unwrap_or_else(|e| panic!("Invalid rate limit configuration: {}", e))
+}
+
+/// Creates a filter answering rate limited requests itself, see [`with_rate_limit_auto`],
+/// after checking the configuration with [`RateLimitConfig::validate`]
+pub fn try_with_rate_limit_auto(
+    config: RateLimitConfig,
+) -> Result<
+    impl Filter<Extract = (Result<RateLimitInfo, Response>,), Error = Rejection> + Clone,
+    ConfigError,
+> {
+    RateLimitRecover::new().auto(config)
+}
+
+impl RateLimitRecover {
+    /// Creates a rate limiting filter answering rate limited requests with the
+    /// responses of this builder, see [`with_rate_limit_auto`]
+    ///
+    /// The rejection mapper of `config` is not used, as the rejections are answered
+    /// before leaving the filter.
+    ///
+    /// ```rust,no_run,ignore
+    /// let limit = RateLimitRecover::new().message("Slow down!").auto(config)?;
+    /// ```
+    pub fn auto(
+        self,
+        mut config: RateLimitConfig,
+    ) -> Result<
+        impl Filter<Extract = (Result<RateLimitInfo, Response>,), Error = Rejection> + Clone,
+        ConfigError,
+    > {
+        config.validate()?;
+        config.rejection_mapper = None;
+        Ok(rate_limit_filter(RateLimiter::new(config))
+            .map(Ok)
+            .or_else(move |rejection| {
+                ready(self.recover(rejection).map(|response| (Err(response),)))
+            }))
+    }
+}
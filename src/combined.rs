@@ -0,0 +1,193 @@
+use crate::{
+    client_key, ConfigError, RateLimitConfig, RateLimitInfo, RateLimitRejection, RateLimiter,
+    StateGuard,
+};
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, Filter, Rejection};
+
+/// Creates a filter admitting requests only if every configuration admits them, such
+/// as a strict per client limit stacked on a looser one
+///
+/// A request is counted by every configuration, or by none of them when one rejects
+/// it; the `config_index` of the rejection tells which one did. The `RateLimitInfo`
+/// is the one of the configuration with the fewest remaining requests.
+///
+/// # Panics
+///
+/// If a configuration is invalid or none is given, see [`try_with_rate_limits`] for a
+/// fallible version.
+///
+/// ```rust,no_run,ignore
+/// let route = warp::path!("search")
+///     .and(with_rate_limits([
+///         RateLimitConfig::per_second(5),
+///         RateLimitConfig::per_hour(1000),
+///     ]))
+///     .and_then(search_handler);
+/// ```
+pub fn with_rate_limits(
+    configs: impl IntoIterator<Item = RateLimitConfig>,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+    try_with_rate_limits(configs)
+        .unwrap_or_else(|e| panic!("Invalid rate limit configuration: {}", e))
+}
+
+/// Creates a filter admitting requests only if every configuration admits them, after
+/// checking each of them with [`RateLimitConfig::validate`]
+///
+/// The configurations are counted together in memory, so one with a
+/// [`RateLimitConfig::store`] is refused.
+pub fn try_with_rate_limits(
+    configs: impl IntoIterator<Item = RateLimitConfig>,
+) -> Result<impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone, ConfigError> {
+    let limiters = configs
+        .into_iter()
+        .map(|config| {
+            config.validate()?;
+            if config.store.is_some() {
+                return Err(ConfigError::StoreNotSupported("with_rate_limits"));
+            }
+            Ok(RateLimiter::new(config))
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    if limiters.is_empty() {
+        return Err(ConfigError::NoRateLimits);
+    }
+
+    // Each configuration may identify clients differently
+    let keys = limiters.iter().fold(
+        warp::any().map(Vec::new).boxed(),
+        |keys: BoxedFilter<(Vec<String>,)>, limiter| {
+            keys.and(client_key(&limiter.config()))
+                .map(|mut keys: Vec<String>, key| {
+                    keys.push(key);
+                    keys
+                })
+                .boxed()
+        },
+    );
+
+    let limiters = Arc::new(limiters);
+    Ok(warp::any()
+        .map(move || limiters.clone())
+        .and(keys)
+        .and_then(
+            |limiters: Arc<Vec<RateLimiter>>, keys: Vec<String>| async move {
+                check_rate_limits(&limiters, &keys).await
+            },
+        ))
+}
+
+async fn check_rate_limits(
+    limiters: &[RateLimiter],
+    keys: &[String],
+) -> Result<RateLimitInfo, Rejection> {
+    let configs: Vec<_> = limiters.iter().map(RateLimiter::config).collect();
+    let bypass = limiters
+        .iter()
+        .zip(&configs)
+        .zip(keys)
+        .map(|((limiter, config), key)| limiter.bypasses_limit(config, key))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut maps = lock_states(limiters).await;
+    // There is always a limiter, see `try_with_rate_limits`
+    let now = maps[0].clock.now();
+
+    // Each configuration counts the request in a single step, and gives it back if a
+    // later one rejects it
+    let mut admitted = Vec::with_capacity(limiters.len());
+    let mut rejected = None;
+    for (index, ((limiter, bypass), (config, (map, key)))) in limiters
+        .iter()
+        .zip(bypass)
+        .zip(configs.iter().zip(maps.iter_mut().zip(keys)))
+        .enumerate()
+    {
+        limiter
+            .counters
+            .evicted(config.label.as_ref(), map.cleanup(config, now));
+        let limit = config.limit_for(key);
+        if bypass {
+            let entry = map.current_entry(key, &limit, now);
+            admitted.push((limit, entry, false));
+            continue;
+        }
+        match map.incr_within_window(key, &limit, now, 1) {
+            Ok(entry) => admitted.push((limit, entry, true)),
+            Err(entry) => {
+                let (entry, first) = map.record_rejection(key, entry, now);
+                rejected = Some((
+                    limiter,
+                    config,
+                    key,
+                    RateLimitRejection {
+                        config_index: Some(index),
+                        ..limiter.rejection(config, &limit, key, &entry, now)
+                    },
+                    first,
+                ));
+                break;
+            }
+        }
+    }
+    if let Some((limiter, config, key, rejection, first)) = rejected {
+        for ((map, key), (_, entry, counted)) in maps.iter_mut().zip(keys).zip(&admitted) {
+            if *counted {
+                map.refund(key, entry.start, 1, now);
+            }
+        }
+        drop(maps);
+        limiter.notify_rejected(config, key, &rejection, first);
+        return Err(config.reject(rejection));
+    }
+
+    let mut infos = Vec::with_capacity(limiters.len());
+    for (((limiter, config), (map, key)), (limit, entry, counted)) in limiters
+        .iter()
+        .zip(&configs)
+        .zip(maps.iter().zip(keys))
+        .zip(admitted)
+    {
+        let info = limiter.create_info(config, &limit, key, &entry, map, now);
+        infos.push((limiter, config, key, info, counted));
+    }
+    drop(maps);
+
+    let mut most_restrictive: Option<RateLimitInfo> = None;
+    for (limiter, config, key, info, counted) in infos {
+        if counted {
+            limiter.notify_allowed(config, key, &info, 1);
+        }
+        if most_restrictive
+            .as_ref()
+            .is_none_or(|current| info.remaining < current.remaining)
+        {
+            most_restrictive = Some(info);
+        }
+    }
+    // There is at least one limiter
+    Ok(most_restrictive.unwrap())
+}
+
+// Locks the state of every limiter, always in the same order so that concurrent
+// requests can't deadlock
+async fn lock_states(limiters: &[RateLimiter]) -> Vec<StateGuard<'_>> {
+    #[cfg(feature = "sync-lock")]
+    return limiters
+        .iter()
+        .map(|limiter| {
+            limiter
+                .state
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+        })
+        .collect();
+    #[cfg(not(feature = "sync-lock"))]
+    {
+        let mut maps = Vec::with_capacity(limiters.len());
+        for limiter in limiters {
+            maps.push(limiter.state.write().await);
+        }
+        maps
+    }
+}
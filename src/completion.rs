@@ -0,0 +1,291 @@
+use crate::{
+    client_key, core::Entry, with_rate_limit_headers, ConfigError, RateLimitConfig, RateLimitInfo,
+    RateLimiter,
+};
+use std::fmt;
+use warp::{
+    filters::BoxedFilter,
+    http::StatusCode,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+/// Class of an HTTP status code, given by its first digit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatusClass {
+    /// `1xx`
+    Informational,
+    /// `2xx`
+    Success,
+    /// `3xx`
+    Redirection,
+    /// `4xx`
+    ClientError,
+    /// `5xx`
+    ServerError,
+}
+
+impl StatusClass {
+    /// Whether `status` belongs to this class
+    pub fn contains(self, status: StatusCode) -> bool {
+        match self {
+            StatusClass::Informational => status.is_informational(),
+            StatusClass::Success => status.is_success(),
+            StatusClass::Redirection => status.is_redirection(),
+            StatusClass::ClientError => status.is_client_error(),
+            StatusClass::ServerError => status.is_server_error(),
+        }
+    }
+}
+
+/// Rate limits a route counting requests once they are answered, rather than when
+/// they reach the filter
+///
+/// Requests are admitted while the client has capacity left, but only counted once
+/// the wrapped filter produced a response, optionally only when its status is in one
+/// of the [`count_classes`](Self::count_classes) or one of the
+/// [`count_statuses`](Self::count_statuses), and never when it is one of the
+/// [`exempt_statuses`](Self::exempt_statuses). Requests rejected by the wrapped filter,
+/// for example by an authentication filter refusing malformed input, are not counted.
+/// The rate limit headers are added to every response.
+///
+/// Admitted requests hold on to a unit of capacity until they complete, so that
+/// concurrent requests can't exceed the limit. [`slack`](Self::slack) admits that
+/// many more requests, at the cost of exceeding the limit by as much when they all
+/// end up counted.
+///
+/// ```rust,no_run,ignore
+/// let login = warp::path!("login")
+///     .and(credentials())
+///     .and_then(login_handler)
+///     .with(warp::wrap_fn(
+///         CompletionRateLimit::new(RateLimitConfig::strict_login())?
+///             .count_classes([StatusClass::ClientError])
+///             .wrapper(),
+///     ))
+///     .recover(handle_rate_limit_rejection);
+/// ```
+#[derive(Clone)]
+pub struct CompletionRateLimit {
+    limiter: RateLimiter,
+    counted: Counted,
+    slack: u32,
+}
+
+// The responses counted by a `CompletionRateLimit`, all of them but the exempted
+// ones when no class nor status is given
+#[derive(Clone, Debug, Default)]
+struct Counted {
+    classes: Option<Vec<StatusClass>>,
+    statuses: Option<Vec<StatusCode>>,
+    exempt: Vec<StatusCode>,
+}
+
+impl Counted {
+    fn contains(&self, status: StatusCode) -> bool {
+        if self.exempt.contains(&status) {
+            return false;
+        }
+        if self.classes.is_none() && self.statuses.is_none() {
+            return true;
+        }
+        self.classes
+            .iter()
+            .flatten()
+            .any(|class| class.contains(status))
+            || self
+                .statuses
+                .iter()
+                .flatten()
+                .any(|counted| *counted == status)
+    }
+}
+
+impl fmt::Debug for CompletionRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletionRateLimit")
+            .field("config", &self.limiter.config())
+            .field("counted", &self.counted)
+            .field("slack", &self.slack)
+            .finish()
+    }
+}
+
+impl CompletionRateLimit {
+    /// Creates a rate limiter counting every response, after checking the
+    /// configuration with [`RateLimitConfig::validate`]
+    ///
+    /// Reservations are counted in memory, so a config with a
+    /// [`RateLimitConfig::store`] is refused.
+    pub fn new(config: RateLimitConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        if config.store.is_some() {
+            return Err(ConfigError::StoreNotSupported("CompletionRateLimit"));
+        }
+        Ok(Self {
+            limiter: RateLimiter::new(config),
+            counted: Counted::default(),
+            slack: 0,
+        })
+    }
+
+    /// Only counts the responses whose status is in one of `classes`
+    ///
+    /// Combined with [`count_statuses`](Self::count_statuses), responses matching
+    /// either are counted.
+    pub fn count_classes(mut self, classes: impl IntoIterator<Item = StatusClass>) -> Self {
+        self.counted.classes = Some(classes.into_iter().collect());
+        self
+    }
+
+    /// Only counts the responses whose status is one of `statuses`
+    ///
+    /// With `[401, 403]`, a small limit and a long window, only failed login attempts
+    /// use up the quota, so legitimate users can't lock themselves out.
+    pub fn count_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.counted.statuses = Some(statuses.into_iter().collect());
+        self
+    }
+
+    /// Never counts the responses whose status is one of `statuses`, such as
+    /// `304 Not Modified`, even when their class is counted
+    ///
+    /// Well-behaved clients revalidating their cache then don't run into the limit.
+    pub fn exempt_statuses(mut self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.counted.exempt = statuses.into_iter().collect();
+        self
+    }
+
+    /// Sets how many requests may be admitted beyond the remaining capacity while
+    /// others are in flight (default: 0, the limit is never exceeded)
+    pub fn slack(mut self, slack: u32) -> Self {
+        self.slack = slack;
+        self
+    }
+
+    /// Turns the rate limiter into a function that can be passed to `warp::wrap_fn`
+    ///
+    /// Every filter built from the returned function shares the same state.
+    pub fn wrapper<F, R>(self) -> impl Fn(F) -> BoxedFilter<(Response,)> + Clone
+    where
+        F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+        R: Reply,
+    {
+        move |filter: F| {
+            let limiter = self.limiter.clone();
+            let slack = self.slack;
+            let counted = self.counted.clone();
+            client_key(&self.limiter.config())
+                .and_then(move |key: String| {
+                    let limiter = limiter.clone();
+                    async move { limiter.reserve(key, slack).await }
+                })
+                .and(filter)
+                .and_then(move |reservation: Reservation, reply: R| {
+                    let response = reply.into_response();
+                    let counted = counted.contains(response.status());
+                    async move {
+                        let info = reservation.commit(counted).await;
+                        Ok::<_, Rejection>(with_rate_limit_headers(response, &info).into_response())
+                    }
+                })
+                .boxed()
+        }
+    }
+}
+
+// A unit of capacity held by an admitted request until it completes. Given back on
+// drop if the request never does, e.g. because the wrapped filter rejected it.
+struct Reservation {
+    limiter: RateLimiter,
+    key: Option<String>,
+    // Exempted from the limit, nothing to count or give back
+    bypass: bool,
+}
+
+impl Reservation {
+    async fn commit(mut self, counted: bool) -> RateLimitInfo {
+        let key = self.key.take().unwrap_or_default();
+        let config = self.limiter.config();
+        let mut map = self.limiter.lock_state().await;
+        let now = map.clock.now();
+
+        let limit = config.limit_for(&key);
+        let mut entry = map.current_entry(&key, &limit, now);
+        if !self.bypass {
+            entry.pending = entry.pending.saturating_sub(1);
+            entry.last_seen = now;
+            if counted {
+                entry.count = entry.count.saturating_add(1);
+            }
+            map.insert(&key, entry);
+        }
+        let info = self
+            .limiter
+            .create_info(&config, &limit, &key, &entry, &map, now);
+        drop(map);
+        if counted && !self.bypass {
+            self.limiter.notify_allowed(&config, &key, &info, 1);
+        }
+        info
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take().filter(|_| !self.bypass) {
+            self.limiter.with_state_now(move |map| {
+                if let Some(entry) = map.inner.get_mut(&key) {
+                    entry.pending = entry.pending.saturating_sub(1);
+                }
+            });
+        }
+    }
+}
+
+impl RateLimiter {
+    async fn reserve(&self, key: String, slack: u32) -> Result<Reservation, Rejection> {
+        let config = self.config();
+        if self.bypasses_limit(&config, &key)? {
+            return Ok(Reservation {
+                limiter: self.clone(),
+                key: Some(key),
+                bypass: true,
+            });
+        }
+        let mut map = self.lock_state().await;
+        let now = map.clock.now();
+
+        self.counters
+            .evicted(config.label.as_ref(), map.cleanup(&config, now));
+        let limit = config.limit_for(&key);
+        let entry = map.current_entry(&key, &limit, now);
+        // The slack doesn't open up a limit of 0, which blocks every request
+        let allowance = entry.allowance(&limit);
+        if entry.count.saturating_add(entry.pending) >= allowance.saturating_add(slack)
+            || allowance == 0
+            || entry.ban_left(now).is_some()
+        {
+            let (entry, first) = map.record_rejection(&key, entry, now);
+            let rejection = self.rejection(&config, &limit, &key, &entry, now);
+            drop(map);
+            self.notify_rejected(&config, &key, &rejection, first);
+            return Err(config.reject(rejection));
+        }
+        map.insert(
+            &key,
+            Entry {
+                pending: entry.pending.saturating_add(1),
+                last_seen: now,
+                ..entry
+            },
+        );
+        drop(map);
+        self.notify_key_count(&config);
+        Ok(Reservation {
+            limiter: self.clone(),
+            key: Some(key),
+            bypass: false,
+        })
+    }
+}
@@ -1,8 +1,96 @@
+use crate::{
+    core::KeyLimit,
+    store::RateLimitStore,
+    time::{AnchoredClock, Clock, SystemClock},
+    trace, AuditEntry, AuditReason, AuditSink, ConfigError, RateLimitEvent, RateLimitInfo,
+    RateLimitRejection, Timestamp,
+};
+#[cfg(feature = "geoip")]
+use crate::{CountryDenied, CountryRules, GeoIpReader};
+use ipnet::IpNet;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::HashMap, fmt, ops::Deref, panic::AssertUnwindSafe, str::FromStr, sync::Arc,
+    time::Duration,
+};
+
+// The longest window accepted, well beyond any real limit, so that a typo in a config
+// file is caught at startup rather than overflowing the clocks on the first request
+const MAX_WINDOW: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// A function stored in a [`RateLimitConfig`]
+///
+/// Functions can neither be compared nor printed, so two `ConfigFn`s are equal only
+/// when they point to the same function.
+pub struct ConfigFn<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> ConfigFn<F> {
+    /// Wraps a shared function
+    pub fn new(f: Arc<F>) -> Self {
+        Self(f)
+    }
+}
+
+impl<F: ?Sized> From<Arc<F>> for ConfigFn<F> {
+    fn from(f: Arc<F>) -> Self {
+        Self(f)
+    }
+}
+
+impl<F: ?Sized> Clone for ConfigFn<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> Deref for ConfigFn<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.0
+    }
+}
+
+impl<F: ?Sized> PartialEq for ConfigFn<F> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for ConfigFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConfigFn(..)")
+    }
+}
+
+/// Source of the Retry-After jitter: given the maximum, returns a duration in `[0, max)`
+pub type JitterSource = dyn Fn(Duration) -> Duration + Send + Sync;
+
+/// Classifies a client key into a tier, by name. See [`RateLimitConfig::tiers`].
+pub type TierClassifier = dyn Fn(&str) -> Option<&str> + Send + Sync;
+
+/// Turns the rejection of a rate limited request into the one the filter fails with
+pub type RejectionMapper = dyn Fn(RateLimitRejection) -> warp::Rejection + Send + Sync;
+
+/// Called with the requests a rate limiter admitted or rejected, see
+/// [`RateLimitConfig::on_allowed`] and [`RateLimitConfig::on_rejected`]
+pub type EventCallback = dyn Fn(RateLimitEvent) + Send + Sync;
+
+/// Called with the number of clients tracked by a rate limiter, see
+/// [`RateLimitConfig::on_key_count_exceeds`]
+pub type KeyCountCallback = dyn Fn(usize) + Send + Sync;
+
+// Calls a callback, a panic in it being logged rather than failing the request
+fn notify<E>(name: &str, callback: &(dyn Fn(E) + Send + Sync), event: E) {
+    if std::panic::catch_unwind(AssertUnwindSafe(|| callback(event))).is_err() {
+        trace::callback_panic(name);
+    }
+}
 
 /// Format options for the Retry-After header
-#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum RetryAfterFormat {
     /// HTTP-date format (RFC 7231)
     #[default]
@@ -11,28 +99,309 @@ pub enum RetryAfterFormat {
     Seconds,
 }
 
+/// Which family of rate limit headers is emitted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum HeaderStyle {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset` (Unix timestamp)
+    #[default]
+    Legacy,
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (seconds until reset),
+    /// as standardized by the IETF HTTPAPI working group
+    Standard,
+    /// Both families, useful while migrating clients
+    Both,
+}
+
 /// Configuration for the rate limiter
+///
+/// New options are added over time, so the struct can't be built literally from outside
+/// this crate. Start from a constructor and chain the `with_*` setters instead:
+///
+/// ```
+/// use std::time::Duration;
+/// use warp_rate_limit::{RateLimitConfig, RetryAfterFormat};
+///
+/// let config = RateLimitConfig::default()
+///     .with_max_requests(100)
+///     .with_window(Duration::from_secs(30))
+///     .with_retry_after_format(RetryAfterFormat::Seconds);
+/// assert_eq!(config.max_requests, 100);
+/// ```
+///
+/// ```compile_fail
+/// use warp_rate_limit::RateLimitConfig;
+///
+/// let config = RateLimitConfig {
+///     max_requests: 100,
+///     ..Default::default()
+/// };
+/// ```
+///
+/// Deserializing accepts durations either as integer seconds or as strings like `"90s"`,
+/// `"5m"` or `"1h30m"`, and fills missing fields with the defaults:
+///
+/// ```toml
+/// max_requests = 100
+/// window = "5m"
+/// retry_after_format = "Seconds"
+/// ```
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[non_exhaustive]
 pub struct RateLimitConfig {
-    /// Maximum number of requests allowed within the window
+    /// Name telling this configuration apart, copied into `RateLimitInfo`,
+    /// `RateLimitRejection`, `RateLimitEvent`, the traces and the logs, and counted
+    /// apart by [`crate::RateLimiterHandle::label_counters`], e.g. to know which route
+    /// rejected a request
+    pub label: Option<Arc<str>>,
+    /// Maximum number of requests allowed within the window. 0 rejects every request,
+    /// telling the client to come back at the end of its window.
     pub max_requests: u32,
-    /// Time window for rate limiting
+    /// Time window for rate limiting, at most 100 years
+    #[cfg_attr(feature = "serde", serde(with = "duration"))]
     pub window: Duration,
     /// Format for Retry-After header (RFC 7231 Date or Seconds)
     pub retry_after_format: RetryAfterFormat,
+    /// Maximum random delay added to the emitted Retry-After, so that clients rejected
+    /// at the same moment don't all come back at once. The window itself is unaffected.
+    #[cfg_attr(feature = "serde", serde(with = "duration::option"))]
+    pub retry_after_jitter: Option<Duration>,
+    /// Source of the jitter, uniformly random when unset. Serialized as `"<function>"`
+    /// when set, and ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub jitter_source: Option<ConfigFn<JitterSource>>,
+    /// Family of rate limit headers emitted alongside Retry-After
+    pub header_style: HeaderStyle,
+    /// Also emit `X-RateLimit-Used`, the number of requests made in the window
+    pub used_header: bool,
+    /// Leave the client key out of `RateLimitInfo` and `RateLimitRejection`, for
+    /// deployments that must not log client addresses
+    pub redact_key: bool,
+    /// Time the checks of the requests, waiting on the state included, into
+    /// [`crate::RateLimiterHandle::latency_histogram`], e.g. to catch lock contention.
+    /// Off by default, sparing the clock reads.
+    pub measure_latency: bool,
+    /// Turns the `RateLimitRejection` of rate limited requests into another rejection,
+    /// e.g. the application's own error type, before it leaves the filter. Serialized
+    /// as `"<function>"` when set, and ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub rejection_mapper: Option<ConfigFn<RejectionMapper>>,
+    /// Called with every rejected request, e.g. to alert on abusive clients. Runs on
+    /// the request's task once the limiter state is unlocked, so it must be cheap; a
+    /// panic in it is caught and logged. Serialized as `"<function>"` when set, and
+    /// ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub on_rejected: Option<ConfigFn<EventCallback>>,
+    /// Called with every admitted request once it is counted, e.g. for usage-based
+    /// billing. Same as `on_rejected` otherwise, including its serialization.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub on_allowed: Option<ConfigFn<EventCallback>>,
+    /// Number of tracked clients above which `on_key_count_exceeds` is called
+    pub key_count_threshold: Option<usize>,
+    /// Called with the number of tracked clients when it grows above
+    /// `key_count_threshold`, once until it falls back to the threshold, e.g. to alert
+    /// when the memory of the limiter balloons. Same as `on_rejected` otherwise,
+    /// including its serialization.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub on_key_count_exceeds: Option<ConfigFn<KeyCountCallback>>,
+    /// Number of clients followed as heavy hitters, those sending the most requests,
+    /// in a sketch of bounded memory. They get their own `key_tag` in the traces and
+    /// show up in [`crate::RateLimiterHandle::heavy_hitters`], while the long tail is
+    /// aggregated under `"other"`, so per-client metrics don't explode in cardinality.
+    /// A client sending more than 1/(heavy_hitters + 1) of the requests is always
+    /// followed.
+    pub heavy_hitters: Option<usize>,
+    /// Share of its limit, above 0 and up to 1, from which a client gets its own
+    /// `key_tag` in the traces, whether it is a heavy hitter or not
+    pub heavy_usage: Option<f64>,
+    /// Records the actions taken against clients, such as denying access to the clients
+    /// of some countries, e.g. for compliance. Serialized as `"<sink>"` when set, and
+    /// ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "opaque::sink", deserialize_with = "opaque::ignore")
+    )]
+    pub audit_sink: Option<ConfigFn<dyn AuditSink>>,
+    /// Source of the time of the rate limiters created with this config, the system
+    /// clock when unset, e.g. a [`crate::ManualClock`] to test window expiry without
+    /// sleeping. Read once, when the rate limiter is created: updating the config
+    /// doesn't change its clock. Serialized as `"<clock>"` when set, and ignored when
+    /// deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "opaque::clock", deserialize_with = "opaque::ignore")
+    )]
+    pub clock: Option<ConfigFn<dyn Clock>>,
+    /// Where the requests of each client are counted, the memory of the rate limiter
+    /// when unset, e.g. a store shared by the instances of a service. Only the counting
+    /// of the filters and of `RateLimiterHandle::check` and `acquire_n` goes through
+    /// it: bans, refunds and snapshots keep working on the memory of the rate limiter,
+    /// and `RefundRateLimit`, `CompletionRateLimit` and `with_rate_limits` refuse a
+    /// config with a store. A request is let through when the store fails, counted in
+    /// `RateLimitCounters::store_errors`. Serialized as `"<store>"` when set, and
+    /// ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "opaque::store", deserialize_with = "opaque::ignore")
+    )]
+    pub store: Option<ConfigFn<dyn RateLimitStore>>,
+    /// Upper bound of the emitted Retry-After, whatever the window.
+    /// `X-RateLimit-Reset` keeps reporting the real reset time.
+    #[cfg_attr(feature = "serde", serde(with = "duration::option"))]
+    pub max_retry_after: Option<Duration>,
+    /// How long the entry of a client stays in memory after its last request, once
+    /// its window is over. Defaults to the window; a shorter one frees the memory of
+    /// idle clients sooner with long windows, a longer one prunes less often with
+    /// short windows. Pruning happens at most once per this duration.
+    #[cfg_attr(feature = "serde", serde(with = "duration::option"))]
+    pub idle_ttl: Option<Duration>,
+    /// Number of tracked clients above which [`crate::RateLimiterHandle::health`] reports
+    /// the limiter as degraded
+    pub health_max_keys: Option<usize>,
+    /// Time since the last pruning of idle clients above which
+    /// [`crate::RateLimiterHandle::health`] reports the limiter as degraded. Must be
+    /// longer than `idle_ttl`, as pruning happens at most once per `idle_ttl`.
+    ///
+    /// Pruning piggybacks on the requests, so a limiter receiving none reports a growing
+    /// age: don't use it to gate the readiness of a service that may go idle.
+    #[cfg_attr(feature = "serde", serde(with = "duration::option"))]
+    pub health_max_prune_age: Option<Duration>,
+    /// Limits of specific clients, given as `(max_requests, window)` and keyed by ip
+    /// address (or `"unknown"`). Serialized as `"100/1m"` like strings.
+    #[cfg_attr(feature = "serde", serde(with = "overrides"))]
+    pub overrides: HashMap<String, (u32, Duration)>,
+    /// Named tiers, whose `max_requests` and `window` apply to the clients that
+    /// `tier_classifier` puts in them. Clients without a tier, or with a tier missing
+    /// from this list, use the base limit, and `overrides` take precedence over tiers.
+    ///
+    /// Requests are counted per client whatever its tier: a client moving to another
+    /// tier keeps its count, gets the new tier's limit right away, and the new tier's
+    /// window once its current window is over.
+    pub tiers: Vec<(String, RateLimitConfig)>,
+    /// Returns the name of the tier of a client key. Serialized as `"<function>"` when
+    /// set, and ignored when deserializing.
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::function",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub tier_classifier: Option<ConfigFn<TierClassifier>>,
+    /// Limits of the clients located in specific countries, whose `max_requests` and
+    /// `window` apply. Keyed by upper-case ISO 3166 country code, like `"FR"`, as found
+    /// in `geoip_reader`. Clients the database doesn't know about use the base limit,
+    /// and `overrides` and tiers take precedence over countries.
+    #[cfg(feature = "geoip")]
+    pub countries: HashMap<String, RateLimitConfig>,
+    /// Database locating clients in their country. Serialized as `"<database>"` when
+    /// set, and ignored when deserializing.
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(
+        feature = "serde",
+        serde(
+            serialize_with = "opaque::database",
+            deserialize_with = "opaque::ignore"
+        )
+    )]
+    pub geoip_reader: Option<GeoIpReader>,
+    /// Countries whose clients are rejected, or exempted from the limit, before their
+    /// requests are counted. Inert unless `geoip_reader` locates the client.
+    #[cfg(feature = "geoip")]
+    pub country_rules: CountryRules,
 
-    /// Header used to extract the client's ip address
-    pub ip_header: String,
+    /// Header used to extract the client's ip address. When `None`, no header is trusted
+    /// and clients are told apart by the remote address of their connection, which is
+    /// the right choice for services that are not behind a reverse proxy. warp doesn't
+    /// tell that address, the server must put it in the request extensions, see
+    /// `with_peer_addr` (`tower` feature).
+    pub ip_header: Option<String>,
+    /// Networks of the reverse proxies in front of the service. When set, `ip_header`
+    /// is only trusted on connections coming from one of them, and is read as a
+    /// comma separated chain of addresses, like `X-Forwarded-For`: the client is the
+    /// last address of the chain that isn't a trusted proxy. Other connections are told
+    /// apart by their remote address, so clients can't spoof their address.
+    pub trusted_proxies: Option<Vec<IpNet>>,
+    /// Puts the clients whose `ip_header` is missing or doesn't hold an ip address in a
+    /// single `"unknown"` bucket, which any of them can exhaust for all the others. Off
+    /// by default: they are told apart by the remote address of their connection.
+    /// Requests landing in the bucket are counted in
+    /// [`RateLimitCounters::unknown`](crate::RateLimitCounters::unknown) either way.
+    pub unknown_bucket: bool,
 }
 /// Sensible (opinionated) defaults
 impl Default for RateLimitConfig {
     fn default() -> Self {
         Self {
+            label: None,
             max_requests: 60, // 60 req/min baseline
             window: Duration::from_secs(60),
             retry_after_format: RetryAfterFormat::HttpDate,
+            retry_after_jitter: None,
+            jitter_source: None,
+            header_style: HeaderStyle::Legacy,
+            used_header: false,
+            redact_key: false,
+            measure_latency: false,
+            rejection_mapper: None,
+            on_rejected: None,
+            on_allowed: None,
+            key_count_threshold: None,
+            on_key_count_exceeds: None,
+            heavy_hitters: None,
+            heavy_usage: None,
+            audit_sink: None,
+            clock: None,
+            store: None,
+            max_retry_after: None,
+            idle_ttl: None,
+            health_max_keys: None,
+            health_max_prune_age: None,
+            overrides: HashMap::new(),
+            tiers: Vec::new(),
+            tier_classifier: None,
+            #[cfg(feature = "geoip")]
+            countries: HashMap::new(),
+            #[cfg(feature = "geoip")]
+            geoip_reader: None,
+            #[cfg(feature = "geoip")]
+            country_rules: CountryRules::default(),
 
-            ip_header: String::from("X-Forwarded-For"), // It's the one used by most of the revese proxies
+            ip_header: Some(String::from("X-Forwarded-For")), // It's the one used by most of the revese proxies
+            trusted_proxies: None,
+            unknown_bucket: false,
         }
     }
 }
@@ -41,19 +410,1184 @@ impl Default for RateLimitConfig {
 impl RateLimitConfig {
     /// Build a `RateLimitConfig` with sensible defaults for requests per minute
     pub fn max_per_minute(max: u32) -> Self {
-        Self {
-            max_requests: max,
-            window: Duration::from_secs(60),
-            ..Default::default()
-        }
+        Self::builder()
+            .max_requests(max)
+            .window(Duration::from_secs(60))
+            .config
+    }
+
+    /// Build a `RateLimitConfig` with sensible defaults for requests per second
+    pub fn per_second(max: u32) -> Self {
+        Self::max_per_window(max, 1)
+    }
+
+    /// Build a `RateLimitConfig` with sensible defaults for requests per hour
+    pub fn per_hour(max: u32) -> Self {
+        Self::max_per_window(max, 60 * 60)
+    }
+
+    /// Build a `RateLimitConfig` with sensible defaults for requests per day
+    ///
+    /// The day is a rolling 24 hours starting at each client's first request, not a
+    /// calendar day.
+    pub fn per_day(max: u32) -> Self {
+        Self::max_per_window(max, 24 * 60 * 60)
+    }
+
+    /// Build a validated `RateLimitConfig` allowing `max` requests per `window`, given
+    /// as a `Duration` or as text like `"5m"`, see [`parse_duration`]
+    pub fn max_per(window: impl IntoDuration, max: u32) -> Result<Self, ConfigError> {
+        Self::builder().window(window).max_requests(max).build()
     }
 
     /// Build a `RateLimitConfig` with custom window size in seconds
     pub fn max_per_window(max_requests: u32, window_seconds: u64) -> Self {
-        Self {
+        Self::builder()
+            .max_requests(max_requests)
+            .window(Duration::from_secs(window_seconds))
+            .config
+    }
+
+    /// Configuration for services directly exposed to their clients: no header is
+    /// trusted, and clients are told apart by the remote address of their connection
+    ///
+    /// Unlike the default configuration, clients can't pick their own address by
+    /// sending an `X-Forwarded-For` header.
+    pub fn direct() -> Self {
+        Self::default().without_ip_header()
+    }
+
+    /// Configuration for services behind the reverse proxies of the `trusted` networks,
+    /// which append the address of their clients to `X-Forwarded-For`
+    ///
+    /// Addresses added to the header by clients themselves are ignored, see
+    /// [`RateLimitConfig::trusted_proxies`].
+    pub fn behind_proxy(trusted: impl IntoIterator<Item = IpNet>) -> Self {
+        Self::default().with_trusted_proxies(trusted)
+    }
+
+    /// Preset for login and other credential checking endpoints: 5 requests per
+    /// 15 minutes, with Retry-After in seconds
+    ///
+    /// Slows down password guessing while leaving room for a few typos. Keep it on its own
+    /// limiter so a client locked out of logging in can still use the rest of the service.
+    pub fn strict_login() -> Self {
+        Self::max_per_window(5, 15 * 60).with_retry_after_format(RetryAfterFormat::Seconds)
+    }
+
+    /// Preset for public JSON APIs: 60 requests per minute, with Retry-After in seconds
+    /// and both the `X-RateLimit-*` and the standardized `RateLimit-*` headers, which
+    /// API clients can act on
+    pub fn public_api() -> Self {
+        Self::max_per_minute(60)
+            .with_retry_after_format(RetryAfterFormat::Seconds)
+            .with_header_style(HeaderStyle::Both)
+    }
+
+    /// Preset for static assets: 600 requests per minute
+    ///
+    /// A single page load can fetch dozens of assets, so this only stops clients
+    /// hammering the server.
+    pub fn lenient_static() -> Self {
+        Self::max_per_minute(600)
+    }
+
+    /// Sets the label copied into `RateLimitInfo` and `RateLimitRejection`
+    pub fn with_label(mut self, label: impl Into<Arc<str>>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Sets the maximum number of requests allowed within the window
+    pub fn with_max_requests(mut self, max_requests: u32) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Sets the time window for rate limiting
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets the format of the Retry-After header
+    pub fn with_retry_after_format(mut self, retry_after_format: RetryAfterFormat) -> Self {
+        self.retry_after_format = retry_after_format;
+        self
+    }
+
+    /// Sets the maximum random delay added to the emitted Retry-After
+    pub fn with_retry_after_jitter(mut self, max_jitter: Duration) -> Self {
+        self.retry_after_jitter = Some(max_jitter);
+        self
+    }
+
+    /// Sets the source of the jitter
+    pub fn with_jitter_source(
+        mut self,
+        jitter_source: impl Fn(Duration) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.jitter_source = Some(ConfigFn(Arc::new(jitter_source)));
+        self
+    }
+
+    /// Sets the family of rate limit headers emitted alongside Retry-After
+    pub fn with_header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_style = header_style;
+        self
+    }
+
+    /// Sets whether `X-RateLimit-Used` is emitted
+    pub fn with_used_header(mut self, used_header: bool) -> Self {
+        self.used_header = used_header;
+        self
+    }
+
+    /// Sets whether the client key is left out of `RateLimitInfo` and
+    /// `RateLimitRejection`
+    pub fn with_redact_key(mut self, redact_key: bool) -> Self {
+        self.redact_key = redact_key;
+        self
+    }
+
+    /// Sets whether the checks of the requests are timed, see
+    /// [`RateLimitConfig::measure_latency`]
+    pub fn with_measure_latency(mut self, measure_latency: bool) -> Self {
+        self.measure_latency = measure_latency;
+        self
+    }
+
+    /// Sets the function turning the rejection of rate limited requests into another
+    /// rejection, see [`RateLimitConfig::rejection_mapper`]
+    pub fn with_rejection_mapper(
+        mut self,
+        mapper: impl Fn(RateLimitRejection) -> warp::Rejection + Send + Sync + 'static,
+    ) -> Self {
+        self.rejection_mapper = Some(ConfigFn(Arc::new(mapper)));
+        self
+    }
+
+    /// Sets the function called with every rejected request, see
+    /// [`RateLimitConfig::on_rejected`]
+    pub fn with_on_rejected(
+        mut self,
+        callback: impl Fn(RateLimitEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_rejected = Some(ConfigFn(Arc::new(callback)));
+        self
+    }
+
+    /// Sets the function called with every admitted request, see
+    /// [`RateLimitConfig::on_allowed`]
+    pub fn with_on_allowed(
+        mut self,
+        callback: impl Fn(RateLimitEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_allowed = Some(ConfigFn(Arc::new(callback)));
+        self
+    }
+
+    /// Sets the function called when the number of tracked clients grows above
+    /// `threshold`, see [`RateLimitConfig::on_key_count_exceeds`]
+    pub fn with_on_key_count_exceeds(
+        mut self,
+        threshold: usize,
+        callback: impl Fn(usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.key_count_threshold = Some(threshold);
+        self.on_key_count_exceeds = Some(ConfigFn(Arc::new(callback)));
+        self
+    }
+
+    /// Sets the number of clients followed as heavy hitters, see
+    /// [`RateLimitConfig::heavy_hitters`]
+    pub fn with_heavy_hitters(mut self, heavy_hitters: usize) -> Self {
+        self.heavy_hitters = Some(heavy_hitters);
+        self
+    }
+
+    /// Sets the share of its limit from which a client is traced under its own key,
+    /// see [`RateLimitConfig::heavy_usage`]
+    pub fn with_heavy_usage(mut self, share: f64) -> Self {
+        self.heavy_usage = Some(share);
+        self
+    }
+
+    /// Sets where the actions taken against clients are recorded, see
+    /// [`RateLimitConfig::audit_sink`]
+    ///
+    /// ```rust,no_run,ignore
+    /// let audit = Arc::new(AuditRing::new(1000));
+    /// let config = RateLimitConfig::default().with_audit_sink(audit.clone());
+    /// ```
+    pub fn with_audit_sink(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.audit_sink = Some(ConfigFn(Arc::new(sink)));
+        self
+    }
+
+    /// Sets the source of the time of the rate limiters, see [`RateLimitConfig::clock`]
+    ///
+    /// ```rust,no_run,ignore
+    /// let clock = Arc::new(ManualClock::new());
+    /// let config = RateLimitConfig::default().with_clock(clock.clone());
+    /// ```
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(ConfigFn(Arc::new(clock)));
+        self
+    }
+
+    /// Sets where the requests of each client are counted, see
+    /// [`RateLimitConfig::store`]
+    ///
+    /// ```rust,no_run,ignore
+    /// let config = RateLimitConfig::default().with_store(RedisStore::connect(URL).await?);
+    /// ```
+    pub fn with_store(mut self, store: impl RateLimitStore + 'static) -> Self {
+        self.store = Some(ConfigFn(Arc::new(store)));
+        self
+    }
+
+    /// Sets the upper bound of the emitted Retry-After
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after = Some(max_retry_after);
+        self
+    }
+
+    /// Sets how long idle clients are kept once their window is over, see
+    /// [`RateLimitConfig::idle_ttl`]
+    pub fn with_idle_ttl(mut self, idle_ttl: Duration) -> Self {
+        self.idle_ttl = Some(idle_ttl);
+        self
+    }
+
+    /// Sets the number of tracked clients above which the limiter is reported as
+    /// degraded, see [`RateLimitConfig::health_max_keys`]
+    pub fn with_health_max_keys(mut self, max_keys: usize) -> Self {
+        self.health_max_keys = Some(max_keys);
+        self
+    }
+
+    /// Sets the time since the last pruning above which the limiter is reported as
+    /// degraded, see [`RateLimitConfig::health_max_prune_age`]
+    pub fn with_health_max_prune_age(mut self, max_prune_age: Duration) -> Self {
+        self.health_max_prune_age = Some(max_prune_age);
+        self
+    }
+
+    /// Gives the client `key` its own limit, replacing the default one
+    pub fn with_key_override(
+        mut self,
+        key: impl Into<String>,
+        max_requests: u32,
+        window: Duration,
+    ) -> Self {
+        self.overrides.insert(key.into(), (max_requests, window));
+        self
+    }
+
+    /// Adds a tier, see [`RateLimitConfig::tiers`]
+    pub fn with_tier(mut self, name: impl Into<String>, config: RateLimitConfig) -> Self {
+        self.tiers.push((name.into(), config));
+        self
+    }
+
+    /// Sets the function classifying client keys into tiers
+    pub fn with_tier_classifier(
+        mut self,
+        classifier: impl Fn(&str) -> Option<&str> + Send + Sync + 'static,
+    ) -> Self {
+        self.tier_classifier = Some(ConfigFn(Arc::new(classifier)));
+        self
+    }
+
+    /// Gives the clients located in the country `code` their own limit, see
+    /// [`RateLimitConfig::countries`]
+    #[cfg(feature = "geoip")]
+    pub fn with_country(mut self, code: impl Into<String>, config: RateLimitConfig) -> Self {
+        self.countries.insert(code.into(), config);
+        self
+    }
+
+    /// Sets the database locating clients in their country
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip_reader(mut self, reader: impl Into<GeoIpReader>) -> Self {
+        self.geoip_reader = Some(reader.into());
+        self
+    }
+
+    /// Rejects the clients located in one of these countries, see
+    /// [`RateLimitConfig::country_rules`]
+    #[cfg(feature = "geoip")]
+    pub fn with_denied_countries(
+        mut self,
+        codes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.country_rules.deny = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Exempts the clients located in one of these countries from the limit, see
+    /// [`RateLimitConfig::country_rules`]
+    #[cfg(feature = "geoip")]
+    pub fn with_allowed_countries(
+        mut self,
+        codes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.country_rules.allow = codes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the status and plain-text body of the responses to denied countries
+    #[cfg(feature = "geoip")]
+    pub fn with_country_denial(
+        mut self,
+        status: warp::http::StatusCode,
+        reason: impl Into<String>,
+    ) -> Self {
+        self.country_rules.deny_status = status.as_u16();
+        self.country_rules.deny_reason = reason.into();
+        self
+    }
+
+    /// Sets the header used to extract the client's ip address
+    pub fn with_ip_header(mut self, ip_header: impl Into<String>) -> Self {
+        self.ip_header = Some(ip_header.into());
+        self
+    }
+
+    /// Ignores all headers and uses the remote address of the connection instead
+    pub fn without_ip_header(mut self) -> Self {
+        self.ip_header = None;
+        self
+    }
+
+    /// Sets the networks of the trusted reverse proxies, see
+    /// [`RateLimitConfig::trusted_proxies`]
+    pub fn with_trusted_proxies(mut self, trusted: impl IntoIterator<Item = IpNet>) -> Self {
+        self.trusted_proxies = Some(trusted.into_iter().collect());
+        self
+    }
+
+    /// Sets whether the clients without a usable `ip_header` share the `"unknown"`
+    /// bucket, see [`RateLimitConfig::unknown_bucket`]
+    pub fn with_unknown_bucket(mut self, unknown_bucket: bool) -> Self {
+        self.unknown_bucket = unknown_bucket;
+        self
+    }
+
+    /// Starts building a validated `RateLimitConfig` from the defaults
+    pub fn builder() -> RateLimitConfigBuilder {
+        RateLimitConfigBuilder::default()
+    }
+
+    /// Checks the configuration makes sense, as done by [`crate::try_with_rate_limit`]
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_window(self.window)?;
+        for (key, &(_, window)) in &self.overrides {
+            self.validate_window(window)
+                .map_err(|e| ConfigError::InvalidOverride(key.clone(), Box::new(e)))?;
+        }
+        for (index, (name, tier)) in self.tiers.iter().enumerate() {
+            if self.tiers[..index].iter().any(|(other, _)| other == name) {
+                return Err(ConfigError::DuplicateTier(name.clone()));
+            }
+            self.validate_window(tier.window)
+                .map_err(|e| ConfigError::InvalidTier(name.clone(), Box::new(e)))?;
+        }
+        if !self.tiers.is_empty() && self.tier_classifier.is_none() {
+            return Err(ConfigError::TiersWithoutClassifier);
+        }
+        #[cfg(feature = "geoip")]
+        {
+            for (code, country) in &self.countries {
+                self.validate_window(country.window)
+                    .map_err(|e| ConfigError::InvalidCountry(code.clone(), Box::new(e)))?;
+            }
+            if (!self.countries.is_empty() || !self.country_rules.is_empty())
+                && self.geoip_reader.is_none()
+            {
+                return Err(ConfigError::CountriesWithoutGeoIpReader);
+            }
+            let deny_status = self.country_rules.deny_status;
+            if !(400..600).contains(&deny_status) {
+                return Err(ConfigError::InvalidDenyStatus(deny_status));
+            }
+        }
+        if let Some(ip_header) = &self.ip_header {
+            if warp::http::HeaderName::from_bytes(ip_header.as_bytes()).is_err() {
+                return Err(ConfigError::InvalidIpHeader(ip_header.clone()));
+            }
+        }
+        if self.trusted_proxies.is_some() && self.ip_header.is_none() {
+            return Err(ConfigError::TrustedProxiesWithoutIpHeader);
+        }
+        if self.max_retry_after.is_some_and(|max| max.is_zero()) {
+            return Err(ConfigError::ZeroMaxRetryAfter);
+        }
+        if self.idle_ttl.is_some_and(|ttl| ttl.is_zero()) {
+            return Err(ConfigError::ZeroIdleTtl);
+        }
+        if self.heavy_hitters == Some(0) {
+            return Err(ConfigError::ZeroHeavyHitters);
+        }
+        if self
+            .heavy_usage
+            .is_some_and(|share| !(share > 0.0 && share <= 1.0))
+        {
+            return Err(ConfigError::InvalidHeavyUsage);
+        }
+        let idle_ttl = self.idle_ttl.unwrap_or(self.window);
+        if let Some(max_prune_age) = self.health_max_prune_age {
+            if max_prune_age <= idle_ttl {
+                return Err(ConfigError::HealthPruneAgeTooShort(max_prune_age));
+            }
+        }
+        if self.jitter_source.is_some() && self.retry_after_jitter.is_none() {
+            return Err(ConfigError::JitterSourceWithoutJitter);
+        }
+        Ok(())
+    }
+
+    // The limit applying to the client `key`
+    pub(crate) fn limit_for(&self, key: &str) -> KeyLimit<'_> {
+        let tier = self
+            .tier_classifier
+            .as_ref()
+            .and_then(|classify| classify(key))
+            .and_then(|name| self.tiers.iter().find(|(tier, _)| tier == name));
+        let (max_requests, window) = match (self.overrides.get(key), tier) {
+            (Some(&limit), _) => limit,
+            (None, Some((_, tier))) => (tier.max_requests, tier.window),
+            #[cfg(feature = "geoip")]
+            (None, None) => self
+                .country_limit(key)
+                .map_or((self.max_requests, self.window), |country| {
+                    (country.max_requests, country.window)
+                }),
+            #[cfg(not(feature = "geoip"))]
+            (None, None) => (self.max_requests, self.window),
+        };
+        KeyLimit {
             max_requests,
-            window: Duration::from_secs(window_seconds),
-            ..Default::default()
+            window,
+            tier: tier.map(|(name, _)| name.as_str()),
         }
     }
+
+    // The rejection a rate limited request fails with
+    pub(crate) fn reject(&self, rejection: RateLimitRejection) -> warp::Rejection {
+        match &self.rejection_mapper {
+            Some(mapper) => mapper(rejection),
+            None => warp::reject::custom(rejection),
+        }
+    }
+
+    // Reports a rejected request to `on_rejected` and the traces, `first` when it is the first
+    // rejection of the client's window. Must not be called with the state locked, like
+    // `notify_allowed`.
+    pub(crate) fn notify_rejected(
+        &self,
+        rejection: &RateLimitRejection,
+        key_tag: Option<&str>,
+        first: bool,
+    ) {
+        trace::rejected(rejection, key_tag, first);
+        if let Some(callback) = &self.on_rejected {
+            notify("on_rejected", &**callback, RateLimitEvent::from(rejection));
+        }
+    }
+
+    // Reports a request counted as `cost` requests to `on_allowed` and the traces
+    pub(crate) fn notify_allowed(&self, info: &RateLimitInfo, cost: u32, key_tag: Option<&str>) {
+        trace::admitted(info, key_tag);
+        if let Some(callback) = &self.on_allowed {
+            notify(
+                "on_allowed",
+                &**callback,
+                RateLimitEvent::allowed(info, cost),
+            );
+        }
+    }
+
+    // The clock of a rate limiter created with this config, anchored now, each rate
+    // limiter having its own anchor
+    pub(crate) fn anchored_clock(&self) -> AnchoredClock {
+        match &self.clock {
+            Some(clock) => AnchoredClock::new(clock.0.clone()),
+            None => AnchoredClock::new(Arc::new(SystemClock)),
+        }
+    }
+
+    // Records an action taken against the client `key` at `at` to the audit sink, such
+    // as a country denial or a ban. Must not be called with the state locked.
+    pub(crate) fn audit(
+        &self,
+        key: &str,
+        reason: AuditReason,
+        duration: Option<Duration>,
+        at: Timestamp,
+    ) {
+        if let Some(sink) = &self.audit_sink {
+            let entry = AuditEntry::new(
+                self.visible_key(key),
+                self.label.clone(),
+                reason,
+                duration,
+                at,
+            );
+            notify("audit_sink", &|entry| sink.record(entry), entry);
+        }
+    }
+
+    // Reports `count` tracked clients to `on_key_count_exceeds`
+    pub(crate) fn notify_key_count_exceeded(&self, count: usize) {
+        if let Some(callback) = &self.on_key_count_exceeds {
+            notify("on_key_count_exceeds", &**callback, count);
+        }
+    }
+
+    // The client key as exposed in `RateLimitInfo` and `RateLimitRejection`
+    pub(crate) fn visible_key(&self, key: &str) -> Option<String> {
+        (!self.redact_key).then(|| key.to_owned())
+    }
+
+    // Whether the country rules exempt the client `key` from the limit, or the
+    // rejection of a client they deny, audited at the time of `clock`
+    pub(crate) fn bypasses_limit(
+        &self,
+        key: &str,
+        clock: &AnchoredClock,
+    ) -> Result<bool, warp::Rejection> {
+        #[cfg(feature = "geoip")]
+        return self.country_verdict(key).map_err(|denied| {
+            let reason = AuditReason::CountryDenied {
+                country: denied.country.clone(),
+            };
+            self.audit(key, reason, None, clock.wall(clock.now()));
+            warp::reject::custom(denied)
+        });
+        #[cfg(not(feature = "geoip"))]
+        {
+            let _ = (key, clock);
+            Ok(false)
+        }
+    }
+
+    // Whether the country rules exempt the client `key` from the limit, or the denial
+    // of the client, without recording anything
+    #[cfg(feature = "geoip")]
+    pub(crate) fn country_verdict(&self, key: &str) -> Result<bool, CountryDenied> {
+        if self.country_rules.is_empty() {
+            return Ok(false);
+        }
+        match self
+            .geoip_reader
+            .as_ref()
+            .and_then(|reader| reader.country_of(key))
+        {
+            Some(country) => self.country_rules.check(key, country),
+            None => Ok(false),
+        }
+    }
+
+    // The country limit of the client `key`, if the database locates it in one of them
+    #[cfg(feature = "geoip")]
+    fn country_limit(&self, key: &str) -> Option<&RateLimitConfig> {
+        if self.countries.is_empty() {
+            return None;
+        }
+        let code = self.geoip_reader.as_ref()?.country_of(key)?;
+        self.countries.get(code)
+    }
+
+    // Any number of requests is fine, 0 blocking everything, but not any window
+    fn validate_window(&self, window: Duration) -> Result<(), ConfigError> {
+        if window.is_zero() {
+            return Err(ConfigError::ZeroWindow);
+        }
+        // Reset times are computed by adding the window (and jitter) to the time of the
+        // clock of the rate limiter
+        let clock = self.clock.as_deref().unwrap_or(&SystemClock);
+        let longest_wait = window.saturating_add(self.retry_after_jitter.unwrap_or_default());
+        if window > MAX_WINDOW
+            || crate::time::checked_add(clock.now_wall(), longest_wait).is_none()
+            || clock.now_monotonic().checked_add(window).is_none()
+        {
+            return Err(ConfigError::WindowTooLarge(window));
+        }
+        Ok(())
+    }
+}
+
+/// Builder of a [`RateLimitConfig`], checking the configuration makes sense
+///
+/// ```rust,no_run,ignore
+/// let config = RateLimitConfig::builder()
+///     .max_requests(100)
+///     .window(Duration::from_secs(60))
+///     .ip_header("X-Real-IP")
+///     .build()?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfigBuilder {
+    config: RateLimitConfig,
+    // First invalid value given to a setter, reported by `build`
+    error: Option<ConfigError>,
+}
+
+/// Starts from an existing configuration, such as a preset
+impl From<RateLimitConfig> for RateLimitConfigBuilder {
+    fn from(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            error: None,
+        }
+    }
+}
+
+impl RateLimitConfigBuilder {
+    /// Sets the label copied into `RateLimitInfo` and `RateLimitRejection`
+    pub fn label(mut self, label: impl Into<Arc<str>>) -> Self {
+        self.config = self.config.with_label(label);
+        self
+    }
+
+    /// Sets the maximum number of requests allowed within the window, 0 rejecting every
+    /// request
+    pub fn max_requests(mut self, max_requests: u32) -> Self {
+        self.config = self.config.with_max_requests(max_requests);
+        self
+    }
+
+    /// Sets the time window for rate limiting, must not be zero nor longer than 100
+    /// years
+    ///
+    /// Accepts a `Duration` or text like `"90s"`, see [`parse_duration`].
+    pub fn window(mut self, window: impl IntoDuration) -> Self {
+        match window.into_duration() {
+            Ok(window) => self.config = self.config.with_window(window),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Sets the format of the Retry-After header
+    pub fn retry_after_format(mut self, retry_after_format: RetryAfterFormat) -> Self {
+        self.config = self.config.with_retry_after_format(retry_after_format);
+        self
+    }
+
+    /// Sets the maximum random delay added to the emitted Retry-After
+    pub fn retry_after_jitter(mut self, max_jitter: Duration) -> Self {
+        self.config = self.config.with_retry_after_jitter(max_jitter);
+        self
+    }
+
+    /// Sets the source of the jitter, which also requires `retry_after_jitter`
+    pub fn jitter_source(
+        mut self,
+        jitter_source: impl Fn(Duration) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.config = self.config.with_jitter_source(jitter_source);
+        self
+    }
+
+    /// Sets the family of rate limit headers emitted alongside Retry-After
+    pub fn header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.config = self.config.with_header_style(header_style);
+        self
+    }
+
+    /// Sets whether `X-RateLimit-Used` is emitted
+    pub fn used_header(mut self, used_header: bool) -> Self {
+        self.config = self.config.with_used_header(used_header);
+        self
+    }
+
+    /// Sets whether the client key is left out of `RateLimitInfo` and
+    /// `RateLimitRejection`
+    pub fn redact_key(mut self, redact_key: bool) -> Self {
+        self.config = self.config.with_redact_key(redact_key);
+        self
+    }
+
+    /// Sets whether the checks of the requests are timed
+    pub fn measure_latency(mut self, measure_latency: bool) -> Self {
+        self.config = self.config.with_measure_latency(measure_latency);
+        self
+    }
+
+    /// Sets the function turning the rejection of rate limited requests into another
+    /// rejection
+    pub fn rejection_mapper(
+        mut self,
+        mapper: impl Fn(RateLimitRejection) -> warp::Rejection + Send + Sync + 'static,
+    ) -> Self {
+        self.config = self.config.with_rejection_mapper(mapper);
+        self
+    }
+
+    /// Sets the function called with every rejected request
+    pub fn on_rejected(
+        mut self,
+        callback: impl Fn(RateLimitEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.config = self.config.with_on_rejected(callback);
+        self
+    }
+
+    /// Sets the function called with every admitted request
+    pub fn on_allowed(mut self, callback: impl Fn(RateLimitEvent) + Send + Sync + 'static) -> Self {
+        self.config = self.config.with_on_allowed(callback);
+        self
+    }
+
+    /// Sets the function called when the number of tracked clients grows above
+    /// `threshold`
+    pub fn on_key_count_exceeds(
+        mut self,
+        threshold: usize,
+        callback: impl Fn(usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.config = self.config.with_on_key_count_exceeds(threshold, callback);
+        self
+    }
+
+    /// Sets the number of clients followed as heavy hitters, must not be zero
+    pub fn heavy_hitters(mut self, heavy_hitters: usize) -> Self {
+        self.config = self.config.with_heavy_hitters(heavy_hitters);
+        self
+    }
+
+    /// Sets the share of its limit from which a client is traced under its own key,
+    /// must be above 0 and up to 1
+    pub fn heavy_usage(mut self, share: f64) -> Self {
+        self.config = self.config.with_heavy_usage(share);
+        self
+    }
+
+    /// Sets where the actions taken against clients are recorded
+    pub fn audit_sink(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.config = self.config.with_audit_sink(sink);
+        self
+    }
+
+    /// Sets the source of the time of the rate limiters
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config = self.config.with_clock(clock);
+        self
+    }
+
+    /// Sets where the requests of each client are counted
+    pub fn store(mut self, store: impl RateLimitStore + 'static) -> Self {
+        self.config = self.config.with_store(store);
+        self
+    }
+
+    /// Sets the upper bound of the emitted Retry-After, must not be zero
+    pub fn max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.config = self.config.with_max_retry_after(max_retry_after);
+        self
+    }
+
+    /// Sets how long idle clients are kept once their window is over, must not be zero
+    pub fn idle_ttl(mut self, idle_ttl: impl IntoDuration) -> Self {
+        match idle_ttl.into_duration() {
+            Ok(idle_ttl) => self.config = self.config.with_idle_ttl(idle_ttl),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Sets the number of tracked clients above which the limiter is reported as
+    /// degraded
+    pub fn health_max_keys(mut self, max_keys: usize) -> Self {
+        self.config = self.config.with_health_max_keys(max_keys);
+        self
+    }
+
+    /// Sets the time since the last pruning above which the limiter is reported as
+    /// degraded, must be longer than the idle ttl
+    pub fn health_max_prune_age(mut self, max_prune_age: impl IntoDuration) -> Self {
+        match max_prune_age.into_duration() {
+            Ok(max_prune_age) => self.config = self.config.with_health_max_prune_age(max_prune_age),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    /// Gives the client `key` its own limit, which must be valid like the default one
+    pub fn key_override(
+        mut self,
+        key: impl Into<String>,
+        max_requests: u32,
+        window: Duration,
+    ) -> Self {
+        self.config = self.config.with_key_override(key, max_requests, window);
+        self
+    }
+
+    /// Adds a tier, whose limit must be valid and name unique
+    pub fn tier(mut self, name: impl Into<String>, config: RateLimitConfig) -> Self {
+        self.config = self.config.with_tier(name, config);
+        self
+    }
+
+    /// Sets the function classifying client keys into tiers, required when using tiers
+    pub fn tier_classifier(
+        mut self,
+        classifier: impl Fn(&str) -> Option<&str> + Send + Sync + 'static,
+    ) -> Self {
+        self.config = self.config.with_tier_classifier(classifier);
+        self
+    }
+
+    /// Gives the clients located in the country `code` their own limit, which must be
+    /// valid like the default one
+    #[cfg(feature = "geoip")]
+    pub fn country(mut self, code: impl Into<String>, config: RateLimitConfig) -> Self {
+        self.config = self.config.with_country(code, config);
+        self
+    }
+
+    /// Sets the database locating clients in their country, required when using
+    /// country limits
+    #[cfg(feature = "geoip")]
+    pub fn geoip_reader(mut self, reader: impl Into<GeoIpReader>) -> Self {
+        self.config = self.config.with_geoip_reader(reader);
+        self
+    }
+
+    /// Rejects the clients located in one of these countries, requires a GeoIP reader
+    #[cfg(feature = "geoip")]
+    pub fn denied_countries(mut self, codes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config = self.config.with_denied_countries(codes);
+        self
+    }
+
+    /// Exempts the clients located in one of these countries from the limit, requires
+    /// a GeoIP reader
+    #[cfg(feature = "geoip")]
+    pub fn allowed_countries(mut self, codes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config = self.config.with_allowed_countries(codes);
+        self
+    }
+
+    /// Sets the status and body of the responses to denied countries, the status must
+    /// be a client or server error
+    #[cfg(feature = "geoip")]
+    pub fn country_denial(
+        mut self,
+        status: warp::http::StatusCode,
+        reason: impl Into<String>,
+    ) -> Self {
+        self.config = self.config.with_country_denial(status, reason);
+        self
+    }
+
+    /// Sets the header used to extract the client's ip address, must be a valid header name
+    pub fn ip_header(mut self, ip_header: impl Into<String>) -> Self {
+        self.config = self.config.with_ip_header(ip_header);
+        self
+    }
+
+    /// Ignores all headers and uses the remote address of the connection instead
+    pub fn without_ip_header(mut self) -> Self {
+        self.config = self.config.without_ip_header();
+        self
+    }
+
+    /// Sets the networks of the trusted reverse proxies
+    pub fn trusted_proxies(mut self, trusted: impl IntoIterator<Item = IpNet>) -> Self {
+        self.config = self.config.with_trusted_proxies(trusted);
+        self
+    }
+
+    /// Sets whether the clients without a usable `ip_header` share the `"unknown"`
+    /// bucket
+    pub fn unknown_bucket(mut self, unknown_bucket: bool) -> Self {
+        self.config = self.config.with_unknown_bucket(unknown_bucket);
+        self
+    }
+
+    /// Validates and returns the configuration
+    pub fn build(self) -> Result<RateLimitConfig, ConfigError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Parses durations like `"90s"`, `"250ms"` or `"1h30m"`, made of integers each
+/// followed by one of the units `d`, `h`, `m`, `s` and `ms`
+///
+/// The error names the part of the text that could not be parsed.
+pub fn parse_duration(duration: &str) -> Result<Duration, ConfigError> {
+    duration::parse(duration)
+}
+
+/// A duration given either as a [`Duration`] or as text parsed by [`parse_duration`]
+pub trait IntoDuration {
+    /// Converts into a `Duration`
+    fn into_duration(self) -> Result<Duration, ConfigError>;
+}
+
+impl IntoDuration for Duration {
+    fn into_duration(self) -> Result<Duration, ConfigError> {
+        Ok(self)
+    }
+}
+
+impl IntoDuration for &str {
+    fn into_duration(self) -> Result<Duration, ConfigError> {
+        parse_duration(self)
+    }
+}
+
+impl IntoDuration for String {
+    fn into_duration(self) -> Result<Duration, ConfigError> {
+        parse_duration(&self)
+    }
+}
+
+/// Parses `"100/1m"`, `"30/10s"` or `"5/s"` like shorthands into the maximum number of
+/// requests and the window
+pub fn parse_limit(limit: &str) -> Result<(u32, Duration), ConfigError> {
+    let error = |reason| ConfigError::InvalidLimit {
+        input: limit.to_owned(),
+        reason,
+    };
+    let (count, window) = limit.split_once('/').ok_or(error("missing '/'"))?;
+    let count = count
+        .trim()
+        .parse()
+        .map_err(|_| error("COUNT is not a non-negative integer"))?;
+    let window = window.trim();
+    // A bare unit means one of it, as in "5/s"
+    let window = if window.starts_with(|c: char| c.is_ascii_digit()) {
+        duration::parse(window)
+    } else {
+        duration::parse(&format!("1{}", window))
+    };
+    let window =
+        window.map_err(|_| error("DURATION is not a duration like \"10s\" or \"1h30m\""))?;
+    Ok((count, window))
+}
+
+/// Parses `"100/1m"` like shorthands, see [`parse_limit`]. The other options keep their
+/// defaults and the result is validated.
+impl FromStr for RateLimitConfig {
+    type Err = ConfigError;
+
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let (max_requests, window) = parse_limit(limit)?;
+        RateLimitConfig::builder()
+            .max_requests(max_requests)
+            .window(window)
+            .build()
+    }
+}
+
+// (De)serialization of the overrides as "100/1m" like strings
+#[cfg(feature = "serde")]
+mod overrides {
+    use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serializer};
+    use std::{collections::HashMap, time::Duration};
+
+    pub fn serialize<S: Serializer>(
+        overrides: &HashMap<String, (u32, Duration)>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(overrides.len()))?;
+        for (key, (max_requests, window)) in overrides {
+            map.serialize_entry(
+                key,
+                &format!("{}/{}", max_requests, super::duration::format(*window)),
+            )?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, (u32, Duration)>, D::Error> {
+        HashMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, limit)| {
+                let limit = super::parse_limit(&limit).map_err(de::Error::custom)?;
+                Ok((key, limit))
+            })
+            .collect()
+    }
+}
+
+// Members that can't be serialized, written as a marker telling whether they are set
+// and ignored when deserializing, so that a serialized configuration reads back
+#[cfg(feature = "serde")]
+mod opaque {
+    use serde::{de::IgnoredAny, Deserialize, Deserializer, Serializer};
+
+    pub fn function<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        marker(value.is_some(), "<function>", serializer)
+    }
+
+    pub fn sink<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        marker(value.is_some(), "<sink>", serializer)
+    }
+
+    pub fn clock<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        marker(value.is_some(), "<clock>", serializer)
+    }
+
+    pub fn store<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        marker(value.is_some(), "<store>", serializer)
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn database<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        marker(value.is_some(), "<database>", serializer)
+    }
+
+    fn marker<S: Serializer>(set: bool, marker: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        if set {
+            serializer.serialize_some(marker)
+        } else {
+            serializer.serialize_none()
+        }
+    }
+
+    pub fn ignore<'de, T, D: Deserializer<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+        IgnoredAny::deserialize(deserializer)?;
+        Ok(None)
+    }
+}
+
+// (De)serialization of durations as integer seconds or strings like "1h30m"
+pub(crate) mod duration {
+    use crate::ConfigError;
+    #[cfg(feature = "serde")]
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    #[cfg(feature = "serde")]
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Seconds(u64),
+        Text(String),
+    }
+
+    #[cfg(feature = "serde")]
+    impl Repr {
+        fn into_duration<E: de::Error>(self) -> Result<Duration, E> {
+            match self {
+                Repr::Seconds(secs) => Ok(Duration::from_secs(secs)),
+                Repr::Text(text) => parse(&text).map_err(E::custom),
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(*duration))
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Repr::deserialize(deserializer)?.into_duration()
+    }
+
+    #[cfg(feature = "serde")]
+    pub mod option {
+        use super::Repr;
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(
+            duration: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match duration {
+                Some(duration) => serializer.serialize_some(&super::format(*duration)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            Option::<Repr>::deserialize(deserializer)?
+                .map(Repr::into_duration)
+                .transpose()
+        }
+    }
+
+    const UNITS: [(&str, u64); 5] = [
+        ("d", 24 * 60 * 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+
+    // A sequence of integers each followed by a unit, e.g. "1h30m" or "250ms"
+    pub(super) fn parse(text: &str) -> Result<Duration, ConfigError> {
+        let error = |reason, token: &str| ConfigError::InvalidDuration {
+            input: text.to_owned(),
+            token: token.to_owned(),
+            reason,
+        };
+        let mut rest = text.trim();
+        let mut millis: u64 = 0;
+        if rest.is_empty() {
+            return Err(error("expected a duration like \"90s\" or \"1m30s\"", ""));
+        }
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let unit_len = rest[digits..]
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(rest.len() - digits);
+            let (number, unit) = (&rest[..digits], rest[digits..digits + unit_len].trim());
+            let token = &rest[..digits + unit_len];
+            if number.is_empty() {
+                return Err(error("expected a number before", unit));
+            }
+            if unit.is_empty() {
+                return Err(error("missing unit after", number));
+            }
+            let (_, factor) = UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .ok_or_else(|| error("unknown unit", unit))?;
+            millis = number
+                .parse::<u64>()
+                .ok()
+                .and_then(|value| value.checked_mul(*factor))
+                .and_then(|value| millis.checked_add(value))
+                .ok_or_else(|| error("too large", token.trim()))?;
+            rest = &rest[digits + unit_len..];
+        }
+        Ok(Duration::from_millis(millis))
+    }
+
+    // Uses the largest unit representing the duration exactly, so it parses back
+    #[cfg(feature = "serde")]
+    pub(super) fn format(duration: Duration) -> String {
+        let millis = duration.as_millis() as u64;
+        let (name, factor) = UNITS
+            .iter()
+            .find(|(_, factor)| millis.is_multiple_of(*factor))
+            .unwrap_or(&("ms", 1));
+        format!("{}{}", millis / factor, name)
+    }
 }
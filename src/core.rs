@@ -0,0 +1,465 @@
+//! The counting logic of the rate limiters, without anything of warp or http
+//!
+//! Every filter of the crate counts requests with it, but it can also be used on its
+//! own, e.g. to limit the jobs of a scheduler or the messages of a WebSocket, with a
+//! [`CoreLimiter`]:
+//!
+//! ```rust,no_run,ignore
+//! let limiter = CoreLimiter::new(RateLimitConfig::max_per_minute(10))?;
+//! match limiter.check(&user_id) {
+//!     Ok(quota) => run_job(quota.remaining),
+//!     Err(exceeded) => reschedule(exceeded.retry_after),
+//! }
+//! ```
+
+use crate::{
+    counters::KeyGauge, store::WindowCount, time::AnchoredClock, trace, BanReason, ConfigError,
+    RateLimitConfig, Timestamp,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// A rate limiter counting the requests of each client in fixed windows, like the
+/// filters of the crate but called directly, see the [module](self) documentation
+///
+/// The limit of each client follows the config, key overrides and tiers included.
+/// The settings about responses, like the headers or the `Retry-After` format, don't
+/// apply. Cloning is cheap and the clones share the state.
+#[derive(Clone)]
+pub struct CoreLimiter {
+    state: Arc<Mutex<RateLimiterMap>>,
+    config: Arc<RateLimitConfig>,
+}
+
+/// What a client has left in its window once a request is counted, see
+/// [`CoreLimiter::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Quota {
+    /// Requests the client may make in the window
+    pub limit: u32,
+    /// Requests the client made in the window
+    pub used: u32,
+    /// Requests the client may still make in the window
+    pub remaining: u32,
+    /// Length of the window
+    pub window: Duration,
+    /// When the window started
+    pub window_start: Instant,
+    /// Time until the window ends
+    pub resets_in: Duration,
+}
+
+/// A request exceeding the limit of its client, see [`CoreLimiter::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Exceeded {
+    /// Requests the client may make in the window
+    pub limit: u32,
+    /// Requests the client made in the window, not counting the rejected one
+    pub used: u32,
+    /// Length of the window
+    pub window: Duration,
+    /// Time until the client may make requests again, the end of its window or of its
+    /// ban
+    pub retry_after: Duration,
+    /// Whether the client is banned, rather than out of requests
+    pub banned: bool,
+}
+
+impl CoreLimiter {
+    /// Creates a rate limiter with the given configuration, or the error making it
+    /// invalid
+    pub fn new(config: RateLimitConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(RateLimiterMap::new(
+                Arc::default(),
+                config.anchored_clock(),
+            ))),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Counts a request of the client `key`, giving back what it has left, or why the
+    /// request exceeds its limit
+    pub fn check(&self, key: &str) -> Result<Quota, Exceeded> {
+        let mut map = self.lock();
+        let now = map.clock.now();
+        map.cleanup(&self.config, now);
+        let limit = self.config.limit_for(key);
+        match map.incr_within_window(key, &limit, now, 1) {
+            Ok(entry) => Ok(entry.quota(&limit, now)),
+            Err(entry) => {
+                let (entry, _) = map.record_rejection(key, entry, now);
+                Err(entry.exceeded(&limit, now))
+            }
+        }
+    }
+
+    /// Forgets the client `key`, lifting its ban, giving back whether it was tracked
+    pub fn reset(&self, key: &str) -> bool {
+        self.lock().remove(key).is_some()
+    }
+
+    /// Number of clients currently tracked
+    pub fn key_count(&self) -> usize {
+        self.lock().inner.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RateLimiterMap> {
+        // Entries are replaced as a whole, so a panic can't leave a broken one behind
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl std::fmt::Debug for CoreLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CoreLimiter")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+// The limit applying to a client, see `RateLimitConfig::limit_for`
+pub(crate) struct KeyLimit<'a> {
+    pub max_requests: u32,
+    pub window: Duration,
+    pub tier: Option<&'a str>,
+}
+
+// I really didn't want to have two different Arc<RwLock<T>> for data so interlinked
+#[derive(Clone)]
+pub(crate) struct RateLimiterMap {
+    pub(crate) inner: HashMap<String, Entry>,
+    // Active bans by key, apart from the entries so that listing them doesn't go
+    // through every client. Mirrored by the `banned_until` of the entries, which the
+    // requests check.
+    pub(crate) bans: HashMap<String, Ban>,
+    pub(crate) last_cleanup: Instant,
+    // Where every reading of the time comes from
+    pub(crate) clock: AnchoredClock,
+    // Number of entries, readable without the lock
+    keys: Arc<KeyGauge>,
+}
+
+// Requests of a client in its current window
+#[derive(Clone, Copy)]
+pub(crate) struct Entry {
+    pub(crate) start: Instant,
+    // Wall-clock time of `start`, converted once so that the reset reported to the
+    // client stays the same for the whole window, and the anchor of every wall-clock
+    // time derived from the window, see `wall`
+    pub(crate) started_at: Timestamp,
+    pub(crate) count: u32,
+    // The window in effect when this one started, kept until it rolls over
+    pub(crate) window: Duration,
+    // Requests admitted by a `CompletionRateLimit` that didn't complete yet, kept
+    // across rollovers
+    pub(crate) pending: u32,
+    // Last request of the client, from which `idle_ttl` counts
+    pub(crate) last_seen: Instant,
+    // First rejection of the client in this window
+    pub(crate) first_rejected_at: Option<Timestamp>,
+    // End of the ban set by `RateLimiterHandle::block_key`, kept across rollovers
+    pub(crate) banned_until: Option<Instant>,
+    // Requests granted on top of the limit by `RateLimiterHandle::grant`, for this
+    // window only
+    pub(crate) credit: u32,
+}
+
+// A ban of a client, see `RateLimiterHandle::block_key`
+#[derive(Clone)]
+pub(crate) struct Ban {
+    pub(crate) reason: BanReason,
+    pub(crate) imposed_at: Timestamp,
+    pub(crate) expires_at: Timestamp,
+    pub(crate) until: Instant,
+}
+
+impl Ban {
+    pub(crate) fn new(
+        reason: BanReason,
+        clock: &AnchoredClock,
+        now: Instant,
+        duration: Duration,
+    ) -> Self {
+        let imposed_at = clock.wall(now);
+        Self {
+            reason,
+            imposed_at,
+            expires_at: imposed_at + duration,
+            until: now + duration,
+        }
+    }
+}
+
+impl Entry {
+    // The time left on the ban of the client, if it is banned at `now`
+    pub(crate) fn ban_left(&self, now: Instant) -> Option<Duration> {
+        self.banned_until
+            .filter(|&until| until > now)
+            .map(|until| until - now)
+    }
+
+    // Whether a request of the client consuming `cost` units fits in this window under
+    // `limit` at `now`
+    pub(crate) fn admits(&self, limit: &KeyLimit, now: Instant, cost: u32) -> bool {
+        self.count.saturating_add(cost) <= self.allowance(limit) && self.ban_left(now).is_none()
+    }
+
+    // Requests the client may make in this window under `limit`, credits included
+    pub(crate) fn allowance(&self, limit: &KeyLimit) -> u32 {
+        limit.max_requests.saturating_add(self.credit)
+    }
+
+    // Wall-clock time at which this window ends
+    pub(crate) fn reset_at(&self) -> Timestamp {
+        self.started_at + self.window
+    }
+
+    // Wall-clock time of `at`, as far from `started_at` as `at` is from `start`, so
+    // that the times told to the client agree with the reset of its window
+    pub(crate) fn wall(&self, at: Instant) -> Timestamp {
+        match at.checked_duration_since(self.start) {
+            Some(since) => self.started_at + since,
+            None => self.started_at - (self.start - at),
+        }
+    }
+
+    // What the client has left in this window under `limit` at `now`
+    pub(crate) fn quota(&self, limit: &KeyLimit, now: Instant) -> Quota {
+        Quota {
+            limit: limit.max_requests,
+            used: self.count,
+            // The limit may have been lowered below the count by a config update, and
+            // banned clients have nothing left whatever their count
+            remaining: match self.ban_left(now) {
+                Some(_) => 0,
+                None => self.allowance(limit).saturating_sub(self.count),
+            },
+            window: self.window,
+            window_start: self.start,
+            resets_in: self.window.saturating_sub(now.duration_since(self.start)),
+        }
+    }
+
+    // Why a request of the client is rejected under `limit` at `now`
+    pub(crate) fn exceeded(&self, limit: &KeyLimit, now: Instant) -> Exceeded {
+        let ban_left = self.ban_left(now);
+        Exceeded {
+            limit: limit.max_requests,
+            used: self.count,
+            window: self.window,
+            retry_after: ban_left
+                .unwrap_or_else(|| self.window.saturating_sub(now.duration_since(self.start))),
+            banned: ban_left.is_some(),
+        }
+    }
+}
+
+impl RateLimiterMap {
+    pub(crate) fn new(keys: Arc<KeyGauge>, clock: AnchoredClock) -> Self {
+        Self {
+            inner: HashMap::default(),
+            bans: HashMap::default(),
+            last_cleanup: clock.now(),
+            clock,
+            keys,
+        }
+    }
+
+    // Removes the entries of clients whose window is over and who have been idle for
+    // the configured `idle_ttl`, at most once per `idle_ttl`, and gives back how many
+    pub(crate) fn cleanup(&mut self, config: &RateLimitConfig, now: Instant) -> usize {
+        let evicted = self.prune(config.idle_ttl.unwrap_or(config.window), now);
+        if evicted > 0 {
+            trace::evicted(config.label.as_deref(), evicted, self.inner.len());
+        }
+        evicted
+    }
+
+    // Same as `cleanup`, with clients idle for `idle_ttl`, without tracing anything
+    pub(crate) fn prune(&mut self, idle_ttl: Duration, now: Instant) -> usize {
+        if now - self.last_cleanup <= idle_ttl {
+            return 0;
+        }
+        let tracked = self.inner.len();
+        self.bans.retain(|_key, ban| ban.until > now);
+        self.inner.retain(|_ip, entry| {
+            entry.pending > 0
+                || entry.ban_left(now).is_some()
+                || now - entry.start < entry.window
+                || now - entry.last_seen < idle_ttl
+        });
+        self.last_cleanup = now;
+        let evicted = tracked - self.inner.len();
+        self.keys.removed(evicted);
+        evicted
+    }
+
+    // Sets the entry of `key`, keeping track of the number of entries
+    pub(crate) fn insert(&mut self, key: &str, entry: Entry) {
+        if self.inner.insert(key.to_owned(), entry).is_none() {
+            self.keys.added();
+        }
+    }
+
+    // Removes the entry of `key`, and its ban, keeping track of the number of entries
+    pub(crate) fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.bans.remove(key);
+        let entry = self.inner.remove(key);
+        if entry.is_some() {
+            self.keys.removed(1);
+        }
+        entry
+    }
+
+    // Removes every entry, and every ban, giving back how many entries there were
+    pub(crate) fn clear(&mut self) -> usize {
+        let cleared = self.inner.len();
+        self.bans.clear();
+        self.inner.clear();
+        self.keys.removed(cleared);
+        cleared
+    }
+
+    // Bans `key`, whose current entry is `entry`, replacing any previous ban
+    pub(crate) fn ban(&mut self, key: &str, entry: Entry, ban: Ban) {
+        let banned_until = Some(ban.until);
+        self.bans.insert(key.to_owned(), ban);
+        self.insert(
+            key,
+            Entry {
+                banned_until,
+                ..entry
+            },
+        );
+    }
+
+    // Lifts the ban of `key`, giving back whether it was still running at `now`
+    pub(crate) fn unban(&mut self, key: &str, now: Instant) -> bool {
+        if let Some(entry) = self.inner.get_mut(key) {
+            entry.banned_until = None;
+        }
+        self.bans.remove(key).is_some_and(|ban| ban.until > now)
+    }
+
+    // The entry of `key` in its current window, starting a new window if needed
+    pub(crate) fn current_entry(&self, key: &str, limit: &KeyLimit, now: Instant) -> Entry {
+        let (entry, reset) = self.window_entry(key, limit, now);
+        if reset {
+            trace::window_reset(limit.window);
+        }
+        entry
+    }
+
+    // The entry of `key` in its current window, and whether its previous window is
+    // over, without tracing anything
+    pub(crate) fn window_entry(&self, key: &str, limit: &KeyLimit, now: Instant) -> (Entry, bool) {
+        match self.inner.get(key) {
+            Some(entry) if now.duration_since(entry.start) <= entry.window => (*entry, false),
+            entry => {
+                let fresh = Entry {
+                    start: now,
+                    started_at: self.clock.wall(now),
+                    count: 0,
+                    window: limit.window,
+                    pending: entry.map_or(0, |entry| entry.pending),
+                    last_seen: entry.map_or(now, |entry| entry.last_seen),
+                    first_rejected_at: None,
+                    banned_until: entry.and_then(|entry| entry.banned_until),
+                    credit: 0,
+                };
+                (fresh, entry.is_some())
+            }
+        }
+    }
+
+    // The entry of `key` as counted by a `RateLimitStore`, whose window ends in
+    // `count.resets_in` at `now`, keeping the ban, the pending requests and the first
+    // rejection of the client
+    pub(crate) fn store_entry(
+        &self,
+        key: &str,
+        count: &WindowCount,
+        limit: &KeyLimit,
+        now: Instant,
+    ) -> Entry {
+        let (entry, _) = self.window_entry(key, limit, now);
+        let start = (now + count.resets_in)
+            .checked_sub(limit.window)
+            .unwrap_or(now);
+        Entry {
+            start,
+            started_at: self.clock.wall(start),
+            count: count.count,
+            window: limit.window,
+            last_seen: now,
+            credit: 0,
+            ..entry
+        }
+    }
+
+    // Records that the request of `key`, whose current entry is `entry`, exceeds the
+    // limit at `now`, and gives back the updated entry, and whether it is the first
+    // rejection of the window
+    pub(crate) fn record_rejection(
+        &mut self,
+        key: &str,
+        mut entry: Entry,
+        now: Instant,
+    ) -> (Entry, bool) {
+        let first = entry.first_rejected_at.is_none();
+        if first {
+            entry.first_rejected_at = Some(entry.wall(now));
+            self.insert(key, entry);
+        }
+        (entry, first)
+    }
+}
+
+impl RateLimiterMap {
+    // Counts a request of `key` consuming `cost` units if it fits under `limit`, giving
+    // back the updated entry, or else the current entry, unchanged
+    //
+    // The check and the increment are a single step on the exclusively borrowed map,
+    // the contract of `RateLimitStore::incr_within_window`, so that two requests can't
+    // both fit in the last slot of a window
+    pub(crate) fn incr_within_window(
+        &mut self,
+        key: &str,
+        limit: &KeyLimit,
+        now: Instant,
+        cost: u32,
+    ) -> Result<Entry, Entry> {
+        let entry = self.current_entry(key, limit, now);
+        if !entry.admits(limit, now, cost) {
+            return Err(entry);
+        }
+        let entry = Entry {
+            count: entry.count.saturating_add(cost),
+            last_seen: now,
+            ..entry
+        };
+        self.insert(key, entry);
+        Ok(entry)
+    }
+
+    // Takes `n` units off the count of `key` if its window is still the one started at
+    // `start` at `now`, telling whether they were
+    pub(crate) fn refund(&mut self, key: &str, start: Instant, n: u32, now: Instant) -> bool {
+        match self.inner.get_mut(key) {
+            Some(entry)
+                if entry.start == start && now.duration_since(entry.start) <= entry.window =>
+            {
+                entry.count = entry.count.saturating_sub(n);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+/// Totals of the requests seen by a rate limiter, see [`crate::RateLimiterHandle::counters`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RateLimitCounters {
+    /// Requests counted and admitted, not including the clients exempted from the limit
+    pub allowed: u64,
+    /// Requests exceeding the limit
+    pub rejected: u64,
+    /// Idle clients pruned from the state
+    pub evicted: u64,
+    /// Requests let through because the [`crate::RateLimitConfig::store`] failed to
+    /// count them, always 0 with the in-memory state
+    pub store_errors: u64,
+    /// Requests of clients that couldn't be identified, counted in the shared
+    /// `"unknown"` bucket, see [`crate::RateLimitConfig::unknown_bucket`]
+    pub unknown: u64,
+}
+
+impl RateLimitCounters {
+    // The counts since `previous`, an earlier snapshot, or since the reset of the
+    // counters if they were `reset` in between
+    pub(crate) fn since(&self, previous: &RateLimitCounters, reset: bool) -> RateLimitCounters {
+        if reset {
+            return *self;
+        }
+        RateLimitCounters {
+            allowed: self.allowed.saturating_sub(previous.allowed),
+            rejected: self.rejected.saturating_sub(previous.rejected),
+            evicted: self.evicted.saturating_sub(previous.evicted),
+            store_errors: self.store_errors.saturating_sub(previous.store_errors),
+            unknown: self.unknown.saturating_sub(previous.unknown),
+        }
+    }
+}
+
+// The live counters of a limiter, updated without locking its state, in total and for
+// each label its configuration had
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    total: Totals,
+    // Only write locked the first time a label is seen
+    by_label: RwLock<HashMap<Arc<str>, Arc<Totals>>>,
+    // Number of resets, telling the counts since a snapshot apart from those since
+    // a reset
+    resets: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+    evicted: AtomicU64,
+    store_errors: AtomicU64,
+    unknown: AtomicU64,
+}
+
+impl Totals {
+    fn snapshot(&self) -> RateLimitCounters {
+        RateLimitCounters {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            store_errors: self.store_errors.load(Ordering::Relaxed),
+            unknown: self.unknown.load(Ordering::Relaxed),
+        }
+    }
+
+    // Each counter is swapped on its own, so that a request counted concurrently is
+    // counted in either period, never lost
+    fn reset(&self) -> RateLimitCounters {
+        RateLimitCounters {
+            allowed: self.allowed.swap(0, Ordering::Relaxed),
+            rejected: self.rejected.swap(0, Ordering::Relaxed),
+            evicted: self.evicted.swap(0, Ordering::Relaxed),
+            store_errors: self.store_errors.swap(0, Ordering::Relaxed),
+            unknown: self.unknown.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Counters {
+    pub(crate) fn allowed(&self, label: Option<&Arc<str>>) {
+        self.add(label, |totals| &totals.allowed, 1);
+    }
+
+    pub(crate) fn rejected(&self, label: Option<&Arc<str>>) {
+        self.add(label, |totals| &totals.rejected, 1);
+    }
+
+    pub(crate) fn evicted(&self, label: Option<&Arc<str>>, evicted: usize) {
+        if evicted > 0 {
+            self.add(label, |totals| &totals.evicted, evicted as u64);
+        }
+    }
+
+    pub(crate) fn store_error(&self, label: Option<&Arc<str>>) {
+        self.add(label, |totals| &totals.store_errors, 1);
+    }
+
+    pub(crate) fn unknown(&self, label: Option<&Arc<str>>) {
+        self.add(label, |totals| &totals.unknown, 1);
+    }
+
+    fn add(&self, label: Option<&Arc<str>>, counter: fn(&Totals) -> &AtomicU64, n: u64) {
+        counter(&self.total).fetch_add(n, Ordering::Relaxed);
+        if let Some(label) = label {
+            counter(&self.labelled(label)).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn labelled(&self, label: &Arc<str>) -> Arc<Totals> {
+        // Counters are updated as a whole, so a panic can't leave broken ones behind
+        let by_label = self.by_label.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(totals) = by_label.get(label) {
+            return totals.clone();
+        }
+        drop(by_label);
+        let mut by_label = self
+            .by_label
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        by_label.entry(label.clone()).or_default().clone()
+    }
+
+    pub(crate) fn snapshot(&self) -> RateLimitCounters {
+        self.total.snapshot()
+    }
+
+    pub(crate) fn by_label(&self) -> HashMap<Arc<str>, RateLimitCounters> {
+        let by_label = self.by_label.read().unwrap_or_else(PoisonError::into_inner);
+        by_label
+            .iter()
+            .map(|(label, totals)| (label.clone(), totals.snapshot()))
+            .collect()
+    }
+
+    pub(crate) fn resets(&self) -> u64 {
+        self.resets.load(Ordering::Relaxed)
+    }
+
+    // Zeroes the counters, the ones of every label included, giving back the totals
+    pub(crate) fn reset(&self) -> RateLimitCounters {
+        self.resets.fetch_add(1, Ordering::Relaxed);
+        let by_label = self.by_label.read().unwrap_or_else(PoisonError::into_inner);
+        for totals in by_label.values() {
+            totals.reset();
+        }
+        self.total.reset()
+    }
+}
+
+// Number of clients tracked by a limiter, maintained as entries come and go so that it
+// can be read without locking the state
+#[derive(Debug, Default)]
+pub(crate) struct KeyGauge {
+    count: AtomicUsize,
+    // Whether the count went above the threshold of the config and was reported
+    exceeded: AtomicBool,
+}
+
+impl KeyGauge {
+    pub(crate) fn get(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn added(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn removed(&self, removed: usize) {
+        self.count.fetch_sub(removed, Ordering::Relaxed);
+    }
+
+    // The count if it went above `threshold` since it was last seen at or below it,
+    // so that each upward crossing is only reported once
+    pub(crate) fn crossed(&self, threshold: usize) -> Option<usize> {
+        let count = self.get();
+        if count <= threshold {
+            self.exceeded.store(false, Ordering::Relaxed);
+            return None;
+        }
+        (!self.exceeded.swap(true, Ordering::Relaxed)).then_some(count)
+    }
+}
+
+/// Clients sending the most requests to a rate limiter, see
+/// [`crate::RateLimiterHandle::heavy_hitters`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct HeavyHitterReport {
+    /// The clients followed individually, by decreasing number of requests
+    pub hitters: Vec<HeavyHitter>,
+    /// Requests of all the other clients, the long tail
+    pub other: u64,
+}
+
+/// A client followed individually by [`HeavyHitterReport`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct HeavyHitter {
+    /// Key of the client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Hash of the key when the config redacts it, the same as in the traces
+    pub key_hash: Option<u64>,
+    /// Requests of the client, a lower bound missing at most the requests of the
+    /// report divided by [`RateLimitConfig::heavy_hitters`](crate::RateLimitConfig::heavy_hitters) + 1
+    pub requests: u64,
+}
+
+// Clients sending the most requests to a limiter, followed with a Misra-Gries sketch:
+// at most `capacity` counters are kept, and any client sending more than
+// 1/(capacity + 1) of the requests is guaranteed to hold one
+#[derive(Debug, Default)]
+pub(crate) struct HeavyHitterSketch {
+    sketch: Mutex<Sketch>,
+}
+
+#[derive(Debug, Default)]
+struct Sketch {
+    // Lower bounds of the requests of the followed clients
+    counts: HashMap<String, u64>,
+    total: u64,
+}
+
+impl HeavyHitterSketch {
+    // Counts a request of `key`, giving back whether it is followed afterwards
+    pub(crate) fn observe(&self, key: &str, capacity: usize) -> bool {
+        // Counts are updated one at a time, so a panic can't leave broken ones behind
+        let mut sketch = self.sketch.lock().unwrap_or_else(PoisonError::into_inner);
+        sketch.total += 1;
+        if let Some(count) = sketch.counts.get_mut(key) {
+            *count += 1;
+            return true;
+        }
+        if sketch.counts.len() < capacity {
+            sketch.counts.insert(key.to_owned(), 1);
+            return true;
+        }
+        // Full: the request cancels out one of each followed client, dropping the
+        // ones left without any
+        sketch.counts.retain(|_, count| {
+            *count -= 1;
+            *count > 0
+        });
+        false
+    }
+
+    // The followed clients with their counts, by decreasing count, and the requests
+    // counted in total
+    pub(crate) fn snapshot(&self) -> (Vec<(String, u64)>, u64) {
+        let sketch = self.sketch.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut counts: Vec<_> = sketch
+            .counts
+            .iter()
+            .map(|(key, &count)| (key.clone(), count))
+            .collect();
+        counts.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        (counts, sketch.total)
+    }
+}
+
+// Upper bounds of the buckets of the latency histogram, the last bucket holding the
+// slower checks
+const LATENCY_BOUNDS_MICROS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 5_000, 25_000];
+
+/// Distribution of the time taken to check requests against a rate limiter, see
+/// [`crate::RateLimiterHandle::latency_histogram`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct LatencyHistogram {
+    /// Checks per bucket, by increasing duration. Each bucket counts the checks slower
+    /// than the previous one, not cumulatively.
+    pub buckets: Vec<LatencyBucket>,
+    /// Number of checks measured
+    pub count: u64,
+    /// Total time of the checks measured
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::seconds::serialize"))]
+    pub sum: Duration,
+}
+
+/// A bucket of a [`LatencyHistogram`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct LatencyBucket {
+    /// Longest duration of the checks in the bucket, `None` for the last one
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::seconds::option"))]
+    pub le: Option<Duration>,
+    /// Checks in the bucket
+    pub count: u64,
+}
+
+// Time taken by the checks of a limiter, in fixed buckets updated without locking
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    buckets: [AtomicU64; LATENCY_BOUNDS_MICROS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Latencies {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros();
+        let bucket = LATENCY_BOUNDS_MICROS
+            .iter()
+            .position(|&bound| micros <= u128::from(bound))
+            .unwrap_or(LATENCY_BOUNDS_MICROS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        let bounds = LATENCY_BOUNDS_MICROS
+            .iter()
+            .map(|&micros| Some(Duration::from_micros(micros)));
+        let buckets: Vec<_> = bounds
+            .chain([None])
+            .zip(&self.buckets)
+            .map(|(le, count)| LatencyBucket {
+                le,
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect();
+        LatencyHistogram {
+            count: buckets.iter().map(|bucket| bucket.count).sum(),
+            buckets,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -1,8 +1,15 @@
+use warp::http::header::HeaderName;
+
 /// Errors that can occur during rate limiting logic
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RateLimitError {
     /// Failed to set rate limit headers
     HeaderError(warp::http::header::InvalidHeaderValue),
+    /// A rate limit header was already present and the policy forbids touching it
+    HeaderExists(HeaderName),
+    /// The rate limiting configuration is invalid
+    Config(ConfigError),
     /// Other unexpected errors
     Other(Box<dyn std::error::Error + Send + Sync>),
 }
@@ -11,6 +18,10 @@ impl std::fmt::Display for RateLimitError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RateLimitError::HeaderError(e) => write!(f, "Failed to set rate limit header: {}", e),
+            RateLimitError::HeaderExists(name) => {
+                write!(f, "Rate limit header already present: {}", name)
+            }
+            RateLimitError::Config(e) => write!(f, "Invalid rate limit configuration: {}", e),
             RateLimitError::Other(e) => write!(f, "Rate limit error: {}", e),
         }
     }
@@ -20,7 +31,199 @@ impl std::error::Error for RateLimitError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             RateLimitError::HeaderError(e) => Some(e),
+            RateLimitError::HeaderExists(_) => None,
+            RateLimitError::Config(e) => Some(e),
             RateLimitError::Other(e) => Some(&**e),
         }
     }
 }
+
+// Rejects the requests whose limiter couldn't be created, see `MultiRateLimiter::filter`
+impl warp::reject::Reject for RateLimitError {}
+
+impl From<ConfigError> for RateLimitError {
+    fn from(e: ConfigError) -> Self {
+        RateLimitError::Config(e)
+    }
+}
+
+/// Errors caused by an invalid rate limiting configuration
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The status of rate limited responses must be a client or server error
+    InvalidRejectionStatus(warp::http::StatusCode),
+    /// The rejection message template uses a placeholder that does not exist
+    UnknownPlaceholder(String),
+    /// The rejection message template has a `{` or `}` that is neither a
+    /// placeholder delimiter nor escaped as `{{` / `}}`
+    UnbalancedBrace(usize),
+    /// The window must not be empty
+    ZeroWindow,
+    /// The window is longer than 100 years, or too large to compute reset times
+    WindowTooLarge(std::time::Duration),
+    /// The ip header is not a valid header name
+    InvalidIpHeader(String),
+    /// `max_retry_after` must not be zero
+    ZeroMaxRetryAfter,
+    /// `idle_ttl` must not be zero
+    ZeroIdleTtl,
+    /// `health_max_prune_age` must be longer than the idle ttl, or the limiter would
+    /// be reported as degraded between two prunings
+    HealthPruneAgeTooShort(std::time::Duration),
+    /// `heavy_hitters` must be at least 1
+    ZeroHeavyHitters,
+    /// `heavy_usage` must be above 0 and up to 1
+    InvalidHeavyUsage,
+    /// A `COUNT/DURATION` limit shorthand could not be parsed
+    InvalidLimit {
+        /// The text that was parsed
+        input: String,
+        /// What is wrong with it
+        reason: &'static str,
+    },
+    /// A duration could not be parsed
+    InvalidDuration {
+        /// The text that was parsed
+        input: String,
+        /// The part of the text that is wrong
+        token: String,
+        /// What is wrong with it
+        reason: &'static str,
+    },
+    /// The limit overriding the default one for a client is invalid
+    InvalidOverride(String, Box<ConfigError>),
+    /// Two tiers have the same name
+    DuplicateTier(String),
+    /// The limit of a tier is invalid
+    InvalidTier(String, Box<ConfigError>),
+    /// Tiers are configured without a classifier, so they would never be used
+    TiersWithoutClassifier,
+    /// The limit of a country is invalid
+    #[cfg(feature = "geoip")]
+    InvalidCountry(String, Box<ConfigError>),
+    /// Country limits or rules are configured without a database to locate clients
+    #[cfg(feature = "geoip")]
+    CountriesWithoutGeoIpReader,
+    /// The status of the responses to denied countries isn't a client or server error
+    #[cfg(feature = "geoip")]
+    InvalidDenyStatus(u16),
+    /// A name was registered twice in a [`crate::RateLimitRegistry`]
+    DuplicateLimiter(String),
+    /// No limiter is registered under this name in a [`crate::RateLimitRegistry`]
+    UnknownLimiter(String),
+    /// A jitter source is set but `retry_after_jitter` is not, so it would never be used
+    JitterSourceWithoutJitter,
+    /// `with_rate_limits` was given no configuration
+    NoRateLimits,
+    /// A [`crate::MultiRateLimiter`] must keep at least one tenant
+    ZeroMaxTenants,
+    /// Trusted proxies are set but `ip_header` is not, so they would never be used
+    TrustedProxiesWithoutIpHeader,
+    /// A [`crate::RateLimitConfig::store`] is set for a limiter counting in memory only,
+    /// named here, which would silently count per process
+    StoreNotSupported(&'static str),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::InvalidRejectionStatus(status) => write!(
+                f,
+                "Rate limited responses need a 4xx or 5xx status, got {}",
+                status
+            ),
+            ConfigError::UnknownPlaceholder(name) => write!(
+                f,
+                "Unknown placeholder {{{}}} in rejection message template",
+                name
+            ),
+            ConfigError::UnbalancedBrace(position) => write!(
+                f,
+                "Unbalanced brace at byte {} of rejection message template",
+                position
+            ),
+            ConfigError::ZeroWindow => write!(f, "The rate limiting window must not be zero"),
+            ConfigError::WindowTooLarge(window) => {
+                write!(f, "The rate limiting window of {:?} is too large", window)
+            }
+            ConfigError::InvalidIpHeader(name) => {
+                write!(f, "Invalid ip header name {:?}", name)
+            }
+            ConfigError::InvalidLimit { input, reason } => write!(
+                f,
+                "expected COUNT/DURATION like \"100/1m\", got {:?}: {}",
+                input, reason
+            ),
+            ConfigError::InvalidDuration {
+                input,
+                token,
+                reason,
+            } => {
+                write!(f, "Invalid duration {:?}: {}", input, reason)?;
+                if !token.is_empty() {
+                    write!(f, " {:?}", token)?;
+                }
+                Ok(())
+            }
+            ConfigError::InvalidOverride(key, e) => {
+                write!(f, "Invalid limit override for {:?}: {}", key, e)
+            }
+            ConfigError::DuplicateTier(name) => write!(f, "Duplicate tier {:?}", name),
+            ConfigError::InvalidTier(name, e) => write!(f, "Invalid tier {:?}: {}", name, e),
+            ConfigError::TiersWithoutClassifier => write!(
+                f,
+                "Tiers are configured without a tier classifier, they would never be used"
+            ),
+            #[cfg(feature = "geoip")]
+            ConfigError::InvalidCountry(code, e) => {
+                write!(f, "Invalid limit for country {:?}: {}", code, e)
+            }
+            #[cfg(feature = "geoip")]
+            ConfigError::CountriesWithoutGeoIpReader => write!(
+                f,
+                "Country limits or rules are configured without a GeoIP reader, they would never be used"
+            ),
+            #[cfg(feature = "geoip")]
+            ConfigError::InvalidDenyStatus(status) => write!(
+                f,
+                "Invalid status {} for denied countries, expected a client or server error",
+                status
+            ),
+            ConfigError::DuplicateLimiter(name) => {
+                write!(f, "A rate limiter named {:?} is already registered", name)
+            }
+            ConfigError::UnknownLimiter(name) => {
+                write!(f, "No rate limiter named {:?} is registered", name)
+            }
+            ConfigError::ZeroMaxRetryAfter => write!(f, "max_retry_after must not be zero"),
+            ConfigError::ZeroIdleTtl => write!(f, "idle_ttl must not be zero"),
+            ConfigError::ZeroHeavyHitters => write!(f, "heavy_hitters must be at least 1"),
+            ConfigError::InvalidHeavyUsage => {
+                write!(f, "heavy_usage must be above 0 and up to 1")
+            }
+            ConfigError::HealthPruneAgeTooShort(max_prune_age) => write!(
+                f,
+                "health_max_prune_age of {:?} must be longer than the idle ttl",
+                max_prune_age
+            ),
+            ConfigError::JitterSourceWithoutJitter => write!(
+                f,
+                "A jitter source is set without retry_after_jitter, it would never be used"
+            ),
+            ConfigError::TrustedProxiesWithoutIpHeader => write!(
+                f,
+                "Trusted proxies are set without ip_header, they would never be used"
+            ),
+            ConfigError::NoRateLimits => {
+                write!(f, "At least one rate limit configuration is required")
+            }
+            ConfigError::ZeroMaxTenants => write!(f, "max_tenants must be at least 1"),
+            ConfigError::StoreNotSupported(limiter) => {
+                write!(f, "{} counts in memory and can't use a store", limiter)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
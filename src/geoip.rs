@@ -0,0 +1,129 @@
+use maxminddb::{geoip2, Reader};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, ops::Deref, sync::Arc};
+use warp::http::StatusCode;
+
+/// A MaxMind database, such as GeoLite2 Country, shared between the limiters using it
+///
+/// The crate never loads the database itself: open it with `maxminddb::Reader` and
+/// keep it up to date as you see fit. Two `GeoIpReader`s are equal only when they
+/// point to the same database.
+pub struct GeoIpReader(Arc<Reader<Vec<u8>>>);
+
+impl GeoIpReader {
+    /// Wraps a shared database
+    pub fn new(reader: Arc<Reader<Vec<u8>>>) -> Self {
+        Self(reader)
+    }
+
+    /// ISO code of the country of the client `key`, if it is an ip address the
+    /// database knows about
+    pub fn country_of(&self, key: &str) -> Option<&str> {
+        let ip: IpAddr = key.parse().ok()?;
+        self.0.lookup::<geoip2::Country>(ip).ok()?.country?.iso_code
+    }
+}
+
+impl From<Arc<Reader<Vec<u8>>>> for GeoIpReader {
+    fn from(reader: Arc<Reader<Vec<u8>>>) -> Self {
+        Self(reader)
+    }
+}
+
+impl Clone for GeoIpReader {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl Deref for GeoIpReader {
+    type Target = Reader<Vec<u8>>;
+
+    fn deref(&self) -> &Reader<Vec<u8>> {
+        &self.0
+    }
+}
+
+impl PartialEq for GeoIpReader {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for GeoIpReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("GeoIpReader")
+            .field(&self.0.metadata.database_type)
+            .finish()
+    }
+}
+
+/// Countries whose clients are rejected, or exempted from the limit, before their
+/// requests are counted
+///
+/// Only evaluated for the clients `geoip_reader` locates: others are rate limited as
+/// usual. A country in both lists is denied.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+#[non_exhaustive]
+pub struct CountryRules {
+    /// ISO codes of the countries whose clients are rejected with a [`CountryDenied`]
+    pub deny: Vec<String>,
+    /// ISO codes of the countries whose clients bypass the rate limit entirely
+    pub allow: Vec<String>,
+    /// Status of the responses to denied clients
+    pub deny_status: u16,
+    /// Plain-text body of the responses to denied clients
+    pub deny_reason: String,
+}
+
+impl Default for CountryRules {
+    fn default() -> Self {
+        Self {
+            deny: Vec::new(),
+            allow: Vec::new(),
+            deny_status: StatusCode::FORBIDDEN.as_u16(),
+            deny_reason: String::from("Access from your country is not allowed"),
+        }
+    }
+}
+
+impl CountryRules {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.allow.is_empty()
+    }
+
+    // Whether the client `key`, located in `country`, bypasses the limit, or the
+    // rejection of a denied client
+    pub(crate) fn check(&self, key: &str, country: &str) -> Result<bool, CountryDenied> {
+        if self.deny.iter().any(|denied| denied == country) {
+            return Err(CountryDenied {
+                key: key.to_owned(),
+                country: country.to_owned(),
+                status: StatusCode::from_u16(self.deny_status).unwrap_or(StatusCode::FORBIDDEN),
+                reason: self.deny_reason.clone(),
+            });
+        }
+        Ok(self.allow.iter().any(|allowed| allowed == country))
+    }
+}
+
+/// Rejection of a request whose client is located in a denied country
+///
+/// [`crate::handle_rate_limit_rejection`] and [`crate::RateLimitRecover`] answer it
+/// with the configured status and reason, without any rate limit header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CountryDenied {
+    /// Key of the client, usually its ip address
+    pub key: String,
+    /// ISO code of the country of the client, the deny rule that matched
+    pub country: String,
+    /// Status of the response
+    pub status: StatusCode,
+    /// Plain-text body of the response
+    pub reason: String,
+}
+
+impl warp::reject::Reject for CountryDenied {}
@@ -0,0 +1,166 @@
+use crate::{
+    get_rate_limit_info, time, HeaderStyle, RateLimitError, RateLimitInfo, RateLimitRejection,
+};
+use warp::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+/// Policy applied when a rate limit header is already present on a response
+///
+/// The headers are handled as a set, so that a response never mixes the values of two
+/// `RateLimitInfo`s, e.g. the stale ones of a handler and the fresh ones of a wrapping
+/// filter. Any of the `X-RateLimit-*` or `RateLimit-*` headers tells that the set was
+/// already written. A `Retry-After` alone may be the application's own, e.g. on a 503.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    /// Replace the rate limit headers already present, removing those of another
+    /// header style too (the behavior of `add_rate_limit_headers`)
+    #[default]
+    Overwrite,
+    /// Leave a set already written untouched, writing nothing. Without one, leave an
+    /// existing `Retry-After` untouched and write the other headers.
+    KeepExisting,
+    /// Write nothing and return `RateLimitError::HeaderExists` if any header is present
+    Fail,
+}
+
+impl RateLimitInfo {
+    /// Builds a standalone map holding the rate limit headers, in the configured style
+    pub fn to_header_map(&self) -> Result<HeaderMap, RateLimitError> {
+        let mut headers = HeaderMap::new();
+        self.extend_header_map(&mut headers)?;
+        Ok(headers)
+    }
+
+    /// Adds the rate limit headers to `headers`, replacing existing values
+    pub fn extend_header_map(&self, headers: &mut HeaderMap) -> Result<(), RateLimitError> {
+        write_headers(headers, self.header_values(), HeaderPolicy::Overwrite).map(|_| ())
+    }
+
+    fn header_values(&self) -> Vec<(HeaderName, String)> {
+        // Each value is formatted once, even when both header families are emitted
+        let limit = self.limit.to_string();
+        let remaining = self.remaining.to_string();
+
+        let mut values = vec![(header::RETRY_AFTER, self.retry_after.clone())];
+        if matches!(self.header_style, HeaderStyle::Legacy | HeaderStyle::Both) {
+            values.extend([
+                (X_RATELIMIT_LIMIT, limit.clone()),
+                (X_RATELIMIT_REMAINING, remaining.clone()),
+                (X_RATELIMIT_RESET, self.reset_timestamp.to_string()),
+            ]);
+        }
+        if matches!(self.header_style, HeaderStyle::Standard | HeaderStyle::Both) {
+            let reset_in = time::between(self.checked_at, self.reset_at);
+            let reset_in = reset_in.as_secs() + u64::from(reset_in.subsec_nanos() > 0);
+            values.extend([
+                (RATELIMIT_LIMIT, limit),
+                (RATELIMIT_REMAINING, remaining),
+                (RATELIMIT_RESET, reset_in.to_string()),
+            ]);
+        }
+        if self.used_header {
+            values.push((X_RATELIMIT_USED, self.used.to_string()));
+        }
+        values
+    }
+}
+
+/// Adds rate limit headers to a response
+pub fn add_rate_limit_headers(
+    headers: &mut HeaderMap,
+    info: &RateLimitInfo,
+) -> Result<(), RateLimitError> {
+    info.extend_header_map(headers)
+}
+
+/// Adds rate limit headers to a response, following `policy` for headers that are
+/// already present. Returns the names of the headers that were actually written.
+pub fn add_rate_limit_headers_with_policy(
+    headers: &mut HeaderMap,
+    info: &RateLimitInfo,
+    policy: HeaderPolicy,
+) -> Result<Vec<HeaderName>, RateLimitError> {
+    write_headers(headers, info.header_values(), policy)
+}
+
+/// Adds rate limit headers to a response
+pub fn add_rate_limit_headers_from_rejection(
+    headers: &mut HeaderMap,
+    rejection: &RateLimitRejection,
+) -> Result<(), RateLimitError> {
+    get_rate_limit_info(rejection).extend_header_map(headers)
+}
+
+/// Adds rate limit headers to a response, following `policy` for headers that are
+/// already present. Returns the names of the headers that were actually written.
+pub fn add_rate_limit_headers_from_rejection_with_policy(
+    headers: &mut HeaderMap,
+    rejection: &RateLimitRejection,
+    policy: HeaderPolicy,
+) -> Result<Vec<HeaderName>, RateLimitError> {
+    add_rate_limit_headers_with_policy(headers, &get_rate_limit_info(rejection), policy)
+}
+
+const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+const X_RATELIMIT_USED: HeaderName = HeaderName::from_static("x-ratelimit-used");
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+// Every header only ever written by the rate limiters, whatever the style
+const RATE_LIMIT_HEADERS: [HeaderName; 7] = [
+    X_RATELIMIT_LIMIT,
+    X_RATELIMIT_REMAINING,
+    X_RATELIMIT_RESET,
+    X_RATELIMIT_USED,
+    RATELIMIT_LIMIT,
+    RATELIMIT_REMAINING,
+    RATELIMIT_RESET,
+];
+
+// Every value is validated before anything is written, so a failure never leaves a
+// partially updated set of headers behind
+fn write_headers(
+    headers: &mut HeaderMap,
+    values: Vec<(HeaderName, String)>,
+    policy: HeaderPolicy,
+) -> Result<Vec<HeaderName>, RateLimitError> {
+    let previous = RATE_LIMIT_HEADERS
+        .into_iter()
+        .find(|name| headers.contains_key(name));
+    if policy == HeaderPolicy::Fail {
+        if let Some(name) = previous {
+            return Err(RateLimitError::HeaderExists(name));
+        }
+    }
+    let mut validated = Vec::with_capacity(values.len());
+    for (name, value) in values {
+        if policy == HeaderPolicy::Fail && headers.contains_key(&name) {
+            return Err(RateLimitError::HeaderExists(name));
+        }
+        validated.push((
+            name,
+            HeaderValue::from_str(&value).map_err(RateLimitError::HeaderError)?,
+        ));
+    }
+
+    match policy {
+        HeaderPolicy::KeepExisting if previous.is_some() => return Ok(Vec::new()),
+        HeaderPolicy::Overwrite => {
+            for name in RATE_LIMIT_HEADERS {
+                headers.remove(name);
+            }
+        }
+        _ => {}
+    }
+    let mut written = Vec::with_capacity(validated.len());
+    for (name, value) in validated {
+        if policy == HeaderPolicy::KeepExisting && headers.contains_key(&name) {
+            continue;
+        }
+        headers.insert(name.clone(), value);
+        written.push(name);
+    }
+    Ok(written)
+}
@@ -0,0 +1,77 @@
+use crate::RateLimitConfig;
+#[cfg(feature = "serde")]
+use crate::RateLimiterHandle;
+#[cfg(feature = "serde")]
+use std::convert::Infallible;
+use std::time::Duration;
+#[cfg(feature = "serde")]
+use warp::{http::StatusCode, Filter, Reply};
+
+/// State of a rate limiter, as seen by [`RateLimiterHandle::health`](crate::RateLimiterHandle::health),
+/// e.g. to back the liveness or readiness probe of a service
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct HealthReport {
+    /// Whether the state of the limiter could be read in time, and its
+    /// [`RateLimitConfig::store`] didn't fail within the last window. A state kept
+    /// locked means the requests are stuck waiting on it, a failing store that they
+    /// are let through uncounted.
+    pub store_ok: bool,
+    /// Number of clients currently tracked
+    pub key_count: usize,
+    /// Time since idle clients were last pruned, or since the limiter was created,
+    /// `None` when the state could not be read
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::seconds::option"))]
+    pub last_prune_age: Option<Duration>,
+    /// Whether the store isn't ok, or the state goes beyond the
+    /// [`RateLimitConfig::health_max_keys`] or [`RateLimitConfig::health_max_prune_age`]
+    /// thresholds
+    pub degraded: bool,
+}
+
+impl HealthReport {
+    pub(crate) fn new(
+        config: &RateLimitConfig,
+        key_count: usize,
+        last_prune_age: Option<Duration>,
+        store_failing: bool,
+    ) -> Self {
+        let store_ok = last_prune_age.is_some() && !store_failing;
+        let too_many_keys = config.health_max_keys.is_some_and(|max| key_count > max);
+        let prune_overdue = last_prune_age
+            .zip(config.health_max_prune_age)
+            .is_some_and(|(age, max)| age > max);
+        Self {
+            store_ok,
+            key_count,
+            last_prune_age,
+            degraded: !store_ok || too_many_keys || prune_overdue,
+        }
+    }
+}
+
+/// Answers every request with the [`HealthReport`] of `limiter` as JSON, with a
+/// 200 status, or 503 when the limiter is degraded
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiterHandle::new(RateLimitConfig::default().with_health_max_keys(100_000))?;
+/// let probe = warp::path("ready").and(health_route(limiter.clone()));
+/// ```
+#[cfg(feature = "serde")]
+pub fn health_route(
+    limiter: RateLimiterHandle,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    warp::any().then(move || {
+        let limiter = limiter.clone();
+        async move {
+            let report = limiter.health().await;
+            let status = if report.degraded {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            warp::reply::with_status(warp::reply::json(&report), status)
+        }
+    })
+}
@@ -0,0 +1,221 @@
+use crate::{
+    add_rate_limit_headers, get_rate_limit_info, ip_header_value, request_key, trace, RateLimiter,
+    RateLimiterHandle,
+};
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+use warp::http::{Extensions, Request, Response, StatusCode};
+
+type RemoteAddr = dyn Fn(&Extensions) -> Option<SocketAddr> + Send + Sync;
+
+/// A tower [`Layer`] counting the requests of a service against a rate limiter, e.g.
+/// in an axum or tonic stack, sharing its counters with the warp filters of the same
+/// [`RateLimiterHandle`]
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100))?;
+/// let warp_routes = warp::path("v1").and(limiter.filter());
+/// let axum_app = Router::new()
+///     .route("/v2", get(handler))
+///     .layer(RateLimitLayer::new(limiter).with_remote_addr(|extensions| {
+///         extensions.get::<ConnectInfo<SocketAddr>>().map(|info| info.0)
+///     }));
+/// ```
+///
+/// Clients are identified like the filters do, by the `ip_header` and
+/// `trusted_proxies` of the config, and by the remote address found in the request
+/// extensions. Admitted requests carry their [`RateLimitInfo`](crate::RateLimitInfo)
+/// in their extensions. Rejected ones are answered with a `429` carrying the rate
+/// limit headers and an empty body, or with the status of the country rule denying
+/// them with the `geoip` feature. The `rejection_mapper` of the config only applies
+/// to warp.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiterHandle,
+    remote_addr: Arc<RemoteAddr>,
+}
+
+impl RateLimitLayer {
+    /// Creates a layer counting requests against `limiter`, taking the remote address
+    /// of the clients from the `SocketAddr` in the request extensions
+    pub fn new(limiter: RateLimiterHandle) -> Self {
+        Self {
+            limiter,
+            remote_addr: Arc::new(|extensions| extensions.get::<SocketAddr>().copied()),
+        }
+    }
+
+    /// Takes the remote address of the clients from the request extensions with
+    /// `remote_addr`, e.g. from axum's `ConnectInfo`
+    pub fn with_remote_addr(
+        mut self,
+        remote_addr: impl Fn(&Extensions) -> Option<SocketAddr> + Send + Sync + 'static,
+    ) -> Self {
+        self.remote_addr = Arc::new(remote_addr);
+        self
+    }
+}
+
+impl fmt::Debug for RateLimitLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitLayer")
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.limiter.clone(),
+            remote_addr: self.remote_addr.clone(),
+        }
+    }
+}
+
+/// The service of a [`RateLimitLayer`]
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimiter,
+    remote_addr: Arc<RemoteAddr>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for RateLimitService<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitService")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    ReqBody: Send + 'static,
+    ResBody: Default + Send,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<ReqBody>) -> Self::Future {
+        // The clone may not be ready, keep the service that is
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let config = limiter.config();
+        let header_value = config
+            .ip_header
+            .as_ref()
+            .and_then(|name| ip_header_value(request.headers(), name));
+        let key = request_key(
+            &config,
+            (self.remote_addr)(request.extensions()),
+            header_value,
+        );
+        Box::pin(async move {
+            // Like `check_rate_limit`, without the rejection mapper of warp
+            let count = async {
+                let bypass = limiter.bypasses_limit(&config, &key)?;
+                let counted = limiter.count_request(&config, &key, bypass, 1).await;
+                counted.map_err(warp::reject::custom)
+            };
+            match limiter.measured(&config, &key, count).await {
+                Ok(info) => {
+                    request.extensions_mut().insert(info);
+                    inner.call(request).await
+                }
+                Err(rejection) => Ok(rejected(&rejection)),
+            }
+        })
+    }
+}
+
+// The response to a request the limiter rejected
+fn rejected<B: Default>(rejection: &warp::Rejection) -> Response<B> {
+    let mut response = Response::new(B::default());
+    #[cfg(feature = "geoip")]
+    if let Some(denied) = rejection.find::<crate::CountryDenied>() {
+        *response.status_mut() = denied.status;
+        return response;
+    }
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    if let Some(rejection) = rejection.find::<crate::RateLimitRejection>() {
+        let info = get_rate_limit_info(rejection);
+        if let Err(e) = add_rate_limit_headers(response.headers_mut(), &info) {
+            trace::header_failure(&e);
+        }
+    }
+    response
+}
+
+/// Wraps `service`, e.g. `warp::service(routes)`, serving a connection from `peer`,
+/// so that the limiters see the address of the peer
+///
+/// warp 0.4 doesn't tell the remote address of the requests, even when `warp::serve`
+/// runs the server, so the limiters identifying clients by their remote address would
+/// put all of them in the same `"unknown"` bucket. Wrapping the service of each
+/// connection accepted by a server of your own puts the address of the peer in the
+/// extensions of its requests, the only place the limiters look for it:
+///
+/// ```rust,no_run,ignore
+/// loop {
+///     let (stream, peer) = listener.accept().await?;
+///     let service = with_peer_addr(warp::service(routes.clone()), peer);
+///     tokio::spawn(
+///         http1::Builder::new()
+///             .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service)),
+///     );
+/// }
+/// ```
+///
+/// A [`RateLimitLayer`] finds the address there as well.
+pub fn with_peer_addr<S>(service: S, peer: SocketAddr) -> PeerAddrService<S> {
+    PeerAddrService {
+        inner: service,
+        peer,
+    }
+}
+
+/// The service of [`with_peer_addr`]
+#[derive(Clone, Debug)]
+pub struct PeerAddrService<S> {
+    inner: S,
+    peer: SocketAddr,
+}
+
+impl<S, B> Service<Request<B>> for PeerAddrService<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        request.extensions_mut().insert(self.peer);
+        self.inner.call(request)
+    }
+}
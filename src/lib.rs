@@ -6,8 +6,11 @@
 //! It provides a Filter you add to your routes that exposes rate-limiting
 //! information to your handlers, and a Rejection Type for error recovery.
 //!
-//! It does not yet provide persistence, nor is the HashMap that stores IPs
-//! bounded. Both of these may be changed in a future version.
+//! The requests of each client are counted in memory, or in a store shared by the
+//! instances of a service, see [`RateLimitConfig::store`]. Clients are pruned once their
+//! window is over and they have been idle for [`RateLimitConfig::idle_ttl`], the window
+//! by default, and the number of tracked clients can be watched with
+//! [`RateLimitConfig::on_key_count_exceeds`] and [`RateLimitConfig::health_max_keys`].
 //!
 //! # Quickstart
 //!
@@ -16,7 +19,7 @@
 //! `cargo add warp-rate-limit`
 //!
 //! 2. Define one or more rate limit configurations. Following are some
-//! examples of available builder methods. The variable names are arbitrary:
+//!    examples of available builder methods. The variable names are arbitrary:
 //!
 //! ```rust,no_run,ignore
 //! // Limit: 60 requests per 60 Earth seconds
@@ -30,8 +33,8 @@
 //! ```
 //!
 //! 3. Use rate limiting information in request handler. If you don't want
-//! to use rate-limiting information related to the IP address associated
-//! with this request, you can skip this part.
+//!    to use rate-limiting information related to the IP address associated
+//!    with this request, you can skip this part.
 //!
 //! ```rust,no_run,ignore
 //! // Example route handler
@@ -48,8 +51,11 @@
 //!             RateLimitError::HeaderError(e) => {
 //!                 eprintln!("Failed to set rate limit headers due to invalid value: {}", e);
 //!             }
-//!             RateLimitError::Other(e) => {
-//!                 eprintln!("Unexpected error setting rate limit headers: {}", e);
+//!             RateLimitError::HeaderExists(name) => {
+//!                 eprintln!("Rate limit header {} was already set", name);
+//!             }
+//!             other => {
+//!                 eprintln!("Unexpected error setting rate limit headers: {}", other);
 //!             }
 //!         }
 //!     }
@@ -69,7 +75,7 @@
 //!     // Somewhere in your rejection handling:
 //!     if let Some(rate_limit_rejection) = rejection.find::<RateLimitRejection>() {
 //!         // We have a rate limit rejection -- so let's get some info about it:
-//!         let info = get_rate_limit_info(rate_limit_rejection);
+//!         let info = RateLimitInfo::from(rate_limit_rejection);
 //!
 //!         // Let's use that info to create a response:
 //!         let message = format!(
@@ -93,8 +99,11 @@
 //!                 RateLimitError::HeaderError(e) => {
 //!                     eprintln!("Failed to set rate limit headers due to invalid value: {}", e);
 //!                 }
-//!                 RateLimitError::Other(e) => {
-//!                     eprintln!("Unexpected error setting rate limit headers: {}", e);
+//!                 RateLimitError::HeaderExists(name) => {
+//!                     eprintln!("Rate limit header {} was already set", name);
+//!                 }
+//!                 other => {
+//!                     eprintln!("Unexpected error setting rate limit headers: {}", other);
 //!                 }
 //!             }
 //!         }
@@ -110,263 +119,1081 @@
 //! }
 //! ```
 
-use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use crate::core::{Entry, KeyLimit, RateLimiterMap};
+use crate::store::{RateLimitStore, WindowCount};
+use crate::time::AnchoredClock;
+use ipnet::IpNet;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::{collections::HashMap, net::IpAddr, str::FromStr as _};
-use tokio::sync::RwLock;
-use warp::{
-    http::header::{self, HeaderMap, HeaderValue},
-    reject, Filter, Rejection,
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    str::FromStr as _,
 };
+use warp::{filters::BoxedFilter, http::HeaderMap, Filter, Rejection};
 
 mod error;
-pub use error::RateLimitError;
+pub use error::{ConfigError, RateLimitError};
+#[cfg(feature = "serde")]
+pub mod admin;
+mod audit;
+pub use audit::{AuditEntry, AuditReason, AuditRing, AuditSink};
+mod auto;
+pub use auto::{try_with_rate_limit_auto, with_rate_limit_auto};
+mod combined;
+pub use combined::{try_with_rate_limits, with_rate_limits};
+mod completion;
+pub use completion::{CompletionRateLimit, StatusClass};
 mod config;
-pub use config::{RateLimitConfig, RetryAfterFormat};
+pub mod core;
+mod counters;
+mod multi;
+pub mod store;
+pub use config::{
+    parse_duration, parse_limit, ConfigFn, EventCallback, HeaderStyle, IntoDuration, JitterSource,
+    KeyCountCallback, RateLimitConfig, RateLimitConfigBuilder, RejectionMapper, RetryAfterFormat,
+    TierClassifier,
+};
+pub use counters::{
+    HeavyHitter, HeavyHitterReport, LatencyBucket, LatencyHistogram, RateLimitCounters,
+};
+pub use multi::MultiRateLimiter;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "geoip")]
+pub use geoip::{CountryDenied, CountryRules, GeoIpReader};
+mod headers;
+pub use headers::{
+    add_rate_limit_headers, add_rate_limit_headers_from_rejection,
+    add_rate_limit_headers_from_rejection_with_policy, add_rate_limit_headers_with_policy,
+    HeaderPolicy,
+};
+mod health;
+#[cfg(feature = "serde")]
+pub use health::health_route;
+pub use health::HealthReport;
+#[cfg(feature = "tower")]
+mod layer;
+#[cfg(feature = "tower")]
+pub use layer::{with_peer_addr, PeerAddrService, RateLimitLayer, RateLimitService};
+mod recover;
+pub use recover::{
+    handle_rate_limit_rejection, RateLimitBody, RateLimitProblem, RateLimitRecover,
+    RejectionBodyFormat, ResponseFn,
+};
+mod refund;
+pub use refund::RefundRateLimit;
+mod registry;
+pub use registry::{
+    BanInfo, BanReason, EntrySnapshot, KeyFilter, Page, RateLimitRegistry, RateLimiterHandle,
+};
+mod reply;
+pub use reply::{with_rate_limit_headers, TooManyRequests, WithRateLimitHeaders};
+mod time;
+pub use time::{Clock, ManualClock, SystemClock, Timestamp};
+mod trace;
+#[cfg(feature = "tracing")]
+mod traced;
+#[cfg(feature = "tracing")]
+pub use traced::trace_request;
+mod wrap;
+pub use wrap::rate_limit_headers;
 
 // Re-exports
+#[cfg(feature = "chrono")]
 pub use chrono;
+pub use ipnet;
+#[cfg(feature = "geoip")]
+pub use maxminddb;
+#[cfg(feature = "serde")]
 pub use serde;
 
 /// Information about the current rate limit status
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RateLimitInfo {
-    /// Time until the rate limit resets
+    /// Time until the rate limit resets, formatted for the Retry-After header
     pub retry_after: String,
+    /// Time until the rate limit resets, rounded up to whole seconds like `retry_after`
+    pub retry_after_duration: Duration,
     /// Maximum requests allowed in the window
     pub limit: u32,
+    /// Length of the rate limiting window
+    pub window: Duration,
+    /// Tier of the client, when classified into one
+    pub tier: Option<String>,
+    /// Key of the client, usually its ip address, unless the config redacts it
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub key: Option<String>,
+    /// Label of the configuration that produced this information
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub label: Option<Arc<str>>,
     /// Remaining requests in the current window
     pub remaining: u32,
+    /// Requests made in the current window, including this one
+    pub used: u32,
     /// Unix timestamp when the rate limit resets
     pub reset_timestamp: i64,
+    /// When the rate limit resets, always the same second as `reset_timestamp`.
+    /// Serialized in RFC 3339 form.
+    pub reset_at: Timestamp,
+    /// When the client first exceeded the limit in the current window, if it did
+    pub first_rejected_at: Option<Timestamp>,
+    /// When the request was counted, by the clock of the rate limiter. `retry_after`
+    /// and the `RateLimit-Reset` header count from it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub checked_at: Timestamp,
     /// Format used for retry-after header
     pub retry_after_format: RetryAfterFormat,
+    /// Family of rate limit headers to emit
+    pub header_style: HeaderStyle,
+    /// Whether to emit the `X-RateLimit-Used` header
+    pub used_header: bool,
+    /// Start of the window the request was counted in, telling it apart from the
+    /// later windows of the client, see [`RateLimiterHandle::refund`]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub window_start: Instant,
 
     /// Number of items in the internal map
     pub internal_map_len: usize,
     /// Least time the map was cleaned up
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub last_cleanup_time: Instant,
 }
 
 /// Custom rejection type for rate limiting
-#[derive(Debug)]
+///
+/// Serialized with durations as (fractional) seconds and `reset_time` in RFC 3339 form.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RateLimitRejection {
     /// Duration until the client can retry
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds::serialize"))]
     pub retry_after: Duration,
     /// Maximum requests allowed in the window
     pub limit: u32,
+    /// Requests counted in the current window, which may exceed `limit` if it was
+    /// lowered by a config update
+    pub used: u32,
+    /// Length of the rate limiting window
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds::serialize"))]
+    pub window: Duration,
+    /// Tier of the client, when classified into one
+    pub tier: Option<String>,
+    /// Key of the rejected client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Label of the configuration that rejected the request
+    pub label: Option<Arc<str>>,
     /// Unix timestamp when the rate limit resets
-    pub reset_time: DateTime<Utc>,
+    pub reset_time: Timestamp,
+    /// When the client first exceeded the limit in the current window, this
+    /// rejection included. Tells for how long it has been over the limit.
+    pub first_rejected_at: Option<Timestamp>,
+    /// When the request was rejected, by the clock of the rate limiter. The emitted
+    /// Retry-After and the `RateLimit-Reset` header count from it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub rejected_at: Timestamp,
+    /// The same instant as `rejected_at`, on the monotonic clock of the rate limiter
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub rejected_instant: Instant,
     /// Format to use for Retry-After header
     pub retry_after_format: RetryAfterFormat,
+    /// Random delay added to the emitted Retry-After
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds::serialize"))]
+    pub retry_after_jitter: Duration,
+    /// Upper bound of the emitted Retry-After
+    #[cfg_attr(feature = "serde", serde(serialize_with = "seconds::option"))]
+    pub max_retry_after: Option<Duration>,
+    /// Family of rate limit headers to emit
+    pub header_style: HeaderStyle,
+    /// Whether to emit the `X-RateLimit-Used` header
+    pub used_header: bool,
+    /// Position of the configuration that rejected the request, among the ones given to
+    /// [`with_rate_limits`]
+    pub config_index: Option<usize>,
 }
 impl RateLimitRejection {
+    /// Information about the rejected client, same as `RateLimitInfo::from(self)`
+    pub fn to_info(&self) -> RateLimitInfo {
+        RateLimitInfo::from(self)
+    }
+
     pub fn formated_retry_after(&self) -> String {
-        match self.retry_after_format {
-            RetryAfterFormat::HttpDate => self.reset_time.to_rfc2822(),
-            RetryAfterFormat::Seconds => self.retry_after.as_secs().to_string(),
-        }
+        self.retry_after_value().1
+    }
+
+    // The emitted wait, rounded up to whole seconds, and its formatted form, counted
+    // from the rejection so that it agrees with the clock of the rate limiter
+    fn retry_after_value(&self) -> (Duration, String) {
+        retry_after_until(
+            self.rejected_at,
+            self.reset_time,
+            self.retry_after_jitter,
+            self.max_retry_after,
+            &self.retry_after_format,
+        )
+    }
+}
+
+// The wait from `now` until `reset`, with `jitter` and capped by `max_retry_after`,
+// rounded up to whole seconds, and its formatted form, for admitted and rejected
+// requests alike
+fn retry_after_until(
+    now: Timestamp,
+    reset: Timestamp,
+    jitter: Duration,
+    max_retry_after: Option<Duration>,
+    format: &RetryAfterFormat,
+) -> (Duration, String) {
+    let wait = cap_retry_after(time::between(now, reset) + jitter, max_retry_after);
+    (
+        round_up_secs(wait),
+        format_retry_after(format, wait, now + wait),
+    )
+}
+
+fn cap_retry_after(wait: Duration, max_retry_after: Option<Duration>) -> Duration {
+    match max_retry_after {
+        Some(max) => wait.min(max),
+        None => wait,
+    }
+}
+
+// Clients are never told to come back too early
+fn round_up_secs(wait: Duration) -> Duration {
+    Duration::from_secs(wait.as_secs() + u64::from(wait.subsec_nanos() > 0))
+}
+
+// Formats the Retry-After value telling a client to wait `wait`, until `at`.
+// Both forms are rounded up so that clients are never told to come back too early.
+fn format_retry_after(format: &RetryAfterFormat, wait: Duration, at: Timestamp) -> String {
+    match format {
+        RetryAfterFormat::HttpDate => time::http_date(at),
+        RetryAfterFormat::Seconds => round_up_secs(wait).as_secs().to_string(),
     }
 }
 
+// Random duration in [0, max), good enough to spread clients apart
+fn random_jitter(max: Duration) -> Duration {
+    use std::hash::{BuildHasher as _, Hasher as _};
+
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    Duration::from_nanos((u128::from(random) % max.as_nanos()) as u64)
+}
+
 impl warp::reject::Reject for RateLimitRejection {}
 
-#[derive(Clone)]
-struct RateLimiter {
-    state: Arc<RwLock<RateLimiterMap>>,
-    config: RateLimitConfig,
+/// A request admitted or rejected by a rate limiter, as given to
+/// [`RateLimitConfig::on_allowed`] and [`RateLimitConfig::on_rejected`]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct RateLimitEvent {
+    /// Whether the request was admitted or rejected
+    pub outcome: RateLimitOutcome,
+    /// Key of the client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Label of the configuration of the rate limiter
+    pub label: Option<Arc<str>>,
+    /// Requests the request was counted as, 0 when rejected
+    pub cost: u32,
+    /// Maximum requests allowed in the window
+    pub limit: u32,
+    /// Requests counted in the current window, including this one
+    pub used: u32,
+    /// Remaining requests in the current window
+    pub remaining: u32,
+    /// When the rate limit resets
+    pub reset_at: Timestamp,
 }
 
-// I really didn't want to have two different Arc<RwLock<T>> for data so interlinked
+/// Outcome of the request of a [`RateLimitEvent`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum RateLimitOutcome {
+    /// The request was counted and admitted
+    Allowed,
+    /// The request exceeded the limit
+    Rejected,
+}
+
+impl RateLimitEvent {
+    // The event of a request admitted as `cost` requests, which `info` was the outcome
+    // of
+    pub(crate) fn allowed(info: &RateLimitInfo, cost: u32) -> Self {
+        Self {
+            outcome: RateLimitOutcome::Allowed,
+            key: info.key.clone(),
+            label: info.label.clone(),
+            cost,
+            limit: info.limit,
+            used: info.used,
+            remaining: info.remaining,
+            reset_at: info.reset_at,
+        }
+    }
+}
+
+impl From<&RateLimitRejection> for RateLimitEvent {
+    fn from(rejection: &RateLimitRejection) -> Self {
+        Self {
+            outcome: RateLimitOutcome::Rejected,
+            key: rejection.key.clone(),
+            label: rejection.label.clone(),
+            cost: 0,
+            limit: rejection.limit,
+            used: rejection.used,
+            remaining: 0,
+            reset_at: rejection.reset_time,
+        }
+    }
+}
+
+// Serialization of durations as seconds, fractional when needed
+#[cfg(feature = "serde")]
+mod seconds {
+    use serde::Serializer;
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn option<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+// The state is never locked across an await. With the `sync-lock` feature it is behind
+// a std Mutex, which doesn't tie it to the tokio runtime and is cheaper to acquire.
+#[cfg(feature = "sync-lock")]
+type StateLock = std::sync::Mutex<RateLimiterMap>;
+#[cfg(feature = "sync-lock")]
+type StateGuard<'a> = std::sync::MutexGuard<'a, RateLimiterMap>;
+#[cfg(not(feature = "sync-lock"))]
+type StateLock = tokio::sync::RwLock<RateLimiterMap>;
+#[cfg(not(feature = "sync-lock"))]
+type StateGuard<'a> = tokio::sync::RwLockWriteGuard<'a, RateLimiterMap>;
+#[cfg(feature = "sync-lock")]
+type StateReadGuard<'a> = std::sync::MutexGuard<'a, RateLimiterMap>;
+#[cfg(not(feature = "sync-lock"))]
+type StateReadGuard<'a> = tokio::sync::RwLockReadGuard<'a, RateLimiterMap>;
+
 #[derive(Clone)]
-struct RateLimiterMap {
-    inner: HashMap<String, (Instant, u32)>,
-    last_cleanup: Instant,
+struct RateLimiter {
+    state: Arc<StateLock>,
+    // Swapped as a whole by `RateLimiterHandle::update_config`, and only locked long
+    // enough to clone the Arc
+    config: Arc<std::sync::RwLock<Arc<RateLimitConfig>>>,
+    counters: Arc<counters::Counters>,
+    // Shared with the state, which keeps it up to date
+    keys: Arc<counters::KeyGauge>,
+    // Only fed when the config follows heavy hitters
+    heavy: Arc<counters::HeavyHitterSketch>,
+    // Only fed when the config measures latency
+    latencies: Arc<counters::Latencies>,
+    // Last failure of the store of the config, reported by `RateLimiterHandle::health`
+    store_failed_at: Arc<std::sync::Mutex<Option<Instant>>>,
+    // Shared with the state, for the times read without locking it
+    clock: AnchoredClock,
+    // Cap of the filters built from this clone, in place of the limit of the config,
+    // see `RateLimiterHandle::filter_with_max_requests`
+    max_requests: Option<u32>,
 }
 
 impl RateLimiter {
     fn new(config: RateLimitConfig) -> Self {
+        let keys = Arc::<counters::KeyGauge>::default();
+        let clock = config.anchored_clock();
         Self {
-            state: Arc::new(RwLock::new(RateLimiterMap {
-                last_cleanup: Instant::now(),
-                inner: HashMap::default(),
-            })),
-            config,
+            state: Arc::new(StateLock::new(RateLimiterMap::new(
+                keys.clone(),
+                clock.clone(),
+            ))),
+            clock,
+            config: Arc::new(std::sync::RwLock::new(Arc::new(config))),
+            counters: Arc::default(),
+            keys,
+            heavy: Arc::default(),
+            max_requests: None,
+            latencies: Arc::default(),
+            store_failed_at: Arc::default(),
+        }
+    }
+
+    fn config(&self) -> Arc<RateLimitConfig> {
+        // A panic while swapping an Arc can't leave a broken config behind
+        self.config
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_config(&self, config: RateLimitConfig) {
+        *self
+            .config
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Arc::new(config);
+    }
+
+    async fn lock_state(&self) -> StateGuard<'_> {
+        // Entries are replaced as a whole, so a panic can't leave a broken one behind
+        #[cfg(feature = "sync-lock")]
+        return self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        #[cfg(not(feature = "sync-lock"))]
+        return self.state.write().await;
+    }
+
+    // Locks the state for reading only, letting other readers in unless it is behind
+    // a Mutex
+    async fn read_state(&self) -> StateReadGuard<'_> {
+        #[cfg(feature = "sync-lock")]
+        return self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        #[cfg(not(feature = "sync-lock"))]
+        return self.state.read().await;
+    }
+
+    // Locks the state for reading like `read_state`, giving up after `timeout`
+    async fn read_state_within(&self, timeout: Duration) -> Option<StateReadGuard<'_>> {
+        #[cfg(feature = "sync-lock")]
+        {
+            // A std Mutex can't be awaited, so poll it until the deadline
+            let deadline = Instant::now() + timeout;
+            loop {
+                match self.state.try_lock() {
+                    Ok(map) => return Some(map),
+                    Err(std::sync::TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+                    Err(std::sync::TryLockError::WouldBlock) => {}
+                }
+                if Instant::now() >= deadline {
+                    return None;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        #[cfg(not(feature = "sync-lock"))]
+        return tokio::time::timeout(timeout, self.state.read()).await.ok();
+    }
+
+    // Runs `f` on the state from a synchronous context such as a Drop, later on if
+    // the state is locked
+    fn with_state_now(&self, f: impl FnOnce(&mut RateLimiterMap) + Send + 'static) {
+        #[cfg(feature = "sync-lock")]
+        f(&mut self
+            .state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner));
+        #[cfg(not(feature = "sync-lock"))]
+        match self.state.try_write() {
+            Ok(mut map) => f(&mut map),
+            Err(_) => {
+                let state = self.state.clone();
+                tokio::spawn(async move { f(&mut *state.write().await) });
+            }
+        }
+    }
+
+    // Counts a rejected request and reports it, see `RateLimitConfig::notify_rejected`
+    fn notify_rejected(
+        &self,
+        config: &RateLimitConfig,
+        key: &str,
+        rejection: &RateLimitRejection,
+        first: bool,
+    ) {
+        self.counters.rejected(config.label.as_ref());
+        let key_tag = self.key_tag(config, key, rejection.used, rejection.limit);
+        config.notify_rejected(rejection, key_tag.as_deref(), first);
+        self.notify_key_count(config);
+    }
+
+    // Counts an admitted request of `cost` requests and reports it, see
+    // `RateLimitConfig::notify_allowed`
+    fn notify_allowed(&self, config: &RateLimitConfig, key: &str, info: &RateLimitInfo, cost: u32) {
+        self.counters.allowed(config.label.as_ref());
+        let key_tag = self.key_tag(config, key, info.used, info.limit);
+        config.notify_allowed(info, cost, key_tag.as_deref());
+        self.notify_key_count(config);
+    }
+
+    // The client `key` as reported in the traces: under its own key (or hash) if it is
+    // a heavy hitter or has used enough of its `limit`, under "other" otherwise, and
+    // not at all unless the config samples clients
+    fn key_tag(
+        &self,
+        config: &RateLimitConfig,
+        key: &str,
+        used: u32,
+        limit: u32,
+    ) -> Option<String> {
+        if config.heavy_hitters.is_none() && config.heavy_usage.is_none() {
+            return None;
+        }
+        let heavy = config
+            .heavy_hitters
+            .is_some_and(|capacity| self.heavy.observe(key, capacity));
+        let busy = config
+            .heavy_usage
+            .is_some_and(|share| f64::from(used) >= share * f64::from(limit));
+        Some(match (heavy || busy, config.redact_key) {
+            (false, _) => String::from("other"),
+            (true, false) => key.to_owned(),
+            (true, true) => trace::key_hash(key).to_string(),
+        })
+    }
+
+    // Reports the number of tracked clients once it goes above the threshold of the
+    // config, once per crossing
+    fn notify_key_count(&self, config: &RateLimitConfig) {
+        if let Some(count) = config
+            .key_count_threshold
+            .and_then(|threshold| self.keys.crossed(threshold))
+        {
+            config.notify_key_count_exceeded(count);
+        }
+    }
+
+    // Whether the country rules exempt the client `key` from the limit, see
+    // `RateLimitConfig::bypasses_limit`, counting the requests of unidentified clients
+    // on the way
+    fn bypasses_limit(&self, config: &RateLimitConfig, key: &str) -> Result<bool, Rejection> {
+        if key == UNKNOWN_CLIENT {
+            self.counters.unknown(config.label.as_ref());
+            trace::unknown_client(config.label.as_deref());
         }
+        config.bypasses_limit(key, &self.clock)
     }
 
     async fn check_rate_limit(&self, key: &str) -> Result<RateLimitInfo, Rejection> {
-        let mut map = self.state.write().await;
-        let now = Instant::now();
-
-        // Cleanup the map to remove old entries
-        if now - map.last_cleanup > self.config.window {
-            map.inner
-                .retain(|_ip, (last_request, ..)| now - *last_request < self.config.window);
-            map.last_cleanup = now;
+        let config = self.config();
+        let count = async {
+            let bypass = self.bypasses_limit(&config, key)?;
+            let counted = self.count_request(&config, key, bypass, 1).await;
+            counted.map_err(|rejection| config.reject(rejection))
+        };
+        self.measured(&config, key, count).await
+    }
+
+    // Counts a request of `key` consuming `cost` units outside of any filter, see
+    // `RateLimiterHandle::check`
+    async fn check_key(&self, key: &str, cost: u32) -> Result<RateLimitInfo, RateLimitRejection> {
+        let config = self.config();
+        let count = self.count_request(&config, key, false, cost);
+        self.measured(&config, key, count).await
+    }
+
+    // Runs `count`, counting a request of `key`, in its span, timing it if the config
+    // measures latency
+    async fn measured<T>(
+        &self,
+        config: &RateLimitConfig,
+        key: &str,
+        count: impl std::future::Future<Output = T>,
+    ) -> T {
+        let started = config.measure_latency.then(Instant::now);
+        let result = trace::in_span(config, key, count).await;
+        if let Some(started) = started {
+            self.latencies.record(started.elapsed());
+        }
+        result
+    }
+
+    // Counts a request of `key` consuming `cost` units, only tracking its window when it
+    // is exempted from the limit by `bypass`
+    async fn count_request(
+        &self,
+        config: &RateLimitConfig,
+        key: &str,
+        bypass: bool,
+        cost: u32,
+    ) -> Result<RateLimitInfo, RateLimitRejection> {
+        if let Some(store) = config.store.as_ref().filter(|_| !bypass) {
+            return self.count_in_store(config, &**store, key, cost).await;
         }
+        let mut map = self.lock_state().await;
+        let now = map.clock.now();
 
-        let current = map.inner.get(key).copied();
-
-        match current {
-            Some((last_request, count)) => {
-                if now.duration_since(last_request) > self.config.window {
-                    // Window has passed, reset counter
-                    map.inner.insert(key.to_owned(), (now, 1));
-                    Ok(self.create_info(
-                        self.config.max_requests - 1,
-                        now,
-                        map.inner.len(),
-                        map.last_cleanup,
-                    ))
-                } else if count >= self.config.max_requests {
-                    // Rate limit exceeded
-                    let retry_after = self.config.window - now.duration_since(last_request);
-                    let reset_time = Utc::now() + ChronoDuration::from_std(retry_after).unwrap();
-
-                    Err(reject::custom(RateLimitRejection {
-                        retry_after,
-                        limit: self.config.max_requests,
-                        reset_time,
-                        retry_after_format: self.config.retry_after_format.clone(),
-                    }))
-                } else {
-                    // Increment counter
-                    map.inner.insert(key.to_owned(), (last_request, count + 1));
-                    Ok(self.create_info(
-                        self.config.max_requests - (count + 1),
-                        last_request,
-                        map.inner.len(),
-                        map.last_cleanup,
-                    ))
+        self.counters
+            .evicted(config.label.as_ref(), map.cleanup(config, now));
+        let limit = self.limit_for(config, key);
+        let entry = if bypass {
+            map.current_entry(key, &limit, now)
+        } else {
+            match map.incr_within_window(key, &limit, now, cost) {
+                Ok(entry) => entry,
+                Err(entry) => {
+                    let (entry, first) = map.record_rejection(key, entry, now);
+                    let rejection = self.rejection(config, &limit, key, &entry, now);
+                    drop(map);
+                    self.notify_rejected(config, key, &rejection, first);
+                    return Err(rejection);
                 }
             }
-            None => {
-                // First request
-                map.inner.insert(key.to_owned(), (now, 1));
-                Ok(self.create_info(
-                    self.config.max_requests - 1,
-                    now,
-                    map.inner.len(),
-                    map.last_cleanup,
-                ))
-            }
+        };
+        let info = self.create_info(config, &limit, key, &entry, &map, now);
+        drop(map);
+        if !bypass {
+            self.notify_allowed(config, key, &info, cost);
+        }
+        Ok(info)
+    }
+
+    // Counts a request of `key` in the store of the config instead of the memory of the
+    // rate limiter, which only keeps the bans and the rejections, see
+    // `RateLimitConfig::store`
+    async fn count_in_store(
+        &self,
+        config: &RateLimitConfig,
+        store: &dyn RateLimitStore,
+        key: &str,
+        cost: u32,
+    ) -> Result<RateLimitInfo, RateLimitRejection> {
+        let limit = self.limit_for(config, key);
+        let banned = {
+            let map = self.read_state().await;
+            let now = map.clock.now();
+            map.window_entry(key, &limit, now).0.ban_left(now).is_some()
+        };
+        // Banned clients are rejected without being counted, like in memory
+        let count = match banned {
+            true => WindowCount::rejected(0, limit.window),
+            false => store
+                .incr_within_window(key, cost, limit.max_requests, limit.window)
+                .await
+                .unwrap_or_else(|error| {
+                    self.store_failed(config);
+                    trace::store_failure(&*error);
+                    WindowCount::admitted(0, limit.window)
+                }),
+        };
+
+        let mut map = self.lock_state().await;
+        let now = map.clock.now();
+        let entry = map.store_entry(key, &count, &limit, now);
+        if !count.admitted || entry.ban_left(now).is_some() {
+            // Kept in memory, telling the first rejection of the window apart
+            let (entry, first) = map.record_rejection(key, entry, now);
+            let rejection = self.rejection(config, &limit, key, &entry, now);
+            drop(map);
+            self.notify_rejected(config, key, &rejection, first);
+            return Err(rejection);
+        }
+        let info = self.create_info(config, &limit, key, &entry, &map, now);
+        drop(map);
+        self.notify_allowed(config, key, &info, cost);
+        Ok(info)
+    }
+
+    // Counts a failure of the store of the config, and remembers when it happened
+    fn store_failed(&self, config: &RateLimitConfig) {
+        self.counters.store_error(config.label.as_ref());
+        *self
+            .store_failed_at
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(self.clock.now());
+    }
+
+    // Whether the store of the config failed within the last window
+    fn store_failing(&self, config: &RateLimitConfig) -> bool {
+        let failed_at = *self
+            .store_failed_at
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        failed_at.is_some_and(|at| self.clock.now().saturating_duration_since(at) <= config.window)
+    }
+
+    // The limit of the client `key`, capped by the filter if it has its own
+    fn limit_for<'a>(&self, config: &'a RateLimitConfig, key: &str) -> KeyLimit<'a> {
+        let mut limit = config.limit_for(key);
+        if let Some(max_requests) = self.max_requests {
+            limit.max_requests = max_requests;
+        }
+        limit
+    }
+
+    // The rejection of a request of `key` whose current entry is `entry`: the
+    // `Exceeded` of the core, along with the response settings of the config
+    fn rejection(
+        &self,
+        config: &RateLimitConfig,
+        limit: &KeyLimit,
+        key: &str,
+        entry: &Entry,
+        now: Instant,
+    ) -> RateLimitRejection {
+        let exceeded = entry.exceeded(limit, now);
+        let retry_after = exceeded.retry_after;
+        // Banned clients may only come back once the ban is over, not at the reset
+        let reset_time = match exceeded.banned {
+            true => entry.wall(now + retry_after),
+            false => entry.reset_at(),
+        };
+
+        RateLimitRejection {
+            retry_after,
+            limit: exceeded.limit,
+            used: exceeded.used,
+            window: exceeded.window,
+            tier: limit.tier.map(str::to_owned),
+            key: config.visible_key(key),
+            label: config.label.clone(),
+            reset_time,
+            first_rejected_at: entry.first_rejected_at,
+            rejected_at: entry.wall(now),
+            rejected_instant: now,
+            retry_after_format: config.retry_after_format.clone(),
+            retry_after_jitter: self.jitter(config),
+            max_retry_after: config.max_retry_after,
+            header_style: config.header_style,
+            used_header: config.used_header,
+            config_index: None,
+        }
+    }
+
+    // Jitter to add to the Retry-After of the next response
+    fn jitter(&self, config: &RateLimitConfig) -> Duration {
+        match (config.retry_after_jitter, &config.jitter_source) {
+            (Some(max), Some(source)) => source(max),
+            (Some(max), None) => random_jitter(max),
+            (None, _) => Duration::ZERO,
         }
     }
 
+    // The info of a request of `key` whose entry in `map` is `entry` at `now`: the `Quota` of
+    // the core, along with the response settings of the config
     fn create_info(
         &self,
-        remaining: u32,
-        start: Instant,
-        map_len: usize,
-        last_cleanup_time: Instant,
+        config: &RateLimitConfig,
+        limit: &KeyLimit,
+        key: &str,
+        entry: &Entry,
+        map: &RateLimiterMap,
+        now: Instant,
     ) -> RateLimitInfo {
-        let reset_time = start + self.config.window;
-        let retry_after = match self.config.retry_after_format {
-            RetryAfterFormat::HttpDate => {
-                (Utc::now() + ChronoDuration::from_std(self.config.window).unwrap()).to_rfc2822()
-            }
-            RetryAfterFormat::Seconds => self.config.window.as_secs().to_string(),
-        };
+        let quota = entry.quota(limit, now);
+        let reset_at = entry.reset_at();
+        let checked_at = entry.wall(now);
+        let (retry_after_duration, retry_after) = retry_after_until(
+            checked_at,
+            reset_at,
+            self.jitter(config),
+            config.max_retry_after,
+            &config.retry_after_format,
+        );
 
         RateLimitInfo {
             retry_after,
-            limit: self.config.max_requests,
-            remaining,
-            reset_timestamp: (Utc::now()
-                + ChronoDuration::from_std(reset_time.duration_since(start)).unwrap())
-            .timestamp(),
-            retry_after_format: self.config.retry_after_format.clone(),
-            internal_map_len: map_len,
-            last_cleanup_time,
+            retry_after_duration,
+            limit: quota.limit,
+            window: quota.window,
+            tier: limit.tier.map(str::to_owned),
+            key: config.visible_key(key),
+            label: config.label.clone(),
+            remaining: quota.remaining,
+            used: quota.used,
+            reset_timestamp: reset_at.timestamp(),
+            reset_at,
+            first_rejected_at: entry.first_rejected_at,
+            checked_at,
+            retry_after_format: config.retry_after_format.clone(),
+            header_style: config.header_style,
+            used_header: config.used_header,
+            window_start: quota.window_start,
+            internal_map_len: map.inner.len(),
+            last_cleanup_time: map.last_cleanup,
         }
     }
 }
 
 /// Creates a rate limiting filter with the given configuration
+///
+/// # Panics
+///
+/// If the configuration is invalid, see [`try_with_rate_limit`] for a fallible version.
 pub fn with_rate_limit(
     config: RateLimitConfig,
 ) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
-    // Leaking the ip_header is fine as this function will only be executed at most once per route creation
-    let ip_header = config.ip_header.clone().leak();
+    try_with_rate_limit(config)
+        .unwrap_or_else(|e| panic!("Invalid rate limit configuration: {}", e))
+}
 
-    let rate_limiter = RateLimiter::new(config);
+/// Creates a rate limiting filter with the given configuration, after checking it
+/// with [`RateLimitConfig::validate`]
+pub fn try_with_rate_limit(
+    config: RateLimitConfig,
+) -> Result<impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone, ConfigError> {
+    config.validate()?;
+    Ok(rate_limit_filter(RateLimiter::new(config)))
+}
 
-    // With a service implementation, it is possible to get the original remote() functionality
-    // https://github.com/seanmonstar/warp/issues/1127
+/// Creates a rate limiting filter counting requests against `limiter`, whose state is
+/// shared with every other filter built from it, see [`RateLimiterHandle::filter`]
+///
+/// Unlike [`with_rate_limit`], which gives each route its own state, a client spreading
+/// its requests over the routes can't go beyond the limit:
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(60))?;
+/// let orders = warp::path("orders").and(with_shared_rate_limit(limiter.clone()));
+/// let invoices = warp::path("invoices").and(with_shared_rate_limit(limiter));
+/// ```
+pub fn with_shared_rate_limit(
+    limiter: RateLimiterHandle,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+    limiter.filter()
+}
 
+// The filter counting requests against `rate_limiter`, whose state is shared by every
+// filter built from a clone of it
+fn rate_limit_filter(
+    rate_limiter: RateLimiter,
+) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+    let key = client_key(&rate_limiter.config());
     warp::filters::any::any()
         .map(move || rate_limiter.clone())
-        .and(warp::filters::header::optional::<String>(ip_header).map(
-            |header_value: Option<String>| {
-                // Try splitting it at ',' and parse the first element as this is the client ip on most reverse proxies
-                // If that does not result in a valid IpAddr, abort and return 'unknown'
-                header_value
-                    .and_then(|s| {
-                        s.split(",")
-                            .next()
-                            .map(str::trim)
-                            .map(IpAddr::from_str)
-                            .and_then(Result::ok)
-                            .as_ref()
-                            .map(ToString::to_string)
-                    })
-                    .unwrap_or("unknown".to_owned())
-            },
-        ))
+        .and(key)
         .and_then(|rate_limiter: RateLimiter, ip: String| async move {
             rate_limiter.check_rate_limit(&ip).await
         })
 }
 
-/// Adds rate limit headers to a response
-pub fn add_rate_limit_headers(
-    headers: &mut HeaderMap,
-    info: &RateLimitInfo,
-) -> Result<(), RateLimitError> {
-    headers.insert(
-        header::RETRY_AFTER,
-        HeaderValue::from_str(&info.retry_after).map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Limit",
-        HeaderValue::from_str(&info.limit.to_string()).map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Remaining",
-        HeaderValue::from_str(&info.remaining.to_string()).map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Reset",
-        HeaderValue::from_str(&info.reset_timestamp.to_string())
-            .map_err(RateLimitError::HeaderError)?,
-    );
-    Ok(())
-}
-
-/// Adds rate limit headers to a response
-pub fn add_rate_limit_headers_from_rejection(
-    headers: &mut HeaderMap,
-    rejection: &RateLimitRejection,
-) -> Result<(), RateLimitError> {
-    headers.insert(
-        header::RETRY_AFTER,
-        HeaderValue::from_str(&rejection.formated_retry_after())
-            .map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Limit",
-        HeaderValue::from_str(&rejection.limit.to_string()).map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Remaining",
-        HeaderValue::from_str("0").map_err(RateLimitError::HeaderError)?,
-    );
-    headers.insert(
-        "X-RateLimit-Reset",
-        HeaderValue::from_str(&rejection.reset_time.timestamp().to_string())
-            .map_err(RateLimitError::HeaderError)?,
-    );
-    Ok(())
+// The key of the clients that couldn't be identified
+const UNKNOWN_CLIENT: &str = "unknown";
+
+// The key identifying the client of a request
+fn client_key(config: &RateLimitConfig) -> BoxedFilter<(String,)> {
+    let config = config.clone();
+    match &config.ip_header {
+        Some(ip_header) => remote_addr()
+            .and(ip_header_filter(ip_header))
+            .map(
+                move |addr: Option<SocketAddr>, header_value: Option<String>| {
+                    header_key(&config, addr, header_value)
+                },
+            )
+            .boxed(),
+        None => remote_addr().map(remote_client).boxed(),
+    }
+}
+
+// The value of the `ip_header` of a request, if it has one
+//
+// warp's `header::optional` wants a `&'static str`, which would mean leaking the name
+// of the header for every filter built, so the header is looked up by hand
+fn ip_header_filter(
+    ip_header: &str,
+) -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Clone {
+    let ip_header: Arc<str> = ip_header.into();
+    warp::filters::header::headers_cloned()
+        .map(move |headers: HeaderMap| ip_header_value(&headers, &ip_header))
+}
+
+// The value of the header `name` in `headers`, if it is there
+//
+// Proxies may add a line of their own instead of extending the existing one, the lines
+// are joined in order, reading like the single comma separated header they stand for.
+// Bytes that aren't text are replaced rather than failing the whole header: they can't
+// be part of an address, so the addresses around them still count, and a header made
+// only of them takes the fallback of any header not holding an address.
+fn ip_header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let mut lines = headers.get_all(name).iter();
+    let mut value = String::from_utf8_lossy(lines.next()?.as_bytes()).into_owned();
+    for line in lines {
+        value.push_str(", ");
+        value.push_str(&String::from_utf8_lossy(line.as_bytes()));
+    }
+    Some(value)
+}
+
+// The address of the peer of the connection, as set in the extensions of the request
+// by `with_peer_addr`, or by any server putting a `SocketAddr` there
+//
+// warp 0.4 doesn't tell the remote address of the requests, its `remote()` filter is
+// gone, see https://github.com/seanmonstar/warp/issues/1127
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::filters::ext::optional::<SocketAddr>()
+}
+
+// The key of the client of a request coming from `addr`, whose `ip_header` holds
+// `header_value`, like `client_key` does for the requests going through warp
+#[cfg(feature = "tower")]
+fn request_key(
+    config: &RateLimitConfig,
+    addr: Option<SocketAddr>,
+    header_value: Option<String>,
+) -> String {
+    match &config.ip_header {
+        Some(_) => header_key(config, addr, header_value),
+        None => remote_client(addr),
+    }
+}
+
+// The key of the client of a request coming from `addr` whose `ip_header` holds
+// `header_value`. Clients the header doesn't identify share the "unknown" bucket if the
+// config says so, or else are told apart by their remote address.
+fn header_key(
+    config: &RateLimitConfig,
+    addr: Option<SocketAddr>,
+    header_value: Option<String>,
+) -> String {
+    let client = match &config.trusted_proxies {
+        Some(trusted) => forwarded_client(trusted, addr, header_value),
+        None => first_forwarded(header_value),
+    };
+    match client {
+        Some(client) => client.to_string(),
+        None if config.unknown_bucket => UNKNOWN_CLIENT.to_owned(),
+        None => remote_client(addr),
+    }
+}
+
+fn first_forwarded(header_value: Option<String>) -> Option<IpAddr> {
+    // Try splitting it at ',' and parse the first element as this is the client ip on most reverse proxies
+    // If that does not result in a valid IpAddr, the header doesn't tell who the client is
+    let value = header_value?;
+    IpAddr::from_str(value.split(',').next()?.trim()).ok()
+}
+
+// Without a remote address (see `remote_addr`), every client ends up in 'unknown'
+fn remote_client(addr: Option<SocketAddr>) -> String {
+    addr.map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| UNKNOWN_CLIENT.to_owned())
+}
+
+// The client of a request, walking the forwarding chain of `header_value` from the
+// connection's remote address for as long as the addresses are trusted proxies
+fn forwarded_client(
+    trusted: &[IpNet],
+    addr: Option<SocketAddr>,
+    header_value: Option<String>,
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut client = addr?.ip();
+    if is_trusted(&client) {
+        for hop in header_value.iter().flat_map(|value| value.rsplit(',')) {
+            // A trusted proxy wouldn't write anything else, don't guess who the client is
+            client = IpAddr::from_str(hop.trim()).ok()?;
+            if !is_trusted(&client) {
+                break;
+            }
+        }
+    }
+    Some(client)
+}
+
+/// Builds a `RateLimitInfo` from a rate limit rejection, for use in rejection handlers
+///
+/// Same as `RateLimitInfo::from(rejection)`.
+pub fn get_rate_limit_info(rejection: &RateLimitRejection) -> RateLimitInfo {
+    RateLimitInfo::from(rejection)
+}
+
+/// The information about a rate limited client carried by `rejection`, `None` when
+/// it wasn't rejected for exceeding its limit
+///
+/// Shorthand for `rejection.find::<RateLimitRejection>().map(RateLimitInfo::from)`.
+pub fn rate_limit_info_from(rejection: &Rejection) -> Option<RateLimitInfo> {
+    rejection
+        .find::<RateLimitRejection>()
+        .map(RateLimitInfo::from)
+}
+
+/// Whether `rejection` comes from a client exceeding its limit
+pub fn is_rate_limited(rejection: &Rejection) -> bool {
+    rejection.find::<RateLimitRejection>().is_some()
+}
+
+/// Why this crate rejected a request, `None` for rejections coming from elsewhere
+///
+/// Only [`RejectionReason::RateLimited`] rejections carry a [`RateLimitInfo`], see
+/// [`rate_limit_info_from`].
+pub fn rejection_reason(rejection: &Rejection) -> Option<RejectionReason> {
+    if is_rate_limited(rejection) {
+        return Some(RejectionReason::RateLimited);
+    }
+    #[cfg(feature = "geoip")]
+    if let Some(denied) = rejection.find::<CountryDenied>() {
+        return Some(RejectionReason::CountryDenied {
+            country: denied.country.clone(),
+        });
+    }
+    None
+}
+
+/// Kind of the rejections produced by this crate, see [`rejection_reason`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RejectionReason {
+    /// The client exceeded its limit, a [`RateLimitRejection`]
+    RateLimited,
+    /// The client is located in a denied country, a [`CountryDenied`]
+    #[cfg(feature = "geoip")]
+    CountryDenied {
+        /// ISO code of the country of the client
+        country: String,
+    },
+}
+
+/// Information about a rejected client, for use in rejection handlers
+///
+/// ```rust
+/// use warp::{http::StatusCode, Rejection, Reply};
+/// use warp_rate_limit::{with_rate_limit_headers, RateLimitInfo, RateLimitRejection};
+///
+/// async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+///     match rejection.find::<RateLimitRejection>() {
+///         Some(rate_limited) => {
+///             let info = RateLimitInfo::from(rate_limited);
+///             let message = format!("Rate limit exceeded. Try again after {}.", info.retry_after);
+///             let reply = warp::reply::with_status(message, StatusCode::TOO_MANY_REQUESTS);
+///             Ok(with_rate_limit_headers(reply, &info))
+///         }
+///         None => Err(rejection),
+///     }
+/// }
+/// ```
+impl From<&RateLimitRejection> for RateLimitInfo {
+    fn from(rejection: &RateLimitRejection) -> Self {
+        let (retry_after_duration, retry_after) = rejection.retry_after_value();
+        RateLimitInfo {
+            retry_after,
+            retry_after_duration,
+            limit: rejection.limit,
+            window: rejection.window,
+            tier: rejection.tier.clone(),
+            key: rejection.key.clone(),
+            label: rejection.label.clone(),
+            remaining: 0,
+            used: rejection.used,
+            reset_timestamp: rejection.reset_time.timestamp(),
+            reset_at: rejection.reset_time,
+            first_rejected_at: rejection.first_rejected_at,
+            checked_at: rejection.rejected_at,
+            retry_after_format: rejection.retry_after_format.clone(),
+            header_style: rejection.header_style,
+            used_header: rejection.used_header,
+            // Rejected requests consume nothing, there is no window to refund to
+            window_start: rejection.rejected_instant,
+            // The rejection does not carry any information about the internal map
+            internal_map_len: 0,
+            last_cleanup_time: rejection.rejected_instant,
+        }
+    }
 }
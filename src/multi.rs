@@ -0,0 +1,177 @@
+use crate::{
+    client_key, ConfigError, RateLimitConfig, RateLimitError, RateLimitInfo, RateLimiter,
+    RateLimiterHandle,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+};
+use warp::{Filter, Rejection};
+
+type Resolver<T> = dyn Fn(&T) -> RateLimitConfig + Send + Sync;
+
+/// Rate limiters created on demand, one per tenant, for multi-tenant services where
+/// each tenant has its own limits and its own clients
+///
+/// The limiter of a tenant is created on its first request, with the configuration
+/// the resolver gives for it, e.g. loaded from a database. At most `max_tenants`
+/// limiters are kept: beyond, the least recently used one is dropped, and its tenant
+/// starts afresh on its next request.
+///
+/// ```rust,no_run,ignore
+/// let limiters = MultiRateLimiter::new(10_000, |tenant: &String| plans.config_of(tenant))?;
+/// let tenant = warp::header::<String>("X-Tenant");
+/// let route = warp::path("orders").and(limiters.filter(tenant));
+/// ```
+///
+/// Cloning is cheap and the clones share the limiters.
+pub struct MultiRateLimiter<T> {
+    tenants: Arc<Mutex<Tenants<T>>>,
+    resolver: Arc<Resolver<T>>,
+    // Identifies the clients of every tenant
+    key_config: Arc<RateLimitConfig>,
+}
+
+struct Tenants<T> {
+    // Limiter of each tenant, with the tick of its last use
+    limiters: HashMap<T, (RateLimiter, u64)>,
+    max_tenants: usize,
+    tick: u64,
+}
+
+impl<T> Clone for MultiRateLimiter<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tenants: self.tenants.clone(),
+            resolver: self.resolver.clone(),
+            key_config: self.key_config.clone(),
+        }
+    }
+}
+
+impl<T> MultiRateLimiter<T> {
+    /// Number of tenants with a live limiter
+    pub fn tenant_count(&self) -> usize {
+        self.lock().limiters.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tenants<T>> {
+        // Tenants are added and dropped as a whole, a panic can't leave broken ones
+        self.tenants.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> fmt::Debug for MultiRateLimiter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiRateLimiter")
+            .field("tenant_count", &self.tenant_count())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Eq + Hash + Clone + Send + Sync + 'static> MultiRateLimiter<T> {
+    /// Creates a registry keeping at most `max_tenants` limiters, configured by
+    /// `resolver` for each tenant
+    pub fn new(
+        max_tenants: usize,
+        resolver: impl Fn(&T) -> RateLimitConfig + Send + Sync + 'static,
+    ) -> Result<Self, ConfigError> {
+        if max_tenants == 0 {
+            return Err(ConfigError::ZeroMaxTenants);
+        }
+        Ok(Self {
+            tenants: Arc::new(Mutex::new(Tenants {
+                limiters: HashMap::new(),
+                max_tenants,
+                tick: 0,
+            })),
+            resolver: Arc::new(resolver),
+            key_config: Arc::new(RateLimitConfig::default()),
+        })
+    }
+
+    /// Identifies the clients of every tenant like `config` does, by its `ip_header`
+    /// and `trusted_proxies`, instead of like [`RateLimitConfig::default`]
+    ///
+    /// The filters are built before knowing the tenant of their requests, so the
+    /// `ip_header` and `trusted_proxies` of the resolved configurations aren't used.
+    pub fn with_key_config(mut self, config: RateLimitConfig) -> Self {
+        self.key_config = Arc::new(config);
+        self
+    }
+
+    /// The limiter of `tenant`, created with the configuration of the resolver if it
+    /// has none yet, or the error of that configuration
+    pub fn get(&self, tenant: &T) -> Result<RateLimiterHandle, ConfigError> {
+        if let Some(limiter) = self.lock().touch(tenant) {
+            return Ok(RateLimiterHandle { limiter });
+        }
+        // The resolver may be slow, the tenants aren't locked meanwhile
+        let config = (self.resolver)(tenant);
+        config.validate()?;
+        let limiter = self.lock().insert(tenant, RateLimiter::new(config));
+        Ok(RateLimiterHandle { limiter })
+    }
+
+    /// Creates a filter counting requests against the limiter of the tenant extracted
+    /// by `tenant`, e.g. `warp::header::<String>("X-Tenant")`
+    ///
+    /// Requests whose tenant can't be extracted are rejected by `tenant`. Requests of
+    /// a tenant whose configuration is invalid are rejected with a
+    /// [`RateLimitError::Config`], which warp answers with a `500`.
+    pub fn filter<F>(
+        &self,
+        tenant: F,
+    ) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone
+    where
+        F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    {
+        let limiters = self.clone();
+        tenant
+            .and(client_key(&self.key_config))
+            .and_then(move |tenant: T, key: String| {
+                let limiters = limiters.clone();
+                async move {
+                    let limiter = limiters
+                        .get(&tenant)
+                        .map_err(|e| warp::reject::custom(RateLimitError::Config(e)))?;
+                    limiter.limiter.check_rate_limit(&key).await
+                }
+            })
+    }
+}
+
+impl<T: Eq + Hash + Clone> Tenants<T> {
+    // The limiter of `tenant`, if it has one, marked as the most recently used
+    fn touch(&mut self, tenant: &T) -> Option<RateLimiter> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.limiters.get_mut(tenant).map(|(limiter, used)| {
+            *used = tick;
+            limiter.clone()
+        })
+    }
+
+    // Gives `tenant` the `limiter`, unless another request created one meanwhile,
+    // dropping the least recently used limiter if there are too many
+    fn insert(&mut self, tenant: &T, limiter: RateLimiter) -> RateLimiter {
+        if let Some(limiter) = self.touch(tenant) {
+            return limiter;
+        }
+        if self.limiters.len() >= self.max_tenants {
+            let oldest = self
+                .limiters
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(tenant, _)| tenant.clone());
+            if let Some(oldest) = oldest {
+                self.limiters.remove(&oldest);
+            }
+        }
+        self.limiters
+            .insert(tenant.clone(), (limiter.clone(), self.tick));
+        limiter
+    }
+}
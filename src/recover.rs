@@ -0,0 +1,383 @@
+use crate::{
+    add_rate_limit_headers, get_rate_limit_info, trace, ConfigError, RateLimitInfo,
+    RateLimitRejection,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::{ready, Ready},
+    sync::Arc,
+};
+use warp::{
+    http::StatusCode,
+    reply::{Reply, Response},
+    Rejection,
+};
+
+/// Handles `RateLimitRejection`s with a `429 Too Many Requests` response carrying the
+/// rate limit headers, and passes every other rejection through
+///
+/// Meant to be chained before the application's own recover handler:
+///
+/// ```rust,no_run,ignore
+/// let route = my_routes
+///     .recover(handle_rate_limit_rejection)
+///     .recover(handle_other_rejections);
+/// ```
+pub async fn handle_rate_limit_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    RateLimitRecover::new().recover(rejection)
+}
+
+/// Format of the body of rate limited responses
+///
+/// The JSON formats require the `serde` feature.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RejectionBodyFormat {
+    /// The plain-text message of the builder
+    #[default]
+    PlainText,
+    /// A JSON document, see [`RateLimitBody`]
+    #[cfg(feature = "serde")]
+    Json,
+    /// An RFC 7807 `application/problem+json` document, see [`RateLimitProblem`]
+    #[cfg(feature = "serde")]
+    ProblemJson {
+        /// URI identifying the problem type, typically pointing at your documentation
+        type_uri: String,
+    },
+}
+
+impl RejectionBodyFormat {
+    /// RFC 7807 body using `about:blank` as the problem type
+    #[cfg(feature = "serde")]
+    pub fn problem_json() -> Self {
+        Self::ProblemJson {
+            type_uri: String::from("about:blank"),
+        }
+    }
+}
+
+/// JSON body of rate limited responses when using [`RejectionBodyFormat::Json`]
+///
+/// The field names are part of the public API and will not change:
+///
+/// ```json
+/// {"error":"rate_limited","retry_after_seconds":12,"limit":60,"reset":1699999999}
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RateLimitBody {
+    /// Always `"rate_limited"`
+    pub error: String,
+    /// Seconds until the client can retry
+    pub retry_after_seconds: u64,
+    /// Maximum requests allowed in the window, same as `X-RateLimit-Limit`
+    pub limit: u32,
+    /// Unix timestamp when the rate limit resets, same as `X-RateLimit-Reset`
+    pub reset: i64,
+}
+
+impl From<&RateLimitInfo> for RateLimitBody {
+    fn from(info: &RateLimitInfo) -> Self {
+        Self {
+            error: String::from("rate_limited"),
+            retry_after_seconds: info.retry_after_duration.as_secs(),
+            limit: info.limit,
+            reset: info.reset_timestamp,
+        }
+    }
+}
+
+impl From<&RateLimitRejection> for RateLimitBody {
+    fn from(rejection: &RateLimitRejection) -> Self {
+        Self::from(&get_rate_limit_info(rejection))
+    }
+}
+
+/// RFC 7807 body of rate limited responses when using [`RejectionBodyFormat::ProblemJson`]
+///
+/// `limit`, `remaining` and `reset` are extension members mirroring the
+/// `X-RateLimit-*` headers.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RateLimitProblem {
+    /// URI identifying the problem type
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub type_uri: String,
+    /// Short summary of the problem type
+    pub title: String,
+    /// HTTP status code of the response
+    pub status: u16,
+    /// Explanation specific to this occurrence of the problem
+    pub detail: String,
+    /// Maximum requests allowed in the window
+    pub limit: u32,
+    /// Remaining requests in the current window
+    pub remaining: u32,
+    /// Unix timestamp when the rate limit resets
+    pub reset: i64,
+}
+
+/// Builder for a customized version of [`handle_rate_limit_rejection`]
+///
+/// ```rust,no_run,ignore
+/// let route = my_routes
+///     .recover(RateLimitRecover::new().message("Slow down!").handler())
+///     .recover(handle_other_rejections);
+/// ```
+#[derive(Clone)]
+pub struct RateLimitRecover {
+    message: String,
+    rejection_message: Option<MessageTemplate>,
+    body_format: RejectionBodyFormat,
+    status: StatusCode,
+    response_fn: Option<Arc<ResponseFn>>,
+    add_headers: bool,
+}
+
+/// Function building the whole response of a rate limited request
+pub type ResponseFn = dyn Fn(&RateLimitInfo) -> Response + Send + Sync;
+
+impl Default for RateLimitRecover {
+    fn default() -> Self {
+        Self {
+            message: String::from("Rate limit exceeded"),
+            rejection_message: None,
+            body_format: RejectionBodyFormat::PlainText,
+            status: StatusCode::TOO_MANY_REQUESTS,
+            response_fn: None,
+            add_headers: true,
+        }
+    }
+}
+
+impl fmt::Debug for RateLimitRecover {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimitRecover")
+            .field("message", &self.message)
+            .field("rejection_message", &self.rejection_message)
+            .field("body_format", &self.body_format)
+            .field("status", &self.status)
+            .field("response_fn", &self.response_fn.as_ref().map(|_| "Fn"))
+            .field("add_headers", &self.add_headers)
+            .finish()
+    }
+}
+
+impl RateLimitRecover {
+    /// Creates a builder producing the same responses as `handle_rate_limit_rejection`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the plain-text body of the response, replacing any message template
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = message.into();
+        self.rejection_message = None;
+        self
+    }
+
+    /// Sets a template rendered as the plain-text body of each response
+    ///
+    /// Placeholders are written `{name}` and literal braces `{{` and `}}`. The available
+    /// placeholders are `retry_after`, `limit`, `remaining`, `used`, `reset` (Unix
+    /// timestamp) and `window` (in seconds):
+    ///
+    /// ```rust,no_run,ignore
+    /// let recover = RateLimitRecover::new()
+    ///     .rejection_message("Too many requests. Retry after {retry_after} (limit {limit}/{window}s).")?;
+    /// ```
+    pub fn rejection_message(mut self, template: &str) -> Result<Self, ConfigError> {
+        self.rejection_message = Some(MessageTemplate::parse(template)?);
+        Ok(self)
+    }
+
+    /// Sets the format of the response body
+    pub fn body_format(mut self, body_format: RejectionBodyFormat) -> Self {
+        self.body_format = body_format;
+        self
+    }
+
+    /// Sets the status of the response (default: `429 Too Many Requests`)
+    ///
+    /// Only client and server errors are accepted. `Retry-After` is emitted whatever
+    /// the status, as RFC 7231 also defines it for `503 Service Unavailable`.
+    pub fn status(mut self, status: StatusCode) -> Result<Self, ConfigError> {
+        if !status.is_client_error() && !status.is_server_error() {
+            return Err(ConfigError::InvalidRejectionStatus(status));
+        }
+        self.status = status;
+        Ok(self)
+    }
+
+    /// Uses `response_fn` to build the whole response, replacing the message and body
+    /// format
+    ///
+    /// The status of the returned response is used verbatim, so make sure it is an
+    /// error status; the status set with [`RateLimitRecover::status`] is ignored. The
+    /// rate limit headers are still added afterwards, unless disabled with
+    /// [`RateLimitRecover::headers`].
+    pub fn response_with(
+        mut self,
+        response_fn: impl Fn(&RateLimitInfo) -> Response + Send + Sync + 'static,
+    ) -> Self {
+        self.response_fn = Some(Arc::new(response_fn));
+        self
+    }
+
+    /// Sets whether the rate limit headers are added to the response (default: `true`)
+    pub fn headers(mut self, add_headers: bool) -> Self {
+        self.add_headers = add_headers;
+        self
+    }
+
+    /// Builds the response for a rate limit rejection
+    pub fn response(&self, rejection: &RateLimitRejection) -> Response {
+        self.response_from_info(&get_rate_limit_info(rejection))
+    }
+
+    /// Builds the rate limited response described by `info`
+    pub fn response_from_info(&self, info: &RateLimitInfo) -> Response {
+        let mut response = match (&self.response_fn, &self.body_format) {
+            (Some(response_fn), _) => response_fn(info),
+            (None, RejectionBodyFormat::PlainText) => {
+                let message = match &self.rejection_message {
+                    Some(template) => template.render(info),
+                    None => self.message.clone(),
+                };
+                warp::reply::with_status(message, self.status).into_response()
+            }
+            #[cfg(feature = "serde")]
+            (None, RejectionBodyFormat::Json) => {
+                warp::reply::with_status(warp::reply::json(&RateLimitBody::from(info)), self.status)
+                    .into_response()
+            }
+            #[cfg(feature = "serde")]
+            (None, RejectionBodyFormat::ProblemJson { type_uri }) => {
+                let problem = RateLimitProblem {
+                    type_uri: type_uri.clone(),
+                    title: self
+                        .status
+                        .canonical_reason()
+                        .unwrap_or("Rate Limited")
+                        .to_owned(),
+                    status: self.status.as_u16(),
+                    detail: format!("Rate limit exceeded. Try again after {}.", info.retry_after),
+                    limit: info.limit,
+                    remaining: info.remaining,
+                    reset: info.reset_timestamp,
+                };
+                let mut response =
+                    warp::reply::with_status(warp::reply::json(&problem), self.status)
+                        .into_response();
+                response.headers_mut().insert(
+                    warp::http::header::CONTENT_TYPE,
+                    warp::http::HeaderValue::from_static("application/problem+json"),
+                );
+                response
+            }
+        };
+        if self.add_headers {
+            if let Err(e) = add_rate_limit_headers(response.headers_mut(), info) {
+                trace::header_failure(&e);
+            }
+        }
+        response
+    }
+
+    /// Produces the response for rate limit rejections, and for [`crate::CountryDenied`]
+    /// with the `geoip` feature, and gives back any other rejection
+    pub fn recover(&self, rejection: Rejection) -> Result<Response, Rejection> {
+        #[cfg(feature = "geoip")]
+        if let Some(denied) = rejection.find::<crate::CountryDenied>() {
+            return Ok(
+                warp::reply::with_status(denied.reason.clone(), denied.status).into_response(),
+            );
+        }
+        match rejection.find::<RateLimitRejection>() {
+            Some(rate_limit_rejection) => Ok(self.response(rate_limit_rejection)),
+            None => Err(rejection),
+        }
+    }
+
+    /// Turns the builder into a function that can be passed to `Filter::recover`
+    pub fn handler(
+        self,
+    ) -> impl Fn(Rejection) -> Ready<Result<Response, Rejection>> + Clone + Send + Sync + 'static
+    {
+        move |rejection| ready(self.recover(rejection))
+    }
+}
+
+/// A rejection message template, parsed once so rendering cannot fail
+#[derive(Clone, Debug)]
+struct MessageTemplate(Vec<Segment>);
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    RetryAfter,
+    Limit,
+    Remaining,
+    Used,
+    Reset,
+    Window,
+}
+
+impl MessageTemplate {
+    fn parse(template: &str) -> Result<Self, ConfigError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|&(_, c)| c == '{').is_some() => literal.push('{'),
+                '}' if chars.next_if(|&(_, c)| c == '}').is_some() => literal.push('}'),
+                '{' => {
+                    let rest = &template[position + 1..];
+                    let end = rest
+                        .find('}')
+                        .ok_or(ConfigError::UnbalancedBrace(position))?;
+                    let segment = match &rest[..end] {
+                        "retry_after" => Segment::RetryAfter,
+                        "limit" => Segment::Limit,
+                        "remaining" => Segment::Remaining,
+                        "used" => Segment::Used,
+                        "reset" => Segment::Reset,
+                        "window" => Segment::Window,
+                        name => return Err(ConfigError::UnknownPlaceholder(name.to_owned())),
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(segment);
+                    // Skip the placeholder name and its closing brace
+                    while chars.next_if(|&(i, _)| i <= position + 1 + end).is_some() {}
+                }
+                '}' => return Err(ConfigError::UnbalancedBrace(position)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+
+    fn render(&self, info: &RateLimitInfo) -> String {
+        let mut message = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => message.push_str(literal),
+                Segment::RetryAfter => message.push_str(&info.retry_after),
+                Segment::Limit => message.push_str(&info.limit.to_string()),
+                Segment::Remaining => message.push_str(&info.remaining.to_string()),
+                Segment::Used => message.push_str(&info.used.to_string()),
+                Segment::Reset => message.push_str(&info.reset_timestamp.to_string()),
+                Segment::Window => message.push_str(&info.window.as_secs_f64().to_string()),
+            }
+        }
+        message
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    client_key, with_rate_limit_headers, ConfigError, RateLimitConfig, RateLimitInfo, RateLimiter,
+    StatusClass,
+};
+use std::{fmt, future::ready, time::Instant};
+use warp::{
+    filters::BoxedFilter,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+/// Rate limits a route like [`crate::with_rate_limit`], giving back the requests
+/// the client gave up on
+///
+/// Requests are counted when they reach the filter. If the client disconnects, or
+/// the request is cancelled in any other way, before the wrapped filter produced a
+/// response or a rejection, its request is refunded, so that users on flaky
+/// connections aren't punished for the requests they never got an answer to.
+/// Completed requests keep being counted. A request is only refunded to the window
+/// it was counted in, never to a newer one. The rate limit headers are added to
+/// every response.
+///
+/// Responses whose status is in one of the [`refund_on_status`](Self::refund_on_status)
+/// classes are refunded as well, so that clients don't burn their quota on the errors
+/// of a failing backend.
+///
+/// ```rust,no_run,ignore
+/// let export = warp::path!("export")
+///     .and_then(long_running_export)
+///     .with(warp::wrap_fn(
+///         RefundRateLimit::new(RateLimitConfig::max_per_minute(10))?.wrapper(),
+///     ))
+///     .recover(handle_rate_limit_rejection);
+/// ```
+#[derive(Clone)]
+pub struct RefundRateLimit {
+    limiter: RateLimiter,
+    refund_classes: Vec<StatusClass>,
+}
+
+impl fmt::Debug for RefundRateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefundRateLimit")
+            .field("config", &self.limiter.config())
+            .field("refund_classes", &self.refund_classes)
+            .finish()
+    }
+}
+
+impl RefundRateLimit {
+    /// Creates a rate limiter refunding cancelled requests, after checking the
+    /// configuration with [`RateLimitConfig::validate`]
+    ///
+    /// Refunds are counted in memory, so a config with a [`RateLimitConfig::store`] is
+    /// refused.
+    pub fn new(config: RateLimitConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        if config.store.is_some() {
+            return Err(ConfigError::StoreNotSupported("RefundRateLimit"));
+        }
+        Ok(Self {
+            limiter: RateLimiter::new(config),
+            refund_classes: Vec::new(),
+        })
+    }
+
+    /// Also refunds the responses whose status is in one of `classes`, such as
+    /// [`StatusClass::ServerError`]
+    ///
+    /// A request is counted before its response is known, so the rate limit headers
+    /// of a refunded response don't account for its refund yet.
+    pub fn refund_on_status(mut self, classes: impl IntoIterator<Item = StatusClass>) -> Self {
+        self.refund_classes = classes.into_iter().collect();
+        self
+    }
+
+    /// Turns the rate limiter into a function that can be passed to `warp::wrap_fn`
+    ///
+    /// Every filter built from the returned function shares the same state.
+    pub fn wrapper<F, R>(self) -> impl Fn(F) -> BoxedFilter<(Response,)> + Clone
+    where
+        F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+    {
+        move |filter: F| {
+            let limiter = self.limiter.clone();
+            let refund_classes = self.refund_classes.clone();
+            // Rejections are answers too, tell them apart from cancellations
+            let outcome = filter
+                .map(Ok)
+                .or_else(|rejection| ready(Ok::<_, Rejection>((Err(rejection),))));
+            client_key(&self.limiter.config())
+                .and_then(move |key: String| {
+                    let limiter = limiter.clone();
+                    async move { limiter.consume(key).await }
+                })
+                .and(outcome)
+                .and_then(
+                    move |(consumption, info): (Consumption, RateLimitInfo),
+                          outcome: Result<R, Rejection>| {
+                        let response = outcome.map(Reply::into_response);
+                        let refunded = response.as_ref().is_ok_and(|response| {
+                            let status = response.status();
+                            refund_classes.iter().any(|class| class.contains(status))
+                        });
+                        // Dropping the consumption refunds it
+                        if !refunded {
+                            consumption.keep();
+                        }
+                        ready(response.map(|response| {
+                            with_rate_limit_headers(response, &info).into_response()
+                        }))
+                    },
+                )
+                .boxed()
+        }
+    }
+}
+
+// A request counted in the window starting at `start`, refunded on drop unless kept
+struct Consumption {
+    limiter: RateLimiter,
+    // None once kept, or when the client is exempted from the limit
+    key: Option<String>,
+    start: Instant,
+}
+
+impl Consumption {
+    fn keep(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for Consumption {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let start = self.start;
+            self.limiter.with_state_now(move |map| {
+                match map.inner.get_mut(&key) {
+                    // The window the request was counted in is still running
+                    Some(entry) if entry.start == start => {
+                        entry.count = entry.count.saturating_sub(1);
+                    }
+                    _ => {}
+                }
+            });
+        }
+    }
+}
+
+impl RateLimiter {
+    async fn consume(&self, key: String) -> Result<(Consumption, RateLimitInfo), Rejection> {
+        let config = self.config();
+        let bypass = self.bypasses_limit(&config, &key)?;
+        let mut map = self.lock_state().await;
+        let now = map.clock.now();
+
+        self.counters
+            .evicted(config.label.as_ref(), map.cleanup(&config, now));
+        let limit = config.limit_for(&key);
+        let entry = if bypass {
+            map.current_entry(&key, &limit, now)
+        } else {
+            match map.incr_within_window(&key, &limit, now, 1) {
+                Ok(entry) => entry,
+                Err(entry) => {
+                    let (entry, first) = map.record_rejection(&key, entry, now);
+                    let rejection = self.rejection(&config, &limit, &key, &entry, now);
+                    drop(map);
+                    self.notify_rejected(&config, &key, &rejection, first);
+                    return Err(config.reject(rejection));
+                }
+            }
+        };
+        let info = self.create_info(&config, &limit, &key, &entry, &map, now);
+        drop(map);
+        if !bypass {
+            self.notify_allowed(&config, &key, &info, 1);
+        }
+        let consumption = Consumption {
+            limiter: self.clone(),
+            key: (!bypass).then_some(key),
+            start: entry.start,
+        };
+        Ok((consumption, info))
+    }
+}
@@ -0,0 +1,768 @@
+use crate::{
+    core::{Ban, Entry, KeyLimit},
+    rate_limit_filter, trace, AuditEntry, AuditReason, ConfigError, HealthReport, HeavyHitter,
+    HeavyHitterReport, LatencyHistogram, RateLimitConfig, RateLimitCounters, RateLimitInfo,
+    RateLimitRejection, RateLimiter, Timestamp,
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use warp::{Filter, Rejection};
+
+// Most time `RateLimiterHandle::health` waits on the state before reporting it stuck
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A rate limiter whose state can be shared by several routes
+///
+/// Every filter built from the same handle (or from clones of it) counts requests
+/// against the same clients, and follows the configuration updates made through it:
+///
+/// ```rust,no_run,ignore
+/// let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100))?;
+/// let route = warp::path("orders").and(limiter.filter());
+///
+/// // Later, during an incident
+/// limiter.update_config(RateLimitConfig::max_per_minute(10))?;
+/// ```
+#[derive(Clone)]
+pub struct RateLimiterHandle {
+    pub(crate) limiter: RateLimiter,
+}
+
+impl RateLimiterHandle {
+    /// Creates a rate limiter after checking the configuration with
+    /// [`RateLimitConfig::validate`]
+    pub fn new(config: RateLimitConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            limiter: RateLimiter::new(config),
+        })
+    }
+
+    /// Current configuration of the rate limiter
+    pub fn config(&self) -> Arc<RateLimitConfig> {
+        self.limiter.config()
+    }
+
+    /// Replaces the configuration of the rate limiter and of every filter built from it,
+    /// keeping the requests counted so far
+    ///
+    /// The new `max_requests` applies immediately, including to clients in the middle of
+    /// a window. The new `window` only applies to a client once its current window is
+    /// over.
+    ///
+    /// Existing filters keep identifying clients as they did: their `ip_header`,
+    /// `trusted_proxies` and `unknown_bucket` can't be changed, only filters built
+    /// afterwards use the new ones. The `clock` of the rate limiter can't be changed
+    /// either.
+    pub fn update_config(&self, config: RateLimitConfig) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.limiter.set_config(config);
+        Ok(())
+    }
+
+    /// Number of clients currently tracked
+    ///
+    /// Kept up to date as clients are added and pruned, so reading it never waits on
+    /// requests being counted. See [`RateLimitConfig::on_key_count_exceeds`] to be told
+    /// when it grows too large.
+    pub fn key_count(&self) -> usize {
+        self.limiter.keys.get()
+    }
+
+    /// Current usage of the client `key`, as seen by its next request but without
+    /// counting anything, or `None` if the client isn't tracked
+    ///
+    /// `key` is the client key the filters derive from requests, usually the ip address
+    /// of the client. A client whose window is over reports its full budget.
+    pub async fn usage(&self, key: &str) -> Option<RateLimitInfo> {
+        let (info, tracked) = self.view(key).await;
+        tracked.then_some(info)
+    }
+
+    /// Counts a request of the client `key`, like the filters built from this rate
+    /// limiter do, e.g. for background jobs or the messages of a WebSocket
+    ///
+    /// `key` is any string telling clients apart; the filters use the ip address of the
+    /// client, so using one counts against the same client as its HTTP requests. The
+    /// rejection is the one of the limiter, before any
+    /// [`RateLimitConfig::rejection_mapper`], and the country rules of the `geoip`
+    /// feature, which are about HTTP clients, don't apply.
+    pub async fn check(&self, key: &str) -> Result<RateLimitInfo, RateLimitRejection> {
+        self.limiter.check_key(key, 1).await
+    }
+
+    /// Counts a request of the client `key` consuming `n` units of its budget at once,
+    /// e.g. one per item of a batch, like [`check`](Self::check) does for one
+    ///
+    /// The units are consumed if and only if the client has `n` left, otherwise none
+    /// are, and the rejection tells when its window resets, after which a request
+    /// asking for more than its limit still can't fit. Acquiring 0 units only
+    /// [`peek`](Self::peek)s.
+    pub async fn acquire_n(&self, key: &str, n: u32) -> Result<RateLimitInfo, RateLimitRejection> {
+        if n == 0 {
+            return Ok(self.peek(key).await);
+        }
+        self.limiter.check_key(key, n).await
+    }
+
+    /// Usage of the client `key`, as seen by its next request but without counting
+    /// anything, a full budget for a client that isn't tracked
+    ///
+    /// A window that is over reports the full budget, as if it had rolled over, and a
+    /// banned client reports nothing left. Only reads the state, so it can be called
+    /// alongside the requests being counted, e.g. to check the client has budget left
+    /// before an expensive operation, and [`check`](Self::check) once it starts.
+    pub async fn peek(&self, key: &str) -> RateLimitInfo {
+        self.view(key).await.0
+    }
+
+    /// Whether the next request of the client `key` would be rejected, because it used
+    /// up its budget, is banned, or is located in a denied country, without counting
+    /// anything, e.g. for a dashboard
+    ///
+    /// Only reads the state, without building any [`RateLimitInfo`], so it is cheap
+    /// enough to call on every page render. Clients exempted from the limit by the
+    /// country rules of the `geoip` feature never are.
+    pub async fn is_limited(&self, key: &str) -> bool {
+        let config = self.limiter.config();
+        #[cfg(feature = "geoip")]
+        match config.country_verdict(key) {
+            Ok(false) => {}
+            Ok(true) => return false,
+            Err(_) => return true,
+        }
+        let map = self.limiter.read_state().await;
+        let now = map.clock.now();
+        let limit = config.limit_for(key);
+        let (entry, _) = map.window_entry(key, &limit, now);
+        !entry.admits(&limit, now, 1)
+    }
+
+    /// Gives back `n` of the units charged to the client `key` by the request `charge`
+    /// was the outcome of, e.g. for the items of a batch that weren't processed,
+    /// telling whether they were refunded
+    ///
+    /// Units are only refunded to the window they were charged in: once it is over,
+    /// refunding them would raise the budget of a newer one, so nothing is. A client
+    /// never goes below zero used requests.
+    ///
+    /// ```rust,no_run,ignore
+    /// let charge = limiter.acquire_n(&key, 100).await?;
+    /// let processed = process(&items).await;
+    /// limiter.refund(&key, 100 - processed, &charge).await;
+    /// ```
+    pub async fn refund(&self, key: &str, n: u32, charge: &RateLimitInfo) -> bool {
+        let mut map = self.limiter.lock_state().await;
+        let now = map.clock.now();
+        map.refund(key, charge.window_start, n, now)
+    }
+
+    /// Forgets the client `key`, whose next request starts a fresh window, see
+    /// [`reset_key`](Self::reset_key)
+    pub async fn reset(&self, key: &str) {
+        self.reset_key(key).await;
+    }
+
+    /// Forgets the client `key`, whose next request starts a fresh window with its
+    /// full budget, giving back whether it was tracked, e.g. to unblock a customer
+    /// right away. Lifts its ban as well, see [`block_key`](Self::block_key).
+    ///
+    /// Requests being counted for the client concurrently land either before the
+    /// reset, and are forgotten, or after it, in the fresh window.
+    pub async fn reset_key(&self, key: &str) -> bool {
+        self.limiter.lock_state().await.remove(key).is_some()
+    }
+
+    /// Bans the client `key` for `duration`, rejecting all its requests whatever its
+    /// usage, with a `retry_after` of the time left on the ban, e.g. for an abuser
+    /// spotted in the logs
+    ///
+    /// The ban outlasts the windows of the client, replaces any previous one, and is
+    /// recorded to the [`RateLimitConfig::audit_sink`]. Clients exempted from the limit
+    /// by the country rules of the `geoip` feature aren't affected.
+    pub async fn block_key(&self, key: &str, duration: Duration) {
+        let config = self.limiter.config();
+        let mut map = self.limiter.lock_state().await;
+        let now = map.clock.now();
+        let (entry, _) = map.window_entry(key, &config.limit_for(key), now);
+        let ban = Ban::new(BanReason::Manual, &map.clock, now, duration);
+        let imposed_at = ban.imposed_at;
+        map.ban(key, entry, ban);
+        drop(map);
+        config.audit(key, AuditReason::Blocked, Some(duration), imposed_at);
+    }
+
+    /// Lifts the ban of the client `key` before it ends, from its very next request
+    /// on, keeping its usage, giving back whether it was banned
+    pub async fn unban_key(&self, key: &str) -> bool {
+        let mut map = self.limiter.lock_state().await;
+        let now = map.clock.now();
+        map.unban(key, now)
+    }
+
+    /// The bans still running, ordered by key, e.g. to review them before lifting
+    /// some with [`unban_key`](Self::unban_key)
+    ///
+    /// Bans are indexed apart from the clients, so listing them only goes through
+    /// the bans.
+    pub async fn active_bans(&self) -> Vec<BanInfo> {
+        let config = self.limiter.config();
+        let map = self.limiter.read_state().await;
+        let now = map.clock.now();
+        let mut bans: Vec<_> = map.bans.iter().filter(|(_, ban)| ban.until > now).collect();
+        bans.sort_unstable_by_key(|(key, _)| *key);
+        bans.into_iter()
+            .map(|(key, ban)| BanInfo {
+                key: config.visible_key(key),
+                key_hash: config.redact_key.then(|| trace::key_hash(key)),
+                reason: ban.reason.clone(),
+                imposed_at: ban.imposed_at,
+                expires_at: ban.expires_at,
+            })
+            .collect()
+    }
+
+    /// Grants the client `key` `extra` requests on top of its limit, giving back its
+    /// usage afterwards, e.g. for a one-off bump without changing its plan
+    ///
+    /// Grants add up and only last for the current window of the client: they are
+    /// dropped when it rolls over. The `limit` reported to the client stays the one of
+    /// the config, only `remaining` grows.
+    pub async fn grant(&self, key: &str, extra: u32) -> RateLimitInfo {
+        self.adjust(key, |entry, _| {
+            entry.credit = entry.credit.saturating_add(extra)
+        })
+        .await
+    }
+
+    /// Sets the requests the client `key` has left in its current window to
+    /// `remaining`, giving back its usage afterwards
+    ///
+    /// Requests beyond its limit are granted like by [`grant`](Self::grant), and the
+    /// ones below are counted as used, until the window of the client rolls over.
+    pub async fn set_remaining(&self, key: &str, remaining: u32) -> RateLimitInfo {
+        self.adjust(key, |entry, max_requests| {
+            let allowance = entry.count.saturating_add(remaining);
+            entry.credit = allowance.saturating_sub(max_requests);
+            entry.count = entry.count.max(max_requests.saturating_sub(remaining));
+        })
+        .await
+    }
+
+    // Applies `adjust` to the entry of `key` in its current window, given the maximum
+    // requests of the client, and gives back its usage afterwards
+    async fn adjust(&self, key: &str, adjust: impl FnOnce(&mut Entry, u32)) -> RateLimitInfo {
+        let config = self.limiter.config();
+        let mut map = self.limiter.lock_state().await;
+        let now = map.clock.now();
+        let limit = config.limit_for(key);
+        let (mut entry, _) = map.window_entry(key, &limit, now);
+        adjust(&mut entry, limit.max_requests);
+        map.insert(key, entry);
+        self.limiter
+            .create_info(&config, &limit, key, &entry, &map, now)
+    }
+
+    /// Forgets every client at once, giving back how many were tracked, e.g. to open
+    /// the floodgates in an emergency
+    ///
+    /// Requests being counted concurrently land either before the clearing, and are
+    /// forgotten, or after it, in fresh windows. Reported by a `state cleared` event.
+    pub async fn clear_all(&self) -> usize {
+        let cleared = self.limiter.lock_state().await.clear();
+        trace::cleared(self.limiter.config().label.as_deref(), cleared);
+        cleared
+    }
+
+    // Usage of the client `key` as seen by its next request, and whether it is tracked
+    async fn view(&self, key: &str) -> (RateLimitInfo, bool) {
+        let config = self.limiter.config();
+        let map = self.limiter.read_state().await;
+        let now = map.clock.now();
+        let limit = config.limit_for(key);
+        let (entry, _) = map.window_entry(key, &limit, now);
+        let info = self
+            .limiter
+            .create_info(&config, &limit, key, &entry, &map, now);
+        (info, map.inner.contains_key(key))
+    }
+
+    /// Usage of the tracked clients ordered by key, skipping the first `offset` ones
+    /// and listing at most `limit`, e.g. for an admin dashboard
+    ///
+    /// Each entry is consistent on its own, but the snapshot as a whole isn't atomic:
+    /// the pages are taken under separate locks, between which clients come and go.
+    /// Clients whose window is over report an empty one, as their next request would
+    /// see it.
+    pub async fn snapshot(&self, offset: usize, limit: usize) -> Vec<EntrySnapshot> {
+        let config = self.limiter.config();
+        let map = self.limiter.read_state().await;
+        let now = map.clock.now();
+
+        let mut keys: Vec<&String> = map.inner.keys().collect();
+        keys.sort_unstable();
+        keys.into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|key| {
+                let key_limit = config.limit_for(key);
+                let (entry, _) = map.window_entry(key, &key_limit, now);
+                EntrySnapshot::new(&config, key, &key_limit, &entry, now)
+            })
+            .collect()
+    }
+
+    /// The tracked clients matching `filter`, ordered by key, a page at a time, e.g.
+    /// to find the clients currently limited
+    ///
+    /// The clients are filtered while going through the state, only the matching
+    /// ones being sorted and paged, under a single lock. Clients whose window is over
+    /// are seen as their next request would see them, like in [`snapshot`](Self::snapshot).
+    ///
+    /// ```rust,no_run,ignore
+    /// let page = limiter
+    ///     .list_keys(KeyFilter::default().with_prefix("10.0.").with_limited(true))
+    ///     .await;
+    /// ```
+    pub async fn list_keys(&self, filter: KeyFilter) -> Page<EntrySnapshot> {
+        let config = self.limiter.config();
+        let map = self.limiter.read_state().await;
+        let now = map.clock.now();
+
+        let prefix = filter.prefix.as_deref().unwrap_or_default();
+        let mut matching: Vec<_> = map
+            .inner
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .filter_map(|key| {
+                let key_limit = config.limit_for(key);
+                let (entry, _) = map.window_entry(key, &key_limit, now);
+                filter
+                    .matches(&key_limit, &entry, now)
+                    .then_some((key, key_limit, entry))
+            })
+            .collect();
+        matching.sort_unstable_by_key(|(key, ..)| *key);
+
+        let items: Vec<_> = matching
+            .iter()
+            .skip(filter.offset)
+            .take(filter.limit)
+            .map(|(key, key_limit, entry)| EntrySnapshot::new(&config, key, key_limit, entry, now))
+            .collect();
+        let next_offset = filter.offset.saturating_add(items.len());
+        Page {
+            total: matching.len(),
+            next_offset: (next_offset < matching.len()).then_some(next_offset),
+            items,
+        }
+    }
+
+    /// The `n` most recent actions taken against clients, newest first, if the
+    /// [`RateLimitConfig::audit_sink`] keeps them around like an [`AuditRing`](crate::AuditRing)
+    pub fn recent_audit(&self, n: usize) -> Vec<AuditEntry> {
+        let config = self.limiter.config();
+        config
+            .audit_sink
+            .as_ref()
+            .map_or_else(Vec::new, |sink| sink.recent(n))
+    }
+
+    /// State of the rate limiter, against the health thresholds of its configuration,
+    /// see [`RateLimitConfig::health_max_keys`] and [`RateLimitConfig::health_max_prune_age`]
+    ///
+    /// Waits up to a second on the state, beyond which it is reported as not ok, so
+    /// that a probe doesn't hang along with the requests. A [`RateLimitConfig::store`]
+    /// that failed within the last window is reported as not ok either. See
+    /// [`crate::health_route`] for a ready-made probe.
+    pub async fn health(&self) -> HealthReport {
+        let config = self.limiter.config();
+        let last_cleanup = self
+            .limiter
+            .read_state_within(HEALTH_TIMEOUT)
+            .await
+            .map(|map| map.clock.now().saturating_duration_since(map.last_cleanup));
+        let store_failing = self.limiter.store_failing(&config);
+        HealthReport::new(&config, self.key_count(), last_cleanup, store_failing)
+    }
+
+    /// Totals of the requests seen by this rate limiter and every filter built from it
+    ///
+    /// The counters are kept apart from the state, so reading them never waits on
+    /// requests being counted.
+    pub fn counters(&self) -> RateLimitCounters {
+        self.limiter.counters.snapshot()
+    }
+
+    /// Counters of the requests seen under each label of the configuration, see
+    /// [`RateLimitConfig::label`]
+    ///
+    /// Requests are counted under the label in effect when they are seen, so a label
+    /// changed by [`update_config`](Self::update_config) starts from zero. Requests
+    /// seen without a label only count in [`counters`](Self::counters).
+    pub fn label_counters(&self) -> HashMap<Arc<str>, RateLimitCounters> {
+        self.limiter.counters.by_label()
+    }
+
+    /// The clients sending the most requests, followed by the sketch of
+    /// [`RateLimitConfig::heavy_hitters`], and the requests of all the others
+    ///
+    /// Empty unless the config follows heavy hitters. Requests are counted since the
+    /// rate limiter was created, admitted or rejected alike.
+    pub fn heavy_hitters(&self) -> HeavyHitterReport {
+        let config = self.limiter.config();
+        let (counts, total) = self.limiter.heavy.snapshot();
+        let followed: u64 = counts.iter().map(|(_, requests)| requests).sum();
+        HeavyHitterReport {
+            hitters: counts
+                .into_iter()
+                .map(|(key, requests)| HeavyHitter {
+                    key: config.visible_key(&key),
+                    key_hash: config.redact_key.then(|| trace::key_hash(&key)),
+                    requests,
+                })
+                .collect(),
+            other: total - followed,
+        }
+    }
+
+    /// Time taken to check the requests counted by [`with_rate_limit`](crate::with_rate_limit)
+    /// like filters, waiting on the state included, when the config enables
+    /// [`RateLimitConfig::measure_latency`]
+    ///
+    /// The buckets go from 1µs to 25ms, plus one for the slower checks.
+    pub fn latency_histogram(&self) -> LatencyHistogram {
+        self.limiter.latencies.snapshot()
+    }
+
+    /// Spawns a task reporting, every `interval`, the requests the rate limiter saw
+    /// since the previous report and the number of clients it tracks, for deployments
+    /// without a metrics stack
+    ///
+    /// Reports are `rate limiter stats` tracing events and, with the `log` feature,
+    /// records like `rate-limiter[login]: 1523 allowed, 87 rejected, 412 keys,
+    /// 3 evictions (last 60s)`. Counters zeroed by [`reset_counters`](Self::reset_counters)
+    /// in between are reported from zero. The task stops once the rate limiter and
+    /// every filter built from it are dropped, or when the returned handle is aborted.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is zero, or when called outside of a tokio runtime.
+    pub fn spawn_stats_logger(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        // Weak, so that the task doesn't keep the limiter alive
+        let config = Arc::downgrade(&self.limiter.config);
+        let counters = Arc::downgrade(&self.limiter.counters);
+        let keys = Arc::downgrade(&self.limiter.keys);
+        let mut previous = (self.limiter.counters.resets(), self.counters());
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        tokio::spawn(async move {
+            loop {
+                ticks.tick().await;
+                let (Some(config), Some(counters), Some(keys)) =
+                    (config.upgrade(), counters.upgrade(), keys.upgrade())
+                else {
+                    return;
+                };
+                let label = config
+                    .read()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .label
+                    .clone();
+                let current = (counters.resets(), counters.snapshot());
+                let since = current.1.since(&previous.1, current.0 != previous.0);
+                trace::stats(label.as_deref(), &since, keys.get(), interval);
+                previous = current;
+            }
+        })
+    }
+
+    /// Zeroes the counters, the ones of every label included, giving back their totals
+    /// since the previous reset, e.g. to report them periodically
+    pub fn reset_counters(&self) -> RateLimitCounters {
+        self.limiter.counters.reset()
+    }
+
+    /// Creates a filter counting requests against this rate limiter
+    pub fn filter(&self) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+        rate_limit_filter(self.limiter.clone())
+    }
+
+    /// Creates a filter counting requests against this rate limiter, like
+    /// [`filter`](Self::filter), but rejecting them once the client used
+    /// `max_requests` in its window, e.g. for a sub-quota of an expensive route
+    ///
+    /// The requests it admits drain the same counter as every other filter of the
+    /// limiter, and the ones of the other filters count towards its cap:
+    ///
+    /// ```rust,no_run,ignore
+    /// let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100))?;
+    /// let search = warp::path("search").and(limiter.filter());
+    /// // At most 10 of the 100 requests a minute on exports
+    /// let export = warp::path("export").and(limiter.filter_with_max_requests(10));
+    /// ```
+    ///
+    /// `max_requests` takes the place of the limit of the config, tiers and key
+    /// overrides included, and is reported as the `limit` of the filter. The window
+    /// stays the one of the config.
+    pub fn filter_with_max_requests(
+        &self,
+        max_requests: u32,
+    ) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+        rate_limit_filter(RateLimiter {
+            max_requests: Some(max_requests),
+            ..self.limiter.clone()
+        })
+    }
+}
+
+/// Usage of a client tracked by a rate limiter, see [`RateLimiterHandle::snapshot`]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct EntrySnapshot {
+    /// Key of the client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Hash of the key when the config redacts it, the same as in the traces
+    pub key_hash: Option<u64>,
+    /// Requests counted in the current window
+    pub used: u32,
+    /// Maximum requests allowed in the window for this client
+    pub limit: u32,
+    /// Unix timestamp of the start of the current window
+    pub window_start: i64,
+    /// Unix timestamp of the reset of the rate limit
+    pub reset_timestamp: i64,
+    /// When the rate limit resets
+    pub reset_at: Timestamp,
+    /// When the ban of the client ends, if it is banned, see
+    /// [`RateLimiterHandle::block_key`]
+    pub banned_until: Option<Timestamp>,
+}
+
+impl EntrySnapshot {
+    fn new(
+        config: &RateLimitConfig,
+        key: &str,
+        limit: &KeyLimit,
+        entry: &Entry,
+        now: Instant,
+    ) -> Self {
+        let reset_at = entry.reset_at();
+        Self {
+            key: config.visible_key(key),
+            key_hash: config.redact_key.then(|| trace::key_hash(key)),
+            used: entry.count,
+            limit: limit.max_requests,
+            window_start: entry.started_at.timestamp(),
+            reset_timestamp: reset_at.timestamp(),
+            reset_at,
+            banned_until: entry.ban_left(now).map(|left| entry.wall(now + left)),
+        }
+    }
+}
+
+/// A ban of a client, see [`RateLimiterHandle::active_bans`]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct BanInfo {
+    /// Key of the client, usually its ip address, unless the config redacts it
+    pub key: Option<String>,
+    /// Hash of the key when the config redacts it, the same as in the traces
+    pub key_hash: Option<u64>,
+    /// Why the client was banned
+    pub reason: BanReason,
+    /// When the client was banned
+    pub imposed_at: Timestamp,
+    /// When the ban ends
+    pub expires_at: Timestamp,
+}
+
+/// Why a client was banned, see [`BanInfo`]
+///
+/// The limiters don't ban clients on their own, e.g. by escalating penalties for
+/// repeated rejections, so every ban is manual for now. More reasons may come with
+/// such bans.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub enum BanReason {
+    /// By [`RateLimiterHandle::block_key`]
+    Manual,
+}
+
+/// Which clients [`RateLimiterHandle::list_keys`] lists, and which page of them
+///
+/// Every condition set must hold. By default, the first 100 clients are listed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KeyFilter {
+    /// Only the clients whose key starts with this prefix, e.g. `"10.0."`
+    pub prefix: Option<String>,
+    /// Only the clients whose next request would be rejected, see
+    /// [`RateLimiterHandle::is_limited`]. The country rules aren't taken into account.
+    pub limited: bool,
+    /// Only the banned clients, see [`RateLimiterHandle::block_key`]
+    pub banned: bool,
+    /// Only the clients whose window ends within this duration
+    pub expiring_within: Option<Duration>,
+    /// Matching clients to skip
+    pub offset: usize,
+    /// Most matching clients to list
+    pub limit: usize,
+}
+
+impl Default for KeyFilter {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            limited: false,
+            banned: false,
+            expiring_within: None,
+            offset: 0,
+            limit: 100,
+        }
+    }
+}
+
+impl KeyFilter {
+    /// Sets [`KeyFilter::prefix`]
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets [`KeyFilter::limited`]
+    pub fn with_limited(mut self, limited: bool) -> Self {
+        self.limited = limited;
+        self
+    }
+
+    /// Sets [`KeyFilter::banned`]
+    pub fn with_banned(mut self, banned: bool) -> Self {
+        self.banned = banned;
+        self
+    }
+
+    /// Sets [`KeyFilter::expiring_within`]
+    pub fn with_expiring_within(mut self, duration: Duration) -> Self {
+        self.expiring_within = Some(duration);
+        self
+    }
+
+    /// Sets [`KeyFilter::offset`] and [`KeyFilter::limit`]
+    pub fn with_page(mut self, offset: usize, limit: usize) -> Self {
+        self.offset = offset;
+        self.limit = limit;
+        self
+    }
+
+    // Whether the client whose current entry is `entry` matches, prefix aside
+    fn matches(&self, limit: &KeyLimit, entry: &Entry, now: Instant) -> bool {
+        let window_left = entry.window.saturating_sub(now.duration_since(entry.start));
+        (!self.limited || !entry.admits(limit, now, 1))
+            && (!self.banned || entry.ban_left(now).is_some())
+            && self
+                .expiring_within
+                .is_none_or(|within| window_left <= within)
+    }
+}
+
+/// A page of the items of a listing, see [`RateLimiterHandle::list_keys`]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct Page<T> {
+    /// Items of the page
+    pub items: Vec<T>,
+    /// Number of items matching, on every page
+    pub total: usize,
+    /// Offset of the next page, `None` on the last one
+    pub next_offset: Option<usize>,
+}
+
+impl std::fmt::Debug for RateLimiterHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiterHandle")
+            .field("config", &self.limiter.config())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Named rate limiters, built once at startup and shared by every route using them
+///
+/// ```rust,no_run,ignore
+/// let registry = RateLimitRegistry::new([
+///     ("public", RateLimitConfig::max_per_minute(60)),
+///     ("partner", RateLimitConfig::max_per_minute(600)),
+/// ])?;
+///
+/// let orders = warp::path("orders").and(registry.filter("partner"));
+/// let invoices = warp::path("invoices").and(registry.filter("partner"));
+/// ```
+///
+/// Cloning is cheap and the clones share the limiters.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitRegistry {
+    limiters: Arc<HashMap<String, RateLimiterHandle>>,
+}
+
+impl RateLimitRegistry {
+    /// Creates a rate limiter for each configuration, which must all be valid and
+    /// have distinct names
+    pub fn new<N: Into<String>>(
+        configs: impl IntoIterator<Item = (N, RateLimitConfig)>,
+    ) -> Result<Self, ConfigError> {
+        let mut limiters = HashMap::new();
+        for (name, config) in configs {
+            let name = name.into();
+            if limiters.contains_key(&name) {
+                return Err(ConfigError::DuplicateLimiter(name));
+            }
+            limiters.insert(name, RateLimiterHandle::new(config)?);
+        }
+        Ok(Self {
+            limiters: Arc::new(limiters),
+        })
+    }
+
+    /// Creates a filter counting requests against the limiter named `name`
+    ///
+    /// # Panics
+    ///
+    /// If no limiter is registered under this name, see [`RateLimitRegistry::try_filter`]
+    /// for a fallible version.
+    pub fn filter(
+        &self,
+        name: &str,
+    ) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+        self.try_filter(name).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates a filter counting requests against the limiter named `name`
+    pub fn try_filter(
+        &self,
+        name: &str,
+    ) -> Result<impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone, ConfigError>
+    {
+        self.get(name)
+            .map(RateLimiterHandle::filter)
+            .ok_or_else(|| ConfigError::UnknownLimiter(name.to_owned()))
+    }
+
+    /// The limiter named `name`
+    pub fn get(&self, name: &str) -> Option<&RateLimiterHandle> {
+        self.limiters.get(name)
+    }
+
+    /// Every registered limiter with its name, in no particular order
+    pub fn limiters(&self) -> impl Iterator<Item = (&str, &RateLimiterHandle)> {
+        self.limiters
+            .iter()
+            .map(|(name, limiter)| (name.as_str(), limiter))
+    }
+}
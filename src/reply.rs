@@ -0,0 +1,87 @@
+use crate::{
+    add_rate_limit_headers, get_rate_limit_info, trace, RateLimitInfo, RateLimitRecover,
+    RateLimitRejection,
+};
+use warp::reply::{Reply, Response};
+
+/// A reply that gets the rate limit headers added when converted into a response
+///
+/// Created by [`with_rate_limit_headers`].
+pub struct WithRateLimitHeaders<R> {
+    reply: R,
+    info: RateLimitInfo,
+}
+
+/// Wraps a reply so that the rate limit headers described by `info` are added to it
+///
+/// The status and existing headers of the inner reply are preserved. Failing to
+/// build a header value is only logged, as it cannot happen with the values this
+/// crate produces.
+///
+/// ```rust,no_run,ignore
+/// async fn handler(info: RateLimitInfo) -> Result<impl Reply, Rejection> {
+///     let json = warp::reply::json(&"Hello world");
+///     Ok(with_rate_limit_headers(json, &info))
+/// }
+/// ```
+pub fn with_rate_limit_headers<R: Reply>(
+    reply: R,
+    info: &RateLimitInfo,
+) -> WithRateLimitHeaders<R> {
+    WithRateLimitHeaders {
+        reply,
+        info: info.clone(),
+    }
+}
+
+impl<R: Reply> Reply for WithRateLimitHeaders<R> {
+    fn into_response(self) -> Response {
+        let mut response = self.reply.into_response();
+        if let Err(e) = add_rate_limit_headers(response.headers_mut(), &self.info) {
+            trace::header_failure(&e);
+        }
+        response
+    }
+}
+
+/// A rate limited response, as produced by [`RateLimitRecover`], usable as a reply
+///
+/// Useful when a handler detects the limit itself and wants to answer with the
+/// standard response without going through a rejection.
+///
+/// ```rust,no_run,ignore
+/// return Ok(TooManyRequests::from_info(info).into_response());
+/// ```
+#[derive(Clone, Debug)]
+pub struct TooManyRequests {
+    info: RateLimitInfo,
+    recover: RateLimitRecover,
+}
+
+impl TooManyRequests {
+    /// Creates the response for `info`, with the default status and body
+    pub fn from_info(info: RateLimitInfo) -> Self {
+        Self {
+            info,
+            recover: RateLimitRecover::default(),
+        }
+    }
+
+    /// Uses the status, body and headers configured on `recover`
+    pub fn with_recover(mut self, recover: RateLimitRecover) -> Self {
+        self.recover = recover;
+        self
+    }
+}
+
+impl From<&RateLimitRejection> for TooManyRequests {
+    fn from(rejection: &RateLimitRejection) -> Self {
+        Self::from_info(get_rate_limit_info(rejection))
+    }
+}
+
+impl Reply for TooManyRequests {
+    fn into_response(self) -> Response {
+        self.recover.response_from_info(&self.info)
+    }
+}
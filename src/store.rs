@@ -0,0 +1,213 @@
+//! Where the rate limiters keep the requests of each client, see [`RateLimitStore`]
+//!
+//! The limiters of the crate count in memory, with the counting step of
+//! [`MemoryStore`], or in the store of [`RateLimitConfig::store`]. A store kept
+//! elsewhere, e.g. in Redis to share the limits between instances, only has to provide
+//! that step, atomically:
+//!
+//! ```rust,no_run,ignore
+//! impl RateLimitStore for RedisStore {
+//!     fn incr_within_window<'a>(
+//!         &'a self,
+//!         key: &'a str,
+//!         cost: u32,
+//!         max_requests: u32,
+//!         window: Duration,
+//!     ) -> StoreFuture<'a> {
+//!         // One script, run by Redis as a whole
+//!         Box::pin(async move { self.script.run(key, cost, max_requests, window).await })
+//!     }
+//! }
+//! ```
+//!
+//! and pass [`conformance::concurrent_admissions`].
+//!
+//! [`RateLimitConfig::store`]: crate::RateLimitConfig::store
+
+use crate::{
+    core::{KeyLimit, RateLimiterMap},
+    time::{AnchoredClock, SystemClock},
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// The failure of a store, e.g. a lost connection to the database behind it
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The future of [`RateLimitStore::incr_within_window`]
+pub type StoreFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<WindowCount, StoreError>> + Send + 'a>>;
+
+/// Keeps the number of requests of each client in its current window
+///
+/// There is no way to read a count and write it back: a request is counted with
+/// [`incr_within_window`](Self::incr_within_window), checking the limit and
+/// incrementing the count in a single atomic step. Two steps would over-admit as soon
+/// as requests of a client race, both reading the same count and both fitting in the
+/// last slot. The process-wide lock of the in-memory state hides the race, a store
+/// shared over the network doesn't, so backends run the whole step on their side, e.g.
+/// as a Lua script in Redis or a conditional `UPDATE` in SQL.
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request of the client `key` consuming `cost` units if its count stays
+    /// within `max_requests` in its window of length `window`, starting a new window if
+    /// the previous one is over. A request that doesn't fit isn't counted.
+    fn incr_within_window<'a>(
+        &'a self,
+        key: &'a str,
+        cost: u32,
+        max_requests: u32,
+        window: Duration,
+    ) -> StoreFuture<'a>;
+}
+
+/// The count of a client after [`RateLimitStore::incr_within_window`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WindowCount {
+    /// Whether the request fit under the limit, and was counted
+    pub admitted: bool,
+    /// Units counted in the window, the request included if it was admitted
+    pub count: u32,
+    /// Time until the window ends
+    pub resets_in: Duration,
+}
+
+impl WindowCount {
+    /// The count of a client whose request was admitted, and counted in `count`
+    pub fn admitted(count: u32, resets_in: Duration) -> Self {
+        Self {
+            admitted: true,
+            count,
+            resets_in,
+        }
+    }
+
+    /// The count of a client whose request didn't fit, and wasn't counted
+    pub fn rejected(count: u32, resets_in: Duration) -> Self {
+        Self {
+            admitted: false,
+            count,
+            resets_in,
+        }
+    }
+}
+
+/// The in-memory store, counting like the limiters of the crate do
+///
+/// Clients whose window is over are forgotten as the requests of others come in.
+/// Cloning is cheap and the clones share the counts.
+#[derive(Clone)]
+pub struct MemoryStore {
+    state: Arc<Mutex<RateLimiterMap>>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RateLimiterMap::new(
+                Arc::default(),
+                AnchoredClock::new(Arc::new(SystemClock)),
+            ))),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemoryStore").finish_non_exhaustive()
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn incr_within_window<'a>(
+        &'a self,
+        key: &'a str,
+        cost: u32,
+        max_requests: u32,
+        window: Duration,
+    ) -> StoreFuture<'a> {
+        // Entries are replaced as a whole, so a panic can't leave a broken one behind
+        let mut map = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = map.clock.now();
+        map.prune(window, now);
+        let limit = KeyLimit {
+            max_requests,
+            window,
+            tier: None,
+        };
+        let count = match map.incr_within_window(key, &limit, now, cost) {
+            Ok(entry) => WindowCount::admitted(entry.count, entry.quota(&limit, now).resets_in),
+            Err(entry) => WindowCount::rejected(entry.count, entry.quota(&limit, now).resets_in),
+        };
+        Box::pin(std::future::ready(Ok(count)))
+    }
+}
+
+/// Checks every [`RateLimitStore`] must pass, whatever keeps the counts
+pub mod conformance {
+    use super::RateLimitStore;
+    use std::{sync::Arc, time::Duration};
+
+    /// Counts a request of a single client from each of 64 concurrent tasks against a
+    /// limit of 10, and checks exactly 10 are admitted, each seeing a count of its own
+    ///
+    /// Run it on a multi-threaded runtime, against a store that was never used with
+    /// the key `"conformance"`:
+    ///
+    /// ```rust,no_run,ignore
+    /// #[tokio::test(flavor = "multi_thread")]
+    /// async fn test_redis_store_conformance() {
+    ///     conformance::concurrent_admissions(Arc::new(RedisStore::connect(URL).await?)).await;
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the store fails, over-admits, or admits fewer requests than the limit.
+    pub async fn concurrent_admissions<S: RateLimitStore + ?Sized + 'static>(store: Arc<S>) {
+        const TASKS: u32 = 64;
+        const MAX_REQUESTS: u32 = 10;
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..TASKS {
+            let store = store.clone();
+            tasks.spawn(async move {
+                let window = Duration::from_secs(60);
+                store
+                    .incr_within_window("conformance", 1, MAX_REQUESTS, window)
+                    .await
+                    .unwrap_or_else(|e| panic!("the store failed: {}", e))
+            });
+        }
+        let mut admitted_counts = Vec::new();
+        while let Some(count) = tasks.join_next().await {
+            let count = count.expect("a task counting a request panicked");
+            match count.admitted {
+                true => admitted_counts.push(count.count),
+                false => assert_eq!(
+                    count.count, MAX_REQUESTS,
+                    "a request was rejected before the limit was reached"
+                ),
+            }
+        }
+        admitted_counts.sort_unstable();
+        assert_eq!(
+            admitted_counts,
+            (1..=MAX_REQUESTS).collect::<Vec<_>>(),
+            "{} requests admitted, each should see a count of its own, out of {} allowed",
+            admitted_counts.len(),
+            MAX_REQUESTS
+        );
+    }
+}
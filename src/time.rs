@@ -0,0 +1,309 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A point in wall-clock time, such as the moment a rate limit resets
+///
+/// This is `chrono::DateTime<Utc>` with the default `chrono` feature. Without it,
+/// a lightweight type backed by [`SystemTime`] offering the same methods used by
+/// this crate. Both convert to and from `SystemTime`.
+#[cfg(feature = "chrono")]
+pub type Timestamp = chrono::DateTime<chrono::Utc>;
+
+/// A point in wall-clock time, such as the moment a rate limit resets
+///
+/// This is `chrono::DateTime<Utc>` with the default `chrono` feature. Without it,
+/// a lightweight type backed by [`SystemTime`] offering the same methods used by
+/// this crate. Both convert to and from `SystemTime`.
+#[cfg(not(feature = "chrono"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(SystemTime);
+
+#[cfg(not(feature = "chrono"))]
+impl Timestamp {
+    /// The current time
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+
+    /// Creates a timestamp from a Unix timestamp and nanoseconds, like
+    /// `chrono::DateTime::from_timestamp`
+    pub fn from_timestamp(secs: i64, nsecs: u32) -> Option<Self> {
+        if nsecs >= 1_000_000_000 {
+            return None;
+        }
+        let time = if secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+        };
+        time.and_then(|time| time.checked_add(Duration::from_nanos(nsecs.into())))
+            .map(Self)
+    }
+
+    /// Number of whole seconds since the Unix epoch, rounded down
+    pub fn timestamp(&self) -> i64 {
+        unix_parts(self.0).0
+    }
+
+    /// Nanoseconds past the last whole second
+    pub fn timestamp_subsec_nanos(&self) -> u32 {
+        unix_parts(self.0).1
+    }
+}
+
+#[cfg(not(feature = "chrono"))]
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+
+#[cfg(not(feature = "chrono"))]
+impl From<Timestamp> for SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+#[cfg(not(feature = "chrono"))]
+impl std::ops::Add<Duration> for Timestamp {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        Self(self.0 + duration)
+    }
+}
+
+#[cfg(not(feature = "chrono"))]
+impl std::ops::Sub<Duration> for Timestamp {
+    type Output = Self;
+
+    fn sub(self, duration: Duration) -> Self {
+        Self(self.0 - duration)
+    }
+}
+
+// RFC 3339, with the same fractional digits as chrono so both modes serialize alike
+#[cfg(not(feature = "chrono"))]
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (secs, nanos) = unix_parts(self.0);
+        let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60
+        )?;
+        match nanos {
+            0 => {}
+            _ if nanos.is_multiple_of(1_000_000) => write!(f, ".{:03}", nanos / 1_000_000)?,
+            _ if nanos.is_multiple_of(1_000) => write!(f, ".{:06}", nanos / 1_000)?,
+            _ => write!(f, ".{nanos:09}")?,
+        }
+        f.write_str("Z")
+    }
+}
+
+#[cfg(all(feature = "serde", not(feature = "chrono")))]
+impl serde::Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Seconds since the epoch, rounded down, and the nanoseconds past them
+#[cfg(not(feature = "chrono"))]
+fn unix_parts(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            let secs = -(before.as_secs() as i64);
+            match before.subsec_nanos() {
+                0 => (secs, 0),
+                nanos => (secs - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+// Year, month and day of the given number of days since 1970-01-01, from
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+#[cfg(not(feature = "chrono"))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Source of the time of a rate limiter, see
+/// [`RateLimitConfig::with_clock`](crate::RateLimitConfig::with_clock)
+///
+/// The windows are tracked with its monotonic time. Its wall-clock time is read once,
+/// when the limiter is created, and the times told to clients are derived from it and
+/// the monotonic time, so that they always agree with the windows.
+pub trait Clock: Send + Sync {
+    /// The current monotonic time
+    fn now_monotonic(&self) -> Instant;
+
+    /// The current wall-clock time
+    fn now_wall(&self) -> Timestamp;
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now_monotonic(&self) -> Instant {
+        (**self).now_monotonic()
+    }
+
+    fn now_wall(&self) -> Timestamp {
+        (**self).now_wall()
+    }
+}
+
+/// The clock of the system, used unless the config sets another one
+///
+/// Its monotonic time is tokio's, so that in the tests pausing the time with
+/// `tokio::time::pause()`, `tokio::time::advance(window)` lets the next request see a
+/// fresh window. Outside of them, with tokio's `test-util` feature off, it is the
+/// time of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_monotonic(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn now_wall(&self) -> Timestamp {
+        SystemTime::now().into()
+    }
+}
+
+/// A clock standing still until advanced, to test window expiry, bans or pruning
+/// without sleeping
+///
+/// ```rust,no_run,ignore
+/// let clock = Arc::new(ManualClock::new());
+/// let limiter = RateLimiterHandle::new(
+///     RateLimitConfig::max_per_minute(1).with_clock(clock.clone()),
+/// )?;
+/// limiter.check("10.0.0.1").await?;
+/// clock.advance(Duration::from_secs(60));
+/// limiter.check("10.0.0.1").await?;
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_at: Timestamp,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Creates a clock standing at the current time
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_at: SystemTime::now().into(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_monotonic(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn now_wall(&self) -> Timestamp {
+        self.start_at + self.elapsed()
+    }
+}
+
+// The clock of a limiter, with the wall-clock time of one of its monotonic readings.
+// Windows are tracked with its monotonic readings, and the wall-clock times told to
+// clients are derived from them through this single anchor, so that they agree with
+// each other and with the windows, even once the system clock is stepped, e.g. by NTP.
+#[derive(Clone)]
+pub(crate) struct AnchoredClock {
+    clock: Arc<dyn Clock>,
+    anchor: Instant,
+    anchor_at: Timestamp,
+}
+
+impl AnchoredClock {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            anchor: clock.now_monotonic(),
+            anchor_at: clock.now_wall(),
+            clock,
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now_monotonic()
+    }
+
+    // The wall-clock time of the reading `at`
+    pub(crate) fn wall(&self, at: Instant) -> Timestamp {
+        match at.checked_duration_since(self.anchor) {
+            Some(since) => self.anchor_at + since,
+            None => self.anchor_at - (self.anchor - at),
+        }
+    }
+}
+
+// Time from `from` until `to`, zero if `to` comes first
+pub(crate) fn between(from: Timestamp, to: Timestamp) -> Duration {
+    SystemTime::from(to)
+        .duration_since(SystemTime::from(from))
+        .unwrap_or_default()
+}
+
+// `at + duration`, if representable
+pub(crate) fn checked_add(at: Timestamp, duration: Duration) -> Option<Timestamp> {
+    #[cfg(feature = "chrono")]
+    return chrono::Duration::from_std(duration)
+        .ok()
+        .and_then(|duration| at.checked_add_signed(duration));
+    #[cfg(not(feature = "chrono"))]
+    return at.0.checked_add(duration).map(Timestamp);
+}
+
+// IMF-fixdate as required for Retry-After, rounded up to the next whole second
+pub(crate) fn http_date(at: Timestamp) -> String {
+    let at = SystemTime::from(at);
+    let at = match at.duration_since(UNIX_EPOCH) {
+        Ok(since) if since.subsec_nanos() > 0 => {
+            at + Duration::from_nanos(u64::from(1_000_000_000 - since.subsec_nanos()))
+        }
+        _ => at,
+    };
+    httpdate::fmt_http_date(at)
+}
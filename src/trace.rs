@@ -0,0 +1,301 @@
+// Diagnostics of the limiters, emitted with the `tracing` feature, and as plain
+// records with the `log` feature, under the `warp_rate_limit` target. With the `otel`
+// feature, the outcome of each request is also recorded on the active OpenTelemetry
+// span. The span, field and attribute names are part of the public API, see the README.
+
+use crate::{
+    RateLimitConfig, RateLimitCounters, RateLimitError, RateLimitInfo, RateLimitRejection,
+};
+use std::{future::Future, time::Duration};
+
+// Runs `future`, counting a request of the client `key`, in a `rate_limit` span
+// recording the key, or only its hash if the config redacts it, and the label
+pub(crate) async fn in_span<F: Future>(
+    config: &RateLimitConfig,
+    key: &str,
+    future: F,
+) -> F::Output {
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::{field, Instrument as _};
+
+        let span = tracing::debug_span!(
+            target: "warp_rate_limit",
+            "rate_limit",
+            key = field::Empty,
+            key_hash = field::Empty,
+            label = config.label.as_deref(),
+        );
+        if config.redact_key {
+            span.record("key_hash", key_hash(key));
+        } else {
+            span.record("key", key);
+        }
+        future.instrument(span).await
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (config, key);
+        future.await
+    }
+}
+
+// Tells apart redacted keys without revealing them, also used by the snapshots
+pub(crate) fn key_hash(key: &str) -> u64 {
+    use std::hash::{Hash as _, Hasher as _};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn admitted(info: &RateLimitInfo, key_tag: Option<&str>) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        target: "warp_rate_limit",
+        label = info.label.as_deref(),
+        key_tag,
+        used = info.used,
+        limit = info.limit,
+        remaining = info.remaining,
+        "request admitted"
+    );
+    #[cfg(feature = "otel")]
+    otel_outcome(
+        false,
+        info.label.as_deref(),
+        info.limit,
+        info.remaining,
+        info.reset_timestamp,
+    );
+    #[cfg(not(any(feature = "tracing", feature = "otel")))]
+    let _ = info;
+    #[cfg(not(feature = "tracing"))]
+    let _ = key_tag;
+}
+
+// Only the `first` rejection of a client's window is logged, so that a client
+// hammering the limit logs once per window rather than once per request
+pub(crate) fn rejected(rejection: &RateLimitRejection, key_tag: Option<&str>, first: bool) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "warp_rate_limit",
+        label = rejection.label.as_deref(),
+        key_tag,
+        used = rejection.used,
+        limit = rejection.limit,
+        retry_after_secs = rejection.retry_after.as_secs_f64(),
+        "request rejected"
+    );
+    #[cfg(feature = "otel")]
+    otel_outcome(
+        true,
+        rejection.label.as_deref(),
+        rejection.limit,
+        0,
+        rejection.reset_time.timestamp(),
+    );
+    #[cfg(feature = "log")]
+    if first {
+        log::debug!(
+            target: "warp_rate_limit",
+            "{}Rate limited {} ({}/{} requests), retry after {:.3}s",
+            LogLabel(rejection.label.as_deref()),
+            rejection.key.as_deref().unwrap_or("<redacted>"),
+            rejection.used,
+            rejection.limit,
+            rejection.retry_after.as_secs_f64(),
+        );
+    }
+    #[cfg(not(feature = "log"))]
+    let _ = (rejection, first);
+    #[cfg(not(feature = "tracing"))]
+    let _ = key_tag;
+}
+
+// Records the outcome of a request on the active OpenTelemetry span, marking it as
+// failed when the request is rejected
+#[cfg(feature = "otel")]
+fn otel_outcome(rejected: bool, label: Option<&str>, limit: u32, remaining: u32, reset: i64) {
+    use opentelemetry::{
+        trace::{get_active_span, Status},
+        KeyValue,
+    };
+
+    get_active_span(|span| {
+        let outcome = if rejected { "rejected" } else { "allowed" };
+        span.set_attribute(KeyValue::new("ratelimit.outcome", outcome));
+        span.set_attribute(KeyValue::new("ratelimit.limit", i64::from(limit)));
+        span.set_attribute(KeyValue::new("ratelimit.remaining", i64::from(remaining)));
+        span.set_attribute(KeyValue::new("ratelimit.reset", reset));
+        if let Some(label) = label {
+            span.set_attribute(KeyValue::new("ratelimit.label", label.to_owned()));
+        }
+        if rejected {
+            span.set_status(Status::error("rate limited"));
+        }
+    });
+}
+
+// A request of a client that couldn't be identified landed in the "unknown" bucket
+pub(crate) fn unknown_client(label: Option<&str>) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "warp_rate_limit",
+        label,
+        "unknown client"
+    );
+    #[cfg(feature = "log")]
+    log::debug!(
+        target: "warp_rate_limit",
+        "{}Counted a request in the unknown bucket",
+        LogLabel(label)
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = label;
+}
+
+// A client whose previous window is over starts a new one
+pub(crate) fn window_reset(window: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        target: "warp_rate_limit",
+        window_secs = window.as_secs_f64(),
+        "window reset"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = window;
+}
+
+// Idle clients of the limiter labelled `label` were pruned, `tracked` remain
+pub(crate) fn evicted(label: Option<&str>, evicted: usize, tracked: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "warp_rate_limit",
+        label,
+        evicted,
+        tracked,
+        "idle clients evicted"
+    );
+    #[cfg(feature = "log")]
+    log::warn!(
+        target: "warp_rate_limit",
+        "{}Evicted {} idle clients, {} tracked",
+        LogLabel(label),
+        evicted,
+        tracked
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = (label, evicted, tracked);
+}
+
+// Every client of the limiter labelled `label` was forgotten, `cleared` of them
+pub(crate) fn cleared(label: Option<&str>, cleared: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        target: "warp_rate_limit",
+        label,
+        cleared,
+        "state cleared"
+    );
+    #[cfg(feature = "log")]
+    log::warn!(
+        target: "warp_rate_limit",
+        "{}Cleared the state of {} clients",
+        LogLabel(label),
+        cleared
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = (label, cleared);
+}
+
+// The requests seen by the limiter labelled `label` during the last `interval`, and the
+// clients it tracks, see `RateLimiterHandle::spawn_stats_logger`
+pub(crate) fn stats(
+    label: Option<&str>,
+    counters: &RateLimitCounters,
+    tracked: usize,
+    interval: Duration,
+) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        target: "warp_rate_limit",
+        label,
+        allowed = counters.allowed,
+        rejected = counters.rejected,
+        evicted = counters.evicted,
+        tracked,
+        interval_secs = interval.as_secs_f64(),
+        "rate limiter stats"
+    );
+    #[cfg(feature = "log")]
+    log::info!(
+        target: "warp_rate_limit",
+        "rate-limiter{}: {} allowed, {} rejected, {} keys, {} evictions (last {:?})",
+        label.map(|label| format!("[{}]", label)).unwrap_or_default(),
+        counters.allowed,
+        counters.rejected,
+        tracked,
+        counters.evicted,
+        interval
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = (label, counters, tracked, interval);
+}
+
+// Prefixes the log records of a labelled limiter with `[label] `
+#[cfg(feature = "log")]
+struct LogLabel<'a>(Option<&'a str>);
+
+#[cfg(feature = "log")]
+impl std::fmt::Display for LogLabel<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(label) => write!(f, "[{}] ", label),
+            None => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn header_failure(error: &RateLimitError) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        target: "warp_rate_limit",
+        error = %error,
+        "Failed to set rate limit headers"
+    );
+    #[cfg(feature = "log")]
+    log::warn!(
+        target: "warp_rate_limit",
+        "Failed to set rate limit headers: {}",
+        error
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = error;
+}
+
+pub(crate) fn store_failure(error: &(dyn std::error::Error + Send + Sync)) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        target: "warp_rate_limit",
+        error = %error,
+        "Failed to count a request in the store, letting it through"
+    );
+    #[cfg(feature = "log")]
+    log::warn!(
+        target: "warp_rate_limit",
+        "Failed to count a request in the store, letting it through: {}",
+        error
+    );
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = error;
+}
+
+pub(crate) fn callback_panic(callback: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(target: "warp_rate_limit", callback, "Event callback panicked");
+    #[cfg(feature = "log")]
+    log::warn!(target: "warp_rate_limit", "Event callback {} panicked", callback);
+    #[cfg(not(any(feature = "tracing", feature = "log")))]
+    let _ = callback;
+}
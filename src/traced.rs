@@ -0,0 +1,90 @@
+use crate::{client_key, trace, ConfigError, RateLimitConfig, RateLimitInfo, RateLimiter};
+use tracing::{field::Empty, Span};
+use warp::{
+    trace::{Info, Trace},
+    Filter, Rejection,
+};
+
+/// Instruments every request with a `request` span like `warp::trace::request()`, also
+/// declaring the fields recorded by [`RateLimitConfig::traced`]
+///
+/// The span has warp's `method`, `path`, `version` and `referer` fields, plus
+/// `ratelimit.outcome`, `ratelimit.remaining` and `ratelimit.key_hash`, so that the
+/// outcome of the limiter shows up alongside the request it belongs to. warp 0.4
+/// doesn't tell the remote address of the requests, so there is no `remote.addr`.
+///
+/// ```rust,no_run,ignore
+/// let route = warp::path!("hello")
+///     .and(RateLimitConfig::default().traced())
+///     .map(|_| "Hello world")
+///     .with(trace_request());
+/// ```
+pub fn trace_request() -> Trace<impl Fn(Info<'_>) -> Span + Clone> {
+    warp::trace(|info: Info<'_>| {
+        let span = tracing::info_span!(
+            target: "warp_rate_limit",
+            "request",
+            method = %info.method(),
+            path = %info.path(),
+            version = ?info.version(),
+            referer = Empty,
+            ratelimit.outcome = Empty,
+            ratelimit.remaining = Empty,
+            ratelimit.key_hash = Empty,
+        );
+        if let Some(referer) = info.referer() {
+            span.record("referer", tracing::field::display(referer));
+        }
+        span
+    })
+}
+
+impl RateLimitConfig {
+    /// Creates a rate limiting filter like [`crate::with_rate_limit`], recording the
+    /// outcome of each request on the current span, such as the one of
+    /// [`trace_request`]
+    ///
+    /// The `ratelimit.outcome` (`"allowed"` or `"rejected"`), `ratelimit.remaining` and
+    /// `ratelimit.key_hash` fields are recorded, the key being hashed whether the config
+    /// redacts it or not. Spans not declaring these fields are left untouched.
+    ///
+    /// # Panics
+    ///
+    /// If the configuration is invalid, see [`try_traced`](Self::try_traced) for a
+    /// fallible version.
+    pub fn traced(self) -> impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone {
+        self.try_traced()
+            .unwrap_or_else(|e| panic!("Invalid rate limit configuration: {}", e))
+    }
+
+    /// Creates a rate limiting filter recording the outcome of each request on the
+    /// current span, like [`traced`](Self::traced), after checking the configuration
+    /// with [`RateLimitConfig::validate`]
+    pub fn try_traced(
+        self,
+    ) -> Result<impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + Clone, ConfigError>
+    {
+        self.validate()?;
+        let key = client_key(&self);
+        let limiter = RateLimiter::new(self);
+        Ok(key.and_then(move |key: String| {
+            let limiter = limiter.clone();
+            async move {
+                let result = limiter.check_rate_limit(&key).await;
+                let span = Span::current();
+                span.record("ratelimit.key_hash", trace::key_hash(&key));
+                match &result {
+                    Ok(info) => {
+                        span.record("ratelimit.outcome", "allowed");
+                        span.record("ratelimit.remaining", info.remaining);
+                    }
+                    Err(_) => {
+                        span.record("ratelimit.outcome", "rejected");
+                        span.record("ratelimit.remaining", 0);
+                    }
+                }
+                result
+            }
+        }))
+    }
+}
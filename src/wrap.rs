@@ -0,0 +1,32 @@
+use crate::{with_rate_limit, with_rate_limit_headers, RateLimitConfig, RateLimitInfo};
+use warp::{filters::BoxedFilter, reply::Response, Filter, Rejection, Reply};
+
+/// Creates a wrapper that rate limits a whole route and adds the rate limit headers
+/// to every response it produces, without changing the handler signatures
+///
+/// The returned function is meant to be passed to `warp::wrap_fn`. Rejections,
+/// including `RateLimitRejection`, pass through untouched so existing recover
+/// handlers keep working.
+///
+/// ```rust,no_run,ignore
+/// let route = warp::path!("hello")
+///     .map(|| "Hello world")
+///     .with(warp::wrap_fn(rate_limit_headers(RateLimitConfig::default())))
+///     .recover(handle_rejection);
+/// ```
+pub fn rate_limit_headers<F, R>(
+    config: RateLimitConfig,
+) -> impl Fn(F) -> BoxedFilter<(Response,)> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    move |filter: F| {
+        with_rate_limit(config.clone())
+            .and(filter)
+            .map(|info: RateLimitInfo, reply: R| {
+                with_rate_limit_headers(reply, &info).into_response()
+            })
+            .boxed()
+    }
+}
@@ -0,0 +1,142 @@
+#![cfg(feature = "serde")]
+use serde_json::{json, Value};
+use std::time::Duration;
+use warp::{http::StatusCode, test::request, Filter};
+use warp_rate_limit::*;
+
+fn limiter() -> RateLimiterHandle {
+    RateLimiterHandle::new(RateLimitConfig::max_per_minute(3).with_label("api")).unwrap()
+}
+
+fn body(response: &warp::http::Response<impl AsRef<[u8]>>) -> Value {
+    serde_json::from_slice(response.body().as_ref()).unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_keys() {
+    let limiter = limiter();
+    let routes = admin::routes(limiter.clone());
+    for ip in ["10.0.0.3", "10.0.0.1", "10.0.0.2", "10.0.0.1"] {
+        limiter.check(ip).await.unwrap();
+    }
+
+    let response = request().path("/ratelimit/keys").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let keys = body(&response);
+    let listed: Vec<_> = keys.as_array().unwrap().iter().map(|k| &k["key"]).collect();
+    assert_eq!(listed, ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    assert_eq!(keys[0]["used"], 2);
+    assert_eq!(keys[0]["limit"], 3);
+
+    let response = request()
+        .path("/ratelimit/keys?offset=1&limit=1")
+        .reply(&routes)
+        .await;
+    let keys = body(&response);
+    assert_eq!(keys.as_array().unwrap().len(), 1);
+    assert_eq!(keys[0]["key"], "10.0.0.2");
+}
+
+#[tokio::test]
+async fn test_admin_key_usage_and_reset() {
+    let limiter = limiter();
+    let routes = admin::routes(limiter.clone());
+    limiter.check("10.0.0.1").await.unwrap();
+
+    let response = request()
+        .path("/ratelimit/keys/10.0.0.1")
+        .reply(&routes)
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let usage = body(&response);
+    assert_eq!(
+        (usage["used"].clone(), usage["remaining"].clone()),
+        (json!(1), json!(2))
+    );
+    assert_eq!(usage["key"], "10.0.0.1");
+
+    let unknown = request()
+        .path("/ratelimit/keys/10.0.0.2")
+        .reply(&routes)
+        .await;
+    assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+
+    let reset = || request().method("DELETE").path("/ratelimit/keys/10.0.0.1");
+    assert_eq!(
+        reset().reply(&routes).await.status(),
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(reset().reply(&routes).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(limiter.key_count(), 0);
+}
+
+#[tokio::test]
+async fn test_admin_ban() {
+    let limiter = limiter();
+    let routes = admin::routes(limiter.clone());
+    let ban = |body: &str| {
+        request()
+            .method("POST")
+            .path("/ratelimit/keys/10.0.0.1/ban")
+            .body(body)
+    };
+
+    let response = ban(r#"{"duration": "1h"}"#).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert!(rejection.retry_after > Duration::from_secs(3599));
+
+    let response = ban(r#"{"duration": 60}"#).reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert!(rejection.retry_after <= Duration::from_secs(60));
+
+    for invalid in [r#"{"duration": "soon"}"#, r#"{}"#, "60"] {
+        let response = ban(invalid).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn test_admin_stats() {
+    let limiter = limiter();
+    let routes = admin::routes(limiter.clone());
+    for _ in 0..4 {
+        let _ = limiter.check("10.0.0.1").await;
+    }
+    limiter.check("10.0.0.2").await.unwrap();
+
+    let response = request().path("/ratelimit/stats").reply(&routes).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let stats = body(&response);
+    assert_eq!(stats["key_count"], 2);
+    assert_eq!(stats["counters"]["allowed"], 4);
+    assert_eq!(stats["counters"]["rejected"], 1);
+    assert_eq!(stats["labels"]["api"]["allowed"], 4);
+}
+
+#[tokio::test]
+async fn test_admin_unmatched_requests_fall_through() {
+    let routes = admin::routes(limiter()).or(warp::any().map(|| "app"));
+    for (method, path) in [
+        ("GET", "/ratelimit"),
+        ("PUT", "/ratelimit/keys/10.0.0.1"),
+        ("GET", "/ratelimit/keys/10.0.0.1/ban"),
+        ("GET", "/orders"),
+    ] {
+        let response = request().method(method).path(path).reply(&routes).await;
+        assert_eq!(response.body(), "app", "{} {}", method, path);
+    }
+
+    // Behind an authentication filter
+    let routes = warp::header::exact("authorization", "Bearer s3cr3t")
+        .and(admin::routes(limiter()))
+        .recover(|_| async { Ok::<_, std::convert::Infallible>(StatusCode::UNAUTHORIZED) });
+    let stats = || request().path("/ratelimit/stats");
+    assert_eq!(
+        stats().reply(&routes).await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let authorized = stats().header("authorization", "Bearer s3cr3t");
+    assert_eq!(authorized.reply(&routes).await.status(), StatusCode::OK);
+}
@@ -0,0 +1,80 @@
+use std::{sync::Arc, time::Duration};
+use warp_rate_limit::{
+    core::{CoreLimiter, Exceeded},
+    ConfigError, ManualClock, RateLimitConfig,
+};
+
+#[test]
+fn test_core_check() {
+    let limiter = CoreLimiter::new(RateLimitConfig::max_per_minute(2)).unwrap();
+    let first = limiter.check("job-a").unwrap();
+    assert_eq!((first.used, first.remaining, first.limit), (1, 1, 2));
+    assert_eq!(first.window, Duration::from_secs(60));
+    let second = limiter.check("job-a").unwrap();
+    assert_eq!((second.used, second.remaining), (2, 0));
+    assert_eq!(second.window_start, first.window_start);
+    assert!(second.resets_in <= Duration::from_secs(60));
+
+    let Exceeded {
+        limit,
+        used,
+        retry_after,
+        banned,
+        ..
+    } = limiter.check("job-a").unwrap_err();
+    assert_eq!((limit, used, banned), (2, 2, false));
+    assert!(retry_after > Duration::from_secs(59));
+    // Rejections aren't counted, and other clients have their own window
+    assert_eq!(limiter.check("job-a").unwrap_err().used, 2);
+    assert_eq!(limiter.check("job-b").unwrap().used, 1);
+    assert_eq!(limiter.key_count(), 2);
+}
+
+#[test]
+fn test_core_window_rollover() {
+    let clock = Arc::new(ManualClock::new());
+    let limiter =
+        CoreLimiter::new(RateLimitConfig::max_per_minute(1).with_clock(clock.clone())).unwrap();
+    let first = limiter.check("socket-1").unwrap();
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(
+        limiter.check("socket-1").unwrap_err().retry_after,
+        Duration::from_secs(1)
+    );
+    clock.advance(Duration::from_secs(2));
+
+    let next = limiter.check("socket-1").unwrap();
+    assert_eq!(next.used, 1);
+    assert_eq!(
+        next.window_start,
+        first.window_start + Duration::from_secs(61)
+    );
+}
+
+#[test]
+fn test_core_overrides_reset_and_clones() {
+    let limiter = CoreLimiter::new(RateLimitConfig::max_per_minute(1).with_key_override(
+        "batch",
+        3,
+        Duration::from_secs(60),
+    ))
+    .unwrap();
+    let shared = limiter.clone();
+    for _ in 0..3 {
+        limiter.check("batch").unwrap();
+    }
+    assert_eq!(shared.check("batch").unwrap_err().limit, 3);
+
+    assert!(shared.reset("batch"));
+    assert!(!shared.reset("batch"));
+    assert_eq!(limiter.check("batch").unwrap().used, 1);
+}
+
+#[test]
+fn test_core_invalid_config() {
+    let config = RateLimitConfig::default().with_window(Duration::ZERO);
+    assert_eq!(
+        CoreLimiter::new(config).unwrap_err(),
+        ConfigError::ZeroWindow
+    );
+}
@@ -0,0 +1,335 @@
+#![cfg(feature = "geoip")]
+use std::{sync::Arc, time::Duration};
+use warp::{http::StatusCode, test::request, Filter};
+use warp_rate_limit::*;
+
+// A minimal IPv4 Country database locating each `/8` network in the given country
+fn country_db(networks: &[(u8, &str)]) -> GeoIpReader {
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = vec![0x40 | text.len() as u8];
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        NotFound,
+        Node(usize),
+        Data(usize),
+    }
+
+    // Search tree, following the first 8 bits of the address
+    let mut nodes = vec![[Record::NotFound; 2]];
+    let mut data = Vec::new();
+    for &(first_octet, code) in networks {
+        let mut node = 0;
+        for bit in (1..8).rev() {
+            let side = usize::from(first_octet >> bit & 1);
+            if let Record::NotFound = nodes[node][side] {
+                nodes.push([Record::NotFound; 2]);
+                nodes[node][side] = Record::Node(nodes.len() - 1);
+            }
+            if let Record::Node(next) = nodes[node][side] {
+                node = next;
+            }
+        }
+        nodes[node][usize::from(first_octet & 1)] = Record::Data(data.len());
+        data.extend([0xE1]);
+        data.extend(string("country"));
+        data.extend([0xE1]);
+        data.extend(string("iso_code"));
+        data.extend(string(code));
+    }
+
+    let node_count = nodes.len();
+    let mut db = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            Record::NotFound => node_count,
+            Record::Node(node) => node,
+            Record::Data(offset) => node_count + 16 + offset,
+        };
+        db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+    }
+    db.extend([0; 16]);
+    db.extend(data);
+    db.extend(b"\xAB\xCD\xEFMaxMind.com");
+    db.extend([0xE9]);
+    db.extend(string("binary_format_major_version"));
+    db.extend([0xA1, 2]);
+    db.extend(string("binary_format_minor_version"));
+    db.extend([0xA0]);
+    db.extend(string("build_epoch"));
+    db.extend([0x00, 0x02]);
+    db.extend(string("database_type"));
+    db.extend(string("Test-Country"));
+    db.extend(string("description"));
+    db.extend([0xE0]);
+    db.extend(string("ip_version"));
+    db.extend([0xA1, 4]);
+    db.extend(string("languages"));
+    db.extend([0x00, 0x04]);
+    db.extend(string("node_count"));
+    db.extend([0xC4]);
+    db.extend((node_count as u32).to_be_bytes());
+    db.extend(string("record_size"));
+    db.extend([0xA1, 24]);
+
+    GeoIpReader::new(Arc::new(maxminddb::Reader::from_source(db).unwrap()))
+}
+
+#[test]
+fn test_country_of() {
+    let reader = country_db(&[(1, "FR"), (2, "US"), (200, "JP")]);
+    assert_eq!(reader.country_of("1.2.3.4"), Some("FR"));
+    assert_eq!(reader.country_of("2.255.0.1"), Some("US"));
+    assert_eq!(reader.country_of("200.0.0.1"), Some("JP"));
+    assert_eq!(reader.country_of("3.3.3.3"), None);
+    assert_eq!(reader.country_of("unknown"), None);
+    assert_eq!(reader.clone(), reader);
+    assert_ne!(reader, country_db(&[(1, "FR")]));
+}
+
+#[tokio::test]
+async fn test_country_limits() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(5)
+        .with_ip_header("X-Client")
+        .with_geoip_reader(country_db(&[(1, "FR"), (2, "US")]))
+        .with_country("FR", RateLimitConfig::max_per_minute(1))
+        .with_key_override("1.1.1.1", 3, Duration::from_secs(60));
+    assert!(config.validate().is_ok());
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| info.limit.to_string())
+        .recover(handle_rate_limit_rejection);
+
+    // Clients located in a country with its own limit get it
+    let resp = request().header("X-Client", "1.2.3.4").reply(&route).await;
+    assert_eq!(resp.body(), "1");
+    let resp = request().header("X-Client", "1.2.3.4").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Overrides take precedence
+    let resp = request().header("X-Client", "1.1.1.1").reply(&route).await;
+    assert_eq!(resp.body(), "3");
+
+    // Countries without a limit, unknown addresses and failed lookups use the base one
+    for client in ["2.2.2.2", "3.3.3.3", "not an address"] {
+        let resp = request().header("X-Client", client).reply(&route).await;
+        assert_eq!(resp.body(), "5");
+    }
+}
+
+#[test]
+fn test_country_validation() {
+    let france = RateLimitConfig::default().with_country("FR", RateLimitConfig::per_second(1));
+    assert_eq!(
+        france.validate(),
+        Err(ConfigError::CountriesWithoutGeoIpReader)
+    );
+    assert!(france
+        .clone()
+        .with_geoip_reader(country_db(&[(1, "FR")]))
+        .validate()
+        .is_ok());
+
+    let error = RateLimitConfig::builder()
+        .geoip_reader(country_db(&[(1, "FR")]))
+        .country("FR", RateLimitConfig::default().with_window(Duration::ZERO))
+        .build()
+        .unwrap_err();
+    assert_eq!(
+        error,
+        ConfigError::InvalidCountry(String::from("FR"), Box::new(ConfigError::ZeroWindow))
+    );
+}
+
+#[tokio::test]
+async fn test_country_rules() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_ip_header("X-Client")
+        .with_geoip_reader(country_db(&[(1, "FR"), (2, "US"), (3, "JP")]))
+        .with_denied_countries(["FR", "JP"])
+        .with_allowed_countries(["US", "JP"]);
+    let filter = with_rate_limit(config);
+    let route = filter
+        .clone()
+        .map(|info: RateLimitInfo| info.remaining.to_string())
+        .recover(handle_rate_limit_rejection);
+
+    // Denied countries are rejected before counting, denial winning over allowance
+    for client in ["1.2.3.4", "3.3.3.3"] {
+        let resp = request().header("X-Client", client).reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.body(), "Access from your country is not allowed");
+        assert!(resp.headers().get("X-RateLimit-Limit").is_none());
+    }
+    let rejection = request()
+        .header("X-Client", "1.2.3.4")
+        .filter(&filter)
+        .await
+        .unwrap_err();
+    let denied = rejection.find::<CountryDenied>().unwrap();
+    assert_eq!(denied.key, "1.2.3.4");
+    assert_eq!(denied.country, "FR");
+    assert!(rejection.find::<RateLimitRejection>().is_none());
+
+    // Allowed countries bypass the limit
+    for _ in 0..3 {
+        let resp = request().header("X-Client", "2.2.2.2").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "1");
+    }
+
+    // Other clients are rate limited as usual
+    let resp = request().header("X-Client", "4.4.4.4").reply(&route).await;
+    assert_eq!(resp.body(), "0");
+    let resp = request().header("X-Client", "4.4.4.4").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // The denial response is configurable
+    let config = RateLimitConfig::default()
+        .with_ip_header("X-Client")
+        .with_geoip_reader(country_db(&[(1, "FR")]))
+        .with_denied_countries(["FR"])
+        .with_country_denial(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS, "Not here");
+    let route = with_rate_limit(config)
+        .map(|_| "ok")
+        .recover(handle_rate_limit_rejection);
+    let resp = request().header("X-Client", "1.2.3.4").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    assert_eq!(resp.body(), "Not here");
+}
+
+#[test]
+fn test_country_rules_validation() {
+    let denied = RateLimitConfig::default().with_denied_countries(["FR"]);
+    assert_eq!(
+        denied.validate(),
+        Err(ConfigError::CountriesWithoutGeoIpReader)
+    );
+    let denied = denied.with_geoip_reader(country_db(&[(1, "FR")]));
+    assert!(denied.validate().is_ok());
+    assert_eq!(
+        denied.with_country_denial(StatusCode::OK, "ok").validate(),
+        Err(ConfigError::InvalidDenyStatus(200))
+    );
+    assert_eq!(
+        RateLimitConfig::default()
+            .with_allowed_countries(["US"])
+            .validate(),
+        Err(ConfigError::CountriesWithoutGeoIpReader)
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_country_serialize_snapshot() {
+    let config = RateLimitConfig::default()
+        .with_geoip_reader(country_db(&[(1, "FR")]))
+        .with_country("FR", RateLimitConfig::per_second(1))
+        .with_denied_countries(["KP"])
+        .with_allowed_countries(["LU"]);
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["geoip_reader"], "<database>");
+    assert_eq!(json["countries"]["FR"]["max_requests"], 1);
+    assert_eq!(json["countries"]["FR"]["window"], "1s");
+    assert_eq!(
+        json["country_rules"],
+        serde_json::json!({
+            "deny": ["KP"],
+            "allow": ["LU"],
+            "deny_status": 403,
+            "deny_reason": "Access from your country is not allowed",
+        })
+    );
+
+    // Reads back, without the database
+    let read_back: RateLimitConfig = serde_json::from_value(json).unwrap();
+    let mut expected = config;
+    expected.geoip_reader = None;
+    assert_eq!(read_back, expected);
+}
+
+#[tokio::test]
+async fn test_country_denied_reason() {
+    let filter = with_rate_limit(
+        RateLimitConfig::default()
+            .with_ip_header("X-Client")
+            .with_geoip_reader(country_db(&[(1, "FR")]))
+            .with_denied_countries(["FR"]),
+    );
+    let rejection = request()
+        .header("X-Client", "1.2.3.4")
+        .filter(&filter)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        rejection_reason(&rejection),
+        Some(RejectionReason::CountryDenied {
+            country: String::from("FR")
+        })
+    );
+    // Denied clients didn't use up any limit
+    assert!(!is_rate_limited(&rejection));
+    assert!(rate_limit_info_from(&rejection).is_none());
+}
+
+#[tokio::test]
+async fn test_country_denied_audit() {
+    let audit = Arc::new(AuditRing::new(2));
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_label("checkout")
+            .with_ip_header("X-Client")
+            .with_geoip_reader(country_db(&[(1, "FR"), (2, "US")]))
+            .with_denied_countries(["FR"])
+            .with_audit_sink(audit.clone()),
+    )
+    .unwrap();
+    let filter = limiter.filter();
+    for client in ["1.2.3.4", "2.2.2.2", "1.1.1.1", "1.5.5.5"] {
+        let _ = request().header("X-Client", client).filter(&filter).await;
+    }
+
+    // Only the denials are recorded, the ring keeping the latest ones
+    let recent = limiter.recent_audit(10);
+    assert_eq!(recent, audit.recent(10));
+    let keys: Vec<_> = recent.iter().map(|entry| entry.key.as_deref()).collect();
+    assert_eq!(keys, [Some("1.5.5.5"), Some("1.1.1.1")]);
+    let entry = &recent[0];
+    assert_eq!(
+        entry.reason,
+        AuditReason::CountryDenied {
+            country: String::from("FR")
+        }
+    );
+    assert_eq!(entry.label.as_deref(), Some("checkout"));
+    assert_eq!(entry.duration, None);
+    assert_eq!(entry.until, None);
+    assert_eq!(limiter.recent_audit(1).len(), 1);
+}
+
+#[tokio::test]
+async fn test_country_is_limited() {
+    let audit = Arc::new(AuditRing::new(2));
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_geoip_reader(country_db(&[(1, "FR"), (2, "US")]))
+            .with_denied_countries(["FR"])
+            .with_allowed_countries(["US"])
+            .with_audit_sink(audit.clone()),
+    )
+    .unwrap();
+    assert!(limiter.is_limited("1.2.3.4").await);
+    // Asking doesn't deny anyone
+    assert!(audit.recent(1).is_empty());
+
+    // Exempted clients never are, whatever their usage
+    limiter.block_key("2.2.2.2", Duration::from_secs(60)).await;
+    assert!(!limiter.is_limited("2.2.2.2").await);
+    limiter.check("5.5.5.5").await.unwrap();
+    assert!(limiter.is_limited("5.5.5.5").await);
+}
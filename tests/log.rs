@@ -0,0 +1,178 @@
+#![cfg(feature = "log")]
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    sync::{Mutex, Once},
+    time::Duration,
+};
+use warp::test::request;
+use warp_rate_limit::*;
+
+// Records the messages of the crate, shared by the tests of this file as the logger
+// is global. Tests tell their records apart by client IP. With the `tracing` feature,
+// tracing may forward its own records under the same target, which are ignored.
+struct Capture(Mutex<Vec<(Level, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == "warp_rate_limit"
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            let message = record.args().to_string();
+            self.0.lock().unwrap().push((record.level(), message));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+fn records(containing: &str) -> Vec<(Level, String)> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+    let records = CAPTURE.0.lock().unwrap();
+    let matching = records.iter().filter(|(_, m)| m.contains(containing));
+    matching.cloned().collect()
+}
+
+#[tokio::test]
+async fn test_rejections_logged_once_per_window() {
+    records("");
+    let filter =
+        with_rate_limit(RateLimitConfig::max_per_minute(1).with_window(Duration::from_millis(100)));
+    let client = || request().header("X-Forwarded-For", "10.0.0.1");
+
+    client().filter(&filter).await.unwrap();
+    for _ in 0..5 {
+        client().filter(&filter).await.unwrap_err();
+    }
+    let logged = records("Rate limited 10.0.0.1");
+    assert_eq!(logged.len(), 1);
+    assert_eq!(logged[0].0, Level::Debug);
+    assert!(logged[0].1.contains("(1/1 requests)"));
+
+    // The next window logs its first rejection again
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    client().filter(&filter).await.unwrap();
+    client().filter(&filter).await.unwrap_err();
+    client().filter(&filter).await.unwrap_err();
+    assert_eq!(records("Rate limited 10.0.0.1").len(), 2);
+}
+
+#[tokio::test]
+async fn test_rejections_in_a_store_logged_once_per_window() {
+    records("");
+    let config = RateLimitConfig::max_per_minute(1)
+        .with_window(Duration::from_millis(100))
+        .with_store(store::MemoryStore::new());
+    let filter = with_rate_limit(config);
+    let client = || request().header("X-Forwarded-For", "10.0.0.9");
+
+    client().filter(&filter).await.unwrap();
+    for _ in 0..5 {
+        client().filter(&filter).await.unwrap_err();
+    }
+    assert_eq!(records("Rate limited 10.0.0.9").len(), 1);
+
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    client().filter(&filter).await.unwrap();
+    client().filter(&filter).await.unwrap_err();
+    assert_eq!(records("Rate limited 10.0.0.9").len(), 2);
+}
+
+#[tokio::test]
+async fn test_redacted_key_not_logged() {
+    records("");
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1).with_redact_key(true));
+    let client = || request().header("X-Forwarded-For", "10.0.0.2");
+
+    client().filter(&filter).await.unwrap();
+    client().filter(&filter).await.unwrap_err();
+    assert!(records("Rate limited 10.0.0.2").is_empty());
+    assert!(!records("Rate limited <redacted>").is_empty());
+}
+
+#[tokio::test]
+async fn test_evictions_logged() {
+    records("");
+    let filter = with_rate_limit(
+        RateLimitConfig::default()
+            .with_window(Duration::from_millis(50))
+            .with_idle_ttl(Duration::from_millis(100)),
+    );
+    let client = |ip| request().header("X-Forwarded-For", ip);
+
+    client("10.0.0.3").filter(&filter).await.unwrap();
+    client("10.0.0.4").filter(&filter).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    client("10.0.0.3").filter(&filter).await.unwrap();
+
+    let evicted = records("Evicted 2 idle clients");
+    assert_eq!(evicted.len(), 1);
+    assert_eq!(evicted[0].0, Level::Warn);
+}
+
+#[tokio::test]
+async fn test_label_prefix() {
+    records("");
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1).with_label("orders"));
+    let client = || request().header("X-Forwarded-For", "10.0.0.5");
+
+    client().filter(&filter).await.unwrap();
+    client().filter(&filter).await.unwrap_err();
+    assert_eq!(records("[orders] Rate limited 10.0.0.5").len(), 1);
+}
+
+// Waits for the `n`-th record containing `containing`, giving it back
+async fn nth_record(containing: &str, n: usize) -> String {
+    for _ in 0..200 {
+        if let Some((_, message)) = records(containing).get(n - 1) {
+            return message.clone();
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("No record #{} containing {:?}", n, containing);
+}
+
+#[tokio::test]
+async fn test_stats_logger() {
+    records("");
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(3).with_label("stats")).unwrap();
+    let filter = limiter.filter();
+    let client = |ip| request().header("X-Forwarded-For", ip);
+    // Counted before the logger starts, so never reported
+    client("10.0.0.20").filter(&filter).await.unwrap();
+
+    let logger = limiter.spawn_stats_logger(Duration::from_millis(100));
+    for _ in 0..2 {
+        client("10.0.0.20").filter(&filter).await.unwrap();
+    }
+    client("10.0.0.20").filter(&filter).await.unwrap_err();
+    client("10.0.0.21").filter(&filter).await.unwrap();
+    assert_eq!(
+        nth_record("rate-limiter[stats]", 1).await,
+        "rate-limiter[stats]: 3 allowed, 1 rejected, 2 keys, 0 evictions (last 100ms)"
+    );
+
+    // Only the requests since the previous report, the reset ones included
+    limiter.reset_counters();
+    client("10.0.0.20").filter(&filter).await.unwrap_err();
+    client("10.0.0.21").filter(&filter).await.unwrap();
+    assert_eq!(
+        nth_record("rate-limiter[stats]", 2).await,
+        "rate-limiter[stats]: 1 allowed, 1 rejected, 2 keys, 0 evictions (last 100ms)"
+    );
+
+    // Stops with the limiter
+    drop((limiter, filter));
+    tokio::time::timeout(Duration::from_secs(1), logger)
+        .await
+        .unwrap()
+        .unwrap();
+}
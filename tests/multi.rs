@@ -0,0 +1,113 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use warp::test::request;
+use warp_rate_limit::*;
+
+fn plans(tenant: &str) -> RateLimitConfig {
+    match tenant {
+        "free" => RateLimitConfig::max_per_minute(2),
+        "pro" => RateLimitConfig::max_per_minute(4),
+        _ => RateLimitConfig::max_per_minute(1),
+    }
+}
+
+fn client(tenant: &str, ip: &str) -> warp::test::RequestBuilder {
+    request()
+        .header("X-Tenant", tenant)
+        .header("X-Forwarded-For", ip)
+}
+
+#[tokio::test]
+async fn test_tenants_are_limited_independently() {
+    let limiters = MultiRateLimiter::new(10, |tenant: &String| plans(tenant)).unwrap();
+    let filter = limiters.filter(warp::header::<String>("X-Tenant"));
+
+    for used in 1..=2 {
+        let info = client("free", "10.0.0.1").filter(&filter).await.unwrap();
+        assert_eq!((info.used, info.limit), (used, 2));
+    }
+    assert!(is_rate_limited(
+        &client("free", "10.0.0.1")
+            .filter(&filter)
+            .await
+            .unwrap_err()
+    ));
+
+    // The same client has its own budget with another tenant
+    for used in 1..=4 {
+        let info = client("pro", "10.0.0.1").filter(&filter).await.unwrap();
+        assert_eq!((info.used, info.limit), (used, 4));
+    }
+    assert!(client("pro", "10.0.0.1").filter(&filter).await.is_err());
+    // And other clients of the tenant have theirs
+    client("free", "10.0.0.2").filter(&filter).await.unwrap();
+    assert_eq!(limiters.tenant_count(), 2);
+    assert_eq!(limiters.get(&String::from("free")).unwrap().key_count(), 2);
+
+    // Without a tenant, the extraction rejects the request
+    let rejection = request().filter(&filter).await.unwrap_err();
+    assert!(!is_rate_limited(&rejection));
+}
+
+#[tokio::test]
+async fn test_least_recently_used_tenants_are_dropped() {
+    let resolved = Arc::new(AtomicUsize::new(0));
+    let counter = resolved.clone();
+    let limiters = MultiRateLimiter::new(2, move |tenant: &String| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        plans(tenant)
+    })
+    .unwrap();
+    let filter = limiters.filter(warp::header::<String>("X-Tenant"));
+
+    client("free", "10.0.0.1").filter(&filter).await.unwrap();
+    client("pro", "10.0.0.1").filter(&filter).await.unwrap();
+    client("free", "10.0.0.1").filter(&filter).await.unwrap();
+    assert_eq!(resolved.load(Ordering::Relaxed), 2);
+
+    // "pro" is the least recently used
+    client("other", "10.0.0.1").filter(&filter).await.unwrap();
+    assert_eq!(limiters.tenant_count(), 2);
+    let info = client("free", "10.0.0.1")
+        .filter(&filter)
+        .await
+        .unwrap_err();
+    assert!(is_rate_limited(&info));
+    let info = client("pro", "10.0.0.1").filter(&filter).await.unwrap();
+    assert_eq!(info.used, 1);
+    assert_eq!(resolved.load(Ordering::Relaxed), 4);
+}
+
+#[tokio::test]
+async fn test_invalid_tenant_config() {
+    let limiters = MultiRateLimiter::new(10, |tenant: &String| match tenant.as_str() {
+        "broken" => RateLimitConfig::default().with_window(Duration::ZERO),
+        _ => RateLimitConfig::default(),
+    })
+    .unwrap();
+    assert_eq!(
+        limiters.get(&String::from("broken")).unwrap_err(),
+        ConfigError::ZeroWindow
+    );
+
+    let filter = limiters.filter(warp::header::<String>("X-Tenant"));
+    let rejection = client("broken", "10.0.0.1")
+        .filter(&filter)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        rejection.find::<RateLimitError>(),
+        Some(RateLimitError::Config(ConfigError::ZeroWindow))
+    ));
+    assert_eq!(limiters.tenant_count(), 0);
+
+    assert_eq!(
+        MultiRateLimiter::new(0, |tenant: &String| plans(tenant)).unwrap_err(),
+        ConfigError::ZeroMaxTenants
+    );
+}
@@ -0,0 +1,43 @@
+// Runs with `cargo test --no-default-features`, making sure the crate stays usable
+// without serde
+#![cfg(not(feature = "serde"))]
+
+use std::time::Duration;
+use warp::{http::StatusCode, test::request, Filter};
+use warp_rate_limit::*;
+
+#[tokio::test]
+async fn test_rate_limit_without_serde() {
+    let config: RateLimitConfig = "2/1m".parse().unwrap();
+    assert_eq!(config.max_requests, 2);
+    assert_eq!(config.window, Duration::from_secs(60));
+
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| info.remaining.to_string())
+        .recover(handle_rate_limit_rejection);
+
+    assert_eq!(request().reply(&route).await.body(), "1");
+    assert_eq!(request().reply(&route).await.body(), "0");
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "2");
+}
+
+#[test]
+fn test_config_without_serde() {
+    assert_eq!(
+        RateLimitConfig::builder()
+            .window(Duration::ZERO)
+            .build()
+            .unwrap_err(),
+        ConfigError::ZeroWindow
+    );
+    assert_eq!(
+        parse_limit("5/15m").unwrap(),
+        (5, Duration::from_secs(15 * 60))
+    );
+    assert_eq!(
+        RejectionBodyFormat::default(),
+        RejectionBodyFormat::PlainText
+    );
+}
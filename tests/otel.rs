@@ -0,0 +1,65 @@
+#![cfg(feature = "otel")]
+use opentelemetry::{
+    context::FutureExt as _,
+    trace::{Status, TraceContextExt as _, Tracer as _, TracerProvider as _},
+    Context, KeyValue, Value,
+};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use warp::{test::request, Filter, Rejection};
+use warp_rate_limit::*;
+
+// Runs a request through `filter` in its own span, returning the exported span
+async fn traced_request(
+    filter: &(impl Filter<Extract = (RateLimitInfo,), Error = Rejection> + 'static),
+) -> SpanData {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let span = provider.tracer("test").start("request");
+    let cx = Context::current_with_span(span);
+    let _ = request()
+        .header("X-Forwarded-For", "1.2.3.4")
+        .filter(filter)
+        .with_context(cx.clone())
+        .await;
+    cx.span().end();
+    exporter.get_finished_spans().unwrap().remove(0)
+}
+
+fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+    let attribute = span.attributes.iter().find(|kv| kv.key.as_str() == key);
+    attribute.map(|kv: &KeyValue| kv.value.clone())
+}
+
+#[tokio::test]
+async fn test_span_attributes() {
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1).with_label("api"));
+
+    let admitted = traced_request(&filter).await;
+    assert_eq!(
+        attribute(&admitted, "ratelimit.outcome"),
+        Some("allowed".into())
+    );
+    assert_eq!(attribute(&admitted, "ratelimit.limit"), Some(1.into()));
+    assert_eq!(attribute(&admitted, "ratelimit.remaining"), Some(0.into()));
+    assert_eq!(attribute(&admitted, "ratelimit.label"), Some("api".into()));
+    assert!(matches!(
+        attribute(&admitted, "ratelimit.reset"),
+        Some(Value::I64(_))
+    ));
+    assert_eq!(admitted.status, Status::Unset);
+
+    let rejected = traced_request(&filter).await;
+    assert_eq!(
+        attribute(&rejected, "ratelimit.outcome"),
+        Some("rejected".into())
+    );
+    assert_eq!(attribute(&rejected, "ratelimit.limit"), Some(1.into()));
+    assert_eq!(attribute(&rejected, "ratelimit.remaining"), Some(0.into()));
+    assert!(matches!(
+        attribute(&rejected, "ratelimit.reset"),
+        Some(Value::I64(_))
+    ));
+    assert_eq!(rejected.status, Status::error("rate limited"));
+}
@@ -0,0 +1,95 @@
+#![cfg(feature = "tower")]
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpSocket},
+};
+use warp::Filter;
+use warp_rate_limit::*;
+
+// Serves `limiter` on an ephemeral port like a server of our own would, giving back its
+// address
+async fn serve(limiter: RateLimiterHandle) -> SocketAddr {
+    let routes = limiter
+        .filter()
+        .map(|info: RateLimitInfo| info.key.unwrap_or_default())
+        .recover(handle_rate_limit_rejection);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let service = with_peer_addr(warp::service(routes.clone()), peer);
+            tokio::spawn(
+                http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service)),
+            );
+        }
+    });
+    addr
+}
+
+// Sends a request to `server` from `from`, giving back the response as text
+async fn get(server: SocketAddr, from: &str) -> String {
+    get_with_headers(server, from, &[]).await
+}
+
+// Same as `get`, with the header lines `headers` in that order
+async fn get_with_headers(server: SocketAddr, from: &str, headers: &[&str]) -> String {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(from.parse().unwrap()).unwrap();
+    let mut stream = socket.connect(server).await.unwrap();
+    let mut request = String::from("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n");
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_peer_addr_behind_own_server() {
+    let config = RateLimitConfig::max_per_minute(1).without_ip_header();
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    let server = serve(limiter.clone()).await;
+
+    let response = get(server, "127.0.0.1:0").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("127.0.0.1"), "{}", response);
+    // Another connection from the same address is the same client
+    let response = get(server, "127.0.0.1:0").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+
+    let response = get(server, "127.0.0.2:0").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("127.0.0.2"), "{}", response);
+    assert!(limiter.usage("unknown").await.is_none());
+}
+
+#[tokio::test]
+async fn test_ip_header_lines_are_joined() {
+    let config = RateLimitConfig::behind_proxy(["127.0.0.0/8".parse().unwrap()]);
+    let server = serve(RateLimiterHandle::new(config).unwrap()).await;
+    let joined = get_with_headers(
+        server,
+        "127.0.0.1:0",
+        &["X-Forwarded-For: 9.9.9.9, 1.2.3.4"],
+    )
+    .await;
+    let lines = ["X-Forwarded-For: 9.9.9.9", "X-Forwarded-For: 1.2.3.4"];
+    let split = get_with_headers(server, "127.0.0.1:0", &lines).await;
+    // The proxy appended the client it saw on a line of its own
+    assert!(joined.ends_with("1.2.3.4"), "{}", joined);
+    assert!(split.ends_with("1.2.3.4"), "{}", split);
+
+    let server = serve(RateLimiterHandle::new(RateLimitConfig::default()).unwrap()).await;
+    let lines = ["X-Forwarded-For: 5.6.7.8", "X-Forwarded-For: 1.2.3.4"];
+    let response = get_with_headers(server, "127.0.0.1:0", &lines).await;
+    assert!(response.ends_with("5.6.7.8"), "{}", response);
+}
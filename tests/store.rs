@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Duration};
+use warp_rate_limit::{
+    store::{conformance, MemoryStore, RateLimitStore, StoreFuture, WindowCount},
+    try_with_rate_limits, CompletionRateLimit, ConfigError, RateLimitConfig, RateLimiterHandle,
+    RefundRateLimit,
+};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_memory_store_conformance() {
+    conformance::concurrent_admissions(Arc::new(MemoryStore::new())).await;
+    // Through a trait object too, as a limiter would hold it
+    let store: Arc<dyn RateLimitStore> = Arc::new(MemoryStore::default());
+    conformance::concurrent_admissions(store).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_windows() {
+    let store = MemoryStore::new();
+    let window = Duration::from_millis(50);
+    let count = store.incr_within_window("job", 2, 3, window).await.unwrap();
+    assert_eq!((count.admitted, count.count), (true, 2));
+    assert!(count.resets_in <= window);
+    // Doesn't fit, and isn't counted
+    let count = store.incr_within_window("job", 2, 3, window).await.unwrap();
+    assert_eq!((count.admitted, count.count), (false, 2));
+    assert_eq!(
+        store
+            .incr_within_window("other", 3, 3, window)
+            .await
+            .unwrap(),
+        WindowCount::admitted(3, window)
+    );
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let count = store.incr_within_window("job", 2, 3, window).await.unwrap();
+    assert_eq!((count.admitted, count.count), (true, 2));
+}
+
+#[tokio::test]
+async fn test_limiters_count_in_their_store() {
+    // Two instances of a service sharing their counts
+    let store = MemoryStore::new();
+    let config = RateLimitConfig::max_per_minute(3).with_store(store.clone());
+    let first = RateLimiterHandle::new(config.clone()).unwrap();
+    let second = RateLimiterHandle::new(config).unwrap();
+
+    assert_eq!(first.check("client").await.unwrap().remaining, 2);
+    assert_eq!(second.acquire_n("client", 2).await.unwrap().used, 3);
+    let rejection = first.check("client").await.unwrap_err();
+    assert_eq!((rejection.used, rejection.limit), (3, 3));
+    assert!(rejection.retry_after <= Duration::from_secs(60));
+
+    // The memory of the limiters isn't used for counting
+    let count = store
+        .incr_within_window("client", 1, 3, Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!((count.admitted, count.count), (false, 3));
+
+    // Banned clients aren't counted
+    first.block_key("other", Duration::from_secs(600)).await;
+    let rejection = first.check("other").await.unwrap_err();
+    assert!(rejection.retry_after > Duration::from_secs(60));
+    assert_eq!(second.check("other").await.unwrap().used, 1);
+}
+
+struct FailingStore;
+
+impl RateLimitStore for FailingStore {
+    fn incr_within_window<'a>(
+        &'a self,
+        _key: &'a str,
+        _cost: u32,
+        _max_requests: u32,
+        _window: Duration,
+    ) -> StoreFuture<'a> {
+        Box::pin(async { Err("connection refused".into()) })
+    }
+}
+
+#[tokio::test]
+async fn test_failing_store_lets_requests_through() {
+    let config = RateLimitConfig::max_per_minute(1).with_store(FailingStore);
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    assert_eq!(limiter.check("client").await.unwrap().remaining, 1);
+    assert_eq!(limiter.counters().store_errors, 1);
+    for _ in 0..2 {
+        assert_eq!(limiter.check("client").await.unwrap().remaining, 1);
+    }
+    assert_eq!(limiter.counters().store_errors, 3);
+}
+
+#[test]
+fn test_memory_only_limiters_refuse_a_store() {
+    let config = RateLimitConfig::max_per_minute(3).with_store(MemoryStore::new());
+    assert_eq!(
+        RefundRateLimit::new(config.clone()).err(),
+        Some(ConfigError::StoreNotSupported("RefundRateLimit"))
+    );
+    assert_eq!(
+        CompletionRateLimit::new(config.clone()).err(),
+        Some(ConfigError::StoreNotSupported("CompletionRateLimit"))
+    );
+    assert_eq!(
+        try_with_rate_limits([RateLimitConfig::per_second(5), config]).err(),
+        Some(ConfigError::StoreNotSupported("with_rate_limits"))
+    );
+}
@@ -1,11 +1,33 @@
-use chrono::Utc;
-use warp_rate_limit::*;
-use std::{convert::Infallible, time::Duration};
+// Runs with and without the chrono feature: the fixtures build timestamps through the
+// API both kinds of `Timestamp` share
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::task::JoinSet;
-use warp::{reject::Rejection, Reply};
+use warp::http::header;
 use warp::{http::StatusCode, test::request, Filter};
-use warp::hyper::header;
+use warp::{reject::Rejection, Reply};
+use warp_rate_limit::*;
+
+// The current wall-clock time
+fn system_now() -> Timestamp {
+    Timestamp::from(SystemTime::now())
+}
+
+// The Unix time of an HTTP date, such as a Retry-After
+fn http_date_secs(date: &str) -> i64 {
+    let at = httpdate::parse_http_date(date).unwrap();
+    at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
 
+// Time from `from` until `to`, which must not come first
+fn between(from: Timestamp, to: Timestamp) -> Duration {
+    SystemTime::from(to)
+        .duration_since(SystemTime::from(from))
+        .unwrap()
+}
 
 // Helper function to create a test rate limiter with rejection handling
 async fn create_test_route(
@@ -44,6 +66,27 @@ fn test_config_builders() {
     assert_eq!(custom.max_requests, 30);
     assert_eq!(custom.retry_after_format, RetryAfterFormat::HttpDate);
 
+    // Test per_second, per_hour and per_day builders
+    let per_second = RateLimitConfig::per_second(5);
+    assert_eq!(per_second.window, Duration::from_secs(1));
+    assert_eq!(per_second.max_requests, 5);
+    assert_eq!(per_second.retry_after_format, RetryAfterFormat::HttpDate);
+
+    let per_hour = RateLimitConfig::per_hour(1000);
+    assert_eq!(per_hour.window, Duration::from_secs(3600));
+    assert_eq!(per_hour.max_requests, 1000);
+    assert_eq!(per_hour.ip_header.as_deref(), Some("X-Forwarded-For"));
+
+    let per_day = RateLimitConfig::per_day(10_000);
+    assert_eq!(per_day.window, Duration::from_secs(86400));
+    assert_eq!(per_day.max_requests, 10_000);
+    assert_eq!(
+        per_day,
+        RateLimitConfig::default()
+            .with_max_requests(10_000)
+            .with_window(Duration::from_secs(86400))
+    );
+
     // Test default config
     let default = RateLimitConfig::default();
     assert_eq!(default.window, Duration::from_secs(60));
@@ -53,18 +96,16 @@ fn test_config_builders() {
 
 #[tokio::test]
 async fn test_comprehensive_rate_limit_rejection() {
-    let config = RateLimitConfig {
-        max_requests: 1,
-        window: Duration::from_secs(5),
-        retry_after_format: RetryAfterFormat::Seconds,
-        ..Default::default()
-    };
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_window(Duration::from_secs(5))
+        .with_retry_after_format(RetryAfterFormat::Seconds);
 
     let route = create_test_route(config.clone()).await;
 
     // First request succeeds
     let resp1 = request()
-        .remote_addr("127.0.0.1:1234".parse().unwrap())
+        .extension("127.0.0.1:1234".parse::<SocketAddr>().unwrap())
         .reply(&route)
         .await;
     assert_eq!(resp1.status(), 200);
@@ -72,7 +113,7 @@ async fn test_comprehensive_rate_limit_rejection() {
 
     // Second request gets rejected with proper headers
     let resp2 = request()
-        .remote_addr("127.0.0.1:1234".parse().unwrap())
+        .extension("127.0.0.1:1234".parse::<SocketAddr>().unwrap())
         .reply(&route)
         .await;
 
@@ -97,23 +138,21 @@ async fn test_comprehensive_rate_limit_rejection() {
 #[tokio::test]
 async fn test_retry_after_formats() {
     // Test HttpDate format
-    let http_date_config = RateLimitConfig {
-        max_requests: 1,
-        window: Duration::from_secs(15),
-        retry_after_format: RetryAfterFormat::HttpDate,
-        ..Default::default()
-    };
+    let http_date_config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_window(Duration::from_secs(15))
+        .with_retry_after_format(RetryAfterFormat::HttpDate);
 
     let http_date_route = create_test_route(http_date_config).await;
 
     // Trigger rate limit with HttpDate format
     let _ = request()
-        .remote_addr("127.0.0.1:1234".parse().unwrap())
+        .extension("127.0.0.1:1234".parse::<SocketAddr>().unwrap())
         .reply(&http_date_route)
         .await;
 
     let resp_http = request()
-        .remote_addr("127.0.0.1:1234".parse().unwrap())
+        .extension("127.0.0.1:1234".parse::<SocketAddr>().unwrap())
         .reply(&http_date_route)
         .await;
 
@@ -124,26 +163,24 @@ async fn test_retry_after_formats() {
         .unwrap()
         .to_str()
         .unwrap();
-    assert!(!retry_after_http.is_empty()); // RFC2822 date contains GMT
+    assert!(retry_after_http.ends_with(" GMT")); // IMF-fixdate
 
     // Test Seconds format
-    let seconds_config = RateLimitConfig {
-        max_requests: 1,
-        window: Duration::from_secs(5),
-        retry_after_format: RetryAfterFormat::Seconds,
-        ..Default::default()
-    };
+    let seconds_config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_window(Duration::from_secs(5))
+        .with_retry_after_format(RetryAfterFormat::Seconds);
 
     let seconds_route = create_test_route(seconds_config).await;
 
     // Trigger rate limit with Seconds format
     let _ = request()
-        .remote_addr("127.0.0.2:1234".parse().unwrap())
+        .extension("127.0.0.2:1234".parse::<SocketAddr>().unwrap())
         .reply(&seconds_route)
         .await;
 
     let resp_sec = request()
-        .remote_addr("127.0.0.2:1234".parse().unwrap())
+        .extension("127.0.0.2:1234".parse::<SocketAddr>().unwrap())
         .reply(&seconds_route)
         .await;
 
@@ -160,41 +197,241 @@ async fn test_retry_after_formats() {
 
 #[test]
 fn test_rate_limit_info_extraction() {
-    let now = Utc::now();
+    let now = system_now();
+    let reset_time = now + Duration::from_secs(60);
     let rejection = RateLimitRejection {
         retry_after: Duration::from_secs(60),
         limit: 100,
-        reset_time: now,
+        used: 100,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
+        reset_time,
+        first_rejected_at: None,
+        rejected_at: now,
+        rejected_instant: Instant::now(),
         retry_after_format: RetryAfterFormat::Seconds,
+        retry_after_jitter: Duration::ZERO,
+        max_retry_after: None,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        config_index: None,
     };
 
     let info = get_rate_limit_info(&rejection);
 
     assert_eq!(info.limit, 100);
     assert_eq!(info.remaining, 0);
-    assert_eq!(info.reset_timestamp, now.timestamp());
+    assert_eq!(info.reset_timestamp, reset_time.timestamp());
     assert_eq!(info.retry_after, "60");
 
     // Test with HttpDate format
     let rejection_http = RateLimitRejection {
         retry_after: Duration::from_secs(60),
         limit: 100,
-        reset_time: now,
+        used: 100,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
+        reset_time,
+        first_rejected_at: None,
+        rejected_at: now,
+        rejected_instant: Instant::now(),
         retry_after_format: RetryAfterFormat::HttpDate,
+        retry_after_jitter: Duration::ZERO,
+        max_retry_after: None,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        config_index: None,
     };
 
     let info_http = get_rate_limit_info(&rejection_http);
     assert!(!info_http.retry_after.is_empty()); // RFC2822 date format
 }
 
-#[tokio::test]
-async fn test_concurrent_requests() {
-    let config = RateLimitConfig {
-        max_requests: 5,
-        window: Duration::from_secs(1),
+#[test]
+fn test_rate_limit_info_from_rejection() {
+    let now = system_now();
+    let reset_time = now + Duration::from_secs(60);
+    let rejection = RateLimitRejection {
+        retry_after: Duration::from_secs(60),
+        limit: 100,
+        used: 100,
+        window: Duration::from_secs(60),
+        tier: Some(String::from("gold")),
+        key: Some(String::from("1.1.1.1")),
+        label: Some("login".into()),
+        reset_time,
+        first_rejected_at: None,
+        rejected_at: now,
+        rejected_instant: Instant::now(),
         retry_after_format: RetryAfterFormat::Seconds,
-        ..Default::default()
+        retry_after_jitter: Duration::ZERO,
+        max_retry_after: None,
+        header_style: HeaderStyle::Both,
+        used_header: true,
+        config_index: None,
+    };
+
+    let info = RateLimitInfo::from(&rejection);
+    assert_eq!(info.limit, 100);
+    assert_eq!(info.remaining, 0);
+    assert_eq!(info.used, 100);
+    assert_eq!(info.window, Duration::from_secs(60));
+    assert_eq!(info.reset_timestamp, reset_time.timestamp());
+    assert_eq!(info.retry_after, "60");
+    assert_eq!(info.retry_after_duration, Duration::from_secs(60));
+    assert_eq!(info.tier.as_deref(), Some("gold"));
+    assert_eq!(info.key.as_deref(), Some("1.1.1.1"));
+    assert_eq!(info.label.as_deref(), Some("login"));
+    assert_eq!(info.header_style, HeaderStyle::Both);
+    assert!(info.used_header);
+
+    // All the spellings agree
+    for other in [rejection.to_info(), get_rate_limit_info(&rejection)] {
+        assert_eq!(
+            other.to_header_map().unwrap(),
+            info.to_header_map().unwrap()
+        );
+        assert_eq!(other.key, info.key);
+        assert_eq!(other.label, info.label);
+    }
+}
+
+#[tokio::test]
+async fn test_many_filters_with_ip_header() {
+    // Like a router rebuilt on every reload, each filter reading the ip header
+    for i in 0..2000 {
+        let config =
+            RateLimitConfig::max_per_minute(1).with_ip_header(format!("X-Client-Ip-{}", i % 10));
+        let behind_proxy = config
+            .clone()
+            .with_trusted_proxies(["127.0.0.1/32".parse().unwrap()]);
+        let filters = [
+            with_rate_limit(config).boxed(),
+            with_rate_limit(behind_proxy).boxed(),
+        ];
+        for filter in filters {
+            let header = format!("x-client-ip-{}", i % 10);
+            let info = request()
+                .extension("127.0.0.1:8080".parse::<SocketAddr>().unwrap())
+                .header(header.as_str(), "10.0.0.1")
+                .filter(&filter)
+                .await
+                .unwrap();
+            assert_eq!(info.remaining, 0);
+            assert!(request()
+                .extension("127.0.0.1:8080".parse::<SocketAddr>().unwrap())
+                .header(header.as_str(), "10.0.0.1")
+                .filter(&filter)
+                .await
+                .is_err());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unparseable_ip_header_falls_back_to_remote_addr() {
+    let from = |addr: &str, header: &str| {
+        request()
+            .extension(addr.parse::<SocketAddr>().unwrap())
+            .header("X-Forwarded-For", header)
+    };
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(1)).unwrap();
+    let filter = limiter.filter();
+    let info = from("10.0.0.1:4000", "garbage")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("10.0.0.1"));
+    // Another client sending garbage has its own budget, so do clients without a header
+    assert!(from("10.0.0.2:4000", "").filter(&filter).await.is_ok());
+    let info = request()
+        .extension("10.0.0.3:4000".parse::<SocketAddr>().unwrap())
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("10.0.0.3"));
+    assert!(from("10.0.0.1:4001", "nope").filter(&filter).await.is_err());
+    assert_eq!(limiter.counters().unknown, 0);
+
+    // Behind a trusted proxy, a broken chain falls back to the proxy
+    let config =
+        RateLimitConfig::max_per_minute(1).with_trusted_proxies(["10.0.0.0/8".parse().unwrap()]);
+    let filter = with_rate_limit(config);
+    let info = from("10.0.0.9:4000", "1.2.3.4, garbage")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("10.0.0.9"));
+}
+
+#[tokio::test]
+async fn test_ip_header_with_invalid_utf8() {
+    let from = |header: &[u8]| {
+        request()
+            .extension("10.0.0.1:4000".parse::<SocketAddr>().unwrap())
+            .header(
+                "X-Forwarded-For",
+                header::HeaderValue::from_bytes(header).unwrap(),
+            )
     };
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(1)).unwrap();
+    let filter = limiter.filter();
+    // Served and counted under the remote address rather than failing the request
+    let info = from(b"\xff\xfe").filter(&filter).await.unwrap();
+    assert_eq!(info.key.as_deref(), Some("10.0.0.1"));
+    assert!(from(b"\xc3\x28").filter(&filter).await.is_err());
+    assert_eq!(limiter.usage("10.0.0.1").await.unwrap().used, 1);
+
+    // The address before the garbage still names the client
+    let info = from(b"1.2.3.4, \xff").filter(&filter).await.unwrap();
+    assert_eq!(info.key.as_deref(), Some("1.2.3.4"));
+
+    // A trusted proxy wouldn't forward that, so the proxy is the client
+    let config =
+        RateLimitConfig::max_per_minute(1).with_trusted_proxies(["10.0.0.0/8".parse().unwrap()]);
+    let info = from(b"1.2.3.4, \xff")
+        .filter(&with_rate_limit(config))
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("10.0.0.1"));
+}
+
+#[tokio::test]
+async fn test_unknown_bucket() {
+    let config = RateLimitConfig::max_per_minute(1).with_unknown_bucket(true);
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    let filter = limiter.filter();
+    let info = request()
+        .extension("10.0.0.1:4000".parse::<SocketAddr>().unwrap())
+        .header("X-Forwarded-For", "garbage")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("unknown"));
+    // Every unidentified client shares the bucket
+    assert!(request()
+        .extension("10.0.0.2:4000".parse::<SocketAddr>().unwrap())
+        .filter(&filter)
+        .await
+        .is_err());
+    assert!(request()
+        .header("X-Forwarded-For", "1.2.3.4")
+        .filter(&filter)
+        .await
+        .is_ok());
+    assert_eq!(limiter.counters().unknown, 2);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(5)
+        .with_window(Duration::from_secs(1))
+        .with_retry_after_format(RetryAfterFormat::Seconds);
 
     let route = create_test_route(config.clone()).await;
     let mut set = JoinSet::new();
@@ -204,7 +441,7 @@ async fn test_concurrent_requests() {
         let route = route.clone();
         set.spawn(async move {
             request()
-                .remote_addr("127.0.0.1:1234".parse().unwrap())
+                .extension("127.0.0.1:1234".parse::<SocketAddr>().unwrap())
                 .reply(&route)
                 .await
         });
@@ -233,12 +470,3820 @@ fn test_invalid_header_value_handling() {
     let mut headers = header::HeaderMap::new();
     let invalid_info = RateLimitInfo {
         retry_after: "invalid\u{0000}characters".to_string(),
+        retry_after_duration: Duration::from_secs(30),
         limit: 100,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
         remaining: 50,
+        used: 50,
         reset_timestamp: 1234567890,
+        reset_at: Timestamp::from_timestamp(1234567890, 0).unwrap(),
+        first_rejected_at: None,
+        checked_at: Timestamp::from_timestamp(1234567860, 0).unwrap(),
         retry_after_format: RetryAfterFormat::Seconds,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        window_start: Instant::now(),
+        internal_map_len: 0,
+        last_cleanup_time: Instant::now(),
     };
 
     let result = add_rate_limit_headers(&mut headers, &invalid_info);
     assert!(matches!(result, Err(RateLimitError::HeaderError(_))));
 }
+
+fn test_info() -> RateLimitInfo {
+    RateLimitInfo {
+        retry_after: "30".to_string(),
+        retry_after_duration: Duration::from_secs(30),
+        limit: 100,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
+        remaining: 50,
+        used: 50,
+        reset_timestamp: 1234567890,
+        reset_at: Timestamp::from_timestamp(1234567890, 0).unwrap(),
+        first_rejected_at: None,
+        checked_at: Timestamp::from_timestamp(1234567860, 0).unwrap(),
+        retry_after_format: RetryAfterFormat::Seconds,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        window_start: Instant::now(),
+        internal_map_len: 0,
+        last_cleanup_time: Instant::now(),
+    }
+}
+
+#[test]
+fn test_header_policy_overwrite() {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::RETRY_AFTER, "999".parse().unwrap());
+
+    let written =
+        add_rate_limit_headers_with_policy(&mut headers, &test_info(), HeaderPolicy::Overwrite)
+            .unwrap();
+
+    // Every header is written and the pre-existing value is replaced
+    assert_eq!(written.len(), 4);
+    assert!(written.contains(&header::RETRY_AFTER));
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "30");
+    assert_eq!(headers.get_all(header::RETRY_AFTER).iter().count(), 1);
+
+    // The default function behaves the same way
+    headers.insert(header::RETRY_AFTER, "999".parse().unwrap());
+    add_rate_limit_headers(&mut headers, &test_info()).unwrap();
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "30");
+}
+
+#[test]
+fn test_header_policy_keep_existing() {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::RETRY_AFTER, "999".parse().unwrap());
+
+    let written =
+        add_rate_limit_headers_with_policy(&mut headers, &test_info(), HeaderPolicy::KeepExisting)
+            .unwrap();
+
+    // Retry-After is left alone, the other headers are added
+    assert_eq!(written.len(), 3);
+    assert!(!written.contains(&header::RETRY_AFTER));
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "999");
+    assert_eq!(headers.get("X-RateLimit-Remaining").unwrap(), "50");
+}
+
+#[test]
+fn test_header_policy_fail() {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(header::RETRY_AFTER, "999".parse().unwrap());
+
+    let result = add_rate_limit_headers_with_policy(&mut headers, &test_info(), HeaderPolicy::Fail);
+
+    // Nothing is written when a header is already present
+    assert!(
+        matches!(result, Err(RateLimitError::HeaderExists(name)) if name == header::RETRY_AFTER)
+    );
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "999");
+    assert!(!headers.contains_key("X-RateLimit-Limit"));
+
+    // Without conflicts, everything is written
+    let mut empty = header::HeaderMap::new();
+    let written =
+        add_rate_limit_headers_with_policy(&mut empty, &test_info(), HeaderPolicy::Fail).unwrap();
+    assert_eq!(written.len(), 4);
+}
+
+#[test]
+fn test_rate_limit_headers_written_as_a_set() {
+    let stale = RateLimitInfo {
+        header_style: HeaderStyle::Both,
+        used_header: true,
+        ..test_info()
+    };
+    let fresh = RateLimitInfo {
+        retry_after: "5".to_string(),
+        remaining: 10,
+        used: 90,
+        reset_timestamp: 1234567895,
+        ..test_info()
+    };
+    // The handler wrote the headers of its own info, the wrapping reply rewrites them
+    let mut response = warp::reply().into_response();
+    add_rate_limit_headers(response.headers_mut(), &stale).unwrap();
+    let response = with_rate_limit_headers(response, &fresh).into_response();
+    let headers = response.headers();
+    assert_eq!(headers[header::RETRY_AFTER], "5");
+    assert_eq!(headers["X-RateLimit-Limit"], "100");
+    assert_eq!(headers["X-RateLimit-Remaining"], "10");
+    assert_eq!(headers["X-RateLimit-Reset"], "1234567895");
+    assert_eq!(headers.len(), 4);
+
+    // Kept as a whole, or not at all
+    let mut headers = stale.to_header_map().unwrap();
+    let written =
+        add_rate_limit_headers_with_policy(&mut headers, &fresh, HeaderPolicy::KeepExisting)
+            .unwrap();
+    assert!(written.is_empty());
+    assert_eq!(headers, stale.to_header_map().unwrap());
+
+    // Headers of another style are a conflict too
+    let mut headers = RateLimitInfo {
+        header_style: HeaderStyle::Standard,
+        ..test_info()
+    }
+    .to_header_map()
+    .unwrap();
+    headers.remove(header::RETRY_AFTER);
+    assert!(matches!(
+        add_rate_limit_headers_with_policy(&mut headers, &fresh, HeaderPolicy::Fail),
+        Err(RateLimitError::HeaderExists(name)) if name == "ratelimit-limit"
+    ));
+}
+
+#[test]
+fn test_with_rate_limit_headers_reply() {
+    let info = test_info();
+
+    // Composes with `json` and `with_status` and keeps their status and headers
+    let reply = warp::reply::with_status(warp::reply::json(&"hello"), StatusCode::CREATED);
+    let resp = with_rate_limit_headers(reply, &info).into_response();
+
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "100");
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "50");
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "30");
+
+    // Headers set by the inner reply survive
+    let reply = warp::reply::with_header("hello", "X-Custom", "kept");
+    let resp = with_rate_limit_headers(reply, &info).into_response();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("X-Custom").unwrap(), "kept");
+    assert!(resp.headers().contains_key("X-RateLimit-Reset"));
+}
+
+#[tokio::test]
+async fn test_with_rate_limit_headers_in_handler() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(10)).map(|info: RateLimitInfo| {
+        with_rate_limit_headers(warp::reply::json(&info.remaining), &info)
+    });
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "9");
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "9");
+}
+
+#[tokio::test]
+async fn test_rate_limit_headers_wrap() {
+    // The handler knows nothing about rate limiting
+    let route = warp::path!("hello")
+        .map(|| "Hello world")
+        .with(warp::wrap_fn(rate_limit_headers(
+            RateLimitConfig::max_per_minute(1),
+        )))
+        .recover(|rejection: Rejection| async move {
+            // Rejections reach the recover handler untouched
+            if rejection.find::<RateLimitRejection>().is_some() {
+                Ok(warp::reply::with_status(
+                    "limited",
+                    StatusCode::TOO_MANY_REQUESTS,
+                ))
+            } else {
+                Err(rejection)
+            }
+        });
+
+    let resp = request().path("/hello").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "Hello world");
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+
+    let resp = request().path("/hello").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "limited");
+}
+
+#[derive(Debug)]
+struct OtherRejection;
+impl warp::reject::Reject for OtherRejection {}
+
+async fn handle_other_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let status = if rejection.find::<OtherRejection>().is_some() {
+        StatusCode::IM_A_TEAPOT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok(warp::reply::with_status("other", status))
+}
+
+#[tokio::test]
+async fn test_handle_rate_limit_rejection() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(handle_rate_limit_rejection)
+        .recover(handle_other_rejection);
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "Rate limit exceeded");
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    assert!(resp.headers().contains_key("X-RateLimit-Reset"));
+}
+
+#[tokio::test]
+async fn test_handle_rate_limit_rejection_passes_other_rejections() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(10))
+        .and_then(|_info: RateLimitInfo| async {
+            Err::<&str, _>(warp::reject::custom(OtherRejection))
+        })
+        .recover(handle_rate_limit_rejection)
+        .recover(handle_other_rejection);
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+    assert_eq!(resp.body(), "other");
+}
+
+#[tokio::test]
+async fn test_rate_limit_recover_message() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(RateLimitRecover::new().message("Slow down!").handler())
+        .recover(handle_other_rejection);
+
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "Slow down!");
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_rate_limit_recover_json_body() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_retry_after_format(RetryAfterFormat::Seconds);
+    let route = with_rate_limit(config)
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(
+            RateLimitRecover::new()
+                .body_format(RejectionBodyFormat::Json)
+                .handler(),
+        )
+        .recover(handle_other_rejection);
+
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/json"
+    );
+
+    // The body deserializes back and agrees with the headers
+    let body: RateLimitBody = serde_json::from_slice(resp.body()).unwrap();
+    let header_value = |name| resp.headers().get(name).unwrap().to_str().unwrap();
+    assert_eq!(body.error, "rate_limited");
+    assert_eq!(body.limit.to_string(), header_value("X-RateLimit-Limit"));
+    assert_eq!(body.reset.to_string(), header_value("X-RateLimit-Reset"));
+    assert_eq!(
+        body.retry_after_seconds.to_string(),
+        header_value(header::RETRY_AFTER.as_str())
+    );
+
+    // The field names are stable
+    let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    let mut keys = json.as_object().unwrap().keys().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, ["error", "limit", "reset", "retry_after_seconds"]);
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_rate_limit_recover_problem_json_body() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(
+            RateLimitRecover::new()
+                .body_format(RejectionBodyFormat::ProblemJson {
+                    type_uri: "https://example.com/probs/rate-limited".to_owned(),
+                })
+                .handler(),
+        )
+        .recover(handle_other_rejection);
+
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/problem+json"
+    );
+
+    let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(json["type"], "https://example.com/probs/rate-limited");
+    assert_eq!(json["title"], "Too Many Requests");
+    assert_eq!(json["status"], 429);
+    assert!(json["detail"]
+        .as_str()
+        .unwrap()
+        .contains("Rate limit exceeded"));
+
+    // Extension members
+    assert_eq!(json["limit"], 1);
+    assert_eq!(json["remaining"], 0);
+    assert_eq!(
+        json["reset"].to_string(),
+        resp.headers()
+            .get("X-RateLimit-Reset")
+            .unwrap()
+            .to_str()
+            .unwrap()
+    );
+
+    // The default problem type is about:blank
+    assert_eq!(
+        RejectionBodyFormat::problem_json(),
+        RejectionBodyFormat::ProblemJson {
+            type_uri: "about:blank".to_owned()
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_rate_limit_recover_custom_response() {
+    let recover = RateLimitRecover::new().response_with(|info: &RateLimitInfo| {
+        let html = format!("<h1>Slow down</h1><p>Limit: {}</p>", info.limit);
+        let reply =
+            warp::reply::with_status(warp::reply::html(html), StatusCode::TOO_MANY_REQUESTS);
+        warp::reply::with_header(reply, "X-Custom", "yes").into_response()
+    });
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(recover.clone().handler())
+        .recover(handle_other_rejection);
+
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "<h1>Slow down</h1><p>Limit: 1</p>");
+    assert_eq!(
+        resp.headers().get(header::CONTENT_TYPE).unwrap(),
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(resp.headers().get("X-Custom").unwrap(), "yes");
+    // The standard headers are still added
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+    // Unless disabled
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(recover.headers(false).handler())
+        .recover(handle_other_rejection);
+
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("X-Custom").unwrap(), "yes");
+    assert!(!resp.headers().contains_key("X-RateLimit-Limit"));
+    assert!(!resp.headers().contains_key(header::RETRY_AFTER));
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_rate_limit_recover_status() {
+    // 429 by default
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(handle_rate_limit_rejection);
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // 503 keeps Retry-After and the other headers
+    let recover = RateLimitRecover::new()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .unwrap()
+        .body_format(RejectionBodyFormat::problem_json());
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(recover.handler());
+    let _ = request().reply(&route).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    let json: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(json["status"], 503);
+    assert_eq!(json["title"], "Service Unavailable");
+
+    // Non error statuses are refused
+    for status in [StatusCode::OK, StatusCode::MOVED_PERMANENTLY] {
+        assert_eq!(
+            RateLimitRecover::new().status(status).unwrap_err(),
+            ConfigError::InvalidRejectionStatus(status)
+        );
+    }
+}
+
+fn fixed_rejection() -> RateLimitRejection {
+    let now = system_now();
+    RateLimitRejection {
+        retry_after: Duration::from_secs(42),
+        limit: 10,
+        used: 10,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
+        reset_time: now + Duration::from_secs(42),
+        first_rejected_at: None,
+        rejected_at: now,
+        rejected_instant: Instant::now(),
+        retry_after_format: RetryAfterFormat::Seconds,
+        retry_after_jitter: Duration::ZERO,
+        max_retry_after: None,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        config_index: None,
+    }
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_too_many_requests_matches_recover() {
+    let rejection = fixed_rejection();
+    let (reset_time, rejected_at) = (rejection.reset_time, rejection.rejected_at);
+    let make_rejection = move || RateLimitRejection {
+        reset_time,
+        rejected_at,
+        ..fixed_rejection()
+    };
+
+    for recover in [
+        RateLimitRecover::new(),
+        RateLimitRecover::new().body_format(RejectionBodyFormat::Json),
+        RateLimitRecover::new().body_format(RejectionBodyFormat::problem_json()),
+    ] {
+        let recover_route = warp::any()
+            .and_then(
+                move || async move { Err::<String, _>(warp::reject::custom(make_rejection())) },
+            )
+            .recover(recover.clone().handler());
+        let recover_for_reply = recover.clone();
+        let reply_route = warp::any().map(move || {
+            TooManyRequests::from(&make_rejection()).with_recover(recover_for_reply.clone())
+        });
+
+        let expected = request().reply(&recover_route).await;
+        let actual = request().reply(&reply_route).await;
+
+        assert_eq!(actual.status(), expected.status());
+        assert_eq!(actual.headers(), expected.headers());
+        assert_eq!(actual.body(), expected.body());
+    }
+
+    // from_info uses the default recover settings
+    let resp = TooManyRequests::from_info(get_rate_limit_info(&rejection)).into_response();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get(header::RETRY_AFTER).unwrap(), "42");
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "10");
+}
+
+#[tokio::test]
+async fn test_retry_after_jitter() {
+    for retry_after_format in [RetryAfterFormat::Seconds, RetryAfterFormat::HttpDate] {
+        let config = RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_window(Duration::from_secs(10))
+            .with_retry_after_format(retry_after_format.clone())
+            .with_retry_after_jitter(Duration::from_secs(4))
+            .with_jitter_source(|max| max / 2);
+        let route = with_rate_limit(config)
+            .map(|info: RateLimitInfo| with_rate_limit_headers("ok", &info))
+            .recover(handle_rate_limit_rejection);
+
+        // The emitted wait is the window plus the jitter, for successes and rejections
+        let before = system_now();
+        let ok = request().reply(&route).await;
+        let limited = request().reply(&route).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for resp in [ok, limited] {
+            let retry_after = resp.headers().get(header::RETRY_AFTER).unwrap();
+            let retry_after = retry_after.to_str().unwrap();
+            let reset: i64 = resp.headers()["X-RateLimit-Reset"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            let retry_at = match retry_after_format {
+                RetryAfterFormat::Seconds => {
+                    let seconds: i64 = retry_after.parse().unwrap();
+                    assert_eq!(seconds, 12);
+                    before.timestamp() + seconds
+                }
+                RetryAfterFormat::HttpDate => http_date_secs(retry_after),
+            };
+            // Never earlier than the real reset, and pushed back by the jitter
+            assert!(retry_at >= reset);
+            assert!(retry_at >= before.timestamp() + 12);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_random_retry_after_jitter_bounds() {
+    // The built-in random source stays within the configured bound
+    let route = with_rate_limit(
+        RateLimitConfig::default()
+            .with_retry_after_format(RetryAfterFormat::Seconds)
+            .with_retry_after_jitter(Duration::from_secs(3)),
+    )
+    .map(|info: RateLimitInfo| info.retry_after);
+
+    for _ in 0..50 {
+        let resp = request().reply(&route).await;
+        let seconds: u64 = std::str::from_utf8(resp.body()).unwrap().parse().unwrap();
+        assert!((60..=63).contains(&seconds));
+    }
+}
+
+#[tokio::test]
+async fn test_max_retry_after() {
+    let day = Duration::from_secs(24 * 60 * 60);
+
+    for retry_after_format in [RetryAfterFormat::Seconds, RetryAfterFormat::HttpDate] {
+        let config = RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_window(day)
+            .with_retry_after_format(retry_after_format.clone())
+            .with_max_retry_after(Duration::from_secs(60));
+        let route = with_rate_limit(config)
+            .map(|info: RateLimitInfo| with_rate_limit_headers("ok", &info))
+            .recover(handle_rate_limit_rejection);
+
+        let before = system_now().timestamp();
+        let ok = request().reply(&route).await;
+        let limited = request().reply(&route).await;
+        let after = system_now().timestamp();
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for resp in [ok, limited] {
+            let retry_after = resp.headers()[header::RETRY_AFTER].to_str().unwrap();
+            match retry_after_format {
+                RetryAfterFormat::Seconds => assert_eq!(retry_after, "60"),
+                RetryAfterFormat::HttpDate => {
+                    let retry_at = http_date_secs(retry_after);
+                    assert!(retry_at >= before + 60);
+                    assert!(retry_at <= after + 61);
+                }
+            }
+
+            // The reset timestamp is not clamped
+            let reset: i64 = resp.headers()["X-RateLimit-Reset"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(reset >= before + day.as_secs() as i64 - 1);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_reset_stays_the_same_within_a_window() {
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(3));
+    let mut resets = Vec::new();
+    for _ in 0..3 {
+        let info = request().filter(&filter).await.unwrap();
+        resets.push((info.reset_timestamp, info.reset_at));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+    }
+    assert!(resets.iter().all(|reset| *reset == resets[0]));
+
+    // The rejection reports the same reset
+    let rejection = request().filter(&filter).await.unwrap_err();
+    let rejection = rejection.find::<RateLimitRejection>().unwrap();
+    assert_eq!(rejection.reset_time, resets[0].1);
+}
+
+#[tokio::test]
+async fn test_reset_at_matches_reset_timestamp() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|info: RateLimitInfo| {
+            assert_eq!(info.reset_at.timestamp(), info.reset_timestamp);
+            "ok"
+        })
+        .recover(|rejection: Rejection| async move {
+            let info = get_rate_limit_info(rejection.find::<RateLimitRejection>().unwrap());
+            assert_eq!(info.reset_at.timestamp(), info.reset_timestamp);
+            Ok::<_, Infallible>("limited")
+        });
+
+    assert_eq!(request().reply(&route).await.body(), "ok");
+    assert_eq!(request().reply(&route).await.body(), "limited");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_rate_limit_info_serialization() {
+    let json = serde_json::to_value(test_info()).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "retry_after": "30",
+            "retry_after_duration": {"secs": 30, "nanos": 0},
+            "limit": 100,
+            "window": {"secs": 60, "nanos": 0},
+            "tier": null,
+            "remaining": 50,
+            "used": 50,
+            "reset_timestamp": 1234567890,
+            "reset_at": "2009-02-13T23:31:30Z",
+            "first_rejected_at": null,
+            "retry_after_format": "Seconds",
+            "header_style": "Legacy",
+            "used_header": false,
+            "internal_map_len": 0,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_rejection_usage_details() {
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(2));
+    for _ in 0..2 {
+        let info = request().filter(&filter).await.unwrap();
+        assert_eq!(info.first_rejected_at, None);
+    }
+
+    let rejection = |rejection: Rejection| rejection.find::<RateLimitRejection>().unwrap().clone();
+    let first = rejection(request().filter(&filter).await.unwrap_err());
+    assert_eq!(first.used, 2);
+    assert_eq!(first.window, Duration::from_secs(60));
+    let first_rejected_at = first.first_rejected_at.unwrap();
+    assert!(first_rejected_at <= system_now());
+
+    // Stays fixed across the following rejections of the window
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let next = rejection(request().filter(&filter).await.unwrap_err());
+        assert_eq!(next.first_rejected_at, Some(first_rejected_at));
+        assert_eq!(next.used, 2);
+    }
+
+    let info = RateLimitInfo::from(&first);
+    assert_eq!(info.used, 2);
+    assert_eq!(info.first_rejected_at, Some(first_rejected_at));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_rate_limit_rejection_serialization() {
+    let rejection = RateLimitRejection {
+        retry_after: Duration::from_millis(1500),
+        tier: Some(String::from("gold")),
+        key: Some(String::from("1.1.1.1")),
+        label: Some("login".into()),
+        reset_time: Timestamp::from_timestamp(1_234_567_890, 0).unwrap(),
+        max_retry_after: Some(Duration::from_secs(30)),
+        config_index: Some(1),
+        ..fixed_rejection()
+    };
+    assert_eq!(
+        serde_json::to_value(&rejection).unwrap(),
+        serde_json::json!({
+            "retry_after": 1.5,
+            "limit": 10,
+            "used": 10,
+            "window": 60.0,
+            "tier": "gold",
+            "key": "1.1.1.1",
+            "label": "login",
+            "reset_time": "2009-02-13T23:31:30Z",
+            "first_rejected_at": null,
+            "retry_after_format": "Seconds",
+            "retry_after_jitter": 0.0,
+            "max_retry_after": 30.0,
+            "header_style": "Legacy",
+            "used_header": false,
+            "config_index": 1,
+        })
+    );
+
+    // Owned copies can be handed over, e.g. to an audit pipeline
+    assert_eq!(rejection.clone(), rejection);
+}
+
+#[test]
+fn test_header_styles() {
+    let mut info = test_info();
+    info.checked_at = system_now();
+    info.reset_at = info.checked_at + Duration::from_secs(30);
+    info.reset_timestamp = info.reset_at.timestamp();
+
+    // Standard headers only
+    info.header_style = HeaderStyle::Standard;
+    let mut headers = header::HeaderMap::new();
+    add_rate_limit_headers(&mut headers, &info).unwrap();
+    assert_eq!(headers.get("RateLimit-Limit").unwrap(), "100");
+    assert_eq!(headers.get("RateLimit-Remaining").unwrap(), "50");
+    assert!(headers.contains_key("RateLimit-Reset"));
+    assert!(headers.contains_key(header::RETRY_AFTER));
+    assert!(!headers.contains_key("X-RateLimit-Limit"));
+
+    // Both families, with consistent values
+    info.header_style = HeaderStyle::Both;
+    let mut headers = header::HeaderMap::new();
+    let written =
+        add_rate_limit_headers_with_policy(&mut headers, &info, HeaderPolicy::Overwrite).unwrap();
+    assert_eq!(written.len(), 7);
+
+    let value = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_owned();
+    assert_eq!(value("X-RateLimit-Limit"), value("RateLimit-Limit"));
+    assert_eq!(value("X-RateLimit-Remaining"), value("RateLimit-Remaining"));
+
+    // X-RateLimit-Reset is a timestamp, RateLimit-Reset the delta until it
+    let reset_timestamp: i64 = value("X-RateLimit-Reset").parse().unwrap();
+    let reset_delta: i64 = value("RateLimit-Reset").parse().unwrap();
+    assert_eq!(reset_timestamp, info.reset_timestamp);
+    assert!((29..=30).contains(&reset_delta));
+    assert!((reset_timestamp - system_now().timestamp() - reset_delta).abs() <= 1);
+}
+
+#[tokio::test]
+async fn test_header_style_config() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_header_style(HeaderStyle::Both);
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| with_rate_limit_headers("ok", &info))
+        .recover(handle_rate_limit_rejection);
+
+    for status in [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.status(), status);
+        assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+        assert_eq!(resp.headers().get("RateLimit-Limit").unwrap(), "1");
+        assert_eq!(resp.headers().get("RateLimit-Remaining").unwrap(), "0");
+    }
+}
+
+#[test]
+fn test_to_header_map() {
+    let info = test_info();
+
+    let headers = info.to_header_map().unwrap();
+    assert_eq!(headers.len(), 4);
+    assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "30");
+    assert_eq!(headers.get("X-RateLimit-Limit").unwrap(), "100");
+    assert_eq!(headers.get("X-RateLimit-Remaining").unwrap(), "50");
+    assert_eq!(headers.get("X-RateLimit-Reset").unwrap(), "1234567890");
+
+    // extend_header_map keeps unrelated headers and matches the free function
+    let mut extended = header::HeaderMap::new();
+    extended.insert("X-Custom", "kept".parse().unwrap());
+    info.extend_header_map(&mut extended).unwrap();
+    let mut added = header::HeaderMap::new();
+    added.insert("X-Custom", "kept".parse().unwrap());
+    add_rate_limit_headers(&mut added, &info).unwrap();
+    assert_eq!(extended, added);
+    assert_eq!(extended.len(), 5);
+
+    // The header style carried by the info is honored
+    let standard = RateLimitInfo {
+        header_style: HeaderStyle::Standard,
+        ..info
+    };
+    let headers = standard.to_header_map().unwrap();
+    assert!(headers.contains_key("RateLimit-Limit"));
+    assert!(!headers.contains_key("X-RateLimit-Limit"));
+}
+
+#[tokio::test]
+async fn test_used_count() {
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let config = RateLimitConfig::max_per_minute(3)
+        .with_used_header(true)
+        .with_clock(clock.clone());
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| with_rate_limit_headers(info.used.to_string(), &info))
+        .recover(handle_rate_limit_rejection);
+
+    // Increments across consecutive requests within a window
+    for used in 1..=3 {
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.body(), &used.to_string());
+        assert_eq!(
+            resp.headers().get("X-RateLimit-Used").unwrap(),
+            &used.to_string()
+        );
+    }
+
+    // Rejections report the whole limit as used
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("X-RateLimit-Used").unwrap(), "3");
+
+    // Resets after the window expires
+    clock.advance(Duration::from_secs(61));
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.body(), "1");
+
+    // The header is opt-in
+    let headers = test_info().to_header_map().unwrap();
+    assert!(!headers.contains_key("X-RateLimit-Used"));
+}
+
+#[tokio::test]
+async fn test_idle_ttl() {
+    async fn map_len(route: &warp::filters::BoxedFilter<(String,)>, client: &str) -> String {
+        let resp = request().header("X-Client", client).reply(route).await;
+        String::from_utf8(resp.body().to_vec()).unwrap()
+    }
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let route = |config: RateLimitConfig| {
+        with_rate_limit(config.with_ip_header("X-Client"))
+            .map(|info: RateLimitInfo| info.internal_map_len.to_string())
+            .boxed()
+    };
+    let config = RateLimitConfig::max_per_minute(60).with_clock(clock.clone());
+
+    // By default, idle clients are pruned once their window is over
+    let default = route(config.clone());
+    assert_eq!(map_len(&default, "1.1.1.1").await, "1");
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(map_len(&default, "2.2.2.2").await, "1");
+
+    // With a longer ttl, they outlive their window by the ttl
+    let longer = route(config.with_idle_ttl(Duration::from_secs(180)));
+    assert_eq!(map_len(&longer, "1.1.1.1").await, "1");
+    clock.advance(Duration::from_secs(120));
+    assert_eq!(map_len(&longer, "2.2.2.2").await, "2");
+
+    // And no longer
+    clock.advance(Duration::from_secs(61));
+    assert_eq!(map_len(&longer, "3.3.3.3").await, "2");
+}
+
+#[tokio::test]
+async fn test_rejection_message_template() {
+    async fn rejected_body(recover: RateLimitRecover) -> String {
+        let config = RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_retry_after_format(RetryAfterFormat::Seconds);
+        let route = with_rate_limit(config)
+            .map(|_| "ok")
+            .recover(recover.handler());
+        request().reply(&route).await;
+        let resp = request().reply(&route).await;
+        String::from_utf8(resp.body().to_vec()).unwrap()
+    }
+
+    let recover = RateLimitRecover::new()
+        .rejection_message(
+            "Too many requests. Retry after {retry_after} (limit {limit}/{window}s).",
+        )
+        .unwrap();
+    assert_eq!(
+        rejected_body(recover.clone()).await,
+        "Too many requests. Retry after 60 (limit 1/60s)."
+    );
+
+    // Every placeholder, plus escaped braces
+    let template = RateLimitRecover::new()
+        .rejection_message("{{{used}/{limit}}} {remaining} left, reset at {reset}")
+        .unwrap();
+    let body = rejected_body(template).await;
+    let reset = body.strip_prefix("{1/1} 0 left, reset at ").unwrap();
+    assert!(reset.parse::<i64>().unwrap() > system_now().timestamp());
+
+    // A plain message replaces the template
+    assert_eq!(
+        rejected_body(recover.message("Slow down")).await,
+        "Slow down"
+    );
+}
+
+#[test]
+fn test_rejection_message_template_errors() {
+    assert_eq!(
+        RateLimitRecover::new()
+            .rejection_message("Retry in {retry_in}")
+            .unwrap_err(),
+        ConfigError::UnknownPlaceholder(String::from("retry_in"))
+    );
+    assert_eq!(
+        RateLimitRecover::new()
+            .rejection_message("Retry after {retry_after")
+            .unwrap_err(),
+        ConfigError::UnbalancedBrace(12)
+    );
+    assert_eq!(
+        RateLimitRecover::new()
+            .rejection_message("limit}")
+            .unwrap_err(),
+        ConfigError::UnbalancedBrace(5)
+    );
+}
+
+#[tokio::test]
+async fn test_retry_after_duration() {
+    for format in [RetryAfterFormat::Seconds, RetryAfterFormat::HttpDate] {
+        let config = RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_window(Duration::from_millis(2500))
+            .with_retry_after_format(format.clone());
+        let route = with_rate_limit(config)
+            .map(|info: RateLimitInfo| {
+                assert_eq!(info.retry_after_duration, Duration::from_secs(3));
+                "ok"
+            })
+            .recover(|rejection: Rejection| async move {
+                let info = get_rate_limit_info(rejection.find::<RateLimitRejection>().unwrap());
+                Ok::<_, Infallible>(info.retry_after_duration.as_secs().to_string())
+            });
+
+        assert_eq!(request().reply(&route).await.body(), "ok");
+        assert_eq!(request().reply(&route).await.body(), "3");
+    }
+
+    // The string and the duration agree
+    let info = get_rate_limit_info(&fixed_rejection());
+    assert_eq!(info.retry_after, "42");
+    assert_eq!(info.retry_after_duration, Duration::from_secs(42));
+
+    let mut rejection = fixed_rejection();
+    rejection.retry_after_format = RetryAfterFormat::HttpDate;
+    let info = get_rate_limit_info(&rejection);
+    let at = http_date_secs(&info.retry_after);
+    assert_eq!(at, rejection.reset_time.timestamp() + 1);
+    assert_eq!(info.retry_after_duration, Duration::from_secs(42));
+}
+
+#[tokio::test]
+async fn test_retry_after_shrinks_within_window() {
+    let config = |format| {
+        RateLimitConfig::default()
+            .with_max_requests(3)
+            .with_window(Duration::from_millis(2500))
+            .with_retry_after_format(format)
+    };
+    let filter = with_rate_limit(config(RetryAfterFormat::Seconds));
+    let first = request().filter(&filter).await.unwrap();
+    assert_eq!(first.retry_after, "3");
+    tokio::time::sleep(Duration::from_millis(1600)).await;
+
+    // Told the time left in the window, like the reset headers and the 429 say
+    let second = request().filter(&filter).await.unwrap();
+    assert_eq!(second.retry_after, "1");
+    assert_eq!(second.retry_after_duration, Duration::from_secs(1));
+    request().filter(&filter).await.unwrap();
+    let rejection = request().filter(&filter).await.unwrap_err();
+    let rejected = get_rate_limit_info(rejection.find::<RateLimitRejection>().unwrap());
+    assert_eq!(rejected.retry_after, second.retry_after);
+
+    // The date stays on the reset of the window
+    let filter = with_rate_limit(config(RetryAfterFormat::HttpDate));
+    let first = request().filter(&filter).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let second = request().filter(&filter).await.unwrap();
+    assert_eq!(second.retry_after, first.retry_after);
+    assert_eq!(second.retry_after_duration, Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_zero_max_requests_blocks_everything() {
+    let config =
+        RateLimitConfig::max_per_minute(0).with_key_override("5.6.7.8", 2, Duration::from_secs(60));
+    let filter = with_rate_limit(config);
+    for _ in 0..3 {
+        let rejection = request()
+            .header("X-Forwarded-For", "1.2.3.4")
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        let rejection = rejection.find::<RateLimitRejection>().unwrap();
+        assert_eq!((rejection.limit, rejection.used), (0, 0));
+        // Told to come back once the window is over
+        assert!(rejection.retry_after > Duration::from_secs(59));
+    }
+    let info = request()
+        .header("X-Forwarded-For", "5.6.7.8")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.remaining, 1);
+
+    // And a client with an override of 0 is blocked on its own
+    let config =
+        RateLimitConfig::max_per_minute(5).with_key_override("1.2.3.4", 0, Duration::from_secs(60));
+    let filter = with_rate_limit(config);
+    assert!(request()
+        .header("X-Forwarded-For", "1.2.3.4")
+        .filter(&filter)
+        .await
+        .is_err());
+    assert!(request()
+        .header("X-Forwarded-For", "5.6.7.8")
+        .filter(&filter)
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn test_rejection_info_counts_from_the_rejection() {
+    let mut rejection = fixed_rejection();
+    rejection.reset_time = rejection.rejected_at + Duration::from_millis(2500);
+    assert_eq!(get_rate_limit_info(&rejection).retry_after, "3");
+
+    // A handler running late tells the same wait, from the clock of the limiter
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let info = get_rate_limit_info(&rejection);
+    assert_eq!(info.retry_after, "3");
+    assert_eq!(info.retry_after_duration, Duration::from_secs(3));
+    assert_eq!(info.checked_at, rejection.rejected_at);
+    assert_eq!(info.reset_timestamp, rejection.reset_time.timestamp());
+
+    // The HTTP date stays on the reset time
+    rejection.retry_after_format = RetryAfterFormat::HttpDate;
+    let at = http_date_secs(&get_rate_limit_info(&rejection).retry_after);
+    assert!((at - rejection.reset_time.timestamp()) <= 1);
+    assert!(at >= rejection.reset_time.timestamp());
+
+    // Past the reset, the wait is clamped at zero
+    rejection.retry_after_format = RetryAfterFormat::Seconds;
+    rejection.reset_time = rejection.rejected_at - Duration::from_secs(5);
+    let info = get_rate_limit_info(&rejection);
+    assert_eq!(info.retry_after, "0");
+    assert_eq!(info.retry_after_duration, Duration::ZERO);
+}
+
+#[test]
+fn test_config_builder() {
+    let config = RateLimitConfig::builder()
+        .max_requests(100)
+        .window(Duration::from_secs(30))
+        .retry_after_format(RetryAfterFormat::Seconds)
+        .header_style(HeaderStyle::Both)
+        .ip_header("X-Real-IP")
+        .build()
+        .unwrap();
+    assert_eq!(config.max_requests, 100);
+    assert_eq!(config.window, Duration::from_secs(30));
+    assert_eq!(config.retry_after_format, RetryAfterFormat::Seconds);
+    assert_eq!(config.header_style, HeaderStyle::Both);
+    assert_eq!(config.ip_header.as_deref(), Some("X-Real-IP"));
+
+    assert_eq!(
+        RateLimitConfig::builder().build().unwrap(),
+        RateLimitConfig::default()
+    );
+    assert_eq!(
+        RateLimitConfig::max_per_window(10, 20),
+        RateLimitConfig::builder()
+            .max_requests(10)
+            .window(Duration::from_secs(20))
+            .build()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_config_builder_validation() {
+    let error = |builder: RateLimitConfigBuilder| builder.build().unwrap_err();
+
+    assert_eq!(
+        error(RateLimitConfig::builder().window(Duration::ZERO)),
+        ConfigError::ZeroWindow
+    );
+    assert_eq!(
+        error(RateLimitConfig::builder().ip_header("")),
+        ConfigError::InvalidIpHeader(String::new())
+    );
+    assert_eq!(
+        error(RateLimitConfig::builder().ip_header("X Real IP")),
+        ConfigError::InvalidIpHeader(String::from("X Real IP"))
+    );
+    assert_eq!(
+        error(RateLimitConfig::builder().max_retry_after(Duration::ZERO)),
+        ConfigError::ZeroMaxRetryAfter
+    );
+    assert_eq!(
+        error(RateLimitConfig::builder().jitter_source(|max| max / 2)),
+        ConfigError::JitterSourceWithoutJitter
+    );
+    assert!(RateLimitConfig::builder()
+        .retry_after_jitter(Duration::from_secs(5))
+        .jitter_source(|max| max / 2)
+        .build()
+        .is_ok());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_config_deserialize_toml() {
+    let config: RateLimitConfig = toml::from_str(
+        r#"
+        max_requests = 100
+        window = "5m"
+        retry_after_format = "Seconds"
+        max_retry_after = "1h30m"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config,
+        RateLimitConfig::default()
+            .with_max_requests(100)
+            .with_window(Duration::from_secs(300))
+            .with_retry_after_format(RetryAfterFormat::Seconds)
+            .with_max_retry_after(Duration::from_secs(5400))
+    );
+
+    // Integer seconds, and the other spelling of the format
+    let config: RateLimitConfig = toml::from_str(
+        r#"
+        window = 90
+        retry_after_format = "HttpDate"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.window, Duration::from_secs(90));
+    assert_eq!(config.retry_after_format, RetryAfterFormat::HttpDate);
+    assert_eq!(config.max_requests, 60);
+
+    // Round trip
+    let config = RateLimitConfig::default()
+        .with_window(Duration::from_millis(1500))
+        .with_retry_after_jitter(Duration::from_secs(2))
+        .with_header_style(HeaderStyle::Both);
+    let text = toml::to_string(&config).unwrap();
+    assert!(text.contains(r#"window = "1500ms""#));
+    assert_eq!(toml::from_str::<RateLimitConfig>(&text).unwrap(), config);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_config_deserialize_json() {
+    let config: RateLimitConfig = serde_json::from_str(
+        r#"{"max_requests": 10, "window": "90s", "retry_after_format": "Seconds", "ip_header": "X-Real-IP"}"#,
+    )
+    .unwrap();
+    assert_eq!(config.max_requests, 10);
+    assert_eq!(config.window, Duration::from_secs(90));
+    assert_eq!(config.retry_after_format, RetryAfterFormat::Seconds);
+    assert_eq!(config.ip_header.as_deref(), Some("X-Real-IP"));
+
+    let config = RateLimitConfig::per_day(1000);
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["window"], "1d");
+    assert_eq!(json["retry_after_format"], "HttpDate");
+    assert_eq!(
+        serde_json::from_value::<RateLimitConfig>(json).unwrap(),
+        config
+    );
+
+    // Typos and invalid durations are errors
+    assert!(serde_json::from_str::<RateLimitConfig>(r#"{"max_request": 10}"#).is_err());
+    assert!(serde_json::from_str::<RateLimitConfig>(r#"{"window": "5 minutes"}"#).is_err());
+    assert!(serde_json::from_str::<RateLimitConfig>(r#"{"window": "5"}"#).is_err());
+    assert!(serde_json::from_str::<RateLimitConfig>(r#"{"window": ""}"#).is_err());
+}
+
+#[cfg(all(feature = "serde", not(feature = "geoip")))]
+#[test]
+fn test_config_serialize_snapshot() {
+    let config = RateLimitConfig::max_per_minute(100)
+        .with_retry_after_jitter(Duration::from_secs(5))
+        .with_jitter_source(|max| max / 2)
+        .with_idle_ttl(Duration::from_secs(90))
+        .with_health_max_keys(50_000)
+        .with_health_max_prune_age(Duration::from_secs(300))
+        .with_key_override("10.0.0.1", 10, Duration::from_secs(1))
+        .with_tier("gold", RateLimitConfig::per_second(5))
+        .with_tier_classifier(|_| Some("gold"))
+        .with_rejection_mapper(warp::reject::custom)
+        .with_on_rejected(|_| {})
+        .with_on_allowed(|_| {})
+        .with_on_key_count_exceeds(10_000, |_| {})
+        .with_heavy_hitters(20)
+        .with_measure_latency(true)
+        .with_heavy_usage(0.5)
+        .with_audit_sink(AuditRing::new(10))
+        .with_clock(SystemClock)
+        .with_store(store::MemoryStore::new())
+        .with_trusted_proxies(["10.0.0.0/8".parse().unwrap()]);
+    let gold = serde_json::json!({
+        "label": null,
+        "max_requests": 5,
+        "window": "1s",
+        "retry_after_format": "HttpDate",
+        "retry_after_jitter": null,
+        "jitter_source": null,
+        "header_style": "Legacy",
+        "used_header": false,
+        "redact_key": false,
+        "measure_latency": false,
+        "rejection_mapper": null,
+        "on_rejected": null,
+        "on_allowed": null,
+        "key_count_threshold": null,
+        "on_key_count_exceeds": null,
+        "heavy_hitters": null,
+        "heavy_usage": null,
+        "audit_sink": null,
+        "clock": null,
+        "store": null,
+        "max_retry_after": null,
+        "idle_ttl": null,
+        "health_max_keys": null,
+        "health_max_prune_age": null,
+        "overrides": {},
+        "tiers": [],
+        "tier_classifier": null,
+        "ip_header": "X-Forwarded-For",
+        "trusted_proxies": null,
+        "unknown_bucket": false,
+    });
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "label": null,
+            "max_requests": 100,
+            "window": "1m",
+            "retry_after_format": "HttpDate",
+            "retry_after_jitter": "5s",
+            "jitter_source": "<function>",
+            "header_style": "Legacy",
+            "used_header": false,
+            "redact_key": false,
+            "measure_latency": true,
+            "rejection_mapper": "<function>",
+            "on_rejected": "<function>",
+            "on_allowed": "<function>",
+            "key_count_threshold": 10000,
+            "on_key_count_exceeds": "<function>",
+            "heavy_hitters": 20,
+            "heavy_usage": 0.5,
+            "audit_sink": "<sink>",
+            "clock": "<clock>",
+            "store": "<store>",
+            "max_retry_after": null,
+            "idle_ttl": "90s",
+            "health_max_keys": 50000,
+            "health_max_prune_age": "5m",
+            "overrides": { "10.0.0.1": "10/1s" },
+            "tiers": [["gold", gold]],
+            "tier_classifier": "<function>",
+            "ip_header": "X-Forwarded-For",
+            "trusted_proxies": ["10.0.0.0/8"],
+            "unknown_bucket": false,
+        })
+    );
+
+    // Reads back, without the functions
+    let read_back: RateLimitConfig = serde_json::from_value(json).unwrap();
+    let mut expected = config;
+    expected.jitter_source = None;
+    expected.tier_classifier = None;
+    expected.rejection_mapper = None;
+    expected.on_rejected = None;
+    expected.on_allowed = None;
+    expected.on_key_count_exceeds = None;
+    expected.audit_sink = None;
+    expected.clock = None;
+    expected.store = None;
+    assert_eq!(read_back, expected);
+}
+
+#[test]
+fn test_absurd_window_is_a_config_error() {
+    let absurd = Duration::from_secs(u64::MAX);
+    assert_eq!(
+        try_with_rate_limit(RateLimitConfig::default().with_window(absurd)).err(),
+        Some(ConfigError::WindowTooLarge(absurd))
+    );
+    // Even along with jitter, and long before the clocks would overflow
+    let config = RateLimitConfig::default()
+        .with_window(Duration::MAX)
+        .with_retry_after_jitter(Duration::from_secs(1));
+    assert_eq!(
+        config.validate(),
+        Err(ConfigError::WindowTooLarge(Duration::MAX))
+    );
+    assert_eq!(
+        "10/40000d".parse::<RateLimitConfig>(),
+        Err(ConfigError::WindowTooLarge(Duration::from_secs(
+            40000 * 24 * 60 * 60
+        )))
+    );
+    assert!("10/3650d".parse::<RateLimitConfig>().is_ok());
+}
+
+#[test]
+fn test_config_validation() {
+    let invalid = [
+        (
+            RateLimitConfig::default().with_window(Duration::ZERO),
+            ConfigError::ZeroWindow,
+        ),
+        (
+            RateLimitConfig::default().with_window(Duration::MAX),
+            ConfigError::WindowTooLarge(Duration::MAX),
+        ),
+        (
+            RateLimitConfig::default().with_ip_header(String::new()),
+            ConfigError::InvalidIpHeader(String::new()),
+        ),
+        (
+            RateLimitConfig::default().with_ip_header(String::from("X-Forwarded-For:")),
+            ConfigError::InvalidIpHeader(String::from("X-Forwarded-For:")),
+        ),
+        (
+            RateLimitConfig::default().with_max_retry_after(Duration::ZERO),
+            ConfigError::ZeroMaxRetryAfter,
+        ),
+        (
+            RateLimitConfig::default().with_idle_ttl(Duration::ZERO),
+            ConfigError::ZeroIdleTtl,
+        ),
+        (
+            RateLimitConfig::default().with_health_max_prune_age(Duration::from_secs(60)),
+            ConfigError::HealthPruneAgeTooShort(Duration::from_secs(60)),
+        ),
+        (
+            RateLimitConfig::default().with_heavy_hitters(0),
+            ConfigError::ZeroHeavyHitters,
+        ),
+        (
+            RateLimitConfig::default().with_heavy_usage(0.0),
+            ConfigError::InvalidHeavyUsage,
+        ),
+        (
+            RateLimitConfig::default().with_heavy_usage(1.5),
+            ConfigError::InvalidHeavyUsage,
+        ),
+        (
+            RateLimitConfig::default().with_jitter_source(|max| max / 2),
+            ConfigError::JitterSourceWithoutJitter,
+        ),
+    ];
+
+    for (config, error) in invalid {
+        assert_eq!(config.validate(), Err(error.clone()));
+        assert_eq!(try_with_rate_limit(config).err(), Some(error.clone()));
+        assert!(matches!(
+            RateLimitError::from(error.clone()),
+            RateLimitError::Config(e) if e == error
+        ));
+    }
+
+    assert_eq!(RateLimitConfig::default().validate(), Ok(()));
+    assert!(try_with_rate_limit(RateLimitConfig::per_day(1)).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "Invalid rate limit configuration")]
+fn test_with_rate_limit_panics_on_invalid_config() {
+    let _ = with_rate_limit(RateLimitConfig::default().with_window(Duration::ZERO));
+}
+
+#[test]
+fn test_parse_limit() {
+    let valid = [
+        ("100/1m", 100, Duration::from_secs(60)),
+        ("30/10s", 30, Duration::from_secs(10)),
+        (" 5 / s ", 5, Duration::from_secs(1)),
+        ("1000/h", 1000, Duration::from_secs(3600)),
+        ("10000/1d", 10000, Duration::from_secs(86400)),
+        ("50/1h30m", 50, Duration::from_secs(5400)),
+        ("2/500ms", 2, Duration::from_millis(500)),
+        // Blocks every request
+        ("0/1m", 0, Duration::from_secs(60)),
+    ];
+    for (input, max_requests, window) in valid {
+        assert_eq!(parse_limit(input), Ok((max_requests, window)), "{}", input);
+        let config: RateLimitConfig = input.parse().unwrap();
+        assert_eq!(config.max_requests, max_requests);
+        assert_eq!(config.window, window);
+        assert_eq!(config.ip_header.as_deref(), Some("X-Forwarded-For"));
+    }
+
+    let invalid = [
+        ("100", "missing '/'"),
+        ("", "missing '/'"),
+        ("/1m", "COUNT is not a non-negative integer"),
+        ("-1/1m", "COUNT is not a non-negative integer"),
+        ("ten/1m", "COUNT is not a non-negative integer"),
+        (
+            "100/",
+            "DURATION is not a duration like \"10s\" or \"1h30m\"",
+        ),
+        (
+            "100/1",
+            "DURATION is not a duration like \"10s\" or \"1h30m\"",
+        ),
+        (
+            "100/1 minute",
+            "DURATION is not a duration like \"10s\" or \"1h30m\"",
+        ),
+        (
+            "100/1m/1h",
+            "DURATION is not a duration like \"10s\" or \"1h30m\"",
+        ),
+    ];
+    for (input, reason) in invalid {
+        assert_eq!(
+            parse_limit(input),
+            Err(ConfigError::InvalidLimit {
+                input: input.to_owned(),
+                reason
+            }),
+            "{}",
+            input
+        );
+    }
+
+    // Parsed configs are validated
+    assert_eq!("0/1m".parse::<RateLimitConfig>().unwrap().max_requests, 0);
+    assert_eq!(
+        "10/0s".parse::<RateLimitConfig>(),
+        Err(ConfigError::ZeroWindow)
+    );
+    assert_eq!(
+        parse_limit("100").unwrap_err().to_string(),
+        "expected COUNT/DURATION like \"100/1m\", got \"100\": missing '/'"
+    );
+}
+
+#[tokio::test]
+async fn test_registry_shares_state_by_name() {
+    let registry = RateLimitRegistry::new([
+        ("public", RateLimitConfig::max_per_minute(2)),
+        ("partner", RateLimitConfig::max_per_minute(3)),
+    ])
+    .unwrap();
+
+    let route = |name: &str| {
+        registry
+            .filter(name)
+            .map(|info: RateLimitInfo| info.remaining.to_string())
+            .recover(handle_rate_limit_rejection)
+    };
+    let orders = route("partner");
+    let invoices = route("partner");
+    let public = route("public");
+
+    // Both partner routes count against the same limiter
+    assert_eq!(request().reply(&orders).await.body(), "2");
+    assert_eq!(request().reply(&invoices).await.body(), "1");
+    assert_eq!(request().reply(&orders).await.body(), "0");
+    assert_eq!(
+        request().reply(&invoices).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // The public limiter is untouched
+    assert_eq!(request().reply(&public).await.body(), "1");
+
+    // Clones share the limiters
+    let clone = registry.clone();
+    assert_eq!(clone.get("partner").unwrap().key_count(), 1);
+    assert_eq!(clone.get("partner").unwrap().config().max_requests, 3);
+    let mut names: Vec<_> = clone.limiters().map(|(name, _)| name).collect();
+    names.sort();
+    assert_eq!(names, ["partner", "public"]);
+}
+
+#[test]
+fn test_registry_errors() {
+    assert_eq!(
+        RateLimitRegistry::new([
+            ("public", RateLimitConfig::default()),
+            ("public", RateLimitConfig::default()),
+        ])
+        .unwrap_err(),
+        ConfigError::DuplicateLimiter(String::from("public"))
+    );
+    assert_eq!(
+        RateLimitRegistry::new([(
+            "public",
+            RateLimitConfig::default().with_window(Duration::ZERO)
+        )])
+        .unwrap_err(),
+        ConfigError::ZeroWindow
+    );
+
+    let registry = RateLimitRegistry::new([("public", RateLimitConfig::default())]).unwrap();
+    assert!(registry.try_filter("public").is_ok());
+    assert_eq!(
+        registry.try_filter("admin").err(),
+        Some(ConfigError::UnknownLimiter(String::from("admin")))
+    );
+    assert!(registry.get("admin").is_none());
+}
+
+#[test]
+#[should_panic(expected = "No rate limiter named \"admin\" is registered")]
+fn test_registry_unknown_name_panics() {
+    let registry = RateLimitRegistry::new([("public", RateLimitConfig::default())]).unwrap();
+    let _ = registry.filter("admin");
+}
+
+#[tokio::test]
+async fn test_update_config_keeps_state() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(5)).unwrap();
+    let route = limiter
+        .filter()
+        .map(|info: RateLimitInfo| format!("{}/{}", info.remaining, info.limit))
+        .recover(handle_rate_limit_rejection);
+
+    assert_eq!(request().reply(&route).await.body(), "4/5");
+    assert_eq!(request().reply(&route).await.body(), "3/5");
+
+    // Raising the limit applies immediately, without losing the count
+    limiter
+        .update_config(RateLimitConfig::max_per_minute(10))
+        .unwrap();
+    assert_eq!(request().reply(&route).await.body(), "7/10");
+
+    // Lowering it below the count rejects the very next request
+    limiter
+        .update_config(RateLimitConfig::max_per_minute(2))
+        .unwrap();
+    assert_eq!(
+        request().reply(&route).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(limiter.config().max_requests, 2);
+
+    // Invalid configurations are refused and the current one is kept
+    assert_eq!(
+        limiter.update_config(RateLimitConfig::max_per_minute(1).with_window(Duration::ZERO)),
+        Err(ConfigError::ZeroWindow)
+    );
+    assert_eq!(limiter.config().max_requests, 2);
+}
+
+#[tokio::test]
+async fn test_update_config_window_applies_on_rollover() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(1)
+            .with_window(Duration::from_millis(200)),
+    )
+    .unwrap();
+    let route = limiter
+        .filter()
+        .map(|info: RateLimitInfo| info.window.as_millis().to_string())
+        .recover(handle_rate_limit_rejection);
+
+    assert_eq!(request().reply(&route).await.body(), "200");
+
+    // The current window keeps its length
+    limiter
+        .update_config(
+            RateLimitConfig::default()
+                .with_max_requests(1)
+                .with_window(Duration::from_secs(60)),
+        )
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    // Then the next one uses the new length
+    assert_eq!(request().reply(&route).await.body(), "60000");
+    assert_eq!(
+        request().reply(&route).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+#[tokio::test]
+async fn test_key_overrides() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_key_override("10.0.0.1", 3, Duration::from_secs(60));
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| with_rate_limit_headers(info.remaining.to_string(), &info))
+        .recover(handle_rate_limit_rejection);
+    let from = |ip: &str| request().header("X-Forwarded-For", ip);
+
+    // The overridden client reports and enforces its own limit
+    for remaining in ["2", "1", "0"] {
+        let resp = from("10.0.0.1").reply(&route).await;
+        assert_eq!(resp.body(), remaining);
+        assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "3");
+    }
+    let resp = from("10.0.0.1").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "3");
+
+    // Other clients keep the default limit
+    let resp = from("10.0.0.2").reply(&route).await;
+    assert_eq!(resp.body(), "0");
+    assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+    let resp = from("10.0.0.2").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_key_overrides_config() {
+    assert_eq!(
+        RateLimitConfig::builder()
+            .key_override("10.0.0.1", 5, Duration::ZERO)
+            .build()
+            .unwrap_err(),
+        ConfigError::InvalidOverride(String::from("10.0.0.1"), Box::new(ConfigError::ZeroWindow))
+    );
+
+    let config: RateLimitConfig =
+        toml::from_str("[overrides]\n\"10.0.0.1\" = \"100/1m\"\n").unwrap();
+    assert_eq!(config.overrides["10.0.0.1"], (100, Duration::from_secs(60)));
+    let json = serde_json::to_value(&config).unwrap();
+    assert_eq!(json["overrides"]["10.0.0.1"], "100/1m");
+    assert!(toml::from_str::<RateLimitConfig>("[overrides]\n\"10.0.0.1\" = \"100\"\n").is_err());
+}
+
+#[tokio::test]
+async fn test_tiers() {
+    let gold = std::sync::Arc::new(std::sync::Mutex::new(String::from("10.0.0.1")));
+    let gold_key = gold.clone();
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .with_tier("silver", RateLimitConfig::max_per_minute(2))
+        .with_tier("gold", RateLimitConfig::max_per_minute(4))
+        .with_tier_classifier(move |key| {
+            if key == *gold_key.lock().unwrap() {
+                Some("gold")
+            } else if key.starts_with("10.") {
+                Some("silver")
+            } else {
+                None
+            }
+        });
+    let route = with_rate_limit(config)
+        .map(|info: RateLimitInfo| {
+            format!("{}:{}", info.tier.as_deref().unwrap_or("-"), info.remaining)
+        })
+        .recover(|rejection: Rejection| async move {
+            let rejection = rejection.find::<RateLimitRejection>().unwrap();
+            Ok::<_, Infallible>(format!("limited:{}", rejection.tier.as_deref().unwrap()))
+        });
+    let from = |ip: &str| request().header("X-Forwarded-For", ip);
+
+    assert_eq!(from("10.0.0.1").reply(&route).await.body(), "gold:3");
+    assert_eq!(from("10.0.0.2").reply(&route).await.body(), "silver:1");
+    assert_eq!(from("10.0.0.2").reply(&route).await.body(), "silver:0");
+    assert_eq!(
+        from("10.0.0.2").reply(&route).await.body(),
+        "limited:silver"
+    );
+    assert_eq!(from("192.168.0.1").reply(&route).await.body(), "-:0");
+
+    // A client moving to another tier keeps its count but gets the new limit
+    *gold.lock().unwrap() = String::from("10.0.0.2");
+    assert_eq!(from("10.0.0.2").reply(&route).await.body(), "gold:1");
+    assert_eq!(from("10.0.0.1").reply(&route).await.body(), "silver:0");
+    assert_eq!(
+        from("10.0.0.1").reply(&route).await.body(),
+        "limited:silver"
+    );
+}
+
+#[test]
+fn test_tiers_validation() {
+    assert_eq!(
+        RateLimitConfig::builder()
+            .tier("gold", RateLimitConfig::default())
+            .build()
+            .unwrap_err(),
+        ConfigError::TiersWithoutClassifier
+    );
+    assert_eq!(
+        RateLimitConfig::builder()
+            .tier("gold", RateLimitConfig::default())
+            .tier("gold", RateLimitConfig::default())
+            .tier_classifier(|_| Some("gold"))
+            .build()
+            .unwrap_err(),
+        ConfigError::DuplicateTier(String::from("gold"))
+    );
+    assert_eq!(
+        RateLimitConfig::builder()
+            .tier(
+                "gold",
+                RateLimitConfig::default().with_window(Duration::ZERO)
+            )
+            .tier_classifier(|_| Some("gold"))
+            .build()
+            .unwrap_err(),
+        ConfigError::InvalidTier(String::from("gold"), Box::new(ConfigError::ZeroWindow))
+    );
+}
+
+#[tokio::test]
+async fn test_without_ip_header_ignores_forged_headers() {
+    let config = RateLimitConfig::default()
+        .with_max_requests(1)
+        .without_ip_header();
+    let route = with_rate_limit(config)
+        .map(|_| "ok")
+        .recover(handle_rate_limit_rejection);
+    let from = |addr: &str, forged: &str| {
+        request()
+            .extension(addr.parse::<SocketAddr>().unwrap())
+            .header("X-Forwarded-For", forged)
+    };
+
+    assert_eq!(
+        from("10.0.0.1:1234", "1.1.1.1").reply(&route).await.body(),
+        "ok"
+    );
+    // A different forged header, or port, does not make a new client
+    let resp = from("10.0.0.1:5678", "2.2.2.2").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    // A different remote address does
+    assert_eq!(
+        from("10.0.0.2:1234", "2.2.2.2").reply(&route).await.body(),
+        "ok"
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_without_ip_header_config() {
+    let config = RateLimitConfig::builder()
+        .without_ip_header()
+        .build()
+        .unwrap();
+    assert_eq!(config.ip_header, None);
+
+    let config: RateLimitConfig = serde_json::from_str(r#"{"ip_header": null}"#).unwrap();
+    assert_eq!(config.ip_header, None);
+    let config: RateLimitConfig = serde_json::from_str("{}").unwrap();
+    assert_eq!(config.ip_header.as_deref(), Some("X-Forwarded-For"));
+}
+
+#[test]
+fn test_presets() {
+    let login = RateLimitConfig::strict_login();
+    assert_eq!(login.max_requests, 5);
+    assert_eq!(login.window, Duration::from_secs(15 * 60));
+    assert_eq!(login.retry_after_format, RetryAfterFormat::Seconds);
+    assert_eq!(login.header_style, HeaderStyle::Legacy);
+    assert_eq!(login.retry_after_jitter, None);
+    assert_eq!(login.max_retry_after, None);
+
+    let api = RateLimitConfig::public_api();
+    assert_eq!(api.max_requests, 60);
+    assert_eq!(api.window, Duration::from_secs(60));
+    assert_eq!(api.retry_after_format, RetryAfterFormat::Seconds);
+    assert_eq!(api.header_style, HeaderStyle::Both);
+
+    let assets = RateLimitConfig::lenient_static();
+    assert_eq!(assets.max_requests, 600);
+    assert_eq!(assets.window, Duration::from_secs(60));
+    assert_eq!(assets.retry_after_format, RetryAfterFormat::HttpDate);
+    assert_eq!(assets.header_style, HeaderStyle::Legacy);
+
+    for preset in [login, api, assets] {
+        assert_eq!(preset.validate(), Ok(()));
+        assert!(!preset.used_header);
+        assert!(preset.overrides.is_empty());
+        assert_eq!(preset.ip_header.as_deref(), Some("X-Forwarded-For"));
+    }
+}
+
+#[test]
+fn test_presets_compose() {
+    let config = RateLimitConfig::strict_login().with_ip_header("X-Real-IP");
+    assert_eq!(config.max_requests, 5);
+    assert_eq!(config.ip_header.as_deref(), Some("X-Real-IP"));
+
+    let config = RateLimitConfigBuilder::from(RateLimitConfig::public_api())
+        .max_requests(120)
+        .build()
+        .unwrap();
+    assert_eq!(config.max_requests, 120);
+    assert_eq!(config.header_style, HeaderStyle::Both);
+
+    assert_eq!(
+        RateLimitConfigBuilder::from(RateLimitConfig::lenient_static())
+            .max_retry_after(Duration::ZERO)
+            .build(),
+        Err(ConfigError::ZeroMaxRetryAfter)
+    );
+}
+
+#[tokio::test]
+async fn test_strict_login_preset() {
+    let route = with_rate_limit(RateLimitConfig::strict_login())
+        .map(|_| "ok")
+        .recover(handle_rate_limit_rejection);
+
+    for _ in 0..5 {
+        assert_eq!(request().reply(&route).await.body(), "ok");
+    }
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = resp.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 14 * 60 && retry_after <= 15 * 60);
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_skips_rejected_requests() {
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(2))
+        .unwrap()
+        .wrapper();
+    let route = warp::header::exact("authorization", "valid")
+        .map(|| "ok")
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    // Rejected by the inner filter before producing a response: not counted
+    for _ in 0..5 {
+        let resp = request().reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let authorized = || request().header("authorization", "valid");
+    let resp = authorized().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "1");
+    let resp = authorized().reply(&route).await;
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+    let resp = authorized().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_count_classes() {
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(2))
+        .unwrap()
+        .count_classes([StatusClass::ClientError])
+        .wrapper();
+    let route = warp::path::param()
+        .map(|status: u16| warp::reply::with_status("", StatusCode::from_u16(status).unwrap()))
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    let remaining = |resp: &warp::http::Response<_>| {
+        resp.headers()
+            .get("X-RateLimit-Remaining")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    };
+    for (path, expected) in [("/200", "2"), ("/401", "1"), ("/200", "1"), ("/403", "0")] {
+        let resp = request().path(path).reply(&route).await;
+        assert_eq!(remaining(&resp), expected, "{path}");
+    }
+    let resp = request().path("/200").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    assert!(StatusClass::ServerError.contains(StatusCode::SERVICE_UNAVAILABLE));
+    assert!(!StatusClass::Success.contains(StatusCode::NOT_MODIFIED));
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_count_statuses() {
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(2))
+        .unwrap()
+        .count_statuses([StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN])
+        .wrapper();
+    let route = warp::path::param()
+        .map(|status: u16| warp::reply::with_status("", StatusCode::from_u16(status).unwrap()))
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    // Only failed logins use up the quota
+    for (path, expected) in [
+        ("/200", "2"),
+        ("/401", "1"),
+        ("/200", "1"),
+        ("/400", "1"),
+        ("/200", "1"),
+        ("/403", "0"),
+    ] {
+        let resp = request().path(path).reply(&route).await;
+        assert_eq!(
+            resp.headers().get("X-RateLimit-Remaining").unwrap(),
+            expected,
+            "{path}"
+        );
+    }
+    // Checked before the handler, whatever the response would be
+    let resp = request().path("/200").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_exempt_statuses() {
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(3))
+        .unwrap()
+        .exempt_statuses([StatusCode::NOT_MODIFIED])
+        .wrapper();
+    let route = warp::path::param()
+        .map(|status: u16| warp::reply::with_status("", StatusCode::from_u16(status).unwrap()))
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    for path in ["/200", "/304", "/304", "/200", "/304", "/304", "/304"] {
+        let resp = request().path(path).reply(&route).await;
+        assert_eq!(resp.status().as_str(), &path[1..]);
+    }
+    // Only the two 200s consumed quota
+    let resp = request().path("/304").reply(&route).await;
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "1");
+
+    // Exemptions win over the counted classes
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(1))
+        .unwrap()
+        .count_classes([StatusClass::Redirection])
+        .exempt_statuses([StatusCode::NOT_MODIFIED])
+        .wrapper();
+    let route = warp::path::param()
+        .map(|status: u16| warp::reply::with_status("", StatusCode::from_u16(status).unwrap()))
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+    for _ in 0..3 {
+        let resp = request().path("/304").reply(&route).await;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+    let resp = request().path("/302").reply(&route).await;
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_in_flight_requests() {
+    async fn admitted(slack: u32) -> usize {
+        let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(2))
+            .unwrap()
+            .slack(slack)
+            .wrapper();
+        let route = warp::any()
+            .and_then(|| async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok::<_, Rejection>("ok")
+            })
+            .with(warp::wrap_fn(wrapper))
+            .recover(handle_rate_limit_rejection);
+
+        let mut set = JoinSet::new();
+        for _ in 0..5 {
+            let route = route.clone();
+            set.spawn(async move { request().reply(&route).await.status() });
+        }
+        set.join_all()
+            .await
+            .into_iter()
+            .filter(|status| *status == StatusCode::OK)
+            .count()
+    }
+
+    // In flight requests hold their capacity, so the limit can't be exceeded
+    assert_eq!(admitted(0).await, 2);
+    assert_eq!(admitted(1).await, 3);
+}
+
+#[tokio::test]
+async fn test_completion_rate_limit_releases_cancelled_requests() {
+    let wrapper = CompletionRateLimit::new(RateLimitConfig::max_per_minute(1))
+        .unwrap()
+        .wrapper();
+    let route = warp::header::optional::<String>("slow")
+        .and_then(|slow: Option<String>| async move {
+            if slow.is_some() {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            Ok::<_, Rejection>("ok")
+        })
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    // Dropped before completing: the capacity it held is given back
+    let cancelled = tokio::time::timeout(
+        Duration::from_millis(50),
+        request().header("slow", "1").reply(&route),
+    )
+    .await;
+    assert!(cancelled.is_err());
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_refund_rate_limit_cancelled_requests() {
+    let limit = RefundRateLimit::new(
+        RateLimitConfig::max_per_minute(2).with_window(Duration::from_millis(300)),
+    )
+    .unwrap();
+    let slow = warp::any()
+        .and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, Rejection>("slow")
+        })
+        .with(warp::wrap_fn(limit.clone().wrapper()));
+    let route = warp::path::end()
+        .map(|| "ok")
+        .with(warp::wrap_fn(limit.wrapper()))
+        .recover(handle_rate_limit_rejection);
+    let remaining = |resp: &warp::http::Response<_>| {
+        resp.headers().get("X-RateLimit-Remaining").unwrap().clone()
+    };
+
+    // Dropped before completing: refunded
+    let cancelled = tokio::time::timeout(Duration::from_millis(50), request().reply(&slow)).await;
+    assert!(cancelled.is_err());
+    let resp = request().reply(&route).await;
+    assert_eq!(remaining(&resp), "1");
+
+    // Completed: kept
+    let resp = request().reply(&route).await;
+    assert_eq!(remaining(&resp), "0");
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Never refunded into a newer window
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    let mut pending = Box::pin(request().reply(&slow));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), &mut pending)
+            .await
+            .is_err()
+    );
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(remaining(&resp), "1");
+    drop(pending);
+    tokio::task::yield_now().await;
+    let resp = request().reply(&route).await;
+    assert_eq!(remaining(&resp), "0");
+}
+
+#[tokio::test]
+async fn test_refund_rate_limit_keeps_rejected_requests() {
+    let wrapper = RefundRateLimit::new(RateLimitConfig::max_per_minute(2))
+        .unwrap()
+        .wrapper();
+    let route = warp::header::exact("authorization", "valid")
+        .map(|| "ok")
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    // The inner filter answered with a rejection: counted
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = request()
+        .header("authorization", "valid")
+        .reply(&route)
+        .await;
+    assert_eq!(resp.headers().get("X-RateLimit-Remaining").unwrap(), "0");
+    let resp = request()
+        .header("authorization", "valid")
+        .reply(&route)
+        .await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_refund_rate_limit_on_status() {
+    let wrapper = RefundRateLimit::new(RateLimitConfig::max_per_minute(3))
+        .unwrap()
+        .refund_on_status([StatusClass::ServerError])
+        .wrapper();
+    let route = warp::path::param()
+        .map(|status: u16| warp::reply::with_status("", StatusCode::from_u16(status).unwrap()))
+        .with(warp::wrap_fn(wrapper))
+        .recover(handle_rate_limit_rejection);
+
+    // The headers of a refunded response are computed before its refund
+    for (path, expected) in [
+        ("/200", "2"),
+        ("/500", "1"),
+        ("/200", "1"),
+        ("/503", "0"),
+        ("/200", "0"),
+    ] {
+        let resp = request().path(path).reply(&route).await;
+        assert_eq!(
+            resp.headers().get("X-RateLimit-Remaining").unwrap(),
+            expected,
+            "{path}"
+        );
+    }
+    // Only the 200s were counted
+    let resp = request().path("/500").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_combined_rate_limits() {
+    // A per client limit stacked on a limit keyed by another header
+    let route = try_with_rate_limits([
+        RateLimitConfig::max_per_minute(3),
+        RateLimitConfig::max_per_minute(2).with_ip_header("X-Other"),
+    ])
+    .unwrap()
+    .map(|info: RateLimitInfo| format!("{}/{}", info.remaining, info.limit))
+    .recover(|rejection: Rejection| async move {
+        let rejection = rejection.find::<RateLimitRejection>().unwrap();
+        Ok::<_, Infallible>(format!("rejected by {:?}", rejection.config_index))
+    });
+    let from = |other: &str| {
+        request()
+            .header("X-Forwarded-For", "1.1.1.1")
+            .header("X-Other", other)
+    };
+
+    // The info is the one of the most restrictive limit
+    assert_eq!(from("9.9.9.9").reply(&route).await.body(), "1/2");
+    assert_eq!(from("9.9.9.9").reply(&route).await.body(), "0/2");
+    // The first limit admits and the second rejects: nothing is counted
+    assert_eq!(
+        from("9.9.9.9").reply(&route).await.body(),
+        "rejected by Some(1)"
+    );
+    assert_eq!(from("8.8.8.8").reply(&route).await.body(), "0/3");
+    assert_eq!(
+        from("7.7.7.7").reply(&route).await.body(),
+        "rejected by Some(0)"
+    );
+}
+
+#[test]
+fn test_combined_rate_limits_validation() {
+    assert_eq!(
+        try_with_rate_limits([]).err(),
+        Some(ConfigError::NoRateLimits)
+    );
+    assert_eq!(
+        try_with_rate_limits([
+            RateLimitConfig::default(),
+            RateLimitConfig::default().with_window(Duration::ZERO)
+        ])
+        .err(),
+        Some(ConfigError::ZeroWindow)
+    );
+}
+
+#[tokio::test]
+async fn test_direct_ignores_spoofed_headers() {
+    let route = with_rate_limit(RateLimitConfig::direct().with_max_requests(2))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(handle_rate_limit_rejection);
+    let from = |addr: &str, forwarded_for: &str| {
+        request()
+            .extension(addr.parse::<SocketAddr>().unwrap())
+            .header("X-Forwarded-For", forwarded_for)
+    };
+
+    // A new forged address on each request doesn't help
+    for (i, expected) in [
+        StatusCode::OK,
+        StatusCode::OK,
+        StatusCode::TOO_MANY_REQUESTS,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let resp = from("1.2.3.4:1234", &format!("6.6.6.{i}"))
+            .reply(&route)
+            .await;
+        assert_eq!(resp.status(), expected);
+    }
+    let resp = from("5.6.7.8:1234", "6.6.6.0").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Clients are keyed by the address of the connection, never by the header
+    let filter = with_rate_limit(RateLimitConfig::direct());
+    let info = from("9.9.9.9:1234", "6.6.6.6")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("9.9.9.9"));
+    let info = request()
+        .header("X-Forwarded-For", "6.6.6.6")
+        .filter(&filter)
+        .await
+        .unwrap();
+    assert_eq!(info.key.as_deref(), Some("unknown"));
+}
+
+#[tokio::test]
+async fn test_behind_proxy() {
+    let config = RateLimitConfig::behind_proxy(["10.0.0.0/8".parse().unwrap()]);
+    let route = with_rate_limit(config.with_max_requests(1))
+        .map(|_info: RateLimitInfo| "ok")
+        .recover(handle_rate_limit_rejection);
+    let status = |addr: &'static str, forwarded_for: &'static str| {
+        let route = route.clone();
+        async move {
+            request()
+                .extension(addr.parse::<SocketAddr>().unwrap())
+                .header("X-Forwarded-For", forwarded_for)
+                .reply(&route)
+                .await
+                .status()
+        }
+    };
+    const OK: StatusCode = StatusCode::OK;
+    const LIMITED: StatusCode = StatusCode::TOO_MANY_REQUESTS;
+
+    // The proxy appends the real address after the one forged by the client
+    assert_eq!(status("10.0.0.1:1234", "6.6.6.6, 1.1.1.1").await, OK);
+    assert_eq!(status("10.0.0.1:1234", "7.7.7.7, 1.1.1.1").await, LIMITED);
+    assert_eq!(status("10.0.0.1:1234", "2.2.2.2").await, OK);
+    // Through several trusted proxies
+    assert_eq!(status("10.0.0.1:1234", "3.3.3.3, 10.0.0.2").await, OK);
+    assert_eq!(status("10.0.0.2:1234", "3.3.3.3").await, LIMITED);
+    // Connections from elsewhere are keyed by their address
+    assert_eq!(status("5.5.5.5:1234", "4.4.4.4").await, OK);
+    assert_eq!(status("5.5.5.5:1234", "9.9.9.9").await, LIMITED);
+    assert_eq!(status("4.4.4.4:1234", "5.5.5.5").await, OK);
+}
+
+#[test]
+fn test_proxy_topology_config() {
+    let direct = RateLimitConfig::direct();
+    assert_eq!(direct.ip_header, None);
+    assert_eq!(direct.trusted_proxies, None);
+    assert_eq!(direct.validate(), Ok(()));
+
+    let trusted: Vec<ipnet::IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+    let behind_proxy = RateLimitConfig::behind_proxy(trusted.clone());
+    assert_eq!(behind_proxy.ip_header.as_deref(), Some("X-Forwarded-For"));
+    assert_eq!(behind_proxy.trusted_proxies, Some(trusted.clone()));
+    assert_eq!(behind_proxy.validate(), Ok(()));
+
+    assert_eq!(
+        RateLimitConfig::builder()
+            .trusted_proxies(trusted)
+            .without_ip_header()
+            .build()
+            .unwrap_err(),
+        ConfigError::TrustedProxiesWithoutIpHeader
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_trusted_proxies_deserialize() {
+    let config: RateLimitConfig =
+        toml::from_str("trusted_proxies = [\"10.0.0.0/8\", \"fd00::/8\"]\n").unwrap();
+    assert_eq!(
+        config.trusted_proxies,
+        Some(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "fd00::/8".parse().unwrap()
+        ])
+    );
+    assert!(toml::from_str::<RateLimitConfig>("trusted_proxies = [\"10.0.0.0\"]\n").is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    let ok = [
+        ("90s", Duration::from_secs(90)),
+        ("250ms", Duration::from_millis(250)),
+        ("5m", Duration::from_secs(5 * 60)),
+        ("2h", Duration::from_secs(2 * 60 * 60)),
+        ("1d", Duration::from_secs(24 * 60 * 60)),
+        ("1m30s", Duration::from_secs(90)),
+        ("1h30m", Duration::from_secs(90 * 60)),
+        ("1h 30m", Duration::from_secs(90 * 60)),
+        (" 1s500ms ", Duration::from_millis(1500)),
+        ("0s", Duration::ZERO),
+    ];
+    for (input, expected) in ok {
+        assert_eq!(parse_duration(input), Ok(expected), "{input:?}");
+    }
+
+    let invalid = [
+        ("", "", "expected a duration like \"90s\" or \"1m30s\""),
+        ("90", "90", "missing unit after"),
+        ("1m30", "30", "missing unit after"),
+        ("5x", "x", "unknown unit"),
+        ("1h30min", "min", "unknown unit"),
+        ("5 minutes", "minutes", "unknown unit"),
+        ("m", "m", "expected a number before"),
+        ("-5s", "-", "expected a number before"),
+        ("1.5s", ".", "unknown unit"),
+        ("99999999999999999d", "99999999999999999d", "too large"),
+    ];
+    for (input, token, reason) in invalid {
+        assert_eq!(
+            parse_duration(input),
+            Err(ConfigError::InvalidDuration {
+                input: input.to_owned(),
+                token: token.to_owned(),
+                reason,
+            }),
+            "{input:?}"
+        );
+    }
+    assert_eq!(
+        parse_duration("1h30min").unwrap_err().to_string(),
+        "Invalid duration \"1h30min\": unknown unit \"min\""
+    );
+}
+
+#[test]
+fn test_window_from_text() {
+    let config = RateLimitConfig::builder()
+        .window("1m30s")
+        .max_requests(10)
+        .build()
+        .unwrap();
+    assert_eq!(config.window, Duration::from_secs(90));
+    assert_eq!(
+        RateLimitConfig::builder()
+            .window(String::from("2h"))
+            .build()
+            .unwrap()
+            .window,
+        Duration::from_secs(2 * 60 * 60)
+    );
+
+    // The parse error is reported by build, before validation
+    assert!(matches!(
+        RateLimitConfig::builder()
+            .window("5 minutes")
+            .max_requests(0)
+            .build(),
+        Err(ConfigError::InvalidDuration { .. })
+    ));
+
+    assert_eq!(
+        RateLimitConfig::max_per("5m", 100).unwrap(),
+        RateLimitConfig::max_per_window(100, 5 * 60)
+    );
+    assert_eq!(
+        RateLimitConfig::max_per(Duration::from_secs(1), 5).unwrap(),
+        RateLimitConfig::per_second(5)
+    );
+    assert_eq!(RateLimitConfig::max_per("5m", 0).unwrap().max_requests, 0);
+}
+
+#[tokio::test]
+async fn test_client_key_in_info_and_rejection() {
+    async fn keys(config: RateLimitConfig) -> (Option<String>, Option<String>) {
+        let filter = with_rate_limit(config.with_max_requests(1));
+        let request = || {
+            request()
+                .extension("10.0.0.1:1234".parse::<SocketAddr>().unwrap())
+                .header("X-Forwarded-For", "6.6.6.6, 1.1.1.1")
+        };
+        let info = request().filter(&filter).await.unwrap();
+        let rejection = request().filter(&filter).await.unwrap_err();
+        let rejection = rejection.find::<RateLimitRejection>().unwrap();
+        assert_eq!(get_rate_limit_info(rejection).key, rejection.key);
+        (info.key, rejection.key.clone())
+    }
+
+    // The key is the resolved identity of the client
+    let config = RateLimitConfig::behind_proxy(["10.0.0.0/8".parse().unwrap()]);
+    let key = Some(String::from("1.1.1.1"));
+    assert_eq!(keys(config.clone()).await, (key.clone(), key));
+    let key = Some(String::from("10.0.0.1"));
+    assert_eq!(keys(RateLimitConfig::direct()).await, (key.clone(), key));
+
+    // Unless redacted
+    assert_eq!(keys(config.with_redact_key(true)).await, (None, None));
+}
+
+#[tokio::test]
+async fn test_labels() {
+    let limited = |path: &'static str, label: &'static str| {
+        warp::path(path)
+            .and(with_rate_limit(
+                RateLimitConfig::max_per_minute(1).with_label(label),
+            ))
+            .map(|info: RateLimitInfo| info.label.unwrap().to_string())
+    };
+    let route = limited("login", "login")
+        .or(limited("search", "search"))
+        .unify()
+        .recover(|rejection: Rejection| async move {
+            let rejection = rejection.find::<RateLimitRejection>().unwrap();
+            let label = get_rate_limit_info(rejection).label;
+            assert_eq!(label, rejection.label);
+            Ok::<_, std::convert::Infallible>(format!("rejected by {}", label.unwrap()))
+        });
+
+    for path in ["login", "search"] {
+        let resp = request().path(&format!("/{path}")).reply(&route).await;
+        assert_eq!(resp.body(), path);
+        let resp = request().path(&format!("/{path}")).reply(&route).await;
+        assert_eq!(resp.body(), &format!("rejected by {path}"));
+    }
+
+    // Unlabeled by default
+    let config = RateLimitConfig::builder().label("api").build().unwrap();
+    assert_eq!(config.label.as_deref(), Some("api"));
+    assert_eq!(RateLimitConfig::default().label, None);
+}
+
+#[tokio::test]
+async fn test_rate_limit_info_from_rejection_of_any_origin() {
+    #[derive(Debug)]
+    struct Unrelated;
+    impl warp::reject::Reject for Unrelated {}
+
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1).with_label("api"));
+    request().filter(&filter).await.unwrap();
+    let rate_limited = request().filter(&filter).await.unwrap_err();
+    let info = rate_limit_info_from(&rate_limited).unwrap();
+    assert_eq!(info.limit, 1);
+    assert_eq!(info.remaining, 0);
+    assert_eq!(info.label.as_deref(), Some("api"));
+    assert!(is_rate_limited(&rate_limited));
+    assert_eq!(
+        rejection_reason(&rate_limited),
+        Some(RejectionReason::RateLimited)
+    );
+
+    for rejection in [
+        warp::reject::not_found(),
+        warp::reject::custom(Unrelated),
+        request()
+            .path("/other")
+            .filter(&warp::path("api"))
+            .await
+            .unwrap_err(),
+    ] {
+        assert!(rate_limit_info_from(&rejection).is_none());
+        assert!(!is_rate_limited(&rejection));
+        assert_eq!(rejection_reason(&rejection), None);
+    }
+}
+
+#[tokio::test]
+async fn test_rejection_mapper() {
+    #[derive(Debug)]
+    struct AppError {
+        code: &'static str,
+        retry_after: Duration,
+    }
+    impl warp::reject::Reject for AppError {}
+
+    let config = RateLimitConfig::max_per_minute(1).with_rejection_mapper(|rejection| {
+        warp::reject::custom(AppError {
+            code: "rate_limited",
+            retry_after: rejection.retry_after,
+        })
+    });
+    let filter = with_rate_limit(config);
+    request().filter(&filter).await.unwrap();
+    let rejection = request().filter(&filter).await.unwrap_err();
+    let error = rejection.find::<AppError>().unwrap();
+    assert_eq!(error.code, "rate_limited");
+    assert!(error.retry_after <= Duration::from_secs(60));
+    assert!(rejection.find::<RateLimitRejection>().is_none());
+
+    // Unchanged when unset
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1));
+    request().filter(&filter).await.unwrap();
+    let rejection = request().filter(&filter).await.unwrap_err();
+    assert!(rejection.find::<RateLimitRejection>().is_some());
+}
+
+#[tokio::test]
+async fn test_auto_reject() {
+    let route = with_rate_limit_auto(RateLimitConfig::max_per_minute(2))
+        .map(
+            |admission: Result<RateLimitInfo, warp::reply::Response>| match admission {
+                Ok(info) => info.remaining.to_string().into_response(),
+                Err(limited) => limited,
+            },
+        )
+        .recover(|rejection: Rejection| async move {
+            assert!(rejection.find::<RateLimitRejection>().is_none());
+            Ok::<_, Infallible>(StatusCode::INTERNAL_SERVER_ERROR)
+        });
+
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "1");
+    request().reply(&route).await;
+
+    // Answered by the filter, with the response of handle_rate_limit_rejection
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "Rate limit exceeded");
+    assert_eq!(resp.headers()["X-RateLimit-Limit"], "2");
+    assert_eq!(resp.headers()["X-RateLimit-Remaining"], "0");
+    assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+    // Neither a rejection mapper nor a customized response lets a rejection through
+    #[derive(Debug)]
+    struct Mapped;
+    impl warp::reject::Reject for Mapped {}
+    let config =
+        RateLimitConfig::max_per_minute(1).with_rejection_mapper(|_| warp::reject::custom(Mapped));
+    let filter = RateLimitRecover::new()
+        .message("Slow down!")
+        .auto(config)
+        .unwrap();
+    assert!(request().filter(&filter).await.unwrap().is_ok());
+    assert!(request().filter(&filter).await.unwrap().is_err());
+    let route = filter.map(|admission: Result<RateLimitInfo, warp::reply::Response>| {
+        admission.map_or_else(|limited| limited, |_| StatusCode::OK.into_response())
+    });
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.body(), "Slow down!");
+
+    assert_eq!(
+        try_with_rate_limit_auto(RateLimitConfig::default().with_window(Duration::ZERO)).err(),
+        Some(ConfigError::ZeroWindow)
+    );
+}
+
+#[tokio::test]
+async fn test_on_rejected() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let collector = events.clone();
+    let config = RateLimitConfig::max_per_minute(2)
+        .with_label("api")
+        .with_on_rejected(move |event| collector.lock().unwrap().push(event));
+    let filter = with_rate_limit(config);
+
+    for _ in 0..2 {
+        request().filter(&filter).await.unwrap();
+    }
+    assert!(events.lock().unwrap().is_empty());
+    for _ in 0..3 {
+        request().filter(&filter).await.unwrap_err();
+    }
+    {
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].outcome, RateLimitOutcome::Rejected);
+        assert_eq!(events[0].key.as_deref(), Some("unknown"));
+        assert_eq!(events[0].label.as_deref(), Some("api"));
+        assert_eq!(events[0].limit, 2);
+        assert_eq!(events[0].used, 2);
+        assert!(events[0].reset_at > system_now());
+    }
+
+    // A panicking callback doesn't break the limiter
+    let filter = with_rate_limit(
+        RateLimitConfig::max_per_minute(1).with_on_rejected(|_| panic!("callback failure")),
+    );
+    request().filter(&filter).await.unwrap();
+    for _ in 0..2 {
+        let rejection = request().filter(&filter).await.unwrap_err();
+        assert!(is_rate_limited(&rejection));
+    }
+}
+
+#[tokio::test]
+async fn test_on_allowed() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let collector = events.clone();
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(2)).unwrap();
+    let inner = limiter.clone();
+    // The state is no longer locked: another thread can lock it while the callback waits
+    let config = RateLimitConfig::max_per_minute(2).with_on_allowed(move |event| {
+        let limiter = inner.clone();
+        let runtime = tokio::runtime::Handle::current();
+        let usage = std::thread::spawn(move || runtime.block_on(limiter.usage("unknown")))
+            .join()
+            .unwrap();
+        collector.lock().unwrap().push((event, usage));
+    });
+    limiter.update_config(config).unwrap();
+    let filter = limiter.filter();
+
+    for _ in 0..3 {
+        let _ = request().filter(&filter).await;
+    }
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    let (event, usage) = &events[1];
+    assert_eq!(event.outcome, RateLimitOutcome::Allowed);
+    assert_eq!(event.key.as_deref(), Some("unknown"));
+    assert_eq!(event.cost, 1);
+    assert_eq!(event.used, 2);
+    assert_eq!(event.remaining, 0);
+    assert_eq!(usage.as_ref().unwrap().used, 2);
+}
+
+#[tokio::test]
+async fn test_on_allowed_cost() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let collector = events.clone();
+    let config = RateLimitConfig::max_per_minute(10)
+        .with_on_allowed(move |event| collector.lock().unwrap().push(event));
+    let limiter = RateLimiterHandle::new(config).unwrap();
+
+    limiter.acquire_n("10.0.0.1", 5).await.unwrap();
+    limiter.check("10.0.0.1").await.unwrap();
+    let events = events.lock().unwrap();
+    let costs: Vec<_> = events
+        .iter()
+        .map(|event| (event.cost, event.used))
+        .collect();
+    assert_eq!(costs, [(5, 5), (1, 6)]);
+}
+
+#[tokio::test]
+async fn test_counters() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_window(2, 1)
+            .with_window(Duration::from_millis(50))
+            .with_idle_ttl(Duration::from_millis(50)),
+    )
+    .unwrap();
+    let filter = limiter.filter();
+    let client = |ip| request().header("X-Forwarded-For", ip);
+    assert_eq!(limiter.counters(), RateLimitCounters::default());
+
+    for _ in 0..3 {
+        let _ = client("1.1.1.1").filter(&filter).await;
+    }
+    let _ = client("2.2.2.2").filter(&filter).await;
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    // Prunes both idle clients before counting this one
+    let _ = client("3.3.3.3").filter(&filter).await;
+
+    let expected = RateLimitCounters {
+        allowed: 4,
+        rejected: 1,
+        evicted: 2,
+        store_errors: 0,
+        unknown: 0,
+    };
+    assert_eq!(limiter.counters(), expected);
+    assert_eq!(limiter.reset_counters(), expected);
+    assert_eq!(limiter.counters(), RateLimitCounters::default());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_counters_concurrent_requests() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(5)).unwrap();
+    let filter = limiter.filter();
+    let mut set = JoinSet::new();
+
+    for _ in 0..10 {
+        let filter = filter.clone();
+        set.spawn(async move { request().filter(&filter).await.is_ok() });
+    }
+    let mut admitted = 0;
+    while let Some(result) = set.join_next().await {
+        admitted += u64::from(result.unwrap());
+    }
+
+    assert_eq!(admitted, 5);
+    let counters = limiter.counters();
+    assert_eq!(counters.allowed, 5);
+    assert_eq!(counters.rejected, 5);
+    assert_eq!(counters.evicted, 0);
+}
+
+#[tokio::test]
+async fn test_usage() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(3)
+            .with_window(Duration::from_millis(100))
+            .with_label("api"),
+    )
+    .unwrap();
+    let filter = limiter.filter();
+    let client = || request().header("X-Forwarded-For", "1.2.3.4");
+    assert!(limiter.usage("1.2.3.4").await.is_none());
+
+    let first = client().filter(&filter).await.unwrap();
+    let usage = limiter.usage("1.2.3.4").await.unwrap();
+    // Reading the usage doesn't count anything
+    assert_eq!(limiter.usage("1.2.3.4").await.unwrap().used, 1);
+    assert_eq!(usage.used, first.used);
+    assert_eq!(usage.remaining, first.remaining);
+    assert_eq!(usage.key.as_deref(), Some("1.2.3.4"));
+    assert_eq!(usage.label.as_deref(), Some("api"));
+
+    // The next request sees the same window, counting one more request
+    let next = client().filter(&filter).await.unwrap();
+    assert_eq!(next.used, usage.used + 1);
+    assert_eq!(next.remaining, usage.remaining - 1);
+    assert_eq!(next.limit, usage.limit);
+    assert_eq!(next.window, usage.window);
+    assert!(between(usage.reset_at, next.reset_at) < Duration::from_millis(50));
+    assert!(limiter.usage("5.6.7.8").await.is_none());
+
+    // Once the window is over, the full budget is back
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    let usage = limiter.usage("1.2.3.4").await.unwrap();
+    assert_eq!(usage.used, 0);
+    assert_eq!(usage.remaining, 3);
+    assert!(usage.first_rejected_at.is_none());
+    let next = client().filter(&filter).await.unwrap();
+    assert_eq!(next.remaining, usage.remaining - 1);
+}
+
+#[tokio::test]
+async fn test_key_count_threshold() {
+    use std::sync::{Arc, Mutex};
+
+    let notified = Arc::new(Mutex::new(Vec::new()));
+    let collector = notified.clone();
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(10)
+            .with_window(Duration::from_millis(50))
+            .with_idle_ttl(Duration::from_millis(50))
+            .with_on_key_count_exceeds(2, move |count| collector.lock().unwrap().push(count)),
+    )
+    .unwrap();
+    let filter = limiter.filter();
+    let client = |ip| request().header("X-Forwarded-For", ip);
+
+    for ip in ["1.1.1.1", "2.2.2.2", "2.2.2.2"] {
+        client(ip).filter(&filter).await.unwrap();
+    }
+    assert_eq!(limiter.key_count(), 2);
+    assert!(notified.lock().unwrap().is_empty());
+    // Going above the threshold notifies once, however many clients follow
+    for ip in ["3.3.3.3", "4.4.4.4", "3.3.3.3"] {
+        client(ip).filter(&filter).await.unwrap();
+    }
+    assert_eq!(limiter.key_count(), 4);
+    assert_eq!(*notified.lock().unwrap(), [3]);
+
+    // Pruning the idle clients falls back below the threshold...
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    client("5.5.5.5").filter(&filter).await.unwrap();
+    assert_eq!(limiter.key_count(), 1);
+    assert_eq!(*notified.lock().unwrap(), [3]);
+    // ...so that the next crossing notifies again
+    for ip in ["6.6.6.6", "7.7.7.7", "8.8.8.8"] {
+        client(ip).filter(&filter).await.unwrap();
+    }
+    assert_eq!(limiter.key_count(), 4);
+    assert_eq!(*notified.lock().unwrap(), [3, 3]);
+}
+
+#[tokio::test]
+async fn test_label_in_observability_outputs() {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let (allowed, rejected) = (events.clone(), events.clone());
+    let labelled = |label: &str| {
+        let (allowed, rejected) = (allowed.clone(), rejected.clone());
+        RateLimitConfig::max_per_minute(1)
+            .with_label(label)
+            .with_on_allowed(move |event| allowed.lock().unwrap().push(event))
+            .with_on_rejected(move |event| rejected.lock().unwrap().push(event))
+    };
+    let limiter = RateLimiterHandle::new(labelled("orders")).unwrap();
+    let filter = limiter.filter();
+
+    let info = request().filter(&filter).await.unwrap();
+    assert_eq!(info.label.as_deref(), Some("orders"));
+    request().filter(&filter).await.unwrap_err();
+    let labels: Vec<_> = events
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|e| e.label.clone())
+        .collect();
+    assert_eq!(labels, [Some("orders".into()), Some("orders".into())]);
+
+    // Without any address, both requests land in the unknown bucket
+    let expected = RateLimitCounters {
+        allowed: 1,
+        rejected: 1,
+        unknown: 2,
+        ..RateLimitCounters::default()
+    };
+    assert_eq!(
+        limiter.label_counters(),
+        HashMap::from([("orders".into(), expected)])
+    );
+
+    // Requests are counted under the label in effect when they are seen
+    limiter.update_config(labelled("checkout")).unwrap();
+    request().filter(&filter).await.unwrap_err();
+    let counters = limiter.label_counters();
+    assert_eq!(counters["orders"], expected);
+    assert_eq!(counters["checkout"].rejected, 1);
+    assert_eq!(limiter.counters().rejected, 2);
+
+    // Resetting zeroes every label
+    limiter.reset_counters();
+    let counters = limiter.label_counters();
+    assert!(counters
+        .values()
+        .all(|c| *c == RateLimitCounters::default()));
+}
+
+#[tokio::test]
+async fn test_snapshot() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(10).with_key_override(
+        "1.0.0.3",
+        2,
+        Duration::from_secs(60),
+    ))
+    .unwrap();
+    let filter = limiter.filter();
+    for (ip, requests) in [
+        ("1.0.0.2", 2),
+        ("1.0.0.1", 1),
+        ("1.0.0.4", 4),
+        ("1.0.0.3", 3),
+    ] {
+        for _ in 0..requests {
+            let _ = request()
+                .header("X-Forwarded-For", ip)
+                .filter(&filter)
+                .await;
+        }
+    }
+
+    let all = limiter.snapshot(0, usize::MAX).await;
+    let keys: Vec<_> = all
+        .iter()
+        .map(|entry| entry.key.as_deref().unwrap())
+        .collect();
+    assert_eq!(keys, ["1.0.0.1", "1.0.0.2", "1.0.0.3", "1.0.0.4"]);
+    let usage: Vec<_> = all.iter().map(|entry| (entry.used, entry.limit)).collect();
+    assert_eq!(usage, [(1, 10), (2, 10), (2, 2), (4, 10)]);
+    let now = system_now().timestamp();
+    for entry in &all {
+        assert!(entry.key_hash.is_none());
+        assert!((now - 1..=now).contains(&entry.window_start));
+        assert_eq!(entry.reset_timestamp, entry.window_start + 60);
+        assert_eq!(entry.reset_at.timestamp(), entry.reset_timestamp);
+    }
+
+    // Pages follow the key order
+    let page = |entries: Vec<EntrySnapshot>| -> Vec<_> {
+        entries
+            .into_iter()
+            .map(|entry| (entry.key, entry.used))
+            .collect()
+    };
+    assert_eq!(page(limiter.snapshot(1, 2).await), page(all[1..3].to_vec()));
+    assert_eq!(page(limiter.snapshot(3, 10).await), page(all[3..].to_vec()));
+    assert!(limiter.snapshot(4, 10).await.is_empty());
+
+    // Redacted keys are only given as their hash
+    limiter
+        .update_config(RateLimitConfig::max_per_minute(10).with_redact_key(true))
+        .unwrap();
+    let redacted = limiter.snapshot(0, 2).await;
+    assert!(redacted.iter().all(|entry| entry.key.is_none()));
+    assert_ne!(redacted[0].key_hash, redacted[1].key_hash);
+    assert_eq!(redacted[0].used, 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_health() {
+    use std::sync::{mpsc, Mutex};
+
+    // A classifier blocking with the state locked stands for a store that stopped
+    // answering
+    let (entered_tx, entered_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let (entered_tx, release_rx) = (Mutex::new(entered_tx), Mutex::new(release_rx));
+    let config = RateLimitConfig::max_per_minute(10)
+        .with_health_max_keys(1)
+        .with_health_max_prune_age(Duration::from_secs(120))
+        .with_tier_classifier(move |key| {
+            if key == "10.0.0.9" {
+                entered_tx.lock().unwrap().send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+            }
+            None
+        });
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    let filter = limiter.filter();
+    let send = |ip: &'static str| {
+        let filter = filter.clone();
+        async move {
+            request()
+                .header("X-Forwarded-For", ip)
+                .filter(&filter)
+                .await
+                .unwrap()
+        }
+    };
+
+    let report = limiter.health().await;
+    assert!(report.store_ok && !report.degraded);
+    assert_eq!(report.key_count, 0);
+    assert!(report.last_prune_age.unwrap() < Duration::from_secs(1));
+
+    send("10.0.0.1").await;
+    assert!(!limiter.health().await.degraded);
+
+    // Beyond the key threshold
+    send("10.0.0.2").await;
+    let report = limiter.health().await;
+    assert!(report.store_ok && report.degraded);
+    assert_eq!(report.key_count, 2);
+
+    // Stuck state
+    let stuck = tokio::spawn(send("10.0.0.9"));
+    tokio::task::spawn_blocking(move || entered_rx.recv().unwrap())
+        .await
+        .unwrap();
+    let report = limiter.health().await;
+    assert!(!report.store_ok && report.degraded);
+    assert_eq!(report.last_prune_age, None);
+    assert_eq!(report.key_count, 2);
+
+    release_tx.send(()).unwrap();
+    stuck.await.unwrap();
+    assert!(limiter.health().await.store_ok);
+}
+
+struct FailingStore;
+
+impl store::RateLimitStore for FailingStore {
+    fn incr_within_window<'a>(
+        &'a self,
+        _key: &'a str,
+        _cost: u32,
+        _max_requests: u32,
+        _window: Duration,
+    ) -> store::StoreFuture<'a> {
+        Box::pin(async { Err("connection refused".into()) })
+    }
+}
+
+#[tokio::test]
+async fn test_health_of_a_failing_store() {
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(10)
+            .with_clock(clock.clone())
+            .with_store(FailingStore),
+    )
+    .unwrap();
+    assert!(limiter.health().await.store_ok);
+
+    // Let through, but the probe tells
+    limiter.check("10.0.0.1").await.unwrap();
+    let report = limiter.health().await;
+    assert!(!report.store_ok && report.degraded);
+
+    // Until a window went by without failures
+    clock.advance(Duration::from_secs(61));
+    let report = limiter.health().await;
+    assert!(report.store_ok && !report.degraded);
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_health_route() {
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(10).with_health_max_keys(0))
+            .unwrap();
+    let route = warp::path("ready").and(health_route(limiter.clone()));
+
+    let resp = request().path("/ready").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["store_ok"], true);
+    assert_eq!(body["key_count"], 0);
+    assert_eq!(body["degraded"], false);
+    assert!(body["last_prune_age"].is_f64());
+
+    let _ = request()
+        .header("X-Forwarded-For", "10.0.0.1")
+        .filter(&limiter.filter())
+        .await;
+    let resp = request().path("/ready").reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+    assert_eq!(body["key_count"], 1);
+    assert_eq!(body["degraded"], true);
+}
+
+#[tokio::test]
+async fn test_heavy_hitters() {
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(10_000).with_heavy_hitters(4))
+            .unwrap();
+    let filter = limiter.filter();
+    // A dominant client sending half of the requests, among a long tail of clients
+    // sending one each
+    for i in 0..500 {
+        for ip in [
+            String::from("1.1.1.1"),
+            format!("10.0.{}.{}", i / 256, i % 256),
+        ] {
+            request()
+                .header("X-Forwarded-For", ip)
+                .filter(&filter)
+                .await
+                .unwrap();
+        }
+    }
+
+    let report = limiter.heavy_hitters();
+    assert!(report.hitters.len() <= 4);
+    let dominant = &report.hitters[0];
+    assert_eq!(dominant.key.as_deref(), Some("1.1.1.1"));
+    // Misses at most 1000 / (4 + 1) of its 500 requests
+    assert!((300..=500).contains(&dominant.requests));
+    let followed: u64 = report.hitters.iter().map(|hitter| hitter.requests).sum();
+    assert_eq!(followed + report.other, 1000);
+    assert!(report.other >= 500);
+
+    // Nothing is followed without the option
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(10)).unwrap();
+    let _ = request().filter(&limiter.filter()).await;
+    assert_eq!(limiter.heavy_hitters(), HeavyHitterReport::default());
+}
+
+#[tokio::test]
+async fn test_latency_histogram() {
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(5).with_measure_latency(true))
+            .unwrap();
+    let filter = limiter.filter();
+    for _ in 0..10 {
+        let _ = request().filter(&filter).await;
+    }
+
+    // Rejected requests are measured too
+    let histogram = limiter.latency_histogram();
+    assert_eq!(histogram.count, 10);
+    assert_eq!(histogram.buckets.len(), 13);
+    assert_eq!(histogram.buckets[0].le, Some(Duration::from_micros(1)));
+    assert_eq!(histogram.buckets[12].le, None);
+    // Checking an uncontended in-memory state takes well under 25ms, even on a busy CI
+    assert_eq!(histogram.buckets[12].count, 0);
+    assert!(histogram.sum > Duration::ZERO && histogram.sum < Duration::from_millis(250));
+
+    // Nothing is measured when disabled, including after turning it off
+    limiter
+        .update_config(RateLimitConfig::max_per_minute(5))
+        .unwrap();
+    let _ = request().filter(&filter).await;
+    assert_eq!(limiter.latency_histogram(), histogram);
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(5)).unwrap();
+    let _ = request().filter(&limiter.filter()).await;
+    let histogram = limiter.latency_histogram();
+    assert_eq!(histogram.count, 0);
+    assert!(histogram.buckets.iter().all(|bucket| bucket.count == 0));
+}
+
+#[tokio::test]
+async fn test_manual_checks_share_the_filter_state() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(4)).unwrap();
+    let filter = limiter.filter();
+    let http = || {
+        request()
+            .header("X-Forwarded-For", "10.0.0.1")
+            .filter(&filter)
+    };
+
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 4);
+    assert_eq!(http().await.unwrap().remaining, 3);
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().remaining, 2);
+    assert_eq!(http().await.unwrap().remaining, 1);
+    // Peeking doesn't count
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 1);
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().remaining, 0);
+
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert_eq!((rejection.used, rejection.limit), (4, 4));
+    assert!(is_rate_limited(&http().await.unwrap_err()));
+    assert_eq!(limiter.counters().allowed, 4);
+    assert_eq!(limiter.counters().rejected, 2);
+
+    // Other keys, HTTP clients or not, have their own budget
+    assert_eq!(limiter.check("job:42").await.unwrap().remaining, 3);
+    assert_eq!(limiter.key_count(), 2);
+
+    // Resetting starts a fresh window for both paths
+    limiter.reset("10.0.0.1").await;
+    assert_eq!(limiter.key_count(), 1);
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 4);
+    assert_eq!(http().await.unwrap().remaining, 3);
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().remaining, 2);
+    limiter.reset("unknown").await;
+}
+
+#[tokio::test]
+async fn test_reset_key() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(3)).unwrap();
+    let filter = limiter.filter();
+    let client = |ip| request().header("X-Forwarded-For", ip).filter(&filter);
+    for _ in 0..3 {
+        client("10.0.0.1").await.unwrap();
+    }
+    client("10.0.0.2").await.unwrap();
+    assert!(is_rate_limited(&client("10.0.0.1").await.unwrap_err()));
+
+    assert!(limiter.reset_key("10.0.0.1").await);
+    let info = client("10.0.0.1").await.unwrap();
+    assert_eq!((info.used, info.remaining), (1, 2));
+    assert!(info.first_rejected_at.is_none());
+    // Other clients keep their count
+    assert_eq!(client("10.0.0.2").await.unwrap().remaining, 1);
+
+    assert!(!limiter.reset_key("10.0.0.3").await);
+    assert_eq!(limiter.key_count(), 2);
+}
+
+#[tokio::test]
+async fn test_clear_all() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(2)).unwrap();
+    let filter = limiter.filter();
+    let client = |ip| request().header("X-Forwarded-For", ip).filter(&filter);
+    let ips = ["10.0.0.1", "10.0.0.2", "10.0.0.3"];
+    for ip in ips {
+        client(ip).await.unwrap();
+        client(ip).await.unwrap();
+        assert!(is_rate_limited(&client(ip).await.unwrap_err()));
+    }
+
+    assert_eq!(limiter.clear_all().await, 3);
+    assert_eq!(limiter.key_count(), 0);
+    for ip in ips {
+        assert_eq!(client(ip).await.unwrap().remaining, 1);
+    }
+    assert_eq!(limiter.clear_all().await, 3);
+    assert_eq!(limiter.clear_all().await, 0);
+}
+
+#[tokio::test]
+async fn test_block_key() {
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(10).with_clock(clock.clone()))
+            .unwrap();
+    limiter.check("10.0.0.1").await.unwrap();
+    limiter
+        .block_key("10.0.0.1", Duration::from_secs(180))
+        .await;
+
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert_eq!(rejection.retry_after, Duration::from_secs(180));
+    limiter.check("10.0.0.2").await.unwrap();
+
+    // Still banned once the window rolled over, for the time left on the ban
+    clock.advance(Duration::from_secs(90));
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert_eq!(rejection.retry_after, Duration::from_secs(90));
+    let snapshot = limiter.snapshot(0, 1).await;
+    assert_eq!(snapshot[0].key.as_deref(), Some("10.0.0.1"));
+    assert_eq!(snapshot[0].banned_until, Some(rejection.reset_time));
+
+    clock.advance(Duration::from_secs(90));
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().used, 1);
+    assert!(limiter.snapshot(0, 1).await[0].banned_until.is_none());
+}
+
+#[tokio::test]
+async fn test_unban_key() {
+    let audit = std::sync::Arc::new(AuditRing::new(10));
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(3).with_audit_sink(audit.clone()))
+            .unwrap();
+    limiter.check("10.0.0.1").await.unwrap();
+    limiter.block_key("10.0.0.1", Duration::from_secs(60)).await;
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert!(rejection.retry_after > Duration::from_secs(59));
+
+    let recent = limiter.recent_audit(10);
+    assert_eq!(recent.len(), 1);
+    assert_eq!(recent[0].reason, AuditReason::Blocked);
+    assert_eq!(recent[0].duration, Some(Duration::from_secs(60)));
+
+    // The usage is kept
+    assert!(limiter.unban_key("10.0.0.1").await);
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().used, 2);
+    assert!(!limiter.unban_key("10.0.0.1").await);
+    assert!(!limiter.unban_key("10.0.0.2").await);
+
+    // Resetting the client lifts its ban too
+    limiter.block_key("10.0.0.2", Duration::from_secs(60)).await;
+    assert!(limiter.check("10.0.0.2").await.is_err());
+    assert!(limiter.reset_key("10.0.0.2").await);
+    limiter.check("10.0.0.2").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_grant() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(10)
+            .with_window(Duration::from_millis(200)),
+    )
+    .unwrap();
+    for _ in 0..10 {
+        limiter.check("10.0.0.1").await.unwrap();
+    }
+    assert!(limiter.check("10.0.0.1").await.is_err());
+
+    let info = limiter.grant("10.0.0.1", 500).await;
+    assert_eq!((info.used, info.limit, info.remaining), (10, 10, 500));
+    let info = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!((info.used, info.remaining), (11, 499));
+    assert_eq!(limiter.grant("10.0.0.1", 1).await.remaining, 500);
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 500);
+
+    // Credits don't carry over to the next window
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let info = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!((info.used, info.remaining), (1, 9));
+}
+
+#[tokio::test]
+async fn test_set_remaining() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(10)).unwrap();
+    for _ in 0..4 {
+        limiter.check("10.0.0.1").await.unwrap();
+    }
+
+    // Below the limit, by counting requests as used
+    let info = limiter.set_remaining("10.0.0.1", 2).await;
+    assert_eq!((info.used, info.remaining), (8, 2));
+    limiter.check("10.0.0.1").await.unwrap();
+    limiter.check("10.0.0.1").await.unwrap();
+    assert!(limiter.check("10.0.0.1").await.is_err());
+
+    // Beyond the limit, by granting the difference
+    let info = limiter.set_remaining("10.0.0.1", 20).await;
+    assert_eq!((info.used, info.remaining), (10, 20));
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().remaining, 19);
+
+    // Untracked clients start a window
+    assert_eq!(limiter.set_remaining("10.0.0.2", 0).await.remaining, 0);
+    assert!(limiter.check("10.0.0.2").await.is_err());
+}
+
+#[tokio::test]
+async fn test_shared_rate_limit_across_routes() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(4)).unwrap();
+    let route = |name: &'static str| {
+        warp::path(name)
+            .and(with_shared_rate_limit(limiter.clone()))
+            .map(move |_| name)
+    };
+    let routes = route("orders").or(route("invoices"));
+    let client = |path| {
+        request()
+            .path(path)
+            .header("X-Forwarded-For", "10.0.0.1")
+            .filter(&routes)
+    };
+
+    for path in ["/orders", "/invoices", "/orders", "/invoices"] {
+        client(path).await.unwrap();
+    }
+    // The routes add up to the limit, so neither admits the client anymore
+    assert!(is_rate_limited(&client("/orders").await.unwrap_err()));
+    assert!(is_rate_limited(&client("/invoices").await.unwrap_err()));
+    assert_eq!(limiter.peek("10.0.0.1").await.used, 4);
+}
+
+#[tokio::test]
+async fn test_filters_with_their_own_cap() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100)).unwrap();
+    let search = warp::path("search").and(limiter.filter());
+    let export = warp::path("export").and(limiter.filter_with_max_requests(10));
+    let client = |path| request().path(path).header("X-Forwarded-For", "10.0.0.1");
+
+    for _ in 0..6 {
+        client("/search").filter(&search).await.unwrap();
+    }
+    for used in 7..=10 {
+        let info = client("/export").filter(&export).await.unwrap();
+        assert_eq!(
+            (info.used, info.limit, info.remaining),
+            (used, 10, 10 - used)
+        );
+    }
+
+    // The export cap is reached, while search can still dip into the shared counter
+    let rejection = client("/export").filter(&export).await.unwrap_err();
+    let rejection = rejection.find::<RateLimitRejection>().unwrap();
+    assert_eq!((rejection.used, rejection.limit), (10, 10));
+    let info = client("/search").filter(&search).await.unwrap();
+    assert_eq!((info.used, info.limit, info.remaining), (11, 100, 89));
+    assert!(client("/export").filter(&export).await.is_err());
+    assert_eq!(limiter.peek("10.0.0.1").await.used, 11);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_peek_never_consumes() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(50)
+            .with_window(Duration::from_millis(300)),
+    )
+    .unwrap();
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 50);
+    assert_eq!(limiter.key_count(), 0);
+    limiter.check("10.0.0.1").await.unwrap();
+    for _ in 0..10 {
+        assert_eq!(limiter.peek("10.0.0.1").await.remaining, 49);
+    }
+
+    // Alongside real checks, peeks only ever see the checks
+    let mut set = JoinSet::new();
+    for _ in 0..20 {
+        let (checker, peeker) = (limiter.clone(), limiter.clone());
+        set.spawn(async move { checker.check("10.0.0.1").await.unwrap() });
+        set.spawn(async move { peeker.peek("10.0.0.1").await });
+    }
+    while let Some(info) = set.join_next().await {
+        assert!(info.unwrap().remaining >= 29);
+    }
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 29);
+
+    // An expired window is reported as if it had rolled over, which only happens
+    // once the next request is counted
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    let info = limiter.peek("10.0.0.1").await;
+    assert_eq!((info.used, info.remaining), (0, 50));
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 50);
+    assert_eq!(limiter.check("10.0.0.1").await.unwrap().remaining, 49);
+
+    limiter.block_key("10.0.0.1", Duration::from_secs(60)).await;
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 0);
+}
+
+#[tokio::test]
+async fn test_acquire_n() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100)).unwrap();
+    let filter = limiter.filter();
+    request()
+        .header("X-Forwarded-For", "10.0.0.1")
+        .filter(&filter)
+        .await
+        .unwrap();
+
+    let info = limiter.acquire_n("10.0.0.1", 60).await.unwrap();
+    assert_eq!((info.used, info.remaining), (61, 39));
+    // All or nothing
+    let rejection = limiter.acquire_n("10.0.0.1", 40).await.unwrap_err();
+    assert_eq!((rejection.used, rejection.limit), (61, 100));
+    assert!(rejection.retry_after > Duration::from_secs(59));
+    assert_eq!(
+        limiter.acquire_n("10.0.0.1", 0).await.unwrap().remaining,
+        39
+    );
+    assert_eq!(
+        limiter.acquire_n("10.0.0.1", 39).await.unwrap().remaining,
+        0
+    );
+    assert!(limiter.acquire_n("10.0.0.1", 1).await.is_err());
+
+    // Acquiring nothing never tracks a client
+    assert_eq!(
+        limiter.acquire_n("10.0.0.2", 0).await.unwrap().remaining,
+        100
+    );
+    assert!(limiter.acquire_n("10.0.0.2", 101).await.is_err());
+    assert_eq!(
+        limiter.acquire_n("10.0.0.2", 100).await.unwrap().remaining,
+        0
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_acquire_n() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100)).unwrap();
+    let mut set = JoinSet::new();
+    for _ in 0..30 {
+        let limiter = limiter.clone();
+        set.spawn(async move { limiter.acquire_n("10.0.0.1", 7).await });
+    }
+    let mut acquired = 0;
+    while let Some(result) = set.join_next().await {
+        if let Ok(info) = result.unwrap() {
+            assert_eq!(info.used % 7, 0);
+            acquired += 1;
+        }
+    }
+    // As many batches as fit, without any partial one
+    assert_eq!(acquired, 14);
+    assert_eq!(limiter.peek("10.0.0.1").await.used, 98);
+}
+
+#[tokio::test]
+async fn test_refund() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(100)).unwrap();
+    let charge = limiter.acquire_n("10.0.0.1", 100).await.unwrap();
+    assert!(limiter.check("10.0.0.1").await.is_err());
+
+    assert!(limiter.refund("10.0.0.1", 60, &charge).await);
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 60);
+    let info = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!((info.used, info.remaining), (41, 59));
+
+    // Saturating, whatever was charged
+    assert!(limiter.refund("10.0.0.1", 1000, &info).await);
+    assert_eq!(limiter.peek("10.0.0.1").await.used, 0);
+
+    // Nothing to refund to
+    assert!(!limiter.refund("10.0.0.2", 1, &info).await);
+    let rejected = limiter.acquire_n("10.0.0.1", 101).await.unwrap_err();
+    assert!(!limiter.refund("10.0.0.1", 1, &(&rejected).into()).await);
+}
+
+#[tokio::test]
+async fn test_stale_refund_after_rollover() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(10)
+            .with_window(Duration::from_millis(100)),
+    )
+    .unwrap();
+    let charge = limiter.acquire_n("10.0.0.1", 8).await.unwrap();
+
+    // The window is over, but the client didn't come back yet
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!limiter.refund("10.0.0.1", 8, &charge).await);
+
+    // The window rolled over since the charge
+    let info = limiter.acquire_n("10.0.0.1", 5).await.unwrap();
+    assert_ne!(info.window_start, charge.window_start);
+    assert!(!limiter.refund("10.0.0.1", 8, &charge).await);
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 5);
+    assert!(limiter.refund("10.0.0.1", 2, &info).await);
+    assert_eq!(limiter.peek("10.0.0.1").await.remaining, 7);
+}
+
+#[tokio::test]
+async fn test_is_limited() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(2)
+            .with_window(Duration::from_millis(100)),
+    )
+    .unwrap();
+    assert!(!limiter.is_limited("10.0.0.1").await);
+    assert_eq!(limiter.key_count(), 0);
+
+    limiter.check("10.0.0.1").await.unwrap();
+    assert!(!limiter.is_limited("10.0.0.1").await);
+    limiter.check("10.0.0.1").await.unwrap();
+    assert!(limiter.is_limited("10.0.0.1").await);
+    assert!(limiter.is_limited("10.0.0.1").await);
+    assert_eq!(limiter.peek("10.0.0.1").await.used, 2);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!limiter.is_limited("10.0.0.1").await);
+
+    limiter.block_key("10.0.0.2", Duration::from_secs(60)).await;
+    assert!(limiter.is_limited("10.0.0.2").await);
+    limiter.unban_key("10.0.0.2").await;
+    assert!(!limiter.is_limited("10.0.0.2").await);
+}
+
+#[tokio::test]
+async fn test_list_keys() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(5)
+            .with_key_override("172.16.0.1", 5, Duration::from_secs(1))
+            .with_key_override("172.16.0.2", 5, Duration::from_secs(1)),
+    )
+    .unwrap();
+    for i in 0..30 {
+        let key = format!("10.0.0.{}", i);
+        limiter.acquire_n(&key, i % 5 + 1).await.unwrap();
+    }
+    for i in 0..10 {
+        let key = format!("192.168.1.{}", i);
+        limiter.check(&key).await.unwrap();
+        if i < 3 {
+            limiter.block_key(&key, Duration::from_secs(60)).await;
+        }
+    }
+    limiter.check("172.16.0.1").await.unwrap();
+    limiter.check("172.16.0.2").await.unwrap();
+    let keys = |page: &Page<EntrySnapshot>| -> Vec<String> {
+        page.items
+            .iter()
+            .map(|entry| entry.key.clone().unwrap())
+            .collect()
+    };
+
+    let all = limiter.list_keys(KeyFilter::default()).await;
+    assert_eq!(
+        (all.total, all.items.len(), all.next_offset),
+        (42, 42, None)
+    );
+    let mut sorted = keys(&all);
+    sorted.sort();
+    assert_eq!(keys(&all), sorted);
+
+    // Pages of a prefix, in a stable order
+    let prefix = || KeyFilter::default().with_prefix("10.0.0.");
+    let first = limiter.list_keys(prefix().with_page(0, 10)).await;
+    assert_eq!((first.total, first.next_offset), (30, Some(10)));
+    assert_eq!(keys(&first)[..3], ["10.0.0.0", "10.0.0.1", "10.0.0.10"]);
+    let last = limiter.list_keys(prefix().with_page(20, 10)).await;
+    assert_eq!((last.items.len(), last.next_offset), (10, None));
+    assert_eq!(keys(&last)[9], "10.0.0.9");
+    let beyond = limiter.list_keys(prefix().with_page(40, 10)).await;
+    assert_eq!((beyond.total, beyond.items.len()), (30, 0));
+
+    // Exhausted or banned
+    let limited = limiter
+        .list_keys(KeyFilter::default().with_limited(true))
+        .await;
+    assert_eq!(
+        keys(&limited),
+        [
+            "10.0.0.14",
+            "10.0.0.19",
+            "10.0.0.24",
+            "10.0.0.29",
+            "10.0.0.4",
+            "10.0.0.9",
+            "192.168.1.0",
+            "192.168.1.1",
+            "192.168.1.2"
+        ]
+    );
+    let banned = limiter
+        .list_keys(KeyFilter::default().with_banned(true))
+        .await;
+    assert_eq!(keys(&banned), ["192.168.1.0", "192.168.1.1", "192.168.1.2"]);
+    assert!(banned
+        .items
+        .iter()
+        .all(|entry| entry.banned_until.is_some()));
+
+    let expiring = limiter
+        .list_keys(KeyFilter::default().with_expiring_within(Duration::from_secs(2)))
+        .await;
+    assert_eq!(keys(&expiring), ["172.16.0.1", "172.16.0.2"]);
+
+    // Every condition must hold
+    let filter = KeyFilter::default()
+        .with_prefix("192.")
+        .with_limited(true)
+        .with_page(1, 1);
+    let page = limiter.list_keys(filter).await;
+    assert_eq!((page.total, page.next_offset), (3, Some(2)));
+    assert_eq!(keys(&page), ["192.168.1.1"]);
+    let filter = KeyFilter::default().with_prefix("10.").with_banned(true);
+    assert_eq!(limiter.list_keys(filter).await.total, 0);
+}
+
+#[tokio::test]
+async fn test_active_bans() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::default()
+            .with_max_requests(5)
+            .with_window(Duration::from_millis(100)),
+    )
+    .unwrap();
+    assert!(limiter.active_bans().await.is_empty());
+    let before = system_now();
+    limiter.block_key("10.0.0.2", Duration::from_secs(60)).await;
+    limiter
+        .block_key("10.0.0.1", Duration::from_secs(3600))
+        .await;
+    limiter
+        .block_key("10.0.0.3", Duration::from_millis(150))
+        .await;
+
+    let bans = limiter.active_bans().await;
+    let keys: Vec<_> = bans.iter().map(|ban| ban.key.as_deref().unwrap()).collect();
+    assert_eq!(keys, ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    assert!(bans.iter().all(|ban| ban.reason == BanReason::Manual));
+    assert!(bans[0].imposed_at >= before && bans[0].imposed_at <= system_now());
+    assert_eq!(
+        between(bans[0].imposed_at, bans[0].expires_at),
+        Duration::from_secs(3600)
+    );
+
+    // Lifted from the very next request on
+    assert!(limiter.check("10.0.0.2").await.is_err());
+    assert!(limiter.unban_key("10.0.0.2").await);
+    limiter.check("10.0.0.2").await.unwrap();
+    assert!(!limiter.unban_key("10.0.0.2").await);
+
+    // Bans outlast the windows, but not their own end
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    limiter.check("10.0.0.4").await.unwrap();
+    let bans = limiter.active_bans().await;
+    let keys: Vec<_> = bans.iter().map(|ban| ban.key.as_deref().unwrap()).collect();
+    assert_eq!(keys, ["10.0.0.1"]);
+    assert!(!limiter.unban_key("10.0.0.3").await);
+
+    assert!(limiter.reset_key("10.0.0.1").await);
+    assert!(limiter.active_bans().await.is_empty());
+    limiter.block_key("10.0.0.1", Duration::from_secs(60)).await;
+    limiter.clear_all().await;
+    assert!(limiter.active_bans().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_manual_clock_window_expiry() {
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(2).with_clock(clock.clone()))
+            .unwrap();
+    let first = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!(first.retry_after_duration, Duration::from_secs(60));
+    clock.advance(Duration::from_secs(30));
+    let second = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!(second.retry_after_duration, Duration::from_secs(30));
+    assert_eq!(second.reset_at, first.reset_at);
+
+    // Rejected until the end of the window
+    clock.advance(Duration::from_secs(29));
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert_eq!(rejection.retry_after, Duration::from_secs(1));
+    assert_eq!(rejection.reset_time, first.reset_at);
+
+    clock.advance(Duration::from_secs(2));
+    let next = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!((next.used, next.remaining), (1, 1));
+    assert_eq!(next.reset_at, first.reset_at + Duration::from_secs(61));
+    assert!(next.first_rejected_at.is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_paused_time_window_expiry() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(2))
+        .map(|info: RateLimitInfo| info.used.to_string())
+        .recover(handle_rate_limit_rejection);
+    for used in ["1", "2"] {
+        assert_eq!(request().reply(&route).await.body(), used);
+    }
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    tokio::time::advance(Duration::from_secs(30)).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    tokio::time::advance(Duration::from_secs(31)).await;
+    let resp = request().reply(&route).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.body(), "1");
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_limiters_anchor_their_own_clock() {
+    let first = RateLimiterHandle::new(RateLimitConfig::max_per_minute(1)).unwrap();
+    first.check("10.0.0.1").await.unwrap();
+    // Paused time runs ahead of the system clock, which a limiter created later on
+    // still follows
+    tokio::time::advance(Duration::from_secs(60 * 60)).await;
+    let second = RateLimiterHandle::new(RateLimitConfig::max_per_minute(1)).unwrap();
+    let info = second.check("10.0.0.1").await.unwrap();
+    let now = system_now();
+    assert!((info.checked_at.timestamp() - now.timestamp()).abs() <= 1);
+    assert!((info.reset_timestamp - now.timestamp() - 60).abs() <= 1);
+}
+
+#[tokio::test]
+async fn test_times_follow_the_clock_of_the_limiter() {
+    let clock = std::sync::Arc::new(ManualClock::new());
+    let audit = std::sync::Arc::new(AuditRing::new(10));
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(1)
+            .with_clock(clock.clone())
+            .with_audit_sink(audit.clone())
+            .with_header_style(HeaderStyle::Both)
+            .with_retry_after_format(RetryAfterFormat::Seconds),
+    )
+    .unwrap();
+    let reset_in = |info: &RateLimitInfo| {
+        let mut headers = header::HeaderMap::new();
+        add_rate_limit_headers(&mut headers, info).unwrap();
+        let secs = |name: &str| headers[name].to_str().unwrap().parse::<u64>().unwrap();
+        [secs("retry-after"), secs("ratelimit-reset")]
+    };
+
+    // A day ahead of the system clock
+    clock.advance(Duration::from_secs(24 * 60 * 60));
+    let info = limiter.check("10.0.0.1").await.unwrap();
+    assert_eq!(reset_in(&info), [60, 60]);
+    clock.advance(Duration::from_secs(20));
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    assert_eq!(
+        rejection.rejected_at,
+        info.checked_at + Duration::from_secs(20)
+    );
+    assert_eq!(reset_in(&rejection.to_info()), [40, 40]);
+    assert_eq!(rejection.formated_retry_after(), "40");
+
+    limiter.block_key("10.0.0.1", Duration::from_secs(90)).await;
+    let ban = &limiter.active_bans().await[0];
+    assert_eq!(audit.recent(1)[0].at, ban.imposed_at);
+    assert_eq!(ban.imposed_at, rejection.rejected_at);
+
+    clock.advance(Duration::from_secs(300));
+    let health = limiter.health().await;
+    assert!(health.last_prune_age.unwrap() >= Duration::from_secs(300));
+}
+
+#[tokio::test]
+async fn test_time_values_of_a_response_agree() {
+    let limiter = RateLimiterHandle::new(
+        RateLimitConfig::max_per_minute(2)
+            .with_header_style(HeaderStyle::Both)
+            .with_retry_after_format(RetryAfterFormat::Seconds),
+    )
+    .unwrap();
+    // Every time told about the response, in whole seconds from now
+    let told = |info: &RateLimitInfo| {
+        let mut headers = header::HeaderMap::new();
+        add_rate_limit_headers(&mut headers, info).unwrap();
+        let secs = |name: &str| headers[name].to_str().unwrap().parse::<i64>().unwrap();
+        let now = system_now().timestamp();
+        assert_eq!(info.reset_timestamp, info.reset_at.timestamp());
+        assert_eq!(secs("x-ratelimit-reset"), info.reset_timestamp);
+        [
+            secs("retry-after"),
+            info.retry_after_duration.as_secs() as i64,
+            secs("ratelimit-reset"),
+            info.reset_timestamp - now,
+        ]
+    };
+
+    let info = limiter.check("10.0.0.1").await.unwrap();
+    let window_start = limiter.snapshot(0, 1).await[0].window_start;
+    for secs in told(&info) {
+        assert!((59..=60).contains(&secs), "{:?}", told(&info));
+    }
+    assert_eq!(info.reset_timestamp - window_start, 60);
+
+    // Banned clients are told about the end of the ban, all the same
+    limiter.block_key("10.0.0.1", Duration::from_secs(90)).await;
+    let rejection = limiter.check("10.0.0.1").await.unwrap_err();
+    let info = rejection.to_info();
+    for secs in told(&info) {
+        assert!((89..=90).contains(&secs), "{:?}", told(&info));
+    }
+    let snapshot = limiter.snapshot(0, 1).await;
+    assert_eq!(snapshot[0].banned_until, Some(rejection.reset_time));
+    let ban = &limiter.active_bans().await[0];
+    assert_eq!(
+        between(ban.imposed_at, ban.expires_at),
+        Duration::from_secs(90)
+    );
+    assert_eq!(ban.expires_at, rejection.reset_time);
+}
@@ -0,0 +1,117 @@
+// Runs with and without the chrono feature, with the same expectations, so that the
+// minimal-time mode behaves like the default one
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::{http::header, test::request, Filter};
+use warp_rate_limit::*;
+
+fn at(secs: u64, nanos: u32) -> Timestamp {
+    Timestamp::from(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[test]
+fn test_timestamp_parity() {
+    let timestamp = at(1_234_567_890, 500_000_000);
+    assert_eq!(timestamp.timestamp(), 1_234_567_890);
+    assert_eq!(timestamp.timestamp_subsec_nanos(), 500_000_000);
+    assert_eq!(
+        SystemTime::from(timestamp),
+        UNIX_EPOCH + Duration::new(1_234_567_890, 500_000_000)
+    );
+    assert_eq!(
+        Timestamp::from_timestamp(1_234_567_890, 500_000_000),
+        Some(timestamp)
+    );
+
+    // Seconds are rounded down before the epoch too
+    let before_epoch = Timestamp::from(UNIX_EPOCH - Duration::from_millis(500));
+    assert_eq!(before_epoch.timestamp(), -1);
+    assert_eq!(before_epoch.timestamp_subsec_nanos(), 500_000_000);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_timestamp_serialization_parity() {
+    for (timestamp, expected) in [
+        (at(1_234_567_890, 0), "2009-02-13T23:31:30Z"),
+        (at(1_234_567_890, 500_000_000), "2009-02-13T23:31:30.500Z"),
+        (at(1_234_567_890, 500_000), "2009-02-13T23:31:30.000500Z"),
+        (
+            at(1_234_567_890, 123_456_789),
+            "2009-02-13T23:31:30.123456789Z",
+        ),
+        (at(951_782_400, 0), "2000-02-29T00:00:00Z"),
+        (at(0, 0), "1970-01-01T00:00:00Z"),
+    ] {
+        assert_eq!(serde_json::to_value(timestamp).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_retry_after_http_date_parity() {
+    let now = SystemTime::now();
+    let reset_time = Timestamp::from(now + Duration::from_millis(2500));
+    let rejection = RateLimitRejection {
+        retry_after: Duration::from_millis(2500),
+        limit: 10,
+        used: 10,
+        window: Duration::from_secs(60),
+        tier: None,
+        key: None,
+        label: None,
+        reset_time,
+        first_rejected_at: None,
+        rejected_at: Timestamp::from(now),
+        rejected_instant: std::time::Instant::now(),
+        retry_after_format: RetryAfterFormat::HttpDate,
+        retry_after_jitter: Duration::ZERO,
+        max_retry_after: None,
+        header_style: HeaderStyle::Legacy,
+        used_header: false,
+        config_index: None,
+    };
+    let info = get_rate_limit_info(&rejection);
+
+    // An IMF-fixdate, rounded up to the second following the reset
+    assert!(info.retry_after.ends_with(" GMT"));
+    let retry_at = httpdate::parse_http_date(&info.retry_after).unwrap();
+    let reset_secs = reset_time.timestamp() as u64;
+    assert_eq!(
+        retry_at,
+        UNIX_EPOCH
+            + Duration::from_secs(reset_secs + u64::from(reset_time.timestamp_subsec_nanos() > 0))
+    );
+    assert_eq!(info.reset_timestamp, reset_time.timestamp());
+    assert_eq!(info.reset_at, reset_time);
+}
+
+#[tokio::test]
+async fn test_reset_parity() {
+    let route = with_rate_limit(RateLimitConfig::max_per_minute(1))
+        .map(|info: RateLimitInfo| {
+            assert_eq!(info.reset_timestamp, info.reset_at.timestamp());
+            info.reset_timestamp.to_string()
+        })
+        .recover(handle_rate_limit_rejection);
+
+    let before = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let reset: i64 = std::str::from_utf8(request().reply(&route).await.body())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((before + 60..=before + 61).contains(&reset));
+
+    let resp = request().reply(&route).await;
+    let retry_at = httpdate::parse_http_date(
+        resp.headers()
+            .get(header::RETRY_AFTER)
+            .unwrap()
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap();
+    let retry_at = retry_at.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    assert!((before + 59..=before + 61).contains(&retry_at));
+}
@@ -0,0 +1,110 @@
+#![cfg(feature = "tower")]
+use std::{convert::Infallible, net::SocketAddr};
+use tower::{service_fn, Layer as _, ServiceExt as _};
+use warp::{
+    http::{Request, Response, StatusCode},
+    test::request,
+};
+use warp_rate_limit::*;
+
+// A service answering with the number of requests the client has left
+fn remaining_service(
+    layer: &RateLimitLayer,
+) -> impl tower::Service<Request<String>, Response = Response<String>, Error = Infallible> + Clone {
+    layer.layer(service_fn(|request: Request<String>| async move {
+        let info = request.extensions().get::<RateLimitInfo>().unwrap();
+        Ok::<_, Infallible>(Response::new(info.remaining.to_string()))
+    }))
+}
+
+fn from_ip(ip: &str) -> Request<String> {
+    Request::builder()
+        .header("X-Forwarded-For", ip)
+        .body(String::new())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_layer_shares_counters_with_filters() {
+    let limiter = RateLimiterHandle::new(RateLimitConfig::max_per_minute(3)).unwrap();
+    let service = remaining_service(&RateLimitLayer::new(limiter.clone()));
+
+    request()
+        .header("X-Forwarded-For", "1.2.3.4")
+        .filter(&limiter.filter())
+        .await
+        .unwrap();
+    let response = service.clone().oneshot(from_ip("1.2.3.4")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.body(), "1");
+    service.clone().oneshot(from_ip("1.2.3.4")).await.unwrap();
+
+    let response = service.clone().oneshot(from_ip("1.2.3.4")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.body().is_empty());
+    assert_eq!(response.headers()["x-ratelimit-limit"], "3");
+    assert_eq!(response.headers()["x-ratelimit-remaining"], "0");
+    assert!(response.headers().contains_key("retry-after"));
+    // The warp filters see the requests of the service
+    assert!(request()
+        .header("X-Forwarded-For", "1.2.3.4")
+        .filter(&limiter.filter())
+        .await
+        .is_err());
+
+    let response = service.oneshot(from_ip("5.6.7.8")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_layer_joins_ip_header_lines() {
+    let config = RateLimitConfig::behind_proxy(["10.0.0.0/8".parse().unwrap()]);
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    let mut request = Request::builder()
+        .header("X-Forwarded-For", "9.9.9.9")
+        .header("X-Forwarded-For", "1.2.3.4, 10.0.0.2")
+        .body(String::new())
+        .unwrap();
+    request
+        .extensions_mut()
+        .insert("10.0.0.1:4000".parse::<SocketAddr>().unwrap());
+
+    let service = remaining_service(&RateLimitLayer::new(limiter.clone()));
+    let response = service.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(limiter.usage("1.2.3.4").await.unwrap().used, 1);
+}
+
+#[tokio::test]
+async fn test_layer_remote_addr() {
+    let config = RateLimitConfig::max_per_minute(1).without_ip_header();
+    let limiter = RateLimiterHandle::new(config).unwrap();
+    let from_addr = |addr: &str| {
+        let mut request = Request::new(String::new());
+        request
+            .extensions_mut()
+            .insert(addr.parse::<SocketAddr>().unwrap());
+        request
+    };
+
+    let service = remaining_service(&RateLimitLayer::new(limiter.clone()));
+    let response = service.clone().oneshot(from_addr("10.0.0.1:4000")).await;
+    assert_eq!(response.unwrap().status(), StatusCode::OK);
+    let response = service.clone().oneshot(from_addr("10.0.0.1:4001")).await;
+    assert_eq!(response.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = service.oneshot(from_addr("10.0.0.2:4000")).await;
+    assert_eq!(response.unwrap().status(), StatusCode::OK);
+
+    // The address can come from another extension, like axum's ConnectInfo
+    #[derive(Clone)]
+    struct ConnectInfo(SocketAddr);
+    let layer = RateLimitLayer::new(limiter.clone())
+        .with_remote_addr(|extensions| extensions.get::<ConnectInfo>().map(|info| info.0));
+    let mut request = Request::new(String::new());
+    request
+        .extensions_mut()
+        .insert(ConnectInfo("10.0.0.3:4000".parse().unwrap()));
+    let response = remaining_service(&layer).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(limiter.usage("10.0.0.3").await.unwrap().used, 1);
+}
@@ -0,0 +1,266 @@
+#![cfg(feature = "tracing")]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt as _},
+    Layer,
+};
+use warp::{test::request, Filter};
+use warp_rate_limit::*;
+
+type Fields = HashMap<String, String>;
+
+// Records the spans and events of the crate, by name or message
+#[derive(Clone, Default)]
+struct Capture {
+    spans: Arc<Mutex<Vec<(String, Fields)>>>,
+    events: Arc<Mutex<Vec<(String, Fields)>>>,
+}
+
+impl Capture {
+    fn events(&self, message: &str) -> Vec<Fields> {
+        let events = self.events.lock().unwrap();
+        let matching = events.iter().filter(|(m, _)| m == message);
+        matching.map(|(_, fields)| fields.clone()).collect()
+    }
+
+    fn spans(&self) -> Vec<Fields> {
+        let spans = self.spans.lock().unwrap();
+        spans.iter().map(|(_, fields)| fields.clone()).collect()
+    }
+}
+
+struct Visitor<'a>(&'a mut Fields);
+
+impl Visit for Visitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value));
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Capture {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _: Context<'_, S>) {
+        if attrs.metadata().target() != "warp_rate_limit" {
+            return;
+        }
+        let mut fields = Fields::new();
+        attrs.record(&mut Visitor(&mut fields));
+        fields.insert(String::from("id"), id.into_u64().to_string());
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((attrs.metadata().name().to_owned(), fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, _: Context<'_, S>) {
+        let id = id.into_u64().to_string();
+        let mut spans = self.spans.lock().unwrap();
+        if let Some((_, fields)) = spans.iter_mut().rev().find(|(_, f)| f["id"] == id) {
+            values.record(&mut Visitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        if event.metadata().target() != "warp_rate_limit" {
+            return;
+        }
+        let mut fields = Fields::new();
+        event.record(&mut Visitor(&mut fields));
+        let message = fields.remove("message").unwrap_or_default();
+        self.events.lock().unwrap().push((message, fields));
+    }
+}
+
+fn capture() -> (Capture, tracing::subscriber::DefaultGuard) {
+    let capture = Capture::default();
+    let subscriber = tracing_subscriber::registry().with(capture.clone());
+    (capture, tracing::subscriber::set_default(subscriber))
+}
+
+#[tokio::test]
+async fn test_admission_and_rejection_events() {
+    let (capture, _guard) = capture();
+    let filter = with_rate_limit(RateLimitConfig::max_per_minute(1).with_label("api"));
+    let client = || request().header("X-Forwarded-For", "1.2.3.4");
+    client().filter(&filter).await.unwrap();
+    client().filter(&filter).await.unwrap_err();
+
+    let admitted = capture.events("request admitted");
+    assert_eq!(admitted.len(), 1);
+    assert_eq!(admitted[0]["used"], "1");
+    assert_eq!(admitted[0]["limit"], "1");
+    assert_eq!(admitted[0]["remaining"], "0");
+    assert_eq!(admitted[0]["label"], "api");
+
+    let rejected = capture.events("request rejected");
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0]["used"], "1");
+    assert_eq!(rejected[0]["limit"], "1");
+    assert!(rejected[0].contains_key("retry_after_secs"));
+    assert_eq!(rejected[0]["label"], "api");
+
+    let spans = capture.spans();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0]["key"], "1.2.3.4");
+    assert_eq!(spans[0]["label"], "api");
+    assert!(!spans[0].contains_key("key_hash"));
+}
+
+#[tokio::test]
+async fn test_redacted_key_span() {
+    let (capture, _guard) = capture();
+    let filter = with_rate_limit(RateLimitConfig::default().with_redact_key(true));
+    for client in ["1.2.3.4", "1.2.3.4", "5.6.7.8"] {
+        let _ = request()
+            .header("X-Forwarded-For", client)
+            .filter(&filter)
+            .await;
+    }
+
+    let spans = capture.spans();
+    assert!(spans.iter().all(|span| !span.contains_key("key")));
+    // Hashes still tell clients apart
+    assert_eq!(spans[0]["key_hash"], spans[1]["key_hash"]);
+    assert_ne!(spans[0]["key_hash"], spans[2]["key_hash"]);
+}
+
+#[tokio::test]
+async fn test_window_reset_and_eviction_events() {
+    let (capture, _guard) = capture();
+    let filter = with_rate_limit(
+        RateLimitConfig::default()
+            .with_window(Duration::from_millis(50))
+            .with_idle_ttl(Duration::from_millis(200))
+            .with_label("api"),
+    );
+    let client = |ip| request().header("X-Forwarded-For", ip);
+
+    client("1.1.1.1").filter(&filter).await.unwrap();
+    client("2.2.2.2").filter(&filter).await.unwrap();
+    assert!(capture.events("window reset").is_empty());
+    tokio::time::sleep(Duration::from_millis(250)).await;
+
+    // The next request prunes the idle clients, then starts a new window
+    client("1.1.1.1").filter(&filter).await.unwrap();
+    let evicted = capture.events("idle clients evicted");
+    assert_eq!(evicted.len(), 1);
+    assert_eq!(evicted[0]["evicted"], "2");
+    assert_eq!(evicted[0]["tracked"], "0");
+    assert_eq!(evicted[0]["label"], "api");
+
+    client("1.1.1.1").filter(&filter).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    client("1.1.1.1").filter(&filter).await.unwrap();
+    let reset = capture.events("window reset");
+    assert_eq!(reset.len(), 1);
+    assert_eq!(reset[0]["window_secs"], "0.05");
+}
+
+#[tokio::test]
+async fn test_traced_request_span() {
+    let (capture, _guard) = capture();
+    let route = warp::path!("hello")
+        .and(RateLimitConfig::max_per_minute(1).traced())
+        .map(|_| "Hello world")
+        .recover(handle_rate_limit_rejection)
+        .with(trace_request());
+    let client = || {
+        request()
+            .path("/hello")
+            .header("X-Forwarded-For", "1.2.3.4")
+            .header("Referer", "https://example.com/")
+    };
+    assert_eq!(client().reply(&route).await.status(), 200);
+    assert_eq!(client().reply(&route).await.status(), 429);
+
+    // The limiter's fields land in warp's request span, not in a detached one
+    let requests: Vec<_> = capture
+        .spans()
+        .into_iter()
+        .filter(|span| span.contains_key("method"))
+        .collect();
+    assert_eq!(requests.len(), 2);
+    for (span, outcome) in requests.iter().zip(["allowed", "rejected"]) {
+        assert_eq!(span["path"], "/hello");
+        assert_eq!(span["method"], "GET");
+        assert_eq!(span["version"], "HTTP/1.1");
+        assert_eq!(span["referer"], "https://example.com/");
+        assert_eq!(span["ratelimit.outcome"], outcome);
+        assert_eq!(span["ratelimit.remaining"], "0");
+        assert_eq!(
+            span["ratelimit.key_hash"],
+            requests[0]["ratelimit.key_hash"]
+        );
+        assert!(!span.values().any(|value| value.contains("1.2.3.4")));
+    }
+}
+
+#[test]
+fn test_try_traced_invalid_config() {
+    let config = RateLimitConfig::default().with_window(Duration::ZERO);
+    assert_eq!(config.try_traced().err(), Some(ConfigError::ZeroWindow));
+}
+
+#[tokio::test]
+async fn test_key_tag_sampling() {
+    let (capture, _guard) = capture();
+    let filter = with_rate_limit(
+        RateLimitConfig::max_per_minute(10_000)
+            .with_heavy_hitters(2)
+            .with_key_override("9.9.9.9", 2, Duration::from_secs(60))
+            .with_heavy_usage(0.5),
+    );
+    let send = |ip: String| {
+        let filter = filter.clone();
+        async move {
+            let _ = request()
+                .header("X-Forwarded-For", ip)
+                .filter(&filter)
+                .await;
+        }
+    };
+    for i in 0..100 {
+        send(String::from("1.1.1.1")).await;
+        send(format!("10.0.0.{}", i)).await;
+    }
+    // Traced under its own key once it used half of its limit, heavy hitter or not
+    send(String::from("9.9.9.9")).await;
+
+    let tags: Vec<_> = capture
+        .events("request admitted")
+        .into_iter()
+        .map(|fields| fields["key_tag"].clone())
+        .collect();
+    assert_eq!(tags.len(), 201);
+    // The dominant client is always reported on its own
+    assert!(tags[..200].iter().step_by(2).all(|tag| tag == "1.1.1.1"));
+    // The long tail collapses into "other", but for the requests taking the counter
+    // the sketch has left, which only lasts until the next client comes in
+    let long_tail = tags[1..200].iter().step_by(2);
+    assert_eq!(long_tail.filter(|tag| *tag == "other").count(), 50);
+    assert_eq!(tags[200], "9.9.9.9");
+}
+
+#[tokio::test]
+async fn test_clear_all_event() {
+    let (capture, _guard) = capture();
+    let limiter =
+        RateLimiterHandle::new(RateLimitConfig::max_per_minute(2).with_label("api")).unwrap();
+    let _ = request().filter(&limiter.filter()).await;
+    limiter.clear_all().await;
+
+    let cleared = capture.events("state cleared");
+    assert_eq!(cleared.len(), 1);
+    assert_eq!(cleared[0]["label"], "api");
+    assert_eq!(cleared[0]["cleared"], "1");
+}